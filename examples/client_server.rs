@@ -1,84 +1,100 @@
-use std::collections::HashMap;
 use std::net::UdpSocket;
 use std::time::{Duration, Instant};
-use byteorder::{BigEndian, ReadBytesExt};
-use udp_connections::{Client, ClientEvent, Endpoint, MAX_PACKET_SIZE, MessageChannel, NetworkOptions, Server, ServerEvent, TransportExtension};
+use udp_connections::{ChannelError, Channels, Client, ClientEvent, MAX_PACKET_SIZE, MessageChannel, NetworkOptions, Server, ServerChannels, ServerEvent, TransportExtension};
 
 const SERVER: &str = "127.0.0.1:23452";
 const IDENTIFIER: &str = "udp_connections_demo";
 const NETWORK_CONFIG: NetworkOptions = NetworkOptions {
-    packet_loss: 0.25
+    packet_loss: 0.25,
+    packet_corruption: 0.0,
+    packet_reordering: 0.0
 };
 
 fn client() {
     std::thread::sleep(Duration::from_secs_f32(0.5));
-    let socket = UdpSocket::bind(Endpoint::local_any()).unwrap();
-    socket.set_nonblocking(true).unwrap();
-    let mut socket = Client::new(socket.with_options(NETWORK_CONFIG), IDENTIFIER);
+    let mut socket = Client::bind(IDENTIFIER).unwrap();
     let prefix = format!("[Client {}]", socket.local_addr().unwrap());
     println!("{} starting up", prefix);
-    socket.connect(SERVER.parse().unwrap());
+    socket.connect(SERVER).unwrap();
 
-    let mut msg_channel = None;
+    // one channel for the whole lifetime of the client, it is reset whenever a connection ends.
+    // The server wraps its channels in `Channels`, so the client has to do the same.
+    let mut msg_channel = Channels::new([MessageChannel::new()]);
     let mut buffer = [0u8; MAX_PACKET_SIZE];
     let mut i = 1u32;
     let mut last_message = Instant::now();
+    let mut pending = None;
     'outer: loop {
         socket.update();
-        while let Some(event) = socket.next_event(&mut buffer).unwrap() {
+        while let Some(event) = socket.next_event_into(&mut buffer).unwrap() {
             match event {
                 ClientEvent::Connected(id) => {
                     println!("{} Connected as {}", prefix, id);
-                    msg_channel = Some(MessageChannel::new());
                 },
                 ClientEvent::Disconnected(reason) => {
                     println ! ("{} Disconnected: {:?}", prefix, reason);
-                    msg_channel = None;
+                    let undelivered = msg_channel.channel_mut(0).unwrap().reset();
+                    println!("{} {} messages were not delivered", prefix, undelivered.len());
                     break 'outer
                 },
-                ClientEvent::PacketReceived(_, payload) => {
+                ClientEvent::PacketReceived(seq, result, payload) => {
                     //let val = payload.read_u32::<BigEndian>().unwrap();
-                    let mc = &mut msg_channel.as_mut().unwrap();
-                    mc.on_receive(payload).unwrap();
-                    while let Some(packet) = mc.receive_message() {
-                        let mut packet= packet.as_ref();
-                        let val = packet.read_u32::<BigEndian>().unwrap();
+                    msg_channel.on_receive(payload).unwrap();
+                    for (_, val) in msg_channel.receive_messages() {
+                        let val = u32::from_be_bytes(val.as_ref().try_into().unwrap());
                         let connection = socket.connection().unwrap();
-                        println ! ("{} Packet {} ({} ms / {:.2} pl)", prefix, val, connection.rtt(), connection.packet_loss());
+                        println ! ("{} Packet {} in #{} {:?} ({} ms / {:.2} pl)", prefix, val, seq, result, connection.rtt(), connection.packet_loss());
                         //if val >= 100 {
                         //    socket.disconnect().unwrap();
                         //}
                     }
 
                 },
-                ClientEvent::PacketAcknowledged(seq) => {
+                ClientEvent::PacketAcknowledged(seq, _, _) => {
                     //println!("{} got acknowledged", seq);
-                    msg_channel.as_mut().unwrap().on_ack(seq);
+                    msg_channel.on_ack(seq);
+                }
+                ClientEvent::Connecting(_) => {}
+                ClientEvent::PacketLost(seq, _) => {
+                    msg_channel.on_loss(seq);
                 }
-                ClientEvent::PacketLost(_) => {}
+                ClientEvent::Pong(..) | ClientEvent::PongTimeout(_) => {}
+                ClientEvent::QualityChanged(quality) => println!("{} Connection quality: {:?}", prefix, quality),
+                ClientEvent::QueryResponse(..) | ClientEvent::ReliableMessage(_) => {}
+                ClientEvent::SocketError(kind) => println!("{} Socket error: {:?}", prefix, kind),
             }
         }
 
-        if let Some(mc) = msg_channel.as_mut(){
-            if mc.has_unsend_messages() {
-                let seq = socket.connection().unwrap().peek_next_sequence_number();
-                socket.send(mc.send_packets(seq).unwrap()).unwrap();
+        // nothing is batched here, otherwise the batch would have to be flushed before peeking the sequence number
+        if let Ok(connection) = socket.connection() {
+            msg_channel.set_resend_interval(connection.resend_interval());
+            if msg_channel.has_due_messages() {
+                let seq = connection.peek_next_sequence_number();
+                socket.send(msg_channel.send_packets(seq).unwrap()).unwrap();
             }
         }
 
 
         if socket.is_connected() {
-            if last_message.elapsed() >= Duration::from_secs_f32(0.5) {
-                msg_channel.as_mut().unwrap().queue_message(&i.to_be_bytes()).unwrap();
+            if pending.is_none() && last_message.elapsed() >= Duration::from_secs_f32(0.5) {
+                pending = Some(i);
                 last_message = Instant::now();
                 i += 1;
             }
+            if let Some(msg) = pending.take() {
+                match msg_channel.queue_message(0, &msg.to_be_bytes()) {
+                    Ok(()) => {}
+                    // the message is queued again on the next tick
+                    Err(ChannelError::QueueFull { .. }) => pending = Some(msg),
+                    Err(err) => panic!("{}", err)
+                }
+            }
         }
 
         std::thread::sleep(Duration::from_millis(10));
     }
 
-    assert!(msg_channel.is_none());
+    assert!(!msg_channel.has_unsend_messages());
     std::thread::sleep(Duration::from_secs_f32(0.5));
 
     println!("{} shutting down", prefix);
@@ -93,57 +109,37 @@ fn main(){
     let mut socket = Server::new(socket.with_options(NETWORK_CONFIG), IDENTIFIER, 1);
     let prefix = format!("[Server {}]", socket.local_addr().unwrap());
 
-    let mut message_channels = HashMap::new();
-    //let mut i = 0u32;
+    let mut channels = ServerChannels::default();
     let mut buffer = [0u8; MAX_PACKET_SIZE];
     'outer: loop  {
         socket.update();
-        while let Some(event) = socket.next_event(&mut buffer).unwrap() {
+        while let Some(event) = socket.next_event_into(&mut buffer).unwrap() {
+            channels.handle_event(&event).unwrap();
             match event {
-                ServerEvent::ClientConnected(client_id) => {
-                    println!("{} Client {} connected", prefix, client_id);
-                    message_channels.insert(client_id, MessageChannel::new());
-                },
+                ServerEvent::ClientConnected(client_id, addrs, _) => println!("{} Client {} connected from {}", prefix, client_id, addrs),
                 ServerEvent::ClientDisconnected(client_id, reason) => {
                     println!("{} Client {} disconnected: {:?}", prefix, client_id, reason);
-                    message_channels.remove(&client_id);
                     if socket.connected_clients().count() == 0 {
                         break 'outer;
                     }
                 },
-                ServerEvent::PacketReceived(client_id, _, payload) => {
-                    //let val = payload.read_u32::<BigEndian>().unwrap();
-                    //println!("{} Packet {} from {}", prefix, val, client_id);
-                    //socket.send(client_id, &val.to_be_bytes()).unwrap();
-                    let mc = &mut message_channels.get_mut(&client_id).unwrap();
-                    mc.on_receive(payload).unwrap();
-                    while let Some(packet) = mc.receive_message() {
-                        let mut packet= packet.as_ref();
-                        let val = packet.read_u32::<BigEndian>().unwrap();
-                        // println ! ("{} Packet {} from {}", prefix, val, client_id);
-                        mc.queue_message(&val.to_be_bytes()).unwrap();
-                    }
-                },
-                ServerEvent::PacketAcknowledged(client_id, seq) => {
-                    message_channels.get_mut(&client_id).unwrap().on_ack(seq);
-                }
-                ServerEvent::PacketLost(_, _) => {}
+                ServerEvent::ConnectionDenied(addrs, reason) => println!("{} Denied {}: {:?}", prefix, addrs, reason),
+                ServerEvent::ClientMigrated(client_id, _, addrs) => println!("{} Client {} moved to {}", prefix, client_id, addrs),
+                ServerEvent::QualityChanged(client_id, quality) => println!("{} Client {} connection quality: {:?}", prefix, client_id, quality),
+                ServerEvent::SocketError(kind) => println!("{} Socket error: {:?}", prefix, kind),
+                _ => {}
             }
         }
 
-        for (id, channel) in message_channels.iter_mut() {
-            if channel.has_unsend_messages() {
-                let seq = socket.connection(*id).unwrap().peek_next_sequence_number();
-                socket.send(*id, channel.send_packets(seq).unwrap()).unwrap();
-            }
+        // every message is echoed back to its sender
+        let received: Vec<_> = channels.receive_messages().collect();
+        for (client_id, _, msg) in received {
+            channels.queue(client_id, &msg).unwrap();
         }
+        channels.flush(&mut socket);
 
-        //if i % 10 == 0 {
-        //    socket.broadcast(&i.to_be_bytes()).unwrap();
-        //}
-        //i += 1;
         std::thread::sleep(Duration::from_millis(10));
     }
 
     c1.join().unwrap();
-}
\ No newline at end of file
+}
@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use crate::constants::MESSAGE_PACKET_BUDGET;
+use crate::error::{ChannelError, ConnectionError};
+use crate::reliable::{MessageChannel, Priority};
+use crate::sequencing::SequenceNumber;
+use crate::server::{Server, ServerEvent};
+use crate::wire::{read_slice, ReadVarint, varint_len, WriteVarint};
+
+/// Combines several [`MessageChannel`]s into a single payload per packet. Each channel is identified by its index.
+///
+/// Both peers have to use the same channels in the same order.
+#[derive(Debug, Clone)]
+pub struct Channels {
+    buffer: Vec<u8>,
+    channels: Vec<MessageChannel>,
+    packet_budget: usize
+}
+
+impl Channels {
+
+    /// # Panics
+    /// If there are more than 256 channels
+    pub fn new(channels: impl IntoIterator<Item=MessageChannel>) -> Self {
+        let channels: Vec<MessageChannel> = channels.into_iter().collect();
+        assert!(channels.len() <= u8::MAX as usize + 1, "too many channels");
+        Self {
+            buffer: Vec::new(),
+            channels,
+            packet_budget: MESSAGE_PACKET_BUDGET
+        }
+    }
+
+    /// The number of bytes a packet of all channels together takes up at most.
+    /// The budgets of the individual channels still apply to their parts.
+    pub fn with_packet_budget(self, packet_budget: usize) -> Self {
+        Self {
+            packet_budget,
+            ..self
+        }
+    }
+
+    pub fn packet_budget(&self) -> usize {
+        self.packet_budget
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn channel(&self, channel: u8) -> Option<&MessageChannel> {
+        self.channels.get(channel as usize)
+    }
+
+    pub fn channel_mut(&mut self, channel: u8) -> Option<&mut MessageChannel> {
+        self.channels.get_mut(channel as usize)
+    }
+
+    pub fn queue_message(&mut self, channel: u8, msg: &[u8]) -> std::result::Result<(), ChannelError> {
+        self.channel_mut(channel)
+            .ok_or(ChannelError::UnknownChannel(channel))?
+            .queue_message(msg)
+    }
+
+    pub fn queue_message_with_priority(&mut self, channel: u8, msg: &[u8], priority: Priority) -> std::result::Result<(), ChannelError> {
+        self.channel_mut(channel)
+            .ok_or(ChannelError::UnknownChannel(channel))?
+            .queue_message_with_priority(msg, priority)
+    }
+
+    /// See [`MessageChannel::queue_message_owned`]
+    pub fn queue_message_owned(&mut self, channel: u8, msg: impl Into<Vec<u8>>) -> std::result::Result<(), ChannelError> {
+        self.channel_mut(channel)
+            .ok_or(ChannelError::UnknownChannel(channel))?
+            .queue_message_owned(msg)
+    }
+
+    pub fn receive_message(&mut self, channel: u8) -> Option<Box<[u8]>> {
+        self.channel_mut(channel)?.receive_message()
+    }
+
+    /// Receives the messages of all channels, one channel after another
+    pub fn receive_messages(&mut self) -> impl Iterator<Item=(u8, Box<[u8]>)> + '_ {
+        self.channels
+            .iter_mut()
+            .enumerate()
+            .flat_map(|(id, channel)| channel.receive_messages().map(move |msg| (id as u8, msg)))
+    }
+
+    pub fn on_receive(&mut self, mut packet: &[u8]) -> Result<()> {
+        while !packet.is_empty() {
+            let id = packet.read_u8()?;
+            let size = packet.read_varint()? as usize;
+            let part = read_slice(&mut packet, size)?;
+            self.channels
+                .get_mut(id as usize)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "unknown channel"))?
+                .on_receive(part)?;
+        }
+        Ok(())
+    }
+
+    pub fn on_ack(&mut self, seq: SequenceNumber) {
+        for channel in &mut self.channels {
+            channel.on_ack(seq);
+        }
+    }
+
+    pub fn on_loss(&mut self, seq: SequenceNumber) {
+        for channel in &mut self.channels {
+            channel.on_loss(seq);
+        }
+    }
+
+    /// Every channel with unsent messages adds its part, prefixed by its id and length.
+    /// Channels with lower ids get the first pick of the budget.
+    /// `seq` has to be the sequence number of the packet that carries the result, see
+    /// [`MessageChannel::send_packets`].
+    pub fn send_packets(&mut self, seq: SequenceNumber) -> Result<&[u8]> {
+        let packet = &mut self.buffer;
+        packet.clear();
+        for (id, channel) in self.channels.iter_mut().enumerate() {
+            let remaining = self.packet_budget.saturating_sub(packet.len());
+            let header = 1 + varint_len(remaining as u32);
+            if remaining <= header {
+                break;
+            }
+            if channel.has_unsend_messages() {
+                let budget = (remaining - header).min(channel.packet_budget());
+                let part = channel.write_packet(seq, budget)?;
+                if part == [0] {
+                    continue;
+                }
+                packet.write_u8(id as u8)?;
+                packet.write_varint(part.len() as u32)?;
+                packet.extend_from_slice(part);
+            }
+        }
+        Ok(packet.as_slice())
+    }
+
+    pub fn has_unsend_messages(&self) -> bool {
+        self.channels.iter().any(MessageChannel::has_unsend_messages)
+    }
+
+    pub fn has_due_messages(&self) -> bool {
+        self.channels.iter().any(MessageChannel::has_due_messages)
+    }
+
+    /// Sets the resend interval of all channels
+    pub fn set_resend_interval(&mut self, resend_interval: Duration) {
+        for channel in &mut self.channels {
+            channel.set_resend_interval(resend_interval);
+        }
+    }
+
+}
+
+/// One [`Channels`] per client of a [`Server`], created when the client connects and dropped when it disconnects.
+/// Every payload the server sends or receives is expected to be a packet of these channels.
+/// Pass every event to [`handle_event`](Self::handle_event) and call [`flush`](Self::flush) once per update.
+#[derive(Debug, Clone)]
+pub struct ServerChannels {
+    template: Channels,
+    clients: HashMap<u16, Channels>
+}
+
+impl ServerChannels {
+
+    /// Every client gets a clone of `template`, which should not have been used yet
+    pub fn new(template: Channels) -> Self {
+        Self {
+            template,
+            clients: HashMap::new()
+        }
+    }
+
+    pub fn channels(&self, client_id: u16) -> Option<&Channels> {
+        self.clients.get(&client_id)
+    }
+
+    pub fn channels_mut(&mut self, client_id: u16) -> Option<&mut Channels> {
+        self.clients.get_mut(&client_id)
+    }
+
+    /// Creates and drops the channels of clients and passes received packets, acks and losses to them.
+    /// Fails if a received payload is not a valid packet of the channels.
+    pub fn handle_event(&mut self, event: &ServerEvent) -> Result<()> {
+        match event {
+            ServerEvent::ClientConnected(id, _, _) => {
+                self.clients.insert(*id, self.template.clone());
+            }
+            ServerEvent::ClientDisconnected(id, _) => {
+                self.clients.remove(id);
+            }
+            ServerEvent::PacketReceived(id, _, _, data) => if let Some(channels) = self.clients.get_mut(id) {
+                channels.on_receive(data)?;
+            },
+            ServerEvent::PacketAcknowledged(id, seq, _, _) => if let Some(channels) = self.clients.get_mut(id) {
+                channels.on_ack(*seq);
+            },
+            ServerEvent::PacketLost(id, seq, _) => if let Some(channels) = self.clients.get_mut(id) {
+                channels.on_loss(*seq);
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Queues the message on the first channel of the client
+    pub fn queue(&mut self, client_id: u16, msg: &[u8]) -> std::result::Result<(), ConnectionError> {
+        self.queue_on(client_id, 0, msg)
+    }
+
+    pub fn queue_on(&mut self, client_id: u16, channel: u8, msg: &[u8]) -> std::result::Result<(), ConnectionError> {
+        let channels = self.clients.get_mut(&client_id).ok_or(ConnectionError::Disconnected)?;
+        Ok(channels.queue_message(channel, msg)?)
+    }
+
+    /// Queues the message on the first channel of every client
+    pub fn broadcast_reliable(&mut self, msg: &[u8]) -> Vec<(u16, std::result::Result<(), ChannelError>)> {
+        self.clients
+            .iter_mut()
+            .map(|(id, channels)| (*id, channels.queue_message(0, msg)))
+            .collect()
+    }
+
+    /// Receives the messages of all clients with the channel they arrived on
+    pub fn receive_messages(&mut self) -> impl Iterator<Item=(u16, u8, Box<[u8]>)> + '_ {
+        self.clients
+            .iter_mut()
+            .flat_map(|(id, channels)| channels.receive_messages().map(move |(channel, msg)| (*id, channel, msg)))
+    }
+
+    /// Sends a packet to every client with due messages. Returns the clients the packet could not be sent to.
+    /// Their messages are sent again by a later call. Payloads batched with [`Server::send_batched`] are sent first.
+    pub fn flush<D>(&mut self, server: &mut Server<D>) -> Vec<(u16, ConnectionError)> {
+        // a pending batch would take the sequence number the channels are about to use
+        server.flush();
+        let mut failed = Vec::new();
+        for (id, channels) in &mut self.clients {
+            let Ok(connection) = server.connection(*id) else {
+                continue;
+            };
+            channels.set_resend_interval(connection.resend_interval());
+            if !channels.has_due_messages() {
+                continue;
+            }
+            let seq = connection.peek_next_sequence_number();
+            // the sequence number might not be used by the server, so a later ack for it could belong to another packet
+            let Ok(packet) = channels.send_packets(seq) else {
+                channels.on_loss(seq);
+                continue;
+            };
+            match server.send(*id, packet) {
+                Ok(sent) if sent == seq => {},
+                Ok(_) => channels.on_loss(seq),
+                Err(err) => {
+                    channels.on_loss(seq);
+                    failed.push((*id, err));
+                }
+            }
+        }
+        failed
+    }
+
+}
+
+impl Default for ServerChannels {
+    /// A single ordered channel per client
+    fn default() -> Self {
+        Self::new(Channels::new([MessageChannel::new()]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::channels::Channels;
+    use crate::reliable::MessageChannel;
+
+    fn channels() -> Channels {
+        Channels::new([MessageChannel::new_ordered(), MessageChannel::new_unordered(), MessageChannel::new_unreliable()]
+            .map(|channel| channel.with_resend_interval(Duration::ZERO)))
+    }
+
+    #[test]
+    fn test_multiplexing() {
+        let mut sender = channels();
+        let mut receiver = channels();
+        assert_eq!(sender.channel_count(), 3);
+        sender.queue_message(0, &[1]).unwrap();
+        sender.queue_message(2, &[3]).unwrap();
+        sender.queue_message(0, &[2]).unwrap();
+        assert!(sender.queue_message(3, &[4]).is_err());
+
+        let packet = sender.send_packets(1).unwrap();
+        assert_eq!(packet, [0, 7, 2, 1, 1, 1, 1, 1, 2, 2, 4, 1, 1, 1, 3]);
+        receiver.on_receive(packet).unwrap();
+        assert_eq!(receiver.receive_message(2).unwrap().as_ref(), [3]);
+        assert!(receiver.receive_message(1).is_none());
+        assert!(receiver.receive_message(7).is_none());
+        assert_eq!(receiver.receive_messages().map(|(id, msg)| (id, msg[0])).collect::<Vec<_>>(), [(0, 1), (0, 2)]);
+
+        // the unreliable message is only sent once
+        assert!(sender.has_unsend_messages());
+        sender.queue_message(1, &[5]).unwrap();
+        let packet = sender.send_packets(2).unwrap().to_vec();
+        assert_eq!(packet[0], 0);
+        assert_eq!(packet[9..], [1, 4, 1, 1, 1, 5]);
+        sender.on_ack(2);
+        assert!(!sender.has_unsend_messages());
+        assert!(sender.send_packets(3).unwrap().is_empty());
+
+        receiver.on_receive(&packet).unwrap();
+        assert_eq!(receiver.receive_messages().map(|(id, msg)| (id, msg[0])).collect::<Vec<_>>(), [(1, 5)]);
+    }
+
+    #[test]
+    fn test_malformed() {
+        let mut receiver = channels();
+        assert!(receiver.on_receive(&[3, 2, 1, 1]).is_err());
+        assert!(receiver.on_receive(&[0, 5, 1, 1]).is_err());
+        assert!(receiver.on_receive(&[]).is_ok());
+    }
+
+    #[test]
+    fn test_packet_budget() {
+        let mut sender = channels().with_packet_budget(600);
+        let mut receiver = channels();
+        for i in 0..30u8 {
+            sender.queue_message(i % 2, &[i; 100]).unwrap();
+        }
+        let mut received = Vec::new();
+        for seq in 0..20 {
+            let packet = sender.send_packets(seq).unwrap();
+            assert!(packet.len() <= 600);
+            receiver.on_receive(packet).unwrap();
+            received.extend(receiver.receive_messages().map(|(id, msg)| (id, msg[0])));
+            sender.on_ack(seq);
+        }
+        assert!(!sender.has_unsend_messages());
+        received.sort();
+        assert_eq!(received, (0..2).flat_map(|id| (id..30).step_by(2).map(move |i| (id, i))).collect::<Vec<_>>());
+    }
+
+}
@@ -1,40 +1,224 @@
-use std::collections::VecDeque;
-use std::net::SocketAddr;
-use std::io::ErrorKind;
-use std::time::Instant;
-use crate::connection::{PacketSocket, VirtualConnection};
-use crate::constants::{CONNECTION_TIMEOUT, KEEPALIVE_INTERVAL};
-use crate::error::{ConnectionError, IOResult};
-use crate::packets::Packet;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+use crate::connection::{CongestionState, ConnectionConfig, ConnectionStats, Delivery, DeliveryQueue, NetworkQuality, PacketSocket, PendingPayloads, Polled, SocketStats, VirtualConnection};
+use crate::constants::{CONNECTION_RETRY_INTERVAL, CONNECTION_TIMEOUT, DISCONNECT_TIMEOUT, MAX_CONNECTION_ATTEMPTS, MAX_CONNECTION_PAYLOAD_SIZE, PROBE_INTERVAL};
+use crate::error::{ConnectError, ConnectionError, IOResult, is_transient};
+use crate::packets::{Authentication, ConnectionNonce, DenyReason, DisconnectCode, generate_token, Packet, SessionKeys};
 use crate::sequencing::{SequenceNumber, SequenceResult};
-use crate::socket::Transport;
+use crate::socket::{Endpoint, Transport};
 
 #[derive(Debug, Clone)]
 pub enum ClientDisconnectReason {
     Disconnected,
     TimedOut,
-    ConnectionDenied,
+    /// The server denied the connection request, or its answer did not match the [`Authentication`] mode of the client
+    ConnectionDenied(DenyReason),
+    /// The server no longer knows the connection, usually because it restarted
+    ConnectionReset,
+    /// The server disconnected the client with [`Server::kick`](crate::Server::kick)
+    Kicked(DisconnectCode),
     SocketError(ErrorKind)
 }
 
 #[derive(Debug, Clone)]
 pub enum ClientEvent<'a> {
+    /// A connection request was sent. Contains the number of requests sent so far.
+    Connecting(u32),
     Connected(u16),
     Disconnected(ClientDisconnectReason),
-    PacketReceived(bool, &'a [u8]),
-    PacketAcknowledged(SequenceNumber),
-    PacketLost(SequenceNumber)
+    /// A payload with its sequence number and whether it is the newest one received so far ([`SequenceResult::Latest`])
+    /// or arrived out of order ([`SequenceResult::Fresh`]). The payloads of a batch share the sequence number of their packet.
+    PacketReceived(SequenceNumber, SequenceResult, &'a [u8]),
+    /// A packet was acknowledged, with its tag and the unsmoothed round trip time it took
+    PacketAcknowledged(SequenceNumber, u64, Duration),
+    /// A packet was lost, with its tag
+    PacketLost(SequenceNumber, u64),
+    /// The answer to [`Client::ping`] with the measured round trip time
+    Pong(SequenceNumber, Duration),
+    PongTimeout(SequenceNumber),
+    QualityChanged(NetworkQuality),
+    QueryResponse(SocketAddr, Duration, &'a [u8]),
+    /// A message sent with [`Client::send_reliable`] on the other side. Messages arrive exactly once and in order.
+    ReliableMessage(Box<[u8]>),
+    /// Receiving failed with a transient error. Repeated errors of the same kind are only reported once
+    /// until a packet was received again.
+    SocketError(ErrorKind)
+}
+
+impl ClientEvent<'_> {
+
+    fn payload(&self) -> &[u8] {
+        match self {
+            ClientEvent::PacketReceived(_, _, data) => data,
+            ClientEvent::QueryResponse(_, _, data) => data,
+            _ => &[]
+        }
+    }
+
+    fn with_payload(self, payload: &[u8]) -> ClientEvent<'_> {
+        match self {
+            ClientEvent::Connecting(attempt) => ClientEvent::Connecting(attempt),
+            ClientEvent::Connected(id) => ClientEvent::Connected(id),
+            ClientEvent::Disconnected(reason) => ClientEvent::Disconnected(reason),
+            ClientEvent::PacketReceived(seq, result, _) => ClientEvent::PacketReceived(seq, result, payload),
+            ClientEvent::PacketAcknowledged(seq, tag, rtt) => ClientEvent::PacketAcknowledged(seq, tag, rtt),
+            ClientEvent::PacketLost(seq, tag) => ClientEvent::PacketLost(seq, tag),
+            ClientEvent::Pong(seq, rtt) => ClientEvent::Pong(seq, rtt),
+            ClientEvent::PongTimeout(seq) => ClientEvent::PongTimeout(seq),
+            ClientEvent::QualityChanged(quality) => ClientEvent::QualityChanged(quality),
+            ClientEvent::QueryResponse(addrs, rtt, _) => ClientEvent::QueryResponse(addrs, rtt, payload),
+            ClientEvent::ReliableMessage(msg) => ClientEvent::ReliableMessage(msg),
+            ClientEvent::SocketError(kind) => ClientEvent::SocketError(kind)
+        }
+    }
+
+}
+
+/// A [`ClientEvent`] that owns its payload, so it can be sent to other threads
+#[derive(Debug, Clone)]
+pub enum ClientEventOwned {
+    Connecting(u32),
+    Connected(u16),
+    Disconnected(ClientDisconnectReason),
+    PacketReceived(SequenceNumber, SequenceResult, Box<[u8]>),
+    PacketAcknowledged(SequenceNumber, u64, Duration),
+    PacketLost(SequenceNumber, u64),
+    Pong(SequenceNumber, Duration),
+    PongTimeout(SequenceNumber),
+    QualityChanged(NetworkQuality),
+    QueryResponse(SocketAddr, Duration, Box<[u8]>),
+    ReliableMessage(Box<[u8]>),
+    SocketError(ErrorKind)
+}
+
+impl From<ClientEvent<'_>> for ClientEventOwned {
+    fn from(event: ClientEvent<'_>) -> Self {
+        match event {
+            ClientEvent::Connecting(attempt) => ClientEventOwned::Connecting(attempt),
+            ClientEvent::Connected(id) => ClientEventOwned::Connected(id),
+            ClientEvent::Disconnected(reason) => ClientEventOwned::Disconnected(reason),
+            ClientEvent::PacketReceived(seq, result, data) => ClientEventOwned::PacketReceived(seq, result, data.into()),
+            ClientEvent::PacketAcknowledged(seq, tag, rtt) => ClientEventOwned::PacketAcknowledged(seq, tag, rtt),
+            ClientEvent::PacketLost(seq, tag) => ClientEventOwned::PacketLost(seq, tag),
+            ClientEvent::Pong(seq, rtt) => ClientEventOwned::Pong(seq, rtt),
+            ClientEvent::PongTimeout(seq) => ClientEventOwned::PongTimeout(seq),
+            ClientEvent::QualityChanged(quality) => ClientEventOwned::QualityChanged(quality),
+            ClientEvent::QueryResponse(addrs, rtt, data) => ClientEventOwned::QueryResponse(addrs, rtt, data.into()),
+            ClientEvent::ReliableMessage(msg) => ClientEventOwned::ReliableMessage(msg),
+            ClientEvent::SocketError(kind) => ClientEventOwned::SocketError(kind)
+        }
+    }
+}
+
+/// Iterator over all currently available events of a [`Client`]. Created by [`Client::events`].
+#[derive(Debug)]
+pub struct ClientEvents<'a> {
+    client: &'a mut Client,
+    error: Option<Error>
+}
+
+impl ClientEvents<'_> {
+
+    /// The socket error that ended the iteration early, if any
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
+    }
+
+}
+
+impl Iterator for ClientEvents<'_> {
+    type Item = ClientEventOwned;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.error.is_some() {
+            return None;
+        }
+        match self.client.next_event_owned() {
+            Ok(event) => event,
+            Err(err) => {
+                self.error = Some(err);
+                None
+            }
+        }
+    }
+}
+
+/// Controls how a [`Client`] tries to reach a server
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ConnectConfig {
+    /// The time between two connection requests
+    pub retry_interval: Duration,
+    /// The number of requests per candidate address after which the client gives up on it
+    pub max_attempts: u32,
+    /// The time after which the client gives up regardless of the number of requests.
+    /// When the address resolved to multiple candidates, each candidate gets an equal share of it.
+    pub timeout: Duration
+}
+
+impl Default for ConnectConfig {
+    fn default() -> Self {
+        Self {
+            retry_interval: CONNECTION_RETRY_INTERVAL,
+            max_attempts: MAX_CONNECTION_ATTEMPTS,
+            timeout: CONNECTION_TIMEOUT
+        }
+    }
+}
+
+/// Everything needed to (re)connect to a server
+#[derive(Debug, Clone)]
+struct ConnectTarget {
+    candidates: Box<[SocketAddr]>,
+    payload: Box<[u8]>,
+    config: ConnectConfig
 }
 
 #[derive(Debug, Clone)]
 enum ClientState {
     Disconnected,
-    Connecting(SocketAddr, Instant),
+    Connecting {
+        candidates: Box<[SocketAddr]>,
+        candidate: usize,
+        candidate_start: Instant,
+        candidate_attempts: u32,
+        payload: Box<[u8]>,
+        config: ConnectConfig,
+        nonce: ConnectionNonce,
+        last_request: Option<Instant>,
+        attempts: u32,
+        reported: u32
+    },
     Connected(VirtualConnection),
+    /// Sending disconnect packets every `interval` until the server acknowledges,
+    /// `remaining` reaches zero or [`DISCONNECT_TIMEOUT`] expires
+    Closing {
+        connection: VirtualConnection,
+        start: Instant,
+        interval: Duration,
+        last_sent: Option<Instant>,
+        remaining: u32
+    },
     Disconnecting(ClientDisconnectReason)
 }
 
 impl ClientState {
+
+    fn connecting(target: &ConnectTarget) -> Self {
+        ClientState::Connecting {
+            candidates: target.candidates.clone(),
+            candidate: 0,
+            candidate_start: Instant::now(),
+            candidate_attempts: 0,
+            payload: target.payload.clone(),
+            config: target.config,
+            nonce: generate_token(),
+            last_request: None,
+            attempts: 0,
+            reported: 0
+        }
+    }
+
     pub fn get_connection(&self) -> Result<&VirtualConnection, ConnectionError> {
         match &self {
             ClientState::Connected(connection) => Ok(connection),
@@ -56,16 +240,48 @@ impl ClientState {
 pub struct Client {
     socket: PacketSocket,
     state: ClientState,
-    ack_queue: VecDeque<(SequenceNumber, bool)>
+    config: ConnectionConfig,
+    ack_queue: DeliveryQueue<(SequenceNumber, Delivery)>,
+    pending: PendingPayloads,
+    queries: Vec<(u32, SocketAddr, Instant)>,
+    last_connect: Option<ConnectTarget>,
+    /// Set when `connect` replaced an open connection whose disconnect has not been reported yet
+    superseded: bool
 }
 
 impl Client {
 
+    /// Creates a client on a new non-blocking UDP socket bound to any local IPv4 address.
+    /// Use `new` to supply a custom [`Transport`].
+    pub fn bind(identifier: &str) -> IOResult<Self> {
+        let socket = UdpSocket::bind(Endpoint::remote_any())?;
+        socket.set_nonblocking(true)?;
+        Ok(Self::new(socket, identifier))
+    }
+
     pub fn new<T: Transport + 'static>(socket: T, identifier: &str) -> Self{
+        Self::new_with_authentication(socket, identifier, Authentication::Checksum)
+    }
+
+    pub fn new_with_authentication<T: Transport + 'static>(socket: T, identifier: &str, authentication: Authentication) -> Self{
+        Self::from_config(socket, identifier, ConnectionConfig { authentication, ..Default::default() })
+    }
+
+    pub fn new_with_config<T: Transport + 'static>(socket: T, identifier: &str, config: ConnectionConfig) -> Result<Self, ConnectionError> {
+        config.validate()?;
+        Ok(Self::from_config(socket, identifier, config))
+    }
+
+    fn from_config<T: Transport + 'static>(socket: T, identifier: &str, config: ConnectionConfig) -> Self {
         Self {
-            socket: PacketSocket::new(socket, identifier),
+            socket: PacketSocket::new(socket, identifier, config.ack_width),
             state: ClientState::Disconnected,
-            ack_queue: VecDeque::new()
+            ack_queue: DeliveryQueue::new(config.delivery_queue_size),
+            config,
+            pending: PendingPayloads::default(),
+            queries: Vec::new(),
+            last_connect: None,
+            superseded: false
         }
     }
 
@@ -73,11 +289,24 @@ impl Client {
         self.socket.local_addr()
     }
 
+    pub fn socket_stats(&self) -> SocketStats {
+        SocketStats {
+            events_dropped: self.ack_queue.dropped(),
+            ..self.socket.stats()
+        }
+    }
+
+    pub fn reset_socket_stats(&mut self) {
+        self.socket.reset_stats();
+        self.ack_queue.reset_dropped();
+    }
+
     pub fn remote_addr(&self) -> Option<SocketAddr> {
         match &self.state {
             ClientState::Disconnected => None,
-            ClientState::Connecting(addrs, _) => Some(*addrs),
+            ClientState::Connecting { candidates, candidate, .. } => Some(candidates[*candidate]),
             ClientState::Connected(vc) => Some(vc.addrs()),
+            ClientState::Closing { connection, .. } => Some(connection.addrs()),
             ClientState::Disconnecting(_) => None,
         }
     }
@@ -90,117 +319,558 @@ impl Client {
         matches!(self.state, ClientState::Disconnected)
     }
 
-    pub fn connect(&mut self, addrs: SocketAddr) {
-        self.state = ClientState::Connecting(addrs, Instant::now());
+    pub fn connect<A: ToSocketAddrs>(&mut self, addrs: A) -> Result<(), ConnectError> {
+        self.connect_with(addrs, ConnectConfig::default())
     }
 
-    pub fn disconnect(&mut self) -> Result<(), ConnectionError> {
-        let connection = self.state.get_connection_mut()?;
-        let mut attempts = 10;
-        let reason = loop {
-            match self.socket.send_with (Packet::Disconnect, connection) {
-                Ok(_) => match attempts {
-                    0 | 1 => break ClientDisconnectReason::Disconnected,
-                    _ => attempts -= 1
-                },
-                Err(e) => break ClientDisconnectReason::SocketError(e.kind())
+    pub fn connect_with<A: ToSocketAddrs>(&mut self, addrs: A, config: ConnectConfig) -> Result<(), ConnectError> {
+        let candidates = self.resolve(addrs)?;
+        self.start_connecting(ConnectTarget { candidates, payload: Box::default(), config });
+        Ok(())
+    }
+
+    pub fn connect_with_payload<A: ToSocketAddrs>(&mut self, addrs: A, payload: &[u8]) -> Result<(), ConnectError> {
+        if payload.len() > MAX_CONNECTION_PAYLOAD_SIZE {
+            return Err(ConnectError::PayloadTooLarge { len: payload.len(), max: MAX_CONNECTION_PAYLOAD_SIZE });
+        }
+        let candidates = self.resolve(addrs)?;
+        self.start_connecting(ConnectTarget { candidates, payload: payload.into(), config: ConnectConfig::default() });
+        Ok(())
+    }
+
+    /// Resolves `addrs` and puts the addresses matching the family of the local socket first
+    fn resolve<A: ToSocketAddrs>(&self, addrs: A) -> Result<Box<[SocketAddr]>, ConnectError> {
+        let mut candidates: Vec<SocketAddr> = addrs
+            .to_socket_addrs()
+            .map_err(ConnectError::AddressResolution)?
+            .collect();
+        if let Ok(local) = self.local_addr() {
+            candidates.sort_by_key(|addrs| addrs.is_ipv4() != local.is_ipv4());
+        }
+        match candidates.is_empty() {
+            true => Err(ConnectError::NoUsableAddress),
+            false => Ok(candidates.into_boxed_slice())
+        }
+    }
+
+    /// Connects to the server of the last `connect` call again, using the same payload and config.
+    pub fn reconnect(&mut self) -> Result<(), ConnectionError> {
+        let target = self.last_connect.clone().ok_or(ConnectionError::NoRemoteAddress)?;
+        self.start_connecting(target);
+        Ok(())
+    }
+
+    /// An open connection is closed gracefully first: the disconnect is sent `disconnect_redundancy` times
+    /// right away and [`ClientEvent::Disconnected`] is reported before any event of the new connection.
+    fn start_connecting(&mut self, target: ConnectTarget) {
+        if let ClientState::Connected(connection) | ClientState::Closing { connection, .. } = &mut self.state {
+            for _ in 0..self.config.disconnect_redundancy {
+                if self.socket.send_with(Packet::Disconnect(None), connection).is_err() {
+                    break;
+                }
             }
+            self.superseded = true;
+        }
+        self.ack_queue.clear();
+        self.pending = PendingPayloads::default();
+        self.state = ClientState::connecting(&target);
+        self.last_connect = Some(target);
+    }
+
+    /// Sends an unconnected query to `addrs`. The answer is reported as [`ClientEvent::QueryResponse`].
+    /// Works in any state and does not occupy a slot on the server.
+    pub fn query(&mut self, addrs: SocketAddr) -> IOResult<()> {
+        self.queries.retain(|(_, _, sent)| sent.elapsed() < CONNECTION_TIMEOUT);
+        let token = generate_token();
+        self.socket.send_to(Packet::UnconnectedPing(token), addrs)?;
+        self.queries.push((token, addrs, Instant::now()));
+        Ok(())
+    }
+
+    /// Disconnects from the server. `update` resends the disconnect until the server acknowledges it
+    /// or [`DISCONNECT_TIMEOUT`] expires. Only then [`ClientEvent::Disconnected`] is emitted.
+    pub fn disconnect(&mut self) -> Result<(), ConnectionError> {
+        self.start_closing(CONNECTION_RETRY_INTERVAL)
+    }
+
+    /// Like `disconnect`, but `update` sends a disconnect on every call without waiting in between.
+    /// The connection is closed after `disconnect_redundancy` packets even without an acknowledgement.
+    pub fn disconnect_now(&mut self) -> Result<(), ConnectionError> {
+        self.start_closing(Duration::ZERO)
+    }
+
+    fn start_closing(&mut self, interval: Duration) -> Result<(), ConnectionError> {
+        let connection = self.state.get_connection()?.clone();
+        self.state = ClientState::Closing {
+            connection,
+            start: Instant::now(),
+            interval,
+            last_sent: None,
+            remaining: self.config.disconnect_redundancy
         };
-        self.state = ClientState::Disconnecting(reason);
         Ok(())
     }
 
+    /// The number of connection requests sent since the last call to `connect`
+    pub fn connection_attempts(&self) -> u32 {
+        match self.state {
+            ClientState::Connecting { attempts, .. } => attempts,
+            _ => 0
+        }
+    }
+
     pub fn connection(&self) -> Result<&VirtualConnection, ConnectionError> {
         self.state.get_connection()
     }
 
-    pub fn update(&mut self) {
+    pub fn max_payload_size(&self) -> Result<usize, ConnectionError> {
+        self.connection().map(VirtualConnection::max_payload_size)
+    }
+
+    /// The quality as of the last `update`. Changes are reported as [`ClientEvent::QualityChanged`].
+    pub fn quality(&self) -> Result<NetworkQuality, ConnectionError> {
+        self.connection().map(VirtualConnection::quality)
+    }
+
+    /// The number of payloads that were neither acknowledged nor declared lost yet
+    pub fn in_flight(&self) -> Result<usize, ConnectionError> {
+        self.connection().map(VirtualConnection::in_flight)
+    }
+
+    /// Whether the connection is in congestion avoidance as of the last `update`
+    pub fn congestion_state(&self) -> Result<CongestionState, ConnectionError> {
+        self.connection().map(VirtualConnection::congestion_state)
+    }
+
+    /// A snapshot of the current connection. All fields are zero while not connected.
+    pub fn stats(&self) -> ConnectionStats {
+        self.connection().map(VirtualConnection::stats).unwrap_or_default()
+    }
+
+    /// Sends retries, keepalives and acks that are due and checks for timeouts.
+    /// Returns the time until the next call is needed, see [`Client::next_update`].
+    pub fn update(&mut self) -> Duration {
+        self.send_due_packets();
+        self.next_update()
+    }
+
+    /// The time until `update` needs to be called again. [`Duration::ZERO`] means right away
+    /// and [`Duration::MAX`] that nothing is scheduled.
+    pub fn next_update(&self) -> Duration {
+        match &self.state {
+            ClientState::Disconnected | ClientState::Disconnecting(_) => Duration::MAX,
+            ClientState::Connecting { candidates, candidate_start, config, last_request, .. } => {
+                let candidate_timeout = (config.timeout / candidates.len() as u32).saturating_sub(candidate_start.elapsed());
+                let retry = last_request.map_or(Duration::ZERO, |last| config.retry_interval.saturating_sub(last.elapsed()));
+                candidate_timeout.min(retry)
+            }
+            ClientState::Connected(connection) => {
+                let next = connection.next_update(&self.config);
+                match connection.is_probing() {
+                    true => next.min(PROBE_INTERVAL.saturating_sub(connection.last_probe_send())),
+                    false => next
+                }
+            }
+            ClientState::Closing { start, interval, last_sent, remaining, .. } => match remaining {
+                0 => Duration::ZERO,
+                _ => {
+                    let retry = last_sent.map_or(Duration::ZERO, |last| interval.saturating_sub(last.elapsed()));
+                    DISCONNECT_TIMEOUT.saturating_sub(start.elapsed()).min(retry)
+                }
+            }
+        }
+    }
+
+    fn send_due_packets(&mut self) {
         match self.state {
-            ClientState::Connecting(remote, start) => {
-                if start.elapsed() > CONNECTION_TIMEOUT {
-                    self.state = ClientState::Disconnecting(ClientDisconnectReason::TimedOut)
+            ClientState::Connecting {
+                ref candidates, ref mut candidate, ref mut candidate_start, ref mut candidate_attempts,
+                ref payload, config, nonce, ref mut last_request, ref mut attempts, ..
+            } => {
+                let candidate_timeout = config.timeout / candidates.len() as u32;
+                let retry_due = !last_request.is_some_and(|last| last.elapsed() < config.retry_interval);
+                if candidate_start.elapsed() > candidate_timeout || (retry_due && *candidate_attempts >= config.max_attempts) {
+                    *candidate += 1;
+                    if *candidate >= candidates.len() {
+                        self.state = ClientState::Disconnecting(ClientDisconnectReason::TimedOut);
+                        return;
+                    }
+                    *candidate_start = Instant::now();
+                    *candidate_attempts = 0;
+                } else if !retry_due {
+                    return;
                 }
-                if let Err(e) = self.socket.send_to(Packet::ConnectionRequest, remote) {
-                    self.state = ClientState::Disconnecting(ClientDisconnectReason::SocketError(e.kind()));
+                let request = Packet::ConnectionRequest(self.config.authentication.mode(), cfg!(feature = "compression"), nonce, payload);
+                match self.socket.send_to(request, candidates[*candidate]) {
+                    Ok(()) => {
+                        *last_request = Some(Instant::now());
+                        *attempts += 1;
+                        *candidate_attempts += 1;
+                    }
+                    Err(e) => self.state = ClientState::Disconnecting(ClientDisconnectReason::SocketError(e.kind()))
                 }
             }
             ClientState::Connected(ref mut connection) => {
-                if connection.last_packet_send() > KEEPALIVE_INTERVAL {
+                connection.update_quality(&self.config.quality);
+                connection.update_congestion(&self.config.congestion);
+                if let Err(e) = self.socket.send_queued(connection) {
+                    self.state = ClientState::Disconnecting(ClientDisconnectReason::SocketError(e.kind()));
+                    return;
+                }
+                if let Err(e) = self.socket.flush(connection) {
+                    self.state = ClientState::Disconnecting(ClientDisconnectReason::SocketError(e.kind()));
+                    return;
+                }
+                if let Err(e) = self.socket.send_messages(connection, &self.config.congestion) {
+                    self.state = ClientState::Disconnecting(ClientDisconnectReason::SocketError(e.kind()));
+                    return;
+                }
+                if connection.ack_due() {
+                    if let Err(e) = self.socket.send_ack(connection) {
+                        self.state = ClientState::Disconnecting(ClientDisconnectReason::SocketError(e.kind()));
+                        return;
+                    }
+                }
+                if connection.is_probing() && connection.last_probe_send() > PROBE_INTERVAL {
+                    if let Err(e) = self.socket.send_probes(connection) {
+                        self.state = ClientState::Disconnecting(ClientDisconnectReason::SocketError(e.kind()));
+                        return;
+                    }
+                }
+                if connection.last_packet_send() > self.config.keepalive_interval {
                     if let Err(e) = self.socket.send_keepalive(connection) {
                         self.state = ClientState::Disconnecting(ClientDisconnectReason::SocketError(e.kind()));
                         return;
                     }
                 }
-                if connection.last_packet_received() > CONNECTION_TIMEOUT {
+                if connection.last_packet_received() > self.config.timeout {
                     self.state = ClientState::Disconnecting(ClientDisconnectReason::TimedOut);
                 }
             }
+            ClientState::Closing { ref mut connection, start, interval, ref mut last_sent, ref mut remaining } => {
+                if start.elapsed() > DISCONNECT_TIMEOUT || *remaining == 0 {
+                    self.state = ClientState::Disconnecting(ClientDisconnectReason::Disconnected);
+                    return;
+                }
+                if last_sent.is_some_and(|last| last.elapsed() < interval) {
+                    return;
+                }
+                match self.socket.send_with(Packet::Disconnect(None), connection) {
+                    Ok(()) => {
+                        *last_sent = Some(Instant::now());
+                        *remaining -= 1;
+                    }
+                    Err(e) => self.state = ClientState::Disconnecting(ClientDisconnectReason::SocketError(e.kind()))
+                }
+            }
             _ => {}
         }
     }
 
-    pub fn next_event<'a>(&mut self, payload: &'a mut [u8]) -> IOResult<Option<ClientEvent<'a>>> {
-        if let Some((seq, acked)) = self.ack_queue.pop_front() {
-            match acked {
-                true => return Ok(Some(ClientEvent::PacketAcknowledged(seq))),
-                false => return Ok(Some(ClientEvent::PacketLost(seq)))
+    /// Returns the next event and copies received payloads into `payload`.
+    /// Fails with [`ErrorKind::InvalidInput`] if the payload does not fit. The event is lost in that case.
+    pub fn next_event_into<'a>(&mut self, payload: &'a mut [u8]) -> IOResult<Option<ClientEvent<'a>>> {
+        match self.next_event()? {
+            None => Ok(None),
+            Some(event) => {
+                let data = event.payload();
+                let result = payload
+                    .get_mut(..data.len())
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "buffer too small for payload"))?;
+                result.copy_from_slice(data);
+                Ok(Some(event.with_payload(result)))
+            }
+        }
+    }
+
+    /// Returns an iterator that yields owned events until no more are available.
+    /// Socket errors end the iteration and can be retrieved with [`ClientEvents::take_error`].
+    pub fn events(&mut self) -> ClientEvents<'_> {
+        ClientEvents {
+            client: self,
+            error: None
+        }
+    }
+
+    /// Returns the next event with its payload copied into a new allocation.
+    /// Events without a payload do not allocate.
+    pub fn next_event_owned(&mut self) -> IOResult<Option<ClientEventOwned>> {
+        Ok(self.next_event()?.map(ClientEventOwned::from))
+    }
+
+    /// Returns the next event without copying. Received payloads borrow the internal receive buffer,
+    /// so the event has to be dropped before the next call.
+    pub fn next_event(&mut self) -> IOResult<Option<ClientEvent<'_>>> {
+        if self.pending.has_next() {
+            return Ok(self.pending.next().map(|(_, seq, result, data)| ClientEvent::PacketReceived(seq, result, data)))
+        }
+        Ok(match self.poll()? {
+            None => None,
+            Some(Polled::Event(event)) => Some(event),
+            Some(Polled::Received(event)) => Some(event.with_payload(self.socket.last_payload())),
+            Some(Polled::Pending) => self.pending.next().map(|(_, seq, result, data)| ClientEvent::PacketReceived(seq, result, data))
+        })
+    }
+
+    fn poll(&mut self) -> IOResult<Option<Polled<ClientEvent<'static>>>> {
+        if self.superseded {
+            self.superseded = false;
+            return Ok(Some(Polled::Event(ClientEvent::Disconnected(ClientDisconnectReason::Disconnected))));
+        }
+
+        if let Some(event) = self.next_delivery() {
+            return Ok(Some(event));
+        }
+
+        if let ClientState::Connecting { attempts, reported, .. } = &mut self.state {
+            if *reported != *attempts {
+                *reported = *attempts;
+                return Ok(Some(Polled::Event(ClientEvent::Connecting(*attempts))));
             }
         }
 
+        if let Some(quality) = self.state.get_connection_mut().ok().and_then(VirtualConnection::take_quality_change) {
+            return Ok(Some(Polled::Event(ClientEvent::QualityChanged(quality))));
+        }
+
+        if let Some(msg) = self.state.get_connection_mut().ok().and_then(VirtualConnection::receive_message) {
+            return Ok(Some(Polled::Event(ClientEvent::ReliableMessage(msg))));
+        }
+
         if let ClientState::Disconnecting(reason) = &self.state {
             let reason = reason.clone();
             self.state = ClientState::Disconnected;
-            return Ok(Some(ClientEvent::Disconnected(reason)));
+            return Ok(Some(Polled::Event(ClientEvent::Disconnected(reason))));
         }
 
         loop {
-            match self.socket.recv_from() {
-                Ok((packet, src)) => match self.state {
-                    ClientState::Connecting(remote, _) if remote == src => match packet{
-                        Ok(Packet::ConnectionAccepted(id)) => {
-                            self.state = ClientState::Connected(VirtualConnection::new(src, id));
-                            return Ok(Some(ClientEvent::Connected(id)))
+            let state = &self.state;
+            match self.socket.recv_from(|src, _| match state {
+                ClientState::Connected(vc) | ClientState::Closing { connection: vc, .. } if vc.addrs() == src => vc.recv_key(),
+                _ => None
+            }) {
+                Ok((Ok(Packet::UnconnectedPong(token, _)), src, _, _)) => {
+                    if let Some(i) = self.queries.iter().position(|(t, addrs, _)| *t == token && *addrs == src) {
+                        let (_, _, sent) = self.queries.swap_remove(i);
+                        return Ok(Some(Polled::Received(ClientEvent::QueryResponse(src, sent.elapsed(), &[]))))
+                    }
+                },
+                // Delayed packets of an older connection to the same server
+                Ok((_, src, _, Some(nonce))) if matches!(&self.state, ClientState::Connected(vc) | ClientState::Closing { connection: vc, .. }
+                    if vc.addrs() == src && vc.nonce() != nonce) => continue,
+                Ok((packet, src, size, _)) => match self.state {
+                    // Late answers from candidates that were already given up on are ignored, just like answers to older attempts
+                    ClientState::Connecting { ref candidates, candidate, nonce, .. } if candidates[candidate] == src => match packet{
+                        Ok(Packet::ConnectionAccepted(id, key, compression, accepted)) if accepted == nonce => {
+                            let keys = key.and_then(|key| SessionKeys::derive(&self.config.authentication, key, false));
+                            if keys.is_none() != (self.config.authentication == Authentication::Checksum) {
+                                self.state = ClientState::Disconnected;
+                                return Ok(Some(Polled::Event(ClientEvent::Disconnected(ClientDisconnectReason::ConnectionDenied(DenyReason::AuthenticationMismatch)))))
+                            }
+                            let mut connection = VirtualConnection::new(src, id, nonce, keys, compression && cfg!(feature = "compression"), self.config.sent_packets_capacity, self.config.ack_width);
+                            connection.set_rtt_variance_smoothing(self.config.rtt_variance_smoothing);
+                            self.state = ClientState::Connected(connection);
+                            return Ok(Some(Polled::Event(ClientEvent::Connected(id))))
                         },
-                        Ok(Packet::ConnectionDenied) => {
+                        Ok(Packet::ConnectionDenied(denied, Some(reason))) if denied == nonce => {
                             self.state = ClientState::Disconnected;
-                            return  Ok(Some(ClientEvent::Disconnected(ClientDisconnectReason::ConnectionDenied)))
+                            return Ok(Some(Polled::Event(ClientEvent::Disconnected(ClientDisconnectReason::ConnectionDenied(reason)))))
                         }
                         _ => continue
                     },
                     ClientState::Connected(ref mut vc) if vc.addrs() == src => match packet{
-                        Ok(Packet::Payload(seq, ack, data)) => {
-                            let seq = vc.handle_seq(seq);
-                            if let SequenceResult::Latest | SequenceResult::Fresh = seq {
-                                vc.on_receive();
-                                vc.handle_ack(ack, |i, j|self.ack_queue.push_back((i, j)));
-                                let result = &mut payload[..data.len()];
-                                result.copy_from_slice(data);
-                                return Ok(Some(ClientEvent::PacketReceived(seq == SequenceResult::Latest, result)))
+                        Ok(Packet::Payload(seq, ack, _)) => {
+                            let result = vc.handle_seq(seq);
+                            if let SequenceResult::Latest | SequenceResult::Fresh = result {
+                                vc.on_receive(size);
+                                vc.on_receive_payload();
+                                vc.handle_ack(ack, self.config.packet_lost_cutoff, |i, j|self.ack_queue.push((i, j)));
+                                if vc.ack_due() {
+                                    self.socket.send_ack(vc)?;
+                                }
+                                return Ok(Some(Polled::Received(ClientEvent::PacketReceived(seq, result, &[]))))
                             }
                         },
-                        Ok(Packet::KeepAlive(ack)) => {
-                            vc.on_receive();
-                            vc.handle_ack(ack, |i, j|self.ack_queue.push_back((i, j)));
+                        Ok(Packet::Batch(seq, ack, data)) => {
+                            let result = vc.handle_seq(seq);
+                            if let SequenceResult::Latest | SequenceResult::Fresh = result {
+                                vc.on_receive(size);
+                                vc.on_receive_payload();
+                                vc.handle_ack(ack, self.config.packet_lost_cutoff, |i, j|self.ack_queue.push((i, j)));
+                                self.pending.store(vc.id(), seq, result, data);
+                                if vc.ack_due() {
+                                    self.socket.send_ack(vc)?;
+                                }
+                                if self.pending.has_next() {
+                                    return Ok(Some(Polled::Pending))
+                                }
+                            }
                         },
-                        Ok(Packet::Disconnect) => {
+                        Ok(Packet::Messages(seq, ack, data)) => {
+                            if let SequenceResult::Latest | SequenceResult::Fresh = vc.handle_seq(seq) {
+                                vc.on_receive(size);
+                                vc.on_receive_payload();
+                                vc.handle_ack(ack, self.config.packet_lost_cutoff, |i, j|self.ack_queue.push((i, j)));
+                                vc.on_messages(data);
+                                if vc.ack_due() {
+                                    self.socket.send_ack(vc)?;
+                                }
+                                if let Some(msg) = vc.receive_message() {
+                                    return Ok(Some(Polled::Event(ClientEvent::ReliableMessage(msg))))
+                                }
+                            }
+                        },
+                        Ok(Packet::KeepAlive(seq, ack)) => {
+                            if let SequenceResult::Latest | SequenceResult::Fresh = vc.handle_seq(seq) {
+                                vc.on_receive(size);
+                                vc.handle_ack(ack, self.config.packet_lost_cutoff, |i, j|self.ack_queue.push((i, j)));
+                            }
+                        },
+                        Ok(Packet::Ping(seq, ack)) => match vc.handle_seq(seq) {
+                            SequenceResult::Latest | SequenceResult::Fresh => {
+                                vc.on_receive(size);
+                                vc.handle_ack(ack, self.config.packet_lost_cutoff, |i, j|self.ack_queue.push((i, j)));
+                                self.socket.send_ack(vc)?;
+                            }
+                            // the previous ack might have been lost
+                            SequenceResult::Duplicate => self.socket.send_ack(vc)?,
+                            _ => {}
+                        },
+                        Ok(Packet::Ack(ack)) => {
+                            vc.on_receive(size);
+                            vc.handle_ack(ack, self.config.packet_lost_cutoff, |i, j|self.ack_queue.push((i, j)));
+                        },
+                        Ok(Packet::ProbeAck(probe)) => {
+                            vc.on_receive(size);
+                            vc.confirm_size(probe);
+                        },
+                        Ok(Packet::Disconnect(code)) => {
+                            self.state = ClientState::Disconnected;
+                            let reason = code.map_or(ClientDisconnectReason::Disconnected, ClientDisconnectReason::Kicked);
+                            return Ok(Some(Polled::Event(ClientEvent::Disconnected(reason))))
+                        },
+                        Ok(Packet::ConnectionDenied(denied, _)) if denied == vc.nonce() => {
+                            self.state = ClientState::Disconnected;
+                            return Ok(Some(Polled::Event(ClientEvent::Disconnected(ClientDisconnectReason::ConnectionReset))))
+                        },
+                        _ => continue
+                    }
+                    ClientState::Closing { ref connection, .. } if connection.addrs() == src => match packet {
+                        Ok(Packet::DisconnectAck | Packet::Disconnect(_)) => {
+                            self.state = ClientState::Disconnected;
+                            return Ok(Some(Polled::Event(ClientEvent::Disconnected(ClientDisconnectReason::Disconnected))))
+                        },
+                        // The server answers with a denial if it already dropped the connection
+                        Ok(Packet::ConnectionDenied(denied, _)) if denied == connection.nonce() => {
                             self.state = ClientState::Disconnected;
-                            return Ok(Some(ClientEvent::Disconnected(ClientDisconnectReason::Disconnected)))
+                            return Ok(Some(Polled::Event(ClientEvent::Disconnected(ClientDisconnectReason::Disconnected))))
                         },
                         _ => continue
                     }
                     _ => continue
                 }
-                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock) => return Ok(None),
+                // acks might have arrived without a packet that produces an event
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock) => return Ok(self.next_delivery()),
+                Err(e) if is_transient(e.kind()) => return Ok(match self.socket.report_recv_error(e.kind()) {
+                    true => Some(Polled::Event(ClientEvent::SocketError(e.kind()))),
+                    false => self.next_delivery()
+                }),
                 Err(e) => return Err(e)
             }
         }
 
     }
 
+    fn next_delivery(&mut self) -> Option<Polled<ClientEvent<'static>>> {
+        self.ack_queue.pop().map(|(seq, delivery)| Polled::Event(match delivery {
+            Delivery::Acknowledged(tag, rtt) => ClientEvent::PacketAcknowledged(seq, tag, rtt),
+            Delivery::Lost(tag) => ClientEvent::PacketLost(seq, tag),
+            Delivery::Pong(rtt) => ClientEvent::Pong(seq, rtt),
+            Delivery::PongTimeout => ClientEvent::PongTimeout(seq)
+        }))
+    }
+
     pub fn send(&mut self, payload: &[u8]) -> Result<SequenceNumber, ConnectionError> {
+        self.send_payload(payload, Some(0))
+    }
+
+    /// Like `send`, but the [`ClientEvent::PacketAcknowledged`] or [`ClientEvent::PacketLost`] event of the packet
+    /// carries `tag` instead of 0, e.g. to find the message that has to be resent.
+    pub fn send_tagged(&mut self, payload: &[u8], tag: u64) -> Result<SequenceNumber, ConnectionError> {
+        self.send_payload(payload, Some(tag))
+    }
+
+    /// Measures the round trip time to the server without any smoothing.
+    /// The result is reported as [`ClientEvent::Pong`] or [`ClientEvent::PongTimeout`].
+    pub fn ping(&mut self) -> Result<SequenceNumber, ConnectionError> {
         let connection = self.state.get_connection_mut()?;
-        match self.socket.send_payload(payload, connection) {
+        match self.socket.send_ping(connection) {
             Ok(seq) => Ok(seq),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Err(ConnectionError::Backpressured),
+            Err(err) => {
+                self.state = ClientState::Disconnecting(ClientDisconnectReason::SocketError(err.kind()));
+                Err(ConnectionError::Disconnected)
+            }
+        }
+    }
+
+    /// Like `send`, but the packet never produces a [`ClientEvent::PacketAcknowledged`] or [`ClientEvent::PacketLost`] event.
+    pub fn send_unreliable(&mut self, payload: &[u8]) -> Result<SequenceNumber, ConnectionError> {
+        self.send_payload(payload, None)
+    }
+
+    fn send_payload(&mut self, payload: &[u8], tag: Option<u64>) -> Result<SequenceNumber, ConnectionError> {
+        let connection = self.state.get_connection_mut()?;
+        if payload.len() > connection.max_payload_size() {
+            return Err(ConnectionError::PayloadTooLarge { len: payload.len(), max: connection.max_payload_size() });
+        }
+        if connection.exceeds_congestion_budget(&self.config.congestion) {
+            return Err(ConnectionError::Backpressured);
+        }
+        match self.socket.send_payload(payload, connection, tag) {
+            Ok(seq) => Ok(seq),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Err(ConnectionError::Backpressured),
+            Err(err) => {
+                self.state = ClientState::Disconnecting(ClientDisconnectReason::SocketError(err.kind()));
+                Err(ConnectionError::Disconnected)
+            }
+        }
+    }
+
+    /// Queues the payload to be sent together with other payloads in a single packet.
+    /// The batch is sent once it is full, on the next call to `flush` or `update`, or before the next `send`.
+    /// Returns the sequence number of the packet the payload will be part of.
+    pub fn send_batched(&mut self, payload: &[u8]) -> Result<SequenceNumber, ConnectionError> {
+        let connection = self.state.get_connection_mut()?;
+        if payload.len() + 2 > connection.max_payload_size() {
+            return Err(ConnectionError::PayloadTooLarge { len: payload.len(), max: connection.max_payload_size() - 2 });
+        }
+        if connection.exceeds_congestion_budget(&self.config.congestion) {
+            return Err(ConnectionError::Backpressured);
+        }
+        match self.socket.queue_payload(payload, connection) {
+            Ok(seq) => Ok(seq),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Err(ConnectionError::Backpressured),
+            Err(err) => {
+                self.state = ClientState::Disconnecting(ClientDisconnectReason::SocketError(err.kind()));
+                Err(ConnectionError::Disconnected)
+            }
+        }
+    }
+
+    /// Queues the message on the built-in [`MessageChannel`](crate::MessageChannel) of the connection. It is sent by `update`,
+    /// resent until it is acknowledged and arrives as [`ServerEvent::ReliableMessage`](crate::ServerEvent::ReliableMessage).
+    /// Messages that are still queued when the connection ends are dropped.
+    /// Their packets don't produce `PacketAcknowledged` or `PacketLost` events.
+    pub fn send_reliable(&mut self, msg: &[u8]) -> Result<(), ConnectionError> {
+        Ok(self.state.get_connection_mut()?.queue_message(msg)?)
+    }
+
+    pub fn flush(&mut self) -> Result<(), ConnectionError> {
+        let connection = self.state.get_connection_mut()?;
+        match self.socket.flush(connection) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Err(ConnectionError::Backpressured),
             Err(err) => {
                 self.state = ClientState::Disconnecting(ClientDisconnectReason::SocketError(err.kind()));
                 Err(ConnectionError::Disconnected)
@@ -1,16 +1,23 @@
+use std::cell::{Cell, RefCell};
 use std::net::SocketAddr;
-use std::io::Result;
+use std::io::{ErrorKind, Result};
+use std::rc::Rc;
 use crate::socket::Transport;
 
 #[derive(Debug, Copy, Clone)]
 pub struct NetworkOptions {
-    pub packet_loss: f32
+    pub packet_loss: f32,
+    pub packet_corruption: f32,
+    /// The chance that a received packet is held back until after the next one
+    pub packet_reordering: f32
 }
 
 impl Default for NetworkOptions {
     fn default() -> Self {
         Self {
-            packet_loss: 0.0
+            packet_loss: 0.0,
+            packet_corruption: 0.0,
+            packet_reordering: 0.0
         }
     }
 }
@@ -18,7 +25,16 @@ impl Default for NetworkOptions {
 #[derive(Debug)]
 pub struct ConditionedTransport<T: Transport> {
     socket: T,
-    options: NetworkOptions,
+    options: Rc<Cell<NetworkOptions>>,
+    held: RefCell<Option<(Box<[u8]>, SocketAddr)>>,
+    overtaken: Cell<bool>
+}
+
+impl<T: Transport> ConditionedTransport<T> {
+    /// Allows changing the simulated conditions after the transport was moved into a client or server
+    pub fn options_handle(&self) -> Rc<Cell<NetworkOptions>> {
+        self.options.clone()
+    }
 }
 
 pub trait TransportExtension<T: Transport>: Sized {
@@ -29,7 +45,9 @@ impl<T: Transport> TransportExtension<T> for T {
     fn with_options(self, options: NetworkOptions) -> ConditionedTransport<T> {
         ConditionedTransport {
             socket: self,
-            options
+            options: Rc::new(Cell::new(options)),
+            held: RefCell::new(None),
+            overtaken: Cell::new(false)
         }
     }
 }
@@ -40,12 +58,30 @@ impl<T: Transport> Transport for ConditionedTransport<T> {
     }
 
     fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        if self.overtaken.replace(false) {
+            if let Some(held) = self.held.take() {
+                return Ok(release(held, buf));
+            }
+        }
         match self.socket.recv_from(buf) {
-            Ok(result) => if fastrand::f32() < self.options.packet_loss {
+            Ok(result) => if fastrand::f32() < self.options.get().packet_loss {
                 self.recv_from(buf)
             } else {
+                if result.0 > 0 && fastrand::f32() < self.options.get().packet_corruption {
+                    buf[fastrand::usize(..result.0)] ^= 1 << fastrand::u8(..8);
+                }
+                if self.held.borrow().is_some() {
+                    self.overtaken.set(true);
+                } else if fastrand::f32() < self.options.get().packet_reordering {
+                    *self.held.borrow_mut() = Some((buf[..result.0].into(), result.1));
+                    return self.recv_from(buf);
+                }
                 Ok(result)
             }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => match self.held.take() {
+                Some(held) => Ok(release(held, buf)),
+                None => Err(e)
+            }
             Err(e) => Err(e)
         }
     }
@@ -53,4 +89,384 @@ impl<T: Transport> Transport for ConditionedTransport<T> {
     fn local_addr(&self) -> Result<SocketAddr> {
         self.socket.local_addr()
     }
-}
\ No newline at end of file
+}
+
+fn release((packet, addr): (Box<[u8]>, SocketAddr), buf: &mut [u8]) -> (usize, SocketAddr) {
+    let len = packet.len().min(buf.len());
+    buf[..len].copy_from_slice(&packet[..len]);
+    (len, addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::{Client, ClientEvent, CongestionConfig, CongestionState, ConnectConfig, ConnectionConfig, ConnectionError, MAX_PACKET_SIZE, MessageChannel, NetworkOptions, SequencedChannel, Server, ServerEvent, TransportExtension};
+    use crate::sequencing::sequence_less_than;
+    use crate::socket::Endpoint;
+    use crate::testing::MemoryNetwork;
+
+    /// Connects the client to the server on port 1, retrying until a request and its answer make it through the simulated network
+    fn connect(server: &mut Server, client: &mut Client) {
+        client.connect_with(Endpoint::local_port(1), ConnectConfig { retry_interval: Duration::ZERO, max_attempts: 100, ..Default::default() }).unwrap();
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        while !client.is_connected() {
+            assert!(!client.is_disconnected(), "the client gave up connecting");
+            client.update();
+            server.update();
+            while server.next_event_into(&mut buffer).unwrap().is_some() {}
+            while client.next_event_into(&mut buffer).unwrap().is_some() {}
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn test_corrupted_ciphertext() {
+        use crate::Authentication;
+        const KEY: [u8; 32] = [42; 32];
+        let options = NetworkOptions {
+            packet_corruption: 0.5,
+            ..Default::default()
+        };
+        let network = MemoryNetwork::default();
+        let mut server = Server::new_with_authentication(network.bind(1).with_options(options), "test", 1, Authentication::Encrypted(KEY));
+        let mut client = Client::new_with_authentication(network.bind(2).with_options(options), "test", Authentication::Encrypted(KEY));
+        connect(&mut server, &mut client);
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let mut received = 0;
+        for i in 0..200u8 {
+            client.update();
+            server.update();
+            if client.is_connected() {
+                client.send(&[i; 16]).unwrap();
+            }
+            let client_id = server.connected_clients().next();
+            if let Some(id) = client_id {
+                server.send(id, &[i; 16]).unwrap();
+            }
+            while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+                if let ServerEvent::PacketReceived(_, _, _, data) = event {
+                    assert!(data.len() == 16 && data.iter().all(|b| *b == data[0]));
+                    received += 1;
+                }
+            }
+            while let Some(event) = client.next_event_into(&mut buffer).unwrap() {
+                if let ClientEvent::PacketReceived(_, _, data) = event {
+                    assert!(data.len() == 16 && data.iter().all(|b| *b == data[0]));
+                    received += 1;
+                }
+            }
+        }
+        assert!(received > 0);
+    }
+
+    #[test]
+    fn test_congestion_avoidance() {
+        let congestion = CongestionConfig { bad_loss: 0.25, recovery_time: Duration::from_millis(50), send_rate_bps: 32_000, enforce: true, ..Default::default() };
+        let config = ConnectionConfig { congestion, ..Default::default() };
+        let network = MemoryNetwork::default();
+        let mut server = Server::new_with_config(network.bind(1), "test", 1, config).unwrap();
+        let transport = network.bind(2).with_options(NetworkOptions::default());
+        let options = transport.options_handle();
+        let mut client = Client::new(transport, "test");
+        connect(&mut server, &mut client);
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let mut step = |server: &mut Server, client: &mut Client| {
+            client.update();
+            server.update();
+            let result = server.send(0, &[0; 16]);
+            while server.next_event_into(&mut buffer).unwrap().is_some() {}
+            while let Some(event) = client.next_event_into(&mut buffer).unwrap() {
+                assert!(!matches!(event, ClientEvent::Disconnected(_)));
+            }
+            std::thread::sleep(Duration::from_millis(1));
+            result
+        };
+        for _ in 0..10 {
+            step(&mut server, &mut client).unwrap();
+        }
+        assert_eq!(server.congestion_state(0).unwrap(), CongestionState::Good);
+
+        options.set(NetworkOptions { packet_loss: 0.5, ..Default::default() });
+        let mut backpressured = false;
+        for _ in 0..2000 {
+            if server.congestion_state(0).unwrap() == CongestionState::Bad {
+                break;
+            }
+            backpressured |= matches!(step(&mut server, &mut client), Err(ConnectionError::Backpressured));
+        }
+        assert_eq!(server.congestion_state(0).unwrap(), CongestionState::Bad);
+
+        options.set(NetworkOptions::default());
+        for _ in 0..5000 {
+            if server.congestion_state(0).unwrap() == CongestionState::Good {
+                break;
+            }
+            backpressured |= matches!(step(&mut server, &mut client), Err(ConnectionError::Backpressured));
+        }
+        assert_eq!(server.congestion_state(0).unwrap(), CongestionState::Good);
+        assert!(backpressured);
+        assert!(server.send(0, &[0; 16]).is_ok());
+    }
+
+    #[test]
+    fn test_fragmented_message() {
+        let options = NetworkOptions {
+            packet_loss: 0.25,
+            ..Default::default()
+        };
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1).with_options(options), "test", 1);
+        let mut client = Client::new(network.bind(2).with_options(options), "test");
+        connect(&mut server, &mut client);
+
+        let message: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let mut sender = MessageChannel::new();
+        let mut receiver = MessageChannel::new();
+        sender.queue_message(&message).unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let mut received = Vec::new();
+        for _ in 0..5000 {
+            if !received.is_empty() && !sender.has_unsend_messages() {
+                break;
+            }
+            client.update();
+            server.update();
+            if let Ok(connection) = client.connection() {
+                sender.set_resend_interval(connection.resend_interval());
+                let seq = connection.peek_next_sequence_number();
+                if sender.has_due_messages() {
+                    assert_eq!(client.send(sender.send_packets(seq).unwrap()).unwrap(), seq);
+                }
+            }
+            while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+                if let ServerEvent::PacketReceived(_, _, _, data) = event {
+                    receiver.on_receive(data).unwrap();
+                }
+            }
+            while let Some(message) = receiver.receive_message() {
+                received.push(message);
+            }
+            while let Some(event) = client.next_event_into(&mut buffer).unwrap() {
+                if let ClientEvent::PacketAcknowledged(seq, _, _) = event {
+                    sender.on_ack(seq);
+                }
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!sender.has_unsend_messages());
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].as_ref(), message.as_slice());
+    }
+
+    #[test]
+    fn test_resend_pacing() {
+        fn run(paced: bool) -> u64 {
+            let options = NetworkOptions {
+                packet_loss: 0.25,
+                ..Default::default()
+            };
+            let network = MemoryNetwork::default();
+            let mut server = Server::new(network.bind(1).with_options(options), "test", 1);
+            let mut client = Client::new(network.bind(2).with_options(options), "test");
+            connect(&mut server, &mut client);
+
+            let mut sender = MessageChannel::new().with_resend_interval(Duration::ZERO);
+            let mut receiver = MessageChannel::new();
+            for i in 0..100u8 {
+                sender.queue_message(&[i; 200]).unwrap();
+            }
+            let mut buffer = [0u8; MAX_PACKET_SIZE];
+            let mut received = Vec::new();
+            for _ in 0..5000 {
+                if received.len() == 100 && !sender.has_unsend_messages() {
+                    break;
+                }
+                client.update();
+                server.update();
+                if let Ok(connection) = client.connection() {
+                    if paced {
+                        sender.set_resend_interval(connection.resend_interval());
+                    }
+                    let seq = connection.peek_next_sequence_number();
+                    if sender.has_due_messages() {
+                        assert_eq!(client.send(sender.send_packets(seq).unwrap()).unwrap(), seq);
+                    }
+                }
+                while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+                    if let ServerEvent::PacketReceived(_, _, _, data) = event {
+                        receiver.on_receive(data).unwrap();
+                    }
+                }
+                received.extend(std::iter::from_fn(|| receiver.receive_message()));
+                while let Some(event) = client.next_event_into(&mut buffer).unwrap() {
+                    if let ClientEvent::PacketAcknowledged(seq, _, _) = event {
+                        sender.on_ack(seq);
+                    }
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            assert!(!sender.has_unsend_messages());
+            assert_eq!(received.len(), 100);
+            assert!(received.iter().enumerate().all(|(i, msg)| msg.as_ref() == [i as u8; 200]));
+            client.socket_stats().bytes_sent
+        }
+        let unpaced = run(false);
+        let paced = run(true);
+        assert!(paced * 2 < unpaced, "paced: {} unpaced: {}", paced, unpaced);
+    }
+
+    #[test]
+    fn test_loss_reaction() {
+        fn run(react: bool) -> f32 {
+            let options = NetworkOptions {
+                packet_loss: 0.4,
+                ..Default::default()
+            };
+            let config = ConnectionConfig { packet_lost_cutoff: 4, ..Default::default() };
+            let network = MemoryNetwork::default();
+            let mut server = Server::new(network.bind(1).with_options(options), "test", 1);
+            let mut client = Client::new_with_config(network.bind(2), "test", config).unwrap();
+            connect(&mut server, &mut client);
+            let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+            let mut sender = MessageChannel::new_unordered().with_resend_interval(Duration::from_millis(250));
+            let mut receiver = MessageChannel::new_unordered();
+            let mut latencies = Vec::new();
+            for tick in 0..5000u32 {
+                if tick >= 200 && !sender.has_unsend_messages() {
+                    break;
+                }
+                client.update();
+                server.update();
+                if let Ok(connection) = client.connection() {
+                    let seq = connection.peek_next_sequence_number();
+                    if tick < 200 {
+                        sender.queue_message(&tick.to_be_bytes()).unwrap();
+                    }
+                    if sender.has_due_messages() {
+                        assert_eq!(client.send(sender.send_packets(seq).unwrap()).unwrap(), seq);
+                    }
+                }
+                while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+                    if let ServerEvent::PacketReceived(_, _, _, data) = event {
+                        receiver.on_receive(data).unwrap();
+                    }
+                }
+                while let Some(msg) = receiver.receive_message() {
+                    latencies.push(tick - u32::from_be_bytes(msg.as_ref().try_into().unwrap()));
+                }
+                while let Some(event) = client.next_event_into(&mut buffer).unwrap() {
+                    match event {
+                        ClientEvent::PacketAcknowledged(seq, _, _) => sender.on_ack(seq),
+                        ClientEvent::PacketLost(seq, _) if react => sender.on_loss(seq),
+                        _ => {}
+                    }
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            assert!(!sender.has_unsend_messages());
+            assert_eq!(latencies.len(), 200);
+            latencies.iter().sum::<u32>() as f32 / latencies.len() as f32
+        }
+        let without = run(false);
+        let with = run(true);
+        assert!(with < 0.75 * without, "with on_loss: {} without: {}", with, without);
+    }
+
+    #[test]
+    fn test_sequenced_reordering() {
+        let options = NetworkOptions {
+            packet_loss: 0.1,
+            packet_reordering: 0.5,
+            ..Default::default()
+        };
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1).with_options(options), "test", 1);
+        let mut client = Client::new(network.bind(2), "test");
+        connect(&mut server, &mut client);
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+        let mut sender = SequencedChannel::new();
+        let mut receiver = SequencedChannel::new();
+        let mut latest = [0u32; 4];
+        let mut newest_packet = None;
+        let mut reordered = 0;
+        let mut counter = 0u32;
+        for _ in 0..200 {
+            client.update();
+            server.update();
+            // several packets per update, so that they can overtake each other
+            for _ in 0..4 {
+                counter += 1;
+                sender.set(0, &counter.to_be_bytes()).unwrap();
+                sender.set(1 + (counter % 3) as u8, &counter.to_be_bytes()).unwrap();
+                client.send(sender.send_packets().unwrap()).unwrap();
+            }
+            while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+                if let ServerEvent::PacketReceived(_, seq, _, data) = event {
+                    if newest_packet.is_some_and(|newest| sequence_less_than(seq, newest)) {
+                        reordered += 1;
+                    } else {
+                        newest_packet = Some(seq);
+                    }
+                    receiver.on_receive(data).unwrap();
+                }
+            }
+            for (slot, data) in receiver.changes() {
+                let value = u32::from_be_bytes(data.try_into().unwrap());
+                assert!(value > latest[slot as usize], "slot {} went from {} to {}", slot, latest[slot as usize], value);
+                latest[slot as usize] = value;
+            }
+            while client.next_event_into(&mut buffer).unwrap().is_some() {}
+        }
+        assert!(reordered > 0);
+        assert!(latest.iter().all(|value| *value > 0));
+    }
+
+    #[test]
+    fn test_send_reliable() {
+        let options = NetworkOptions {
+            packet_loss: 0.25,
+            ..Default::default()
+        };
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1).with_options(options), "test", 1);
+        let mut client = Client::new(network.bind(2).with_options(options), "test");
+        connect(&mut server, &mut client);
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+        // the server echoes every message, so both directions have to survive the loss
+        let mut echoed = Vec::new();
+        for i in 0..5000u32 {
+            if echoed.len() == 200 {
+                break;
+            }
+            if i < 200 {
+                client.send_reliable(&i.to_be_bytes()).unwrap();
+            }
+            client.update();
+            server.update();
+            while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+                match event {
+                    ServerEvent::ReliableMessage(id, msg) => server.send_reliable(id, &msg).unwrap(),
+                    ServerEvent::PacketReceived(..) => panic!("messages are not reported as payloads"),
+                    _ => {}
+                }
+            }
+            while let Some(event) = client.next_event_into(&mut buffer).unwrap() {
+                match event {
+                    ClientEvent::ReliableMessage(msg) => echoed.push(u32::from_be_bytes(msg.as_ref().try_into().unwrap())),
+                    ClientEvent::PacketAcknowledged(..) | ClientEvent::PacketLost(..) => panic!("messages are not reported as payloads"),
+                    _ => {}
+                }
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(echoed, (0..200).collect::<Vec<_>>());
+        assert!(client.connection().unwrap().message_channel().unwrap().stats().retransmissions > 0);
+    }
+
+}
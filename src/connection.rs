@@ -1,27 +1,252 @@
 use std::fmt::Debug;
+use std::ops::Range;
 use std::net::SocketAddr;
-use std::io::Result;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Result};
 use std::time::{Duration, Instant};
-use crate::constants::{PACKET_LOST_CUTOFF, PL_SMOOTHING_FACTOR, RTT_SMOOTHING_FACTOR};
+use crate::constants::{BAD_PACKET_LOSS, BAD_RTT_MS, CONGESTION_RECOVERY_TIME, CONGESTION_SEND_RATE_BPS, CONNECTION_TIMEOUT, DELIVERY_QUEUE_SIZE, DISCONNECT_REDUNDANCY, KEEPALIVE_INTERVAL, ACK_THRESHOLD, FALLBACK_PROBE_SIZE, MAX_ACK_DELAY, MAX_CONGESTION_RECOVERY_TIME, MAX_MESSAGE_PACKETS_PER_UPDATE, MAX_OUTGOING_QUEUE, MAX_SEQUENCE_JUMP, PACKET_LOST_CUTOFF, PL_SMOOTHING_FACTOR, PROBE_DURATION, PROBE_SIZES, QUALITY_HYSTERESIS, RATE_WINDOW, RTT_SMOOTHING_FACTOR, RTT_VARIANCE_SMOOTHING_FACTOR, SENT_PACKETS_CAPACITY};
+use byteorder::{NetworkEndian, WriteBytesExt};
 use crate::MAX_PACKET_SIZE;
-use crate::packets::Packet;
-use crate::sequencing::{SequenceBuffer, SequenceNumber, SequenceNumberSet, SequenceResult};
+use crate::error::{ChannelError, ConnectionError};
+use crate::packets::{Authentication, batch_iter, ConnectionKey, ConnectionNonce, has_magic, is_bad_signature, Packet, payload_overhead, peek_connection_nonce, ProtocolId, SessionKey, SessionKeys};
+#[cfg(feature = "compression")]
+use crate::constants::COMPRESSION_THRESHOLD;
+#[cfg(feature = "compression")]
+use crate::packets::compress;
+use crate::reliable::MessageChannel;
+use crate::sequencing::{AckWidth, sequence_greater_than, SequenceBuffer, SequenceNumber, SequenceNumberSet, SequenceResult};
 use crate::socket::Transport;
 
 #[derive(Debug)]
 pub struct PacketSocket {
     socket: Box<dyn Transport>,
     buffer: [u8; MAX_PACKET_SIZE],
-    salt: String
+    send_buffer: [u8; MAX_PACKET_SIZE],
+    #[cfg(feature = "compression")]
+    decompressed: [u8; MAX_PACKET_SIZE],
+    received: Option<Received>,
+    protocol: ProtocolId,
+    stats: SocketStats,
+    /// The last transient receive error, cleared by the next successful receive
+    recv_error: Option<ErrorKind>
+}
+
+/// Counters of all traffic of the socket and of the packets it had to drop
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct SocketStats {
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    /// Packets that did not start with the protocol magic
+    pub foreign_packets: u64,
+    /// Packets whose checksum or authentication tag did not match
+    pub bad_signature_packets: u64,
+    /// Packets with a malformed header or body
+    pub invalid_packets: u64,
+    /// Packets outside of a connection that the server dropped because of its [`RateLimit`](crate::RateLimit)
+    pub requests_dropped_rate_limited: u64,
+    /// Ack, loss and pong events that were dropped because they were not polled in time
+    pub events_dropped: u64
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum NetworkQuality {
+    #[default]
+    Good,
+    Poor
+}
+
+/// When a connection is considered poor. A connection becomes poor as soon as one threshold is reached,
+/// but only becomes good again after staying below both thresholds for `hysteresis`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct QualityConfig {
+    pub bad_rtt_ms: u32,
+    pub bad_loss: f32,
+    pub hysteresis: Duration
+}
+
+impl Default for QualityConfig {
+    fn default() -> Self {
+        Self {
+            bad_rtt_ms: BAD_RTT_MS,
+            bad_loss: BAD_PACKET_LOSS,
+            hysteresis: QUALITY_HYSTERESIS
+        }
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum CongestionState {
+    #[default]
+    Good,
+    /// The connection should send less, see [`CongestionConfig::send_rate_bps`]
+    Bad
+}
+
+/// When a connection enters congestion avoidance and how much it should send while in it. It is left once
+/// the conditions stayed good for the recovery time, which doubles whenever the connection relapses within
+/// the recovery time after leaving and halves whenever it stays out for the recovery time.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CongestionConfig {
+    pub bad_rtt_ms: u32,
+    pub bad_loss: f32,
+    pub recovery_time: Duration,
+    /// The limit of the recovery time under repeated relapses
+    pub max_recovery_time: Duration,
+    /// The advised send rate during congestion avoidance, including headers
+    pub send_rate_bps: u64,
+    /// Makes sending fail with [`ConnectionError::Backpressured`] during congestion avoidance while the
+    /// send rate is above `send_rate_bps`, instead of only advising it
+    pub enforce: bool
+}
+
+impl Default for CongestionConfig {
+    fn default() -> Self {
+        Self {
+            bad_rtt_ms: BAD_RTT_MS,
+            bad_loss: BAD_PACKET_LOSS,
+            recovery_time: CONGESTION_RECOVERY_TIME,
+            max_recovery_time: MAX_CONGESTION_RECOVERY_TIME,
+            send_rate_bps: CONGESTION_SEND_RATE_BPS,
+            enforce: false
+        }
+    }
+}
+
+/// Settings of an established connection. The peer may use different values,
+/// as long as its keepalive interval stays comfortably below our timeout.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ConnectionConfig {
+    pub authentication: Authentication,
+    /// The time without any packet from the peer after which the connection is considered lost
+    pub timeout: Duration,
+    /// The time without any outgoing packet after which a keepalive is sent
+    pub keepalive_interval: Duration,
+    /// The number of disconnect packets sent when disconnecting without waiting for an acknowledgement
+    pub disconnect_redundancy: u32,
+    /// The number of ack, loss and pong events that wait to be polled. Once it is reached,
+    /// the oldest events are dropped and counted in [`SocketStats::events_dropped`].
+    pub delivery_queue_size: usize,
+    /// How quickly [`ConnectionStats::rtt_variance_ms`] follows new samples, between 0 and 1
+    pub rtt_variance_smoothing: f32,
+    /// The number of sent packets that are remembered to match them with acknowledgements. Must be a power of two
+    /// up to 32768. Older packets that are still unacknowledged are forgotten without being reported as lost.
+    pub sent_packets_capacity: usize,
+    /// A packet is considered lost once a packet this many sequence numbers newer was acknowledged.
+    /// Must be smaller than `sent_packets_capacity`.
+    pub packet_lost_cutoff: u16,
+    /// How many packets before the newest one every ack covers. Packets that are neither in an ack nor newer
+    /// than `packet_lost_cutoff` are eventually declared lost, so high packet rates on slow links need a wider one.
+    /// Both peers have to use the same width, otherwise they can't connect.
+    pub ack_width: AckWidth,
+    pub quality: QualityConfig,
+    pub congestion: CongestionConfig
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            authentication: Authentication::Checksum,
+            timeout: CONNECTION_TIMEOUT,
+            keepalive_interval: KEEPALIVE_INTERVAL,
+            disconnect_redundancy: DISCONNECT_REDUNDANCY,
+            delivery_queue_size: DELIVERY_QUEUE_SIZE,
+            rtt_variance_smoothing: RTT_VARIANCE_SMOOTHING_FACTOR,
+            sent_packets_capacity: SENT_PACKETS_CAPACITY,
+            packet_lost_cutoff: PACKET_LOST_CUTOFF,
+            ack_width: AckWidth::default(),
+            quality: QualityConfig::default(),
+            congestion: CongestionConfig::default()
+        }
+    }
+}
+
+impl ConnectionConfig {
+
+    pub(crate) fn validate(&self) -> std::result::Result<(), ConnectionError> {
+        if self.keepalive_interval >= self.timeout {
+            return Err(ConnectionError::InvalidConfig("the keepalive interval must be shorter than the timeout"));
+        }
+        if self.disconnect_redundancy == 0 {
+            return Err(ConnectionError::InvalidConfig("at least one disconnect packet must be sent"));
+        }
+        if self.delivery_queue_size == 0 {
+            return Err(ConnectionError::InvalidConfig("the delivery queue must hold at least one event"));
+        }
+        if !(self.rtt_variance_smoothing > 0.0 && self.rtt_variance_smoothing <= 1.0) {
+            return Err(ConnectionError::InvalidConfig("the rtt variance smoothing must be in (0, 1]"));
+        }
+        if !self.sent_packets_capacity.is_power_of_two() || self.sent_packets_capacity > 1 << 15 {
+            return Err(ConnectionError::InvalidConfig("the sent packets capacity must be a power of two up to 32768"));
+        }
+        if self.packet_lost_cutoff == 0 || usize::from(self.packet_lost_cutoff) >= self.sent_packets_capacity {
+            return Err(ConnectionError::InvalidConfig("the packet lost cutoff must be positive and smaller than the sent packets capacity"));
+        }
+        if self.congestion.recovery_time > self.congestion.max_recovery_time {
+            return Err(ConnectionError::InvalidConfig("the congestion recovery time must not exceed its maximum"));
+        }
+        Ok(())
+    }
+
+}
+
+/// A snapshot of the state and traffic of a connection, used by both [`Client::stats`](crate::Client::stats)
+/// and [`Server::client_stats`](crate::Server::client_stats)
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct ConnectionStats {
+    pub rtt_ms: u32,
+    /// The smoothed deviation of the round trip time samples from `rtt_ms`, i.e. the jitter
+    pub rtt_variance_ms: u32,
+    pub packet_loss: f32,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Bits per second sent over the last second
+    pub send_rate_bps: u64,
+    /// Bits per second received over the last second
+    pub recv_rate_bps: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// Packets that were neither acknowledged nor considered lost yet
+    pub in_flight: u32,
+    /// Replayed or duplicated packets that were dropped
+    pub duplicates_dropped: u64,
+    /// Packets that were dropped because they were too old for the receive window
+    pub stale_dropped: u64,
+    pub connected_for: Duration,
+    /// The time since the last packet was received
+    pub last_received: Duration
+}
+
+pub type ClientStats = ConnectionStats;
+
+/// Location of the payload of the most recently received packet
+#[derive(Debug, Clone)]
+enum Received {
+    Buffer(Range<usize>),
+    #[cfg(feature = "compression")]
+    Decompressed(Range<usize>)
+}
+
+/// The range `inner` occupies in the memory region `outer`
+fn position(outer: &Range<*const u8>, inner: &[u8]) -> Option<Range<usize>> {
+    let start = (inner.as_ptr() as usize).checked_sub(outer.start as usize)?;
+    (inner.as_ptr_range().end <= outer.end).then_some(start..start + inner.len())
 }
 
 impl PacketSocket {
 
-    pub fn new<T: Transport + 'static>(socket: T, identifier: &str) -> Self {
+    pub fn new<T: Transport + 'static>(socket: T, identifier: &str, ack_width: AckWidth) -> Self {
         Self {
             socket: Box::new(socket),
             buffer: [0; MAX_PACKET_SIZE],
-            salt: identifier.to_string()
+            send_buffer: [0; MAX_PACKET_SIZE],
+            #[cfg(feature = "compression")]
+            decompressed: [0; MAX_PACKET_SIZE],
+            received: None,
+            stats: SocketStats::default(),
+            protocol: ProtocolId::new(identifier, ack_width),
+            recv_error: None
         }
     }
 
@@ -29,73 +254,489 @@ impl PacketSocket {
         self.socket.local_addr()
     }
 
-    pub fn recv_from(&mut self) -> Result<(Result<Packet>, SocketAddr)> {
+    /// Receives the next packet. Also returns the sender, the size of the datagram and the unverified connection nonce.
+    /// `key` selects the session key from the source address and the connection nonce of the packet
+    pub fn recv_from<F>(&mut self, key: F) -> Result<(Result<Packet<'_>>, SocketAddr, usize, Option<ConnectionNonce>)> where F: FnOnce(SocketAddr, Option<ConnectionNonce>) -> Option<SessionKey> {
         let (size, src) = self.socket.recv_from(&mut self.buffer)?;
-        Ok((Packet::from(&self.buffer[..size], self.salt.as_bytes()), src))
+        self.recv_error = None;
+        self.stats.packets_received += 1;
+        self.stats.bytes_received += size as u64;
+        let nonce = peek_connection_nonce(&self.buffer[..size]);
+        let key = key(src, nonce);
+        let foreign = !has_magic(&self.buffer[..size]);
+        let buffer = self.buffer.as_ptr_range();
+        #[cfg(feature = "compression")]
+        let decompressed = self.decompressed.as_ptr_range();
+        let packet = Packet::from(&mut self.buffer[..size], &self.protocol, key.as_ref());
+        #[cfg(feature = "compression")]
+        let packet = packet.and_then(|packet| packet.decompress(&mut self.decompressed));
+        match &packet {
+            Err(_) if foreign => self.stats.foreign_packets += 1,
+            Err(err) if is_bad_signature(err) => self.stats.bad_signature_packets += 1,
+            Err(_) => self.stats.invalid_packets += 1,
+            Ok(_) => {}
+        }
+        self.received = packet.as_ref().ok().and_then(Packet::payload).and_then(|data| {
+            #[cfg(feature = "compression")]
+            if let Some(range) = position(&decompressed, data) {
+                return Some(Received::Decompressed(range));
+            }
+            position(&buffer, data).map(Received::Buffer)
+        });
+        Ok((packet, src, size, nonce))
+    }
+
+    pub fn stats(&self) -> SocketStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = SocketStats::default();
+    }
+
+    /// Remembers a transient receive error. Returns `false` if the same error was already reported since the last received packet.
+    pub fn report_recv_error(&mut self, kind: ErrorKind) -> bool {
+        self.recv_error.replace(kind) != Some(kind)
+    }
+
+    /// The payload of the most recently received packet. It stays valid until the next call to `recv_from`.
+    pub fn last_payload(&self) -> &[u8] {
+        match &self.received {
+            Some(Received::Buffer(range)) => &self.buffer[range.clone()],
+            #[cfg(feature = "compression")]
+            Some(Received::Decompressed(range)) => &self.decompressed[range.clone()],
+            None => &[]
+        }
     }
 
+    /// Sends a packet outside of a connection. Packets that would block are dropped,
+    /// the handshake and queries are retried by the peer anyway.
     pub fn send_to(&mut self, packet: Packet, addrs: SocketAddr) -> Result<()> {
-        let packet = packet.write(&mut self.buffer, self.salt.as_bytes())?;
-        let i = self.socket.send_to(packet, addrs)?;
-        assert_eq!(packet.len(), i);
-        Ok(())
+        let packet = packet.write(&mut self.send_buffer, &self.protocol, None, 0, 0)?;
+        match send_datagram(self.socket.as_ref(), &mut self.stats, packet, addrs) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e)
+        }
     }
 
+    /// Sends a packet over the connection. If the socket would block the packet is queued
+    /// and sent by `send_queued`. Fails with [`ErrorKind::WouldBlock`] once the queue is full.
     pub fn send_with(&mut self, packet: Packet, connection: &mut VirtualConnection) -> Result<()> {
+        if connection.outgoing.len() >= MAX_OUTGOING_QUEUE {
+            return Err(Error::new(ErrorKind::WouldBlock, "outgoing queue is full"));
+        }
         connection.last_sent_packet = Instant::now();
-        self.send_to(packet, connection.addrs)
+        if packet.carries_ack() {
+            connection.acks_owed = 0;
+        }
+        connection.send_nonce += 1;
+        let key = connection.keys.as_ref().map(|keys| &keys.send);
+        let packet = packet.write(&mut self.send_buffer, &self.protocol, key, connection.nonce, connection.send_nonce)?;
+        connection.packets_sent += 1;
+        connection.bytes_sent += packet.len() as u64;
+        connection.send_rate.record(packet.len(), Instant::now());
+        if connection.outgoing.is_empty() {
+            match send_datagram(self.socket.as_ref(), &mut self.stats, packet, connection.addrs) {
+                Ok(()) => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {},
+                Err(e) => return Err(e)
+            }
+        }
+        connection.outgoing.push_back(packet.into());
+        Ok(())
     }
 
-    pub fn send_payload(&mut self, payload: &[u8], connection: &mut VirtualConnection) -> Result<SequenceNumber> {
-        let seq = connection.next_sequence_number();
+    /// Retries the packets that were queued because the socket would block
+    pub fn send_queued(&mut self, connection: &mut VirtualConnection) -> Result<()> {
+        while let Some(packet) = connection.outgoing.front() {
+            match send_datagram(self.socket.as_ref(), &mut self.stats, packet, connection.addrs) {
+                Ok(()) => {},
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e)
+            }
+            connection.outgoing.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Sends the payload in its own packet. Packets with a tag are tracked and report it back with their ack or loss event,
+    /// untracked packets never produce either.
+    pub fn send_payload(&mut self, payload: &[u8], connection: &mut VirtualConnection, tag: Option<u64>) -> Result<SequenceNumber> {
+        if payload.len() > connection.max_payload_size() {
+            return Err(Error::new(ErrorKind::InvalidInput, "payload exceeds the maximum payload size"));
+        }
+        self.flush(connection)?;
+        let seq = match tag {
+            Some(tag) => connection.next_tagged_sequence_number(tag),
+            None => connection.next_internal_sequence_number()
+        };
         let ack = connection.received_packets;
+        #[cfg(feature = "compression")]
+        if connection.compression && payload.len() > COMPRESSION_THRESHOLD {
+            let mut buffer = [0u8; MAX_PACKET_SIZE];
+            if let Some(compressed) = compress(payload, &mut buffer) {
+                self.send_with(Packet::CompressedPayload(seq, ack, compressed), connection)?;
+                return Ok(seq);
+            }
+        }
         self.send_with(Packet::Payload(seq, ack, payload), connection)?;
         Ok(seq)
     }
 
+    /// Appends the payload to the batch of the connection. The batch is sent as soon as it is full or flushed.
+    pub fn queue_payload(&mut self, payload: &[u8], connection: &mut VirtualConnection) -> Result<SequenceNumber> {
+        if connection.batch.len() + 2 + payload.len() > connection.max_payload_size() {
+            self.flush(connection)?;
+        }
+        connection.batch.write_u16::<NetworkEndian>(payload.len() as u16)?;
+        connection.batch.extend_from_slice(payload);
+        Ok(connection.peek_next_sequence_number())
+    }
+
+    pub fn flush(&mut self, connection: &mut VirtualConnection) -> Result<()> {
+        if connection.batch.is_empty() {
+            return Ok(());
+        }
+        let mut batch = std::mem::take(&mut connection.batch);
+        let seq = connection.next_sequence_number();
+        let ack = connection.received_packets;
+        let result = self.send_with(Packet::Batch(seq, ack, &batch), connection);
+        batch.clear();
+        connection.batch = batch;
+        result
+    }
+
     pub fn send_keepalive(&mut self, connection: &mut VirtualConnection) -> Result<()> {
+        self.flush(connection)?;
+        let seq = connection.next_internal_sequence_number();
+        let ack = connection.received_packets;
+        self.send_with(Packet::KeepAlive(seq, ack), connection)
+    }
+
+    /// Sends a ping that the remote acknowledges right away
+    pub fn send_ping(&mut self, connection: &mut VirtualConnection) -> Result<SequenceNumber> {
+        let seq = connection.next_ping_sequence_number();
+        let ack = connection.received_packets;
+        self.send_with(Packet::Ping(seq, ack), connection)?;
+        Ok(seq)
+    }
+
+    pub fn send_ack(&mut self, connection: &mut VirtualConnection) -> Result<()> {
         let ack = connection.received_packets;
-        self.send_with(Packet::KeepAlive(ack), connection)
+        self.send_with(Packet::Ack(ack), connection)
+    }
+
+    /// Sends the due messages of the built-in channel, up to [`MAX_MESSAGE_PACKETS_PER_UPDATE`] packets.
+    /// Messages that don't go out because of congestion or a full outgoing queue wait for the next call.
+    pub fn send_messages(&mut self, connection: &mut VirtualConnection, congestion: &CongestionConfig) -> Result<()> {
+        let Some(mut channel) = connection.messages.take() else {
+            return Ok(());
+        };
+        channel.set_resend_interval(connection.resend_interval());
+        let budget = channel.packet_budget().min(connection.max_payload_size());
+        let mut result = Ok(());
+        for _ in 0..MAX_MESSAGE_PACKETS_PER_UPDATE {
+            if !channel.has_due_messages() || connection.exceeds_congestion_budget(congestion) {
+                break;
+            }
+            let seq = connection.track(PacketKind::Messages);
+            let ack = connection.received_packets;
+            result = channel
+                .write_packet(seq, budget)
+                .and_then(|packet| self.send_with(Packet::Messages(seq, ack, packet), connection));
+            if result.is_err() {
+                break;
+            }
+        }
+        connection.messages = Some(channel);
+        match result {
+            // the packet is resent once it is reported as lost
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result
+        }
     }
 
+    /// Sends one probe for every candidate size that is larger than the currently confirmed one
+    pub fn send_probes(&mut self, connection: &mut VirtualConnection) -> Result<()> {
+        connection.last_probe = Instant::now();
+        for size in PROBE_SIZES {
+            if connection.confirmed_size < Some(size) {
+                self.send_with(Packet::Probe(size), connection)?;
+            }
+        }
+        Ok(())
+    }
+
+}
+
+/// The result of polling a client or server for the next event
+pub(crate) enum Polled<E> {
+    Event(E),
+    /// The payload of the event still has to be filled in from [`PacketSocket::last_payload`]
+    Received(E),
+    /// A batch was received and its payloads are waiting in [`PendingPayloads`]
+    Pending
+}
+
+/// The payloads of a received batch that have not been turned into events yet
+#[derive(Debug)]
+pub struct PendingPayloads {
+    id: u16,
+    seq: SequenceNumber,
+    result: SequenceResult,
+    data: Vec<u8>,
+    offset: usize
+}
+
+impl Default for PendingPayloads {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            seq: 0,
+            result: SequenceResult::Latest,
+            data: Vec::new(),
+            offset: 0
+        }
+    }
+}
+
+impl PendingPayloads {
+
+    pub fn store(&mut self, id: u16, seq: SequenceNumber, result: SequenceResult, payloads: &[u8]) {
+        self.id = id;
+        self.seq = seq;
+        self.result = result;
+        self.data.clear();
+        self.data.extend_from_slice(payloads);
+        self.offset = 0;
+    }
+
+    pub fn has_next(&self) -> bool {
+        self.offset < self.data.len()
+    }
+
+    /// The next payload together with the id of the connection and the sequence number of the batch
+    pub fn next(&mut self) -> Option<(u16, SequenceNumber, SequenceResult, &[u8])> {
+        let payload = batch_iter(&self.data[self.offset..]).next()?;
+        self.offset += 2 + payload.len();
+        Some((self.id, self.seq, self.result, payload))
+    }
+
+}
+
+/// Ack, loss and pong events that wait to be polled. The oldest event is dropped once `capacity` is reached.
+#[derive(Debug)]
+pub struct DeliveryQueue<T> {
+    events: VecDeque<T>,
+    capacity: usize,
+    dropped: u64
+}
+
+impl<T> DeliveryQueue<T> {
+
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            events: VecDeque::new(),
+            capacity,
+            dropped: 0
+        }
+    }
+
+    pub fn push(&mut self, event: T) {
+        if self.events.len() >= self.capacity {
+            self.events.pop_front();
+            self.dropped += 1;
+        }
+        self.events.push_back(event);
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        self.events.pop_front()
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn reset_dropped(&mut self) {
+        self.dropped = 0;
+    }
+
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum PacketKind {
+    /// Contains the tag that is reported back with the ack or loss event
+    Payload(u64),
+    /// Only feeds the statistics and is never reported to the application
+    Internal,
+    Ping,
+    /// Carries the built-in message channel, which handles the ack or loss itself
+    Messages
 }
 
 #[derive(Clone, Debug)]
 struct PacketInformation{
-    send_time: Instant
+    send_time: Instant,
+    kind: PacketKind
 }
 
 impl PacketInformation {
+    fn new(kind: PacketKind) -> Self {
+        Self {
+            send_time: Instant::now(),
+            kind
+        }
+    }
+}
+
+/// The fate of a packet that is reported to the application
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Delivery {
+    /// Contains the tag of the packet and the unsmoothed round trip time
+    Acknowledged(u64, Duration),
+    /// Contains the tag of the packet
+    Lost(u64),
+    /// Contains the unsmoothed round trip time
+    Pong(Duration),
+    PongTimeout
+}
+
+/// Estimates a data rate over a sliding window by weighting the previous window
+/// with the fraction of it that still overlaps the sliding window
+#[derive(Debug, Clone)]
+struct RateMeter {
+    start: Instant,
+    current: u64,
+    previous: u64
+}
+
+impl RateMeter {
     fn new() -> Self {
         Self {
-            send_time: Instant::now()
+            start: Instant::now(),
+            current: 0,
+            previous: 0
+        }
+    }
+
+    /// The start of the window containing `now` and the bytes of the current and previous window
+    fn buckets(&self, now: Instant) -> (Instant, u64, u64) {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed < RATE_WINDOW {
+            (self.start, self.current, self.previous)
+        } else if elapsed < 2 * RATE_WINDOW {
+            (self.start + RATE_WINDOW, 0, self.current)
+        } else {
+            (now, 0, 0)
         }
     }
+
+    fn record(&mut self, bytes: usize, now: Instant) {
+        let (start, current, previous) = self.buckets(now);
+        self.start = start;
+        self.current = current + bytes as u64;
+        self.previous = previous;
+    }
+
+    fn rate(&self, now: Instant) -> u64 {
+        let (start, current, previous) = self.buckets(now);
+        let overlap = 1.0 - now.saturating_duration_since(start).as_secs_f64() / RATE_WINDOW.as_secs_f64();
+        let bytes = current as f64 + previous as f64 * overlap;
+        (bytes * 8.0 / RATE_WINDOW.as_secs_f64()).round() as u64
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct VirtualConnection {
     addrs: SocketAddr,
     id: u16,
+    nonce: ConnectionNonce,
+    keys: Option<Box<SessionKeys>>,
+    send_nonce: u64,
     last_received_packet: Instant,
     last_sent_packet: Instant,
     received_packets: SequenceNumberSet,
     sent_packets: SequenceBuffer<PacketInformation>,
     rtt: f32,
-    packet_loss: f32
+    rtt_variance: f32,
+    rtt_variance_smoothing: f32,
+    packet_loss: f32,
+    batch: Vec<u8>,
+    acks_owed: u32,
+    oldest_owed_ack: Instant,
+    rejected_packets: u64,
+    duplicates_dropped: u64,
+    stale_dropped: u64,
+    created: Instant,
+    last_probe: Instant,
+    confirmed_size: Option<u16>,
+    compression: bool,
+    packets_sent: u64,
+    bytes_sent: u64,
+    packets_received: u64,
+    bytes_received: u64,
+    send_rate: RateMeter,
+    recv_rate: RateMeter,
+    outgoing: VecDeque<Box<[u8]>>,
+    quality: NetworkQuality,
+    reported_quality: NetworkQuality,
+    good_since: Instant,
+    congestion: CongestionState,
+    /// When congestion avoidance was last left or the recovery time was last halved
+    congestion_changed: Option<Instant>,
+    uncongested_since: Instant,
+    recovery_time: Duration,
+    /// The channel behind `send_reliable`, created once it is first used by either side
+    messages: Option<Box<MessageChannel>>
 }
 
 impl VirtualConnection {
-    pub fn new(addrs: SocketAddr, id: u16) -> Self {
+    pub fn new(addrs: SocketAddr, id: u16, nonce: ConnectionNonce, keys: Option<SessionKeys>, compression: bool, sent_packets_capacity: usize, ack_width: AckWidth) -> Self {
         Self {
             addrs,
             id,
+            nonce,
+            keys: keys.map(Box::new),
+            send_nonce: 0,
             last_received_packet: Instant::now(),
             last_sent_packet: Instant::now(),
-            received_packets: SequenceNumberSet::new(0),
-            sent_packets: SequenceBuffer::with_capacity(1024),
+            received_packets: SequenceNumberSet::empty().with_width(ack_width),
+            sent_packets: SequenceBuffer::with_capacity(sent_packets_capacity),
             rtt: 0.0,
-            packet_loss: 0.0
+            rtt_variance: 0.0,
+            rtt_variance_smoothing: RTT_VARIANCE_SMOOTHING_FACTOR,
+            packet_loss: 0.0,
+            batch: Vec::new(),
+            acks_owed: 0,
+            oldest_owed_ack: Instant::now(),
+            rejected_packets: 0,
+            duplicates_dropped: 0,
+            stale_dropped: 0,
+            created: Instant::now(),
+            last_probe: Instant::now(),
+            confirmed_size: None,
+            compression,
+            packets_sent: 0,
+            bytes_sent: 0,
+            packets_received: 0,
+            bytes_received: 0,
+            send_rate: RateMeter::new(),
+            recv_rate: RateMeter::new(),
+            outgoing: VecDeque::new(),
+            quality: NetworkQuality::Good,
+            reported_quality: NetworkQuality::Good,
+            good_since: Instant::now(),
+            congestion: CongestionState::Good,
+            congestion_changed: None,
+            uncongested_since: Instant::now(),
+            recovery_time: Duration::ZERO,
+            messages: None
         }
     }
 
@@ -107,14 +748,89 @@ impl VirtualConnection {
         self.addrs
     }
 
+    pub(crate) fn nonce(&self) -> ConnectionNonce {
+        self.nonce
+    }
+
+    pub(crate) fn handshake_key(&self) -> Option<ConnectionKey> {
+        self.keys.as_ref().map(|keys| keys.handshake)
+    }
+
+    pub(crate) fn recv_key(&self) -> Option<SessionKey> {
+        self.keys.as_ref().map(|keys| keys.recv)
+    }
+
+    /// Whether large payloads are compressed before sending
+    pub fn compression(&self) -> bool {
+        self.compression
+    }
+
     pub fn rtt(&self) -> u32 {
         f32::round(self.rtt * 1000.0) as u32
     }
 
+    /// The smoothed mean deviation of the round trip time in milliseconds, like `rttvar` of RFC 6298
+    pub fn rtt_variance(&self) -> u32 {
+        f32::round(self.rtt_variance * 1000.0) as u32
+    }
+
+    /// How long to wait for an acknowledgement before resending, like the retransmission timeout of RFC 6298.
+    /// Includes the time the peer may hold back its acknowledgement.
+    pub fn resend_interval(&self) -> Duration {
+        Duration::from_secs_f32(self.rtt + 4.0 * self.rtt_variance) + MAX_ACK_DELAY
+    }
+
+    pub(crate) fn set_rtt_variance_smoothing(&mut self, factor: f32) {
+        self.rtt_variance_smoothing = factor;
+    }
+
     pub fn packet_loss(&self) -> f32 {
         (self.packet_loss * 1000.0).round() / 1000.0
     }
 
+    /// The number of replayed, duplicated or implausibly far ahead packets that were dropped
+    pub fn rejected_packets(&self) -> u64 {
+        self.rejected_packets
+    }
+
+    /// The received sequence numbers as they are acknowledged to the peer
+    pub fn received_window(&self) -> SequenceNumberSet {
+        self.received_packets
+    }
+
+    /// The number of replayed or duplicated packets that were dropped
+    pub fn duplicates_dropped(&self) -> u64 {
+        self.duplicates_dropped
+    }
+
+    /// The number of packets that were dropped because they were too old for the receive window
+    pub fn stale_dropped(&self) -> u64 {
+        self.stale_dropped
+    }
+
+    /// The largest payload that can be sent over this connection without exceeding the discovered packet size.
+    /// Uses a conservative default until a probe got through.
+    pub fn max_payload_size(&self) -> usize {
+        let size = self.confirmed_size.unwrap_or(FALLBACK_PROBE_SIZE).min(MAX_PACKET_SIZE as u16);
+        size as usize - payload_overhead(self.received_packets.width())
+    }
+
+    /// Whether the client side should keep sending probes
+    pub(crate) fn is_probing(&self) -> bool {
+        self.created.elapsed() < PROBE_DURATION && self.confirmed_size != PROBE_SIZES.into_iter().max()
+    }
+
+    pub(crate) fn last_probe_send(&self) -> Duration {
+        self.last_probe.elapsed()
+    }
+
+    /// Records that a probe of the given size made it through. The path is assumed to be symmetric.
+    pub(crate) fn confirm_size(&mut self, size: u16) {
+        if PROBE_SIZES.contains(&size) {
+            self.confirmed_size = self.confirmed_size.max(Some(size));
+        }
+    }
+
     pub fn last_packet_send(&self) -> Duration {
         self.last_sent_packet.elapsed()
     }
@@ -123,41 +839,511 @@ impl VirtualConnection {
         self.last_received_packet.elapsed()
     }
 
-    pub(crate) fn on_receive(&mut self) {
+    pub(crate) fn on_receive(&mut self, size: usize) {
         self.last_received_packet = Instant::now();
+        self.packets_received += 1;
+        self.bytes_received += size as u64;
+        self.recv_rate.record(size, self.last_received_packet);
     }
 
-    pub(crate) fn handle_seq(&mut self, seq: SequenceNumber) -> SequenceResult {
-        self.received_packets.insert(seq)
+    /// The number of bits per second sent over the last [`RATE_WINDOW`], including headers
+    pub fn send_rate_bps(&self) -> u64 {
+        self.send_rate.rate(Instant::now())
     }
 
-    pub(crate) fn handle_ack<F>(&mut self, ack: SequenceNumberSet, mut callback: F) where F: FnMut(SequenceNumber, bool) {
-        for (seq, _) in self.sent_packets.drain_older(ack.latest().wrapping_sub(PACKET_LOST_CUTOFF)) {
-            callback(seq, false);
-            self.packet_loss = lerp(self.packet_loss, 1., PL_SMOOTHING_FACTOR);
+    /// The number of bits per second received over the last [`RATE_WINDOW`], including headers
+    pub fn recv_rate_bps(&self) -> u64 {
+        self.recv_rate.rate(Instant::now())
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            rtt_ms: self.rtt(),
+            rtt_variance_ms: self.rtt_variance(),
+            packet_loss: self.packet_loss(),
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            send_rate_bps: self.send_rate_bps(),
+            recv_rate_bps: self.recv_rate_bps(),
+            packets_sent: self.packets_sent,
+            packets_received: self.packets_received,
+            in_flight: self.in_flight() as u32,
+            duplicates_dropped: self.duplicates_dropped,
+            stale_dropped: self.stale_dropped,
+            connected_for: self.created.elapsed(),
+            last_received: self.last_packet_received()
+        }
+    }
+
+    /// Remembers that the remote is waiting for an ack of a received payload
+    pub(crate) fn on_receive_payload(&mut self) {
+        if self.acks_owed == 0 {
+            self.oldest_owed_ack = Instant::now();
+        }
+        self.acks_owed += 1;
+    }
+
+    /// The quality as of the last update, see [`QualityConfig`]
+    pub fn quality(&self) -> NetworkQuality {
+        self.quality
+    }
+
+    /// Reevaluates the quality from the smoothed round trip time and packet loss
+    pub(crate) fn update_quality(&mut self, config: &QualityConfig) {
+        if self.rtt() >= config.bad_rtt_ms || self.packet_loss() >= config.bad_loss {
+            self.quality = NetworkQuality::Poor;
+            self.good_since = Instant::now();
+        } else if self.good_since.elapsed() >= config.hysteresis {
+            self.quality = NetworkQuality::Good;
         }
+    }
+
+    pub fn congestion_state(&self) -> CongestionState {
+        self.congestion
+    }
+
+    /// Moves in and out of congestion avoidance based on the smoothed round trip time and packet loss
+    pub(crate) fn update_congestion(&mut self, config: &CongestionConfig) {
+        let bad = self.rtt() >= config.bad_rtt_ms || self.packet_loss() >= config.bad_loss;
+        let recovery_time = self.recovery_time.clamp(config.recovery_time, config.max_recovery_time);
+        let now = Instant::now();
+        match self.congestion {
+            CongestionState::Good if bad => {
+                let relapse = self.congestion_changed.is_some_and(|changed| now - changed < recovery_time);
+                self.recovery_time = if relapse { (recovery_time * 2).min(config.max_recovery_time) } else { recovery_time };
+                self.congestion = CongestionState::Bad;
+                self.uncongested_since = now;
+            }
+            CongestionState::Good => if self.congestion_changed.is_some_and(|changed| now - changed >= recovery_time) {
+                self.recovery_time = (recovery_time / 2).max(config.recovery_time);
+                self.congestion_changed = Some(now);
+            }
+            CongestionState::Bad if bad => self.uncongested_since = now,
+            CongestionState::Bad => if now - self.uncongested_since >= recovery_time {
+                self.congestion = CongestionState::Good;
+                self.congestion_changed = Some(now);
+            }
+        }
+    }
+
+    /// Whether sending has to be refused because of [`CongestionConfig::enforce`]
+    pub(crate) fn exceeds_congestion_budget(&self, config: &CongestionConfig) -> bool {
+        config.enforce && self.congestion == CongestionState::Bad && self.send_rate_bps() >= config.send_rate_bps
+    }
+
+    /// Returns the quality if it changed since the last call
+    pub(crate) fn take_quality_change(&mut self) -> Option<NetworkQuality> {
+        (self.quality != self.reported_quality).then(|| {
+            self.reported_quality = self.quality;
+            self.quality
+        })
+    }
+
+    /// The time until the connection needs to flush its batch, send an ack or a keepalive, or check for a timeout
+    pub(crate) fn next_update(&self, config: &ConnectionConfig) -> Duration {
+        if !self.batch.is_empty() || self.ack_due() || self.messages.as_ref().is_some_and(|channel| channel.has_due_messages()) {
+            return Duration::ZERO;
+        }
+        let mut next = config.keepalive_interval.saturating_sub(self.last_packet_send())
+            .min(config.timeout.saturating_sub(self.last_packet_received()));
+        if self.messages.as_ref().is_some_and(|channel| channel.has_unsend_messages()) {
+            next = next.min(self.resend_interval());
+        }
+        if self.acks_owed > 0 {
+            next = next.min(MAX_ACK_DELAY.saturating_sub(self.oldest_owed_ack.elapsed()));
+        }
+        next
+    }
+
+    /// Whether enough payloads went unacknowledged to warrant a dedicated ack packet
+    pub(crate) fn ack_due(&self) -> bool {
+        self.acks_owed >= ACK_THRESHOLD || (self.acks_owed > 0 && self.oldest_owed_ack.elapsed() >= MAX_ACK_DELAY)
+    }
+
+    /// Whether `seq` is newer than every packet received so far and would be accepted by `handle_seq`
+    pub(crate) fn is_newest(&self, seq: SequenceNumber) -> bool {
+        let latest = self.received_packets.latest();
+        self.received_packets.is_empty() || (sequence_greater_than(seq, latest) && seq.wrapping_sub(latest) <= MAX_SEQUENCE_JUMP)
+    }
+
+    /// Moves the connection to a new address and returns the old one
+    pub(crate) fn migrate(&mut self, addrs: SocketAddr) -> SocketAddr {
+        std::mem::replace(&mut self.addrs, addrs)
+    }
+
+    pub(crate) fn handle_seq(&mut self, seq: SequenceNumber) -> SequenceResult {
+        let latest = self.received_packets.latest();
+        // accepting a sequence number far ahead would invalidate the ack state of the entire connection
+        let result = match !self.received_packets.is_empty() && sequence_greater_than(seq, latest) && seq.wrapping_sub(latest) > MAX_SEQUENCE_JUMP {
+            true => SequenceResult::TooNew,
+            false => self.received_packets.insert(seq)
+        };
+        match result {
+            SequenceResult::Latest | SequenceResult::Fresh => return result,
+            SequenceResult::Duplicate => self.duplicates_dropped += 1,
+            SequenceResult::TooOld => self.stale_dropped += 1,
+            SequenceResult::TooNew => {}
+        }
+        self.rejected_packets += 1;
+        result
+    }
+
+    pub(crate) fn handle_ack<F>(&mut self, ack: SequenceNumberSet, lost_cutoff: u16, mut callback: F) where F: FnMut(SequenceNumber, Delivery) {
         for seq in ack.iter() {
             if let Some(info) = self.sent_packets.remove(seq) {
-                callback(seq, true);
-                let rtt = info.send_time.elapsed().as_secs_f32();
-                self.rtt = lerp(self.rtt, rtt, RTT_SMOOTHING_FACTOR);
+                let rtt = info.send_time.elapsed();
+                match info.kind {
+                    PacketKind::Payload(tag) => callback(seq, Delivery::Acknowledged(tag, rtt)),
+                    PacketKind::Internal => {},
+                    PacketKind::Ping => callback(seq, Delivery::Pong(rtt)),
+                    PacketKind::Messages => if let Some(channel) = &mut self.messages {
+                        channel.on_ack(seq);
+                    }
+                }
+                self.rtt_variance = lerp(self.rtt_variance, (self.rtt - rtt.as_secs_f32()).abs(), self.rtt_variance_smoothing);
+                self.rtt = lerp(self.rtt, rtt.as_secs_f32(), RTT_SMOOTHING_FACTOR);
 
                 self.packet_loss = lerp(self.packet_loss, 0., PL_SMOOTHING_FACTOR);
             }
         }
+        // acknowledged packets are removed first, so a wide ack can cover packets beyond the cutoff
+        for (seq, info) in self.sent_packets.drain_older(ack.latest().wrapping_sub(lost_cutoff as SequenceNumber)) {
+            match info.kind {
+                PacketKind::Payload(tag) => callback(seq, Delivery::Lost(tag)),
+                PacketKind::Internal => {},
+                PacketKind::Ping => callback(seq, Delivery::PongTimeout),
+                PacketKind::Messages => if let Some(channel) = &mut self.messages {
+                    channel.on_loss(seq);
+                }
+            }
+            self.packet_loss = lerp(self.packet_loss, 1., PL_SMOOTHING_FACTOR);
+        }
+    }
+
+    /// The channel behind `send_reliable`. `None` until either side used it.
+    pub fn message_channel(&self) -> Option<&MessageChannel> {
+        self.messages.as_deref()
+    }
+
+    fn message_channel_mut(&mut self) -> &mut MessageChannel {
+        self.messages.get_or_insert_with(|| Box::new(MessageChannel::new()))
+    }
+
+    pub(crate) fn queue_message(&mut self, msg: &[u8]) -> std::result::Result<(), ChannelError> {
+        self.message_channel_mut().queue_message(msg)
+    }
+
+    /// Passes the content of a [`Packet::Messages`] to the channel. Malformed content is dropped like a malformed packet.
+    pub(crate) fn on_messages(&mut self, data: &[u8]) {
+        if self.message_channel_mut().on_receive(data).is_err() {
+            self.rejected_packets += 1;
+        }
+    }
+
+    pub(crate) fn receive_message(&mut self) -> Option<Box<[u8]>> {
+        self.messages.as_mut().and_then(|channel| channel.receive_message())
+    }
+
+    /// The number of payload packets that were neither acknowledged nor declared lost yet
+    pub fn in_flight(&self) -> usize {
+        self.sent_packets.iter().filter(|(_, info)| matches!(info.kind, PacketKind::Payload(_))).count()
     }
 
+    /// The sequence number the next packet is sent with. A pending batch is sent before the next payload,
+    /// so it takes this number unless it was flushed first.
     pub fn peek_next_sequence_number(&self) -> SequenceNumber {
         self.sent_packets.next_sequence_number()
     }
 
     pub(crate) fn next_sequence_number(&mut self) -> SequenceNumber {
-        let (seq, _) = self.sent_packets.insert(PacketInformation::new());
+        self.next_tagged_sequence_number(0)
+    }
+
+    pub(crate) fn next_tagged_sequence_number(&mut self, tag: u64) -> SequenceNumber {
+        self.track(PacketKind::Payload(tag))
+    }
+
+    /// Sequence number for packets that only feed the statistics and are never reported to the application
+    pub(crate) fn next_internal_sequence_number(&mut self) -> SequenceNumber {
+        self.track(PacketKind::Internal)
+    }
+
+    pub(crate) fn next_ping_sequence_number(&mut self) -> SequenceNumber {
+        self.track(PacketKind::Ping)
+    }
+
+    fn track(&mut self, kind: PacketKind) -> SequenceNumber {
+        let (seq, _) = self.sent_packets.insert(PacketInformation::new(kind));
         seq
     }
 
 }
 
+/// Sends the packet as a single datagram. A transport that only wrote part of it sent a truncated packet
+/// that the peer is going to reject, so this is reported as an error instead of being counted.
+fn send_datagram(socket: &dyn Transport, stats: &mut SocketStats, packet: &[u8], addrs: SocketAddr) -> Result<()> {
+    let sent = socket.send_to(packet, addrs)?;
+    if sent != packet.len() {
+        return Err(Error::new(ErrorKind::WriteZero, format!("only {} of {} bytes of the packet were sent to {}", sent, packet.len(), addrs)));
+    }
+    stats.packets_sent += 1;
+    stats.bytes_sent += sent as u64;
+    Ok(())
+}
+
 fn lerp(a: f32, b: f32, v: f32) -> f32 {
     a + (b - a) * v
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::connection::{CongestionConfig, CongestionState, Delivery, DeliveryQueue, NetworkQuality, QualityConfig, RateMeter, VirtualConnection};
+    use crate::constants::{PACKET_LOST_CUTOFF, SENT_PACKETS_CAPACITY};
+    use crate::sequencing::{AckWidth, SequenceNumber, SequenceNumberSet, SequenceResult};
+    use crate::socket::Endpoint;
+
+    #[test]
+    fn test_delivery_queue() {
+        let mut queue = DeliveryQueue::new(2);
+        for i in 0..5 {
+            queue.push(i);
+        }
+        assert_eq!(queue.dropped(), 3);
+        assert_eq!(queue.pop(), Some(3));
+        assert_eq!(queue.pop(), Some(4));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn test_internal_sequence_numbers() {
+        let mut connection = VirtualConnection::new(Endpoint::local_port(1), 0, 0, None, false, SENT_PACKETS_CAPACITY, AckWidth::default());
+        let keepalive = connection.next_internal_sequence_number();
+        let payload = connection.next_sequence_number();
+
+        let mut events = Vec::new();
+        let mut ack = SequenceNumberSet::new(keepalive);
+        ack.insert(payload);
+        connection.handle_ack(ack, PACKET_LOST_CUTOFF, |seq, acked| events.push((seq, acked)));
+        assert!(matches!(events[..], [(seq, Delivery::Acknowledged(0, _))] if seq == payload));
+
+        let lost = connection.next_internal_sequence_number();
+        connection.handle_ack(SequenceNumberSet::new(lost.wrapping_add(PACKET_LOST_CUTOFF as SequenceNumber + 1)), PACKET_LOST_CUTOFF, |seq, acked| events.push((seq, acked)));
+        assert!(matches!(events[..], [(seq, Delivery::Acknowledged(0, _))] if seq == payload));
+        assert!(connection.packet_loss() > 0.0);
+    }
+
+    #[test]
+    fn test_sent_packets_capacity() {
+        let burst = |capacity: usize, cutoff: u16| {
+            let mut connection = VirtualConnection::new(Endpoint::local_port(1), 0, 0, None, false, capacity, AckWidth::default());
+            let sent: Vec<_> = (0..3000).map(|_| connection.next_sequence_number()).collect();
+            assert_eq!(connection.in_flight(), capacity.min(sent.len()));
+            let mut events = (0, 0);
+            // every ack covers the full bitfield, but they arrive newest first
+            for chunk in sent.rchunks(SequenceNumberSet::new(0).capacity()) {
+                let ack = SequenceNumberSet::from_bitfield(AckWidth::Bits32, *chunk.last().unwrap(), u128::MAX);
+                connection.handle_ack(ack, cutoff, |_, delivery| match delivery {
+                    Delivery::Acknowledged(..) => events.0 += 1,
+                    Delivery::Lost(_) => events.1 += 1,
+                    _ => unreachable!()
+                });
+            }
+            events
+        };
+        assert_eq!(burst(4096, 4000), (3000, 0));
+        let (acked, lost) = burst(SENT_PACKETS_CAPACITY, PACKET_LOST_CUTOFF);
+        assert!(lost > 0 && acked + lost < 3000);
+    }
+
+    #[test]
+    fn test_rate_meter() {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut meter = RateMeter::new();
+        meter.start = start;
+        meter.record(500, at(0));
+        meter.record(500, at(400));
+        assert_eq!(meter.rate(at(400)), 8000);
+        assert_eq!(meter.rate(at(1500)), 4000);
+        meter.record(250, at(1500));
+        assert_eq!(meter.rate(at(1500)), 6000);
+        assert_eq!(meter.rate(at(2750)), 500);
+        assert_eq!(meter.rate(at(3000)), 0);
+        meter.record(100, at(5000));
+        assert_eq!(meter.rate(at(5000)), 800);
+    }
+
+    #[test]
+    fn test_rtt_variance() {
+        let measure = |delays: [u64; 2]| {
+            let mut connection = VirtualConnection::new(Endpoint::local_port(1), 0, 0, None, false, SENT_PACKETS_CAPACITY, AckWidth::default());
+            for i in 0..12 {
+                let seq = connection.next_sequence_number();
+                std::thread::sleep(Duration::from_millis(delays[i % 2]));
+                connection.handle_ack(SequenceNumberSet::new(seq), PACKET_LOST_CUTOFF, |_, _| {});
+            }
+            connection
+        };
+        let steady = measure([10, 10]);
+        let jittery = measure([0, 20]);
+        assert!(jittery.rtt_variance() > steady.rtt_variance());
+        assert!(jittery.rtt_variance() >= 5);
+        assert!(steady.rtt().abs_diff(jittery.rtt()) <= 5);
+    }
+
+    #[test]
+    fn test_ping_delivery() {
+        let mut connection = VirtualConnection::new(Endpoint::local_port(1), 0, 0, None, false, SENT_PACKETS_CAPACITY, AckWidth::default());
+        let answered = connection.next_ping_sequence_number();
+        let lost = connection.next_ping_sequence_number();
+
+        let mut events = Vec::new();
+        connection.handle_ack(SequenceNumberSet::new(answered), PACKET_LOST_CUTOFF, |seq, delivery| events.push((seq, delivery)));
+        assert!(matches!(events[..], [(seq, Delivery::Pong(_))] if seq == answered));
+
+        events.clear();
+        connection.handle_ack(SequenceNumberSet::new(lost.wrapping_add(PACKET_LOST_CUTOFF as SequenceNumber + 1)), PACKET_LOST_CUTOFF, |seq, delivery| events.push((seq, delivery)));
+        assert_eq!(events, [(lost, Delivery::PongTimeout)]);
+        assert_eq!(connection.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_quality() {
+        let config = QualityConfig { hysteresis: Duration::from_millis(20), ..Default::default() };
+        let mut connection = VirtualConnection::new(Endpoint::local_port(1), 0, 0, None, false, SENT_PACKETS_CAPACITY, AckWidth::default());
+        connection.update_quality(&config);
+        assert_eq!(connection.take_quality_change(), None);
+
+        connection.rtt = 0.5;
+        connection.update_quality(&config);
+        assert_eq!(connection.quality(), NetworkQuality::Poor);
+        assert_eq!(connection.take_quality_change(), Some(NetworkQuality::Poor));
+        assert_eq!(connection.take_quality_change(), None);
+
+        connection.rtt = 0.0;
+        connection.update_quality(&config);
+        assert_eq!(connection.quality(), NetworkQuality::Poor);
+        std::thread::sleep(Duration::from_millis(30));
+        connection.update_quality(&config);
+        assert_eq!(connection.take_quality_change(), Some(NetworkQuality::Good));
+
+        connection.packet_loss = 0.5;
+        connection.update_quality(&config);
+        assert_eq!(connection.quality(), NetworkQuality::Poor);
+    }
+
+    #[test]
+    fn test_congestion() {
+        let config = CongestionConfig { recovery_time: Duration::from_millis(20), max_recovery_time: Duration::from_millis(80), send_rate_bps: 0, enforce: true, ..Default::default() };
+        let mut connection = VirtualConnection::new(Endpoint::local_port(1), 0, 0, None, false, SENT_PACKETS_CAPACITY, AckWidth::default());
+        connection.update_congestion(&config);
+        assert_eq!(connection.congestion_state(), CongestionState::Good);
+        assert!(!connection.exceeds_congestion_budget(&config));
+
+        connection.packet_loss = 0.5;
+        connection.update_congestion(&config);
+        assert_eq!(connection.congestion_state(), CongestionState::Bad);
+        assert_eq!(connection.recovery_time, Duration::from_millis(20));
+        assert!(connection.exceeds_congestion_budget(&config));
+        assert!(!connection.exceeds_congestion_budget(&CongestionConfig { enforce: false, ..config }));
+
+        for expected in [40, 80, 80] {
+            connection.packet_loss = 0.0;
+            connection.update_congestion(&config);
+            assert_eq!(connection.congestion_state(), CongestionState::Bad);
+            std::thread::sleep(connection.recovery_time + Duration::from_millis(5));
+            connection.update_congestion(&config);
+            assert_eq!(connection.congestion_state(), CongestionState::Good);
+
+            connection.packet_loss = 0.5;
+            connection.update_congestion(&config);
+            assert_eq!(connection.congestion_state(), CongestionState::Bad);
+            assert_eq!(connection.recovery_time, Duration::from_millis(expected));
+        }
+
+        connection.packet_loss = 0.0;
+        std::thread::sleep(Duration::from_millis(85));
+        connection.update_congestion(&config);
+        assert_eq!(connection.congestion_state(), CongestionState::Good);
+        std::thread::sleep(Duration::from_millis(85));
+        connection.update_congestion(&config);
+        assert_eq!(connection.recovery_time, Duration::from_millis(40));
+    }
+
+    #[test]
+    fn test_first_sequence_number() {
+        for first in [0, 1, 20000] {
+            let mut connection = VirtualConnection::new(Endpoint::local_port(1), 0, 0, None, false, SENT_PACKETS_CAPACITY, AckWidth::default());
+            assert!(connection.received_window().is_empty());
+            assert!(connection.is_newest(first));
+            assert_eq!(connection.handle_seq(first), SequenceResult::Latest);
+            assert_eq!(connection.handle_seq(first), SequenceResult::Duplicate);
+        }
+    }
+
+    #[test]
+    fn test_replay_protection() {
+        let mut connection = VirtualConnection::new(Endpoint::local_port(1), 0, 0, None, false, SENT_PACKETS_CAPACITY, AckWidth::default());
+        for seq in 1..=100 {
+            assert_eq!(connection.handle_seq(seq), SequenceResult::Latest);
+        }
+        assert_eq!(connection.handle_seq(90), SequenceResult::Duplicate);
+        assert_eq!(connection.handle_seq(3), SequenceResult::TooOld);
+        assert_eq!(connection.handle_seq(100 + 20000), SequenceResult::TooNew);
+        assert_eq!(connection.rejected_packets(), 3);
+        assert_eq!(connection.duplicates_dropped(), 1);
+        assert_eq!(connection.stale_dropped(), 1);
+        assert_eq!(connection.handle_seq(101), SequenceResult::Latest);
+        assert_eq!(connection.rejected_packets(), 3);
+        assert_eq!(connection.received_window().latest(), 101);
+        assert!(connection.received_window().contains(90) && !connection.received_window().contains(3));
+    }
+
+    #[test]
+    fn test_oversized_payload() {
+        use crate::connection::PacketSocket;
+        use crate::testing::MemoryNetwork;
+
+        let network = MemoryNetwork::default();
+        let mut socket = PacketSocket::new(network.bind(1), "test", AckWidth::default());
+        let mut connection = VirtualConnection::new(Endpoint::local_port(2), 0, 0, None, false, SENT_PACKETS_CAPACITY, AckWidth::default());
+        let next = connection.peek_next_sequence_number();
+        let payload = vec![0u8; connection.max_payload_size() + 1];
+        assert_eq!(socket.send_payload(&payload, &mut connection, Some(0)).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(connection.peek_next_sequence_number(), next);
+        assert_eq!(socket.send_payload(&payload[1..], &mut connection, Some(0)).unwrap(), next);
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn test_compression() {
+        use crate::connection::PacketSocket;
+        use crate::constants::MAX_PACKET_SIZE;
+        use crate::packets::{MAX_PAYLOAD_OVERHEAD, Packet, ProtocolId};
+        use crate::socket::Transport;
+        use crate::testing::MemoryNetwork;
+
+        let network = MemoryNetwork::default();
+        let mut socket = PacketSocket::new(network.bind(1), "test", AckWidth::default());
+        let remote = network.bind(2);
+        let mut connection = VirtualConnection::new(Endpoint::local_port(2), 0, 0, None, true, SENT_PACKETS_CAPACITY, AckWidth::default());
+
+        let mut random = vec![0u8; connection.max_payload_size()];
+        getrandom::getrandom(&mut random).unwrap();
+        let compressible = [7u8; 1000];
+        for payload in [&random[..], &compressible[..]] {
+            socket.send_payload(payload, &mut connection, Some(0)).unwrap();
+            let mut buffer = [0u8; MAX_PACKET_SIZE];
+            let (len, _) = remote.recv_from(&mut buffer).unwrap();
+            assert!(len <= payload.len() + MAX_PAYLOAD_OVERHEAD);
+            assert!(payload != compressible || len < payload.len());
+
+            let mut decompressed = [0u8; MAX_PACKET_SIZE];
+            match Packet::from(&mut buffer[..len], &ProtocolId::new("test", AckWidth::default()), None).unwrap().decompress(&mut decompressed).unwrap() {
+                Packet::Payload(_, _, data) => assert_eq!(data, payload),
+                packet => panic!("unexpected packet {:?}", packet)
+            }
+        }
+    }
+
+}
@@ -1,10 +1,82 @@
 use std::time::Duration;
+use crate::sequencing::SequenceNumber;
+
+/// Mixed into the protocol identifier so that incompatible versions reject each other's packets.
+/// Non-default [`AckWidth`](crate::AckWidth)s additionally mix in their width.
+pub const PROTOCOL_VERSION: u8 = 1;
+
 pub const MAX_PACKET_SIZE: usize = 1500;
+pub const CONNECTION_REQUEST_SIZE: usize = 512;
+pub const MAX_CONNECTION_PAYLOAD_SIZE: usize = 200;
+pub const QUERY_PACKET_SIZE: usize = 512;
+pub const MAX_QUERY_RESPONSE_SIZE: usize = 400;
+
+/// Payloads up to this size are never compressed
+#[cfg(feature = "compression")]
+pub const COMPRESSION_THRESHOLD: usize = 128;
+
+pub const PROBE_SIZES: [u16; 3] = [1400, 1200, 1024];
+pub const FALLBACK_PROBE_SIZE: u16 = 1200;
+pub const PROBE_INTERVAL: Duration = Duration::from_millis(200);
+pub const PROBE_DURATION: Duration = Duration::from_millis(1500);
 
 pub const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+pub const CONNECTION_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+pub const MAX_CONNECTION_ATTEMPTS: u32 = 20;
+/// How long a connection request waits for [`Server::accept_pending`](crate::Server::accept_pending) before it is denied
+pub const APPROVAL_TIMEOUT: Duration = Duration::from_secs(5);
+/// Denied connection requests from the same address are reported at most once per interval
+pub const DENIAL_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// The number of addresses that are remembered for [`DENIAL_REPORT_INTERVAL`]. Further denials are not reported.
+pub const MAX_REPORTED_DENIALS: usize = 64;
+/// The number of ip addresses the rate limiter of the server keeps track of
+pub const MAX_RATE_LIMITED_ADDRESSES: usize = 4096;
+/// How often the rate limiter drops the addresses that are back to a full burst
+pub const RATE_LIMIT_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+pub const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
 pub const KEEPALIVE_INTERVAL: Duration = Duration::from_millis(500);
+pub const DISCONNECT_REDUNDANCY: u32 = 10;
+pub const DELIVERY_QUEUE_SIZE: usize = 4096;
+/// The sliding window over which the send and receive rates of a connection are measured
+pub const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+pub const ACK_THRESHOLD: u32 = 8;
+pub const MAX_ACK_DELAY: Duration = Duration::from_millis(50);
 
 pub const PACKET_LOST_CUTOFF: u16 = 40;
+/// The number of sent packets that are remembered per connection to match them with acknowledgements
+pub const SENT_PACKETS_CAPACITY: usize = 1024;
+/// The number of packets that are held back per connection while the socket would block
+pub const MAX_OUTGOING_QUEUE: usize = 64;
+pub const MAX_SEQUENCE_JUMP: SequenceNumber = 1024;
+
+/// The default number of messages a channel buffers per priority, both for sending and for receiving
+pub const MESSAGE_BUFFER_CAPACITY: usize = 256;
+/// Reliable messages larger than this are split into fragments of this size
+pub const MESSAGE_FRAGMENT_SIZE: usize = 512;
+/// The default limit for the size of a reassembled reliable message
+pub const MAX_MESSAGE_SIZE: usize = 256 * 1024;
+/// The default number of bytes the messages of a packet take up. Leaves room for the packet header
+/// and the framing of [`Channels`](crate::Channels) within the smallest probed packet size.
+pub const MESSAGE_PACKET_BUDGET: usize = 960;
+/// How long a channel waits for the acknowledgement of a message before sending it again by default
+pub const MESSAGE_RESEND_INTERVAL: Duration = Duration::from_millis(100);
+/// The number of packets the built-in message channel of a connection sends per update at most
+pub const MAX_MESSAGE_PACKETS_PER_UPDATE: usize = 8;
+/// While messages of a higher priority are waiting as well, lower priorities are owed this fraction
+/// of the packet budget with every packet, so that they are never starved completely
+pub const LOW_PRIORITY_SHARE: f32 = 0.25;
 
 pub const PL_SMOOTHING_FACTOR: f32 = 0.025;
-pub const RTT_SMOOTHING_FACTOR: f32 = 0.1;
\ No newline at end of file
+pub const RTT_SMOOTHING_FACTOR: f32 = 0.1;
+pub const RTT_VARIANCE_SMOOTHING_FACTOR: f32 = 0.25;
+
+pub const BAD_RTT_MS: u32 = 250;
+pub const BAD_PACKET_LOSS: f32 = 0.1;
+/// How long the connection has to stay good before it is reported as good again
+pub const QUALITY_HYSTERESIS: Duration = Duration::from_secs(10);
+
+/// How long the conditions have to stay good before congestion avoidance ends, without any relapses
+pub const CONGESTION_RECOVERY_TIME: Duration = Duration::from_secs(4);
+pub const MAX_CONGESTION_RECOVERY_TIME: Duration = Duration::from_secs(60);
+pub const CONGESTION_SEND_RATE_BPS: u64 = 64_000;
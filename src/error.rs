@@ -1,21 +1,123 @@
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
 
 pub type IOResult<T> = std::io::Result<T>;
 
+/// Errors of the socket that can go away on their own, like the network being temporarily unreachable.
+/// They are reported as events and the connection keeps running, all others are returned as `Err`.
+pub fn is_transient(kind: ErrorKind) -> bool {
+    matches!(kind,
+        ErrorKind::Interrupted |
+        ErrorKind::TimedOut |
+        ErrorKind::ConnectionReset |
+        ErrorKind::ConnectionRefused |
+        ErrorKind::ConnectionAborted |
+        ErrorKind::AddrNotAvailable |
+        ErrorKind::NetworkDown |
+        ErrorKind::NetworkUnreachable |
+        ErrorKind::HostUnreachable)
+}
+
 #[derive(Debug)]
 pub enum ConnectionError {
     Disconnected,
-    ConnectionNotReady
+    ConnectionNotReady,
+    PayloadTooLarge { len: usize, max: usize },
+    NoRemoteAddress,
+    InvalidConfig(&'static str),
+    /// The socket would block and the outgoing queue of the connection is full. The packet was dropped,
+    /// but its sequence number was consumed, so it is eventually reported as lost.
+    /// Also returned without consuming a sequence number when the send rate is limited by
+    /// [`CongestionConfig::enforce`](crate::CongestionConfig::enforce).
+    Backpressured,
+    /// The built-in message channel of the connection did not take the message
+    Channel(ChannelError),
+    /// The socket failed to send a packet that could not be queued for later
+    Socket(std::io::Error)
 }
 
 impl Display for ConnectionError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             ConnectionError::Disconnected => f.write_str("Connection could not be found"),
-            ConnectionError::ConnectionNotReady => f.write_str("Connection is not ready")
+            ConnectionError::ConnectionNotReady => f.write_str("Connection is not ready"),
+            ConnectionError::PayloadTooLarge { len, max } => write!(f, "Payload of {} bytes exceeds the maximum of {} bytes", len, max),
+            ConnectionError::NoRemoteAddress => f.write_str("There is no previous server address"),
+            ConnectionError::InvalidConfig(reason) => write!(f, "Invalid config: {}", reason),
+            ConnectionError::Backpressured => f.write_str("The outgoing queue is full or the send rate is exceeded"),
+            ConnectionError::Channel(err) => write!(f, "Message channel: {}", err),
+            ConnectionError::Socket(err) => write!(f, "Socket error: {}", err)
+        }
+    }
+}
+
+impl Error for ConnectionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConnectionError::Channel(err) => Some(err),
+            ConnectionError::Socket(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<ChannelError> for ConnectionError {
+    fn from(err: ChannelError) -> Self {
+        ConnectionError::Channel(err)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConnectError {
+    AddressResolution(std::io::Error),
+    NoUsableAddress,
+    PayloadTooLarge { len: usize, max: usize }
+}
+
+impl Display for ConnectError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectError::AddressResolution(err) => write!(f, "Failed to resolve the server address: {}", err),
+            ConnectError::NoUsableAddress => f.write_str("The server address did not resolve to any usable address"),
+            ConnectError::PayloadTooLarge { len, max } => write!(f, "Payload of {} bytes exceeds the maximum of {} bytes", len, max)
+        }
+    }
+}
+
+impl Error for ConnectError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConnectError::AddressResolution(err) => Some(err),
+            _ => None
+        }
+    }
+}
+/// Returned when a message can not be queued on a [`MessageChannel`](crate::MessageChannel)
+#[derive(Debug)]
+pub enum ChannelError {
+    /// Too many messages wait to be sent or acknowledged. The message is handed back, so it can be queued again later.
+    QueueFull { returned: Box<[u8]> },
+    /// The message exceeds the maximum message size or can never fit into the packet budget
+    MessageTooLarge { len: usize, max: usize },
+    /// [`Channels`](crate::Channels) has no channel with this index
+    UnknownChannel(u8),
+    /// A `TypedChannel` could not serialize the message
+    Unserializable(String),
+    /// A message received by a `TypedChannel` could not be deserialized. The message is dropped.
+    InvalidMessage(String)
+}
+
+impl Display for ChannelError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelError::QueueFull { .. } => f.write_str("The outgoing message queue is full"),
+            ChannelError::MessageTooLarge { len, max } => write!(f, "Message of {} bytes exceeds the maximum of {} bytes", len, max),
+            ChannelError::UnknownChannel(channel) => write!(f, "There is no channel {}", channel),
+            ChannelError::Unserializable(reason) => write!(f, "The message could not be serialized: {}", reason),
+            ChannelError::InvalidMessage(reason) => write!(f, "The received message could not be deserialized: {}", reason)
         }
     }
 }
 
-impl Error for ConnectionError {}
\ No newline at end of file
+impl Error for ChannelError {}
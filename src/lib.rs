@@ -6,20 +6,41 @@ mod server;
 mod connection;
 mod sequencing;
 mod reliable;
+mod channels;
 mod error;
+mod wire;
+mod limiter;
 
-pub use client::{Client, ClientEvent, ClientDisconnectReason};
-pub use server::{Server, ServerEvent, ServerDisconnectReason};
+pub use client::{Client, ClientEvent, ClientEventOwned, ClientEvents, ClientDisconnectReason, ConnectConfig};
+pub use server::{Server, ServerEvent, ServerEventOwned, ServerEvents, ServerDisconnectReason};
 pub use socket::{Endpoint, Transport};
-pub use constants::MAX_PACKET_SIZE;
-pub use reliable::MessageChannel;
+pub use constants::{MAX_PACKET_SIZE, MAX_CONNECTION_PAYLOAD_SIZE, MAX_QUERY_RESPONSE_SIZE};
+pub use error::{ChannelError, ConnectError, ConnectionError};
+pub use connection::{ClientStats, CongestionConfig, CongestionState, ConnectionConfig, ConnectionStats, NetworkQuality, QualityConfig, SocketStats};
+pub use reliable::{ChannelMode, MessageChannel, MessageChannelConfig, MessageChannelStats, Priority, SequencedChannel};
+pub use channels::{Channels, ServerChannels};
+pub use sequencing::{AckWidth, sequence_greater_than, sequence_less_than, SequenceBuffer, SequenceBufferDrain, SequenceBufferDrainFilter, SequenceBufferIter, SequenceBufferIterMut, SequenceNumber, SequenceNumberSet, SequenceResult, TooOld};
+pub use limiter::RateLimit;
+pub use packets::{Authentication, AuthenticationMode, DenyReason, DisconnectCode, MacKey};
 
+#[cfg(feature = "encryption")]
+pub use packets::EncryptionKey;
+
+#[cfg(feature = "serde")]
+mod typed;
+
+#[cfg(feature = "serde")]
+pub use typed::TypedChannel;
+
+
+#[cfg(test)]
+mod testing;
 
 #[cfg(feature = "network_simulator")]
 mod conditioner;
 
 #[cfg(feature = "network_simulator")]
-pub use conditioner::{NetworkOptions, TransportExtension};
+pub use conditioner::{ConditionedTransport, NetworkOptions, TransportExtension};
 
 
 
@@ -0,0 +1,152 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::Instant;
+use crate::constants::{MAX_RATE_LIMITED_ADDRESSES, RATE_LIMIT_SWEEP_INTERVAL};
+
+/// Limits how many packets the server answers that don't belong to a connection
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RateLimit {
+    /// Packets per second from a single ip address
+    pub per_ip: f32,
+    /// Packets per second across all addresses
+    pub global: f32,
+    /// The number of packets that are allowed at once before the rate applies
+    pub burst: f32
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            per_ip: 10.0,
+            global: 1000.0,
+            burst: 20.0
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Bucket {
+    tokens: f32,
+    refilled: Instant
+}
+
+impl Bucket {
+
+    fn new(tokens: f32) -> Self {
+        Self {
+            tokens,
+            refilled: Instant::now()
+        }
+    }
+
+    fn refill(&mut self, rate: f32, max: f32) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + rate * (now - self.refilled).as_secs_f32()).min(max);
+        self.refilled = now;
+    }
+
+}
+
+/// Token buckets per ip address and for all addresses combined
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    global: Bucket,
+    addresses: HashMap<IpAddr, Bucket>,
+    /// The keys of `addresses` in the order they were added. The oldest one is evicted once the table is full.
+    order: VecDeque<IpAddr>,
+    last_sweep: Instant
+}
+
+impl RateLimiter {
+
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            global: Bucket::new(limit.burst.max(limit.global)),
+            addresses: HashMap::new(),
+            order: VecDeque::new(),
+            last_sweep: Instant::now()
+        }
+    }
+
+    /// Drops the addresses whose bucket is full again, they behave the same as unknown ones
+    fn sweep(&mut self) {
+        let limit = self.limit;
+        self.addresses.retain(|_, bucket| {
+            bucket.refill(limit.per_ip, limit.burst);
+            bucket.tokens < limit.burst
+        });
+        let addresses = &self.addresses;
+        self.order.retain(|ip| addresses.contains_key(ip));
+        self.last_sweep = Instant::now();
+    }
+
+    /// Takes a token for `ip`. Packets of addresses that exceed their own rate don't count against the global one.
+    /// Once [`MAX_RATE_LIMITED_ADDRESSES`] are tracked, new addresses replace the oldest ones, so a flood
+    /// from many addresses can't lock out everybody else. The global rate still applies to all of them.
+    pub fn allow(&mut self, ip: IpAddr) -> bool {
+        let limit = self.limit;
+        if self.last_sweep.elapsed() >= RATE_LIMIT_SWEEP_INTERVAL {
+            self.sweep();
+        }
+        if !self.addresses.contains_key(&ip) {
+            if self.addresses.len() >= MAX_RATE_LIMITED_ADDRESSES {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.addresses.remove(&oldest);
+                }
+            }
+            self.order.push_back(ip);
+        }
+        let bucket = self.addresses.entry(ip).or_insert_with(|| Bucket::new(limit.burst));
+        bucket.refill(limit.per_ip, limit.burst);
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        self.global.refill(limit.global, limit.burst.max(limit.global));
+        if self.global.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        self.global.tokens -= 1.0;
+        true
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use crate::constants::MAX_RATE_LIMITED_ADDRESSES;
+    use crate::limiter::{RateLimit, RateLimiter};
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(RateLimit { per_ip: 0.0, global: 5.0, burst: 3.0 });
+        let a = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let b = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!((0..5).filter(|_| limiter.allow(a)).count(), 3);
+        // only the global limit is left for b
+        assert_eq!((0..3).filter(|_| limiter.allow(b)).count(), 2);
+    }
+
+    #[test]
+    fn test_spoofed_flood() {
+        let mut limiter = RateLimiter::new(RateLimit { per_ip: 0.0, global: 1e9, burst: 1.0 });
+        let spoofed = |i: usize| IpAddr::V4(Ipv4Addr::from(0x0A00_0000 + i as u32));
+        let flood = MAX_RATE_LIMITED_ADDRESSES + 1000;
+        for i in 0..flood {
+            assert!(limiter.allow(spoofed(i)));
+        }
+        assert_eq!(limiter.addresses.len(), MAX_RATE_LIMITED_ADDRESSES);
+        assert_eq!(limiter.order.len(), MAX_RATE_LIMITED_ADDRESSES);
+
+        // a new client still gets through, while the recent flood addresses stay limited
+        let client = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1));
+        assert!(limiter.allow(client));
+        assert!(!limiter.allow(client));
+        assert!(!limiter.allow(spoofed(flood - 1)));
+        assert_eq!(limiter.addresses.len(), MAX_RATE_LIMITED_ADDRESSES);
+    }
+
+}
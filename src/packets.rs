@@ -1,7 +1,288 @@
+use std::hash::Hasher as _;
 use std::io::{Cursor, Error, ErrorKind, Result, Write};
 use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
 use crc32fast::Hasher;
-use crate::sequencing::{SequenceNumber, SequenceNumberSet};
+use siphasher::sip::SipHasher24;
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, KeyInit, Nonce, Tag};
+use crate::constants::{CONNECTION_REQUEST_SIZE, PROTOCOL_VERSION, MAX_CONNECTION_PAYLOAD_SIZE, MAX_PACKET_SIZE, MAX_QUERY_RESPONSE_SIZE, QUERY_PACKET_SIZE};
+use crate::sequencing::{AckWidth, SequenceNumber, SequenceNumberSet};
+use crate::wire::{ReadVarint, varint_len, WriteVarint};
+
+pub type ConnectionKey = [u8; 16];
+
+/// The pre-shared key of [`Authentication::Mac`]
+pub type MacKey = [u8; 16];
+
+/// Chosen randomly by the client for every connection attempt. It is echoed by the handshake
+/// responses and carried by every packet of the resulting connection.
+pub type ConnectionNonce = u32;
+
+#[cfg(feature = "encryption")]
+pub type EncryptionKey = [u8; 32];
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum Authentication {
+    /// Packets are protected by a crc32 of the identifier and the packet.
+    /// This only guards against accidental cross-talk.
+    #[default]
+    Checksum,
+    /// Once connected, packets are protected by a SipHash-2-4 mac. The per-connection keys are derived from
+    /// this pre-shared key and a secret that the server hands out in the clear during the handshake,
+    /// so only peers knowing the pre-shared key can forge packets. Handshake packets still use the crc32.
+    Mac(MacKey),
+    /// Once connected, packets are encrypted using ChaCha20-Poly1305 with per-connection keys
+    /// derived from this pre-shared key. Handshake packets still use the crc32.
+    #[cfg(feature = "encryption")]
+    Encrypted(EncryptionKey)
+}
+
+impl Authentication {
+
+    pub fn mode(&self) -> AuthenticationMode {
+        match self {
+            Authentication::Checksum => AuthenticationMode::Checksum,
+            Authentication::Mac(_) => AuthenticationMode::Mac,
+            #[cfg(feature = "encryption")]
+            Authentication::Encrypted(_) => AuthenticationMode::Encrypted
+        }
+    }
+
+}
+
+/// An application defined reason that is sent along with [`Server::kick`](crate::Server::kick)
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct DisconnectCode(pub u8);
+
+/// Why a connection request was denied. Sent to the client along with the denial.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DenyReason {
+    ServerFull,
+    /// The client uses a different [`Authentication`] mode
+    AuthenticationMismatch,
+    /// The limit of [`Server::set_max_connections_per_ip`](crate::Server::set_max_connections_per_ip) is reached
+    TooManyConnections,
+    /// The application did not answer the request in time, see [`Server::set_approval_required`](crate::Server::set_approval_required)
+    ApprovalTimeout,
+    /// [`Server::shutdown`](crate::Server::shutdown) was called
+    ShuttingDown,
+    /// New connections are refused with [`Server::set_accepting`](crate::Server::set_accepting)
+    NotAccepting,
+    /// The application denied the request, see [`Server::deny_pending`](crate::Server::deny_pending)
+    Rejected
+}
+
+impl DenyReason {
+
+    /// `None` is used for packets of connections the server doesn't know
+    fn from_u8(value: u8) -> Result<Option<Self>> {
+        match value {
+            0x00 => Ok(None),
+            0x01 => Ok(Some(DenyReason::ServerFull)),
+            0x02 => Ok(Some(DenyReason::AuthenticationMismatch)),
+            0x03 => Ok(Some(DenyReason::TooManyConnections)),
+            0x04 => Ok(Some(DenyReason::ApprovalTimeout)),
+            0x05 => Ok(Some(DenyReason::ShuttingDown)),
+            0x06 => Ok(Some(DenyReason::NotAccepting)),
+            0x07 => Ok(Some(DenyReason::Rejected)),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Invalid deny reason"))
+        }
+    }
+
+    fn to_u8(reason: Option<Self>) -> u8 {
+        match reason {
+            None => 0x00,
+            Some(DenyReason::ServerFull) => 0x01,
+            Some(DenyReason::AuthenticationMismatch) => 0x02,
+            Some(DenyReason::TooManyConnections) => 0x03,
+            Some(DenyReason::ApprovalTimeout) => 0x04,
+            Some(DenyReason::ShuttingDown) => 0x05,
+            Some(DenyReason::NotAccepting) => 0x06,
+            Some(DenyReason::Rejected) => 0x07
+        }
+    }
+
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AuthenticationMode {
+    Checksum,
+    Mac,
+    Encrypted
+}
+
+impl AuthenticationMode {
+
+    fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            0x00 => Ok(AuthenticationMode::Checksum),
+            0x01 => Ok(AuthenticationMode::Mac),
+            0x02 => Ok(AuthenticationMode::Encrypted),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Invalid authentication mode"))
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            AuthenticationMode::Checksum => 0x00,
+            AuthenticationMode::Mac => 0x01,
+            AuthenticationMode::Encrypted => 0x02
+        }
+    }
+
+}
+
+pub fn generate_key() -> ConnectionKey {
+    let mut key = ConnectionKey::default();
+    getrandom::getrandom(&mut key).expect("failed to generate a connection key");
+    key
+}
+
+pub fn generate_token() -> u32 {
+    let mut token = [0u8; 4];
+    getrandom::getrandom(&mut token).expect("failed to generate a token");
+    u32::from_be_bytes(token)
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SessionKey {
+    Mac(ConnectionKey),
+    #[cfg(feature = "encryption")]
+    Encrypted(EncryptionKey)
+}
+
+/// The keys of a single connection. Each direction uses its own key so packets can't be reflected back to their sender.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SessionKeys {
+    pub handshake: ConnectionKey,
+    pub send: SessionKey,
+    pub recv: SessionKey
+}
+
+impl SessionKeys {
+
+    pub fn derive(authentication: &Authentication, handshake: ConnectionKey, server: bool) -> Option<Self> {
+        const CLIENT: u8 = 0;
+        const SERVER: u8 = 1;
+        let derive = |direction| match authentication {
+            Authentication::Checksum => None,
+            Authentication::Mac(psk) => Some(SessionKey::Mac(derive_mac_key(psk, &handshake, direction))),
+            #[cfg(feature = "encryption")]
+            Authentication::Encrypted(psk) => Some(SessionKey::Encrypted(derive_encryption_key(psk, &handshake, direction)))
+        };
+        let (send, recv) = match server {
+            true => (derive(SERVER)?, derive(CLIENT)?),
+            false => (derive(CLIENT)?, derive(SERVER)?)
+        };
+        Some(Self {
+            handshake,
+            send,
+            recv
+        })
+    }
+
+}
+
+fn derive_mac_key(psk: &MacKey, key: &ConnectionKey, direction: u8) -> ConnectionKey {
+    let mut result = ConnectionKey::default();
+    for (i, chunk) in result.chunks_exact_mut(8).enumerate() {
+        let mut hasher = SipHasher24::new_with_key(psk);
+        hasher.write(key);
+        hasher.write_u8(direction);
+        hasher.write_u8(i as u8);
+        chunk.copy_from_slice(&hasher.finish().to_be_bytes());
+    }
+    result
+}
+
+#[cfg(feature = "encryption")]
+fn derive_encryption_key(psk: &EncryptionKey, key: &ConnectionKey, direction: u8) -> EncryptionKey {
+    // the keystream for a fresh random nonce doubles as the derived key
+    let mut nonce = Nonce::default();
+    nonce[0] = direction;
+    let len = nonce.len();
+    nonce[1..].copy_from_slice(&key[..len - 1]);
+    let mut result = EncryptionKey::default();
+    ChaCha20Poly1305::new(psk.into())
+        .encrypt_in_place_detached(&nonce, &[], &mut result)
+        .expect("failed to derive the encryption key");
+    result
+}
+
+#[cfg(feature = "encryption")]
+const TAG_SIZE: usize = 16;
+
+/// Every packet starts with these bytes. They are not covered by the signature.
+///
+/// Packet layout: `[magic: 2][id: 1][connection nonce: 4 unless handshake][signature: 4 or 8][body][tag: 16 if encrypted]`.
+/// Encrypted packets put their explicit nonce in place of the signature.
+pub const MAGIC: [u8; 2] = *b"UC";
+
+pub fn has_magic(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+const FLAG_KEY: u8 = 0b01;
+const FLAG_COMPRESSION: u8 = 0b10;
+
+/// Compresses the payload into `buffer`. Returns `None` if compression would not make the payload smaller.
+#[cfg(feature = "compression")]
+pub fn compress<'a>(payload: &[u8], buffer: &'a mut [u8]) -> Option<&'a [u8]> {
+    lz4_flex::block::compress_into(payload, buffer).ok()
+        .filter(|len| *len < payload.len())
+        .map(|len| &buffer[..len])
+}
+
+/// The size of a sequence number on the wire
+pub const SEQUENCE_NUMBER_SIZE: usize = std::mem::size_of::<SequenceNumber>();
+
+/// The worst case overhead of a payload packet: magic, id, connection nonce, signature, authentication tag, sequence, ack and length.
+/// Assumes the default 32 bit ack bitfield, see [`payload_overhead`] for the others.
+pub const MAX_PAYLOAD_OVERHEAD: usize = 2 + 1 + 4 + 8 + 16 + SEQUENCE_NUMBER_SIZE + SEQUENCE_NUMBER_SIZE + 4 + varint_len(MAX_PACKET_SIZE as u32);
+
+/// [`MAX_PAYLOAD_OVERHEAD`] for the given ack width
+pub const fn payload_overhead(width: AckWidth) -> usize {
+    MAX_PAYLOAD_OVERHEAD - AckWidth::Bits32.bytes() + width.bytes()
+}
+
+/// The size of a [`Packet::ConnectionDenied`]: magic, id, checksum, connection nonce and reason
+pub const CONNECTION_DENIED_SIZE: usize = 2 + 1 + 4 + 4 + 1;
+
+/// Iterates over the length prefixed payloads of a [`Packet::Batch`]
+pub fn batch_iter(mut data: &[u8]) -> impl Iterator<Item=&[u8]> {
+    std::iter::from_fn(move || {
+        let len = data.read_u16::<NetworkEndian>().ok()? as usize;
+        let (payload, rest) = data.split_at(len.min(data.len()));
+        data = rest;
+        Some(payload)
+    })
+}
+
+fn validate_batch(mut data: &[u8]) -> Result<()> {
+    while !data.is_empty() {
+        let len = data.read_u16::<NetworkEndian>()? as usize;
+        assert(len <= data.len(), "wrong batch size")?;
+        data = &data[len..];
+    }
+    Ok(())
+}
+
+/// The error of packets whose checksum or authentication tag does not match
+#[derive(Debug)]
+struct BadSignature;
+
+impl std::fmt::Display for BadSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("bad signature")
+    }
+}
+
+impl std::error::Error for BadSignature {}
+
+/// Whether the packet was rejected because of its signature rather than being malformed
+pub fn is_bad_signature(err: &Error) -> bool {
+    err.get_ref().is_some_and(|err| err.is::<BadSignature>())
+}
 
 fn assert(v: bool, reason: &str) -> Result<()> {
     if v {
@@ -11,131 +292,734 @@ fn assert(v: bool, reason: &str) -> Result<()> {
     }
 }
 
+/// The application identifier that is mixed into every packet signature.
+/// The checksum state of the identifier is computed once up front.
+#[derive(Debug, Clone)]
+pub struct ProtocolId {
+    salt: Box<[u8]>,
+    checksum: Hasher,
+    ack_width: AckWidth
+}
+
+impl ProtocolId {
+
+    /// Wider acks and sequence numbers change the layout of most packets, so they are treated as separate protocol versions.
+    /// The defaults keep the salt of older versions of this crate.
+    pub fn new(identifier: &str, ack_width: AckWidth) -> Self {
+        let mut version = vec![PROTOCOL_VERSION];
+        if ack_width != AckWidth::default() {
+            version.push(ack_width.bits() as u8);
+        }
+        if cfg!(feature = "wide_sequence_numbers") {
+            version.extend_from_slice(b"seq32");
+        }
+        let salt: Box<[u8]> = [identifier.as_bytes(), &version].concat().into();
+        let mut checksum = Hasher::new();
+        checksum.update(&salt);
+        Self {
+            salt,
+            checksum,
+            ack_width
+        }
+    }
+
+    pub fn ack_width(&self) -> AckWidth {
+        self.ack_width
+    }
+
+}
+
+fn read_sequence(data: &mut &[u8]) -> Result<SequenceNumber> {
+    Ok(data.read_uint::<NetworkEndian>(SEQUENCE_NUMBER_SIZE)? as SequenceNumber)
+}
+
+fn write_sequence<W: Write>(data: &mut W, sequence: SequenceNumber) -> Result<()> {
+    data.write_uint::<NetworkEndian>(sequence.into(), SEQUENCE_NUMBER_SIZE)
+}
+
+fn read_ack(data: &mut &[u8], width: AckWidth) -> Result<SequenceNumberSet> {
+    let latest = read_sequence(data)?;
+    let bitfield = match width {
+        AckWidth::Bits32 => data.read_u32::<NetworkEndian>()?.into(),
+        AckWidth::Bits64 => data.read_u64::<NetworkEndian>()?.into(),
+        AckWidth::Bits128 => data.read_u128::<NetworkEndian>()?
+    };
+    Ok(SequenceNumberSet::from_bitfield(width, latest, bitfield))
+}
+
+fn write_ack<W: Write>(data: &mut W, width: AckWidth, ack: &SequenceNumberSet) -> Result<()> {
+    let ack = ack.with_width(width);
+    write_sequence(data, ack.latest())?;
+    match width {
+        AckWidth::Bits32 => data.write_u32::<NetworkEndian>(ack.bitfield() as u32),
+        AckWidth::Bits64 => data.write_u64::<NetworkEndian>(ack.bitfield() as u64),
+        AckWidth::Bits128 => data.write_u128::<NetworkEndian>(ack.bitfield())
+    }
+}
+
+/// Handshake and out-of-band packets are sent before a connection exists and therefore always use the checksum
+fn is_handshake(id: u8) -> bool {
+    matches!(id, 0x00..=0x02 | 0x0A | 0x0B)
+}
+
+/// The length of the id and the connection nonce
+fn prefix_len(id: u8) -> usize {
+    match is_handshake(id) {
+        true => 1,
+        false => 5
+    }
+}
+
+/// Reads the connection nonce of a packet without verifying it. Returns `None` for handshake packets.
+pub fn peek_connection_nonce(data: &[u8]) -> Option<ConnectionNonce> {
+    let mut header = data.strip_prefix(&MAGIC)?;
+    let id = header.read_u8().ok()?;
+    match is_handshake(id) {
+        true => None,
+        false => header.read_u32::<NetworkEndian>().ok()
+    }
+}
+
+/// The integrity check of packets that are sent in plain text
+enum Check<'a> {
+    Checksum,
+    Mac(&'a ConnectionKey)
+}
+
+impl Check<'_> {
+
+    fn len(&self) -> usize {
+        match self {
+            Check::Checksum => 4,
+            Check::Mac(_) => 8
+        }
+    }
+
+    /// `prefix` is the id and the connection nonce
+    fn compute(&self, protocol: &ProtocolId, prefix: &[u8], body: &[u8]) -> u64 {
+        match self {
+            Check::Checksum => {
+                let mut hasher = protocol.checksum.clone();
+                hasher.update(prefix);
+                hasher.update(body);
+                hasher.finalize() as u64
+            }
+            Check::Mac(key) => {
+                let mut hasher = SipHasher24::new_with_key(key);
+                hasher.write(&protocol.salt);
+                hasher.write(prefix);
+                hasher.write(body);
+                hasher.finish()
+            }
+        }
+    }
+
+}
+
+enum Signature<'a> {
+    Plain(Check<'a>),
+    #[cfg(feature = "encryption")]
+    Encrypted(&'a EncryptionKey)
+}
+
+impl<'a> Signature<'a> {
+
+    fn select(id: u8, key: Option<&'a SessionKey>) -> Self {
+        match key {
+            _ if is_handshake(id) => Signature::Plain(Check::Checksum),
+            None => Signature::Plain(Check::Checksum),
+            Some(SessionKey::Mac(key)) => Signature::Plain(Check::Mac(key)),
+            #[cfg(feature = "encryption")]
+            Some(SessionKey::Encrypted(key)) => Signature::Encrypted(key)
+        }
+    }
+
+    /// The number of bytes between the prefix and the body
+    fn len(&self) -> usize {
+        match self {
+            Signature::Plain(check) => check.len(),
+            #[cfg(feature = "encryption")]
+            Signature::Encrypted(_) => 8
+        }
+    }
+
+    /// The number of bytes appended to the body
+    fn trailer_len(&self) -> usize {
+        match self {
+            Signature::Plain(_) => 0,
+            #[cfg(feature = "encryption")]
+            Signature::Encrypted(_) => TAG_SIZE
+        }
+    }
+
+    /// Fills in the header and returns the total length of the packet.
+    /// Only encrypted packets use the `nonce`.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    fn seal(&self, protocol: &ProtocolId, nonce: u64, packet: &mut [u8], len: usize) -> Result<usize> {
+        let prefix = prefix_len(packet[0]);
+        let (header, body) = packet.split_at_mut(prefix + self.len());
+        match self {
+            Signature::Plain(check) => {
+                let value = check.compute(protocol, &header[..prefix], &body[..len - header.len()]);
+                (&mut header[prefix..]).write_uint::<NetworkEndian>(value, check.len())?;
+                Ok(len)
+            }
+            #[cfg(feature = "encryption")]
+            Signature::Encrypted(key) => {
+                (&mut header[prefix..]).write_uint::<NetworkEndian>(nonce, self.len())?;
+                let (body, mut rest) = body.split_at_mut(len - header.len());
+                let tag = ChaCha20Poly1305::new((*key).into())
+                    .encrypt_in_place_detached(&encryption_nonce(nonce), &[&protocol.salt[..], header].concat(), body)
+                    .map_err(|_| Error::other("encryption failed"))?;
+                rest.write_all(&tag)?;
+                Ok(len + TAG_SIZE)
+            }
+        }
+    }
+
+    /// Verifies the packet and returns the plain body
+    fn open<'b>(&self, protocol: &ProtocolId, header: &[u8], body: &'b mut [u8]) -> Result<&'b [u8]> {
+        let (prefix, mut value) = header.split_at(header.len() - self.len());
+        let value = value.read_uint::<NetworkEndian>(self.len())?;
+        match self {
+            Signature::Plain(check) => match value == check.compute(protocol, prefix, body) {
+                true => Ok(body),
+                false => Err(Error::new(ErrorKind::InvalidData, BadSignature))
+            },
+            #[cfg(feature = "encryption")]
+            Signature::Encrypted(key) => {
+                assert(body.len() >= TAG_SIZE, "packet too short")?;
+                let (body, tag) = body.split_at_mut(body.len() - TAG_SIZE);
+                ChaCha20Poly1305::new((*key).into())
+                    .decrypt_in_place_detached(&encryption_nonce(value), &[&protocol.salt[..], header].concat(), body, Tag::from_slice(tag))
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, BadSignature))?;
+                Ok(body)
+            }
+        }
+    }
+
+}
+
+/// The nonce is sent explicitly instead of being derived from the sequence number: sequence numbers wrap around,
+/// acks, probes and disconnects don't have one, and it is only known after decrypting the body.
+/// It takes the place of the mac, so encryption only adds the tag on top of [`Authentication::Mac`].
+#[cfg(feature = "encryption")]
+fn encryption_nonce(counter: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Packet<'a> {
-    ConnectionRequest,
-    ConnectionAccepted(u16),
-    ConnectionDenied,
-    KeepAlive(SequenceNumberSet),
-    Disconnect,
-    Payload(SequenceNumber, SequenceNumberSet, &'a [u8])
+    /// The flag signals support for payload compression
+    ConnectionRequest(AuthenticationMode, bool, ConnectionNonce, &'a [u8]),
+    /// The flag signals whether payloads may be compressed
+    ConnectionAccepted(u16, Option<ConnectionKey>, bool, ConnectionNonce),
+    /// Also sent without a reason in response to packets of connections the server doesn't know (anymore)
+    ConnectionDenied(ConnectionNonce, Option<DenyReason>),
+    KeepAlive(SequenceNumber, SequenceNumberSet),
+    /// Carries the code if the server kicked the client
+    Disconnect(Option<DisconnectCode>),
+    DisconnectAck,
+    Payload(SequenceNumber, SequenceNumberSet, &'a [u8]),
+    Batch(SequenceNumber, SequenceNumberSet, &'a [u8]),
+    Ack(SequenceNumberSet),
+    /// Padded to the given total size to find the largest packet that makes it through the network
+    Probe(u16),
+    ProbeAck(u16),
+    /// A keepalive that is acknowledged right away to measure the round trip time
+    Ping(SequenceNumber, SequenceNumberSet),
+    /// Padded to [`QUERY_PACKET_SIZE`] so that the response is never larger than the request
+    UnconnectedPing(u32),
+    UnconnectedPong(u32, &'a [u8]),
+    /// Carries the built-in [`MessageChannel`](crate::MessageChannel) of the connection
+    Messages(SequenceNumber, SequenceNumberSet, &'a [u8]),
+    #[cfg(feature = "compression")]
+    CompressedPayload(SequenceNumber, SequenceNumberSet, &'a [u8])
 }
 
 impl<'a> Packet<'a> {
 
-    pub fn from(data: &'a [u8], salt: &[u8]) -> Result<Self> {
-        let mut data = data;
-        let checksum = data.read_u32::<NetworkEndian>()?;
-        let mut hasher = Hasher::new();
-        hasher.update(salt);
-        hasher.update(data);
-        assert(checksum == hasher.finalize(), "bad checksum")?;
-
-        match data.read_u8()? {
-            0x00 => Ok(Packet::ConnectionRequest),
-            0x01 => Ok(Packet::ConnectionAccepted(data.read_u16::<NetworkEndian>()?)),
-            0x02 => Ok(Packet::ConnectionDenied),
-            0x03 => Ok(Packet::KeepAlive(SequenceNumberSet::from_bitfield(
-                data.read_u16::<NetworkEndian>()?,
-                data.read_u32::<NetworkEndian>()?
-            ))),
-            0x04 => Ok(Packet::Disconnect),
+    /// Parses a packet. Handshake packets are always verified using the checksum,
+    /// all other packets are verified (and decrypted) using the session `key` if one is given.
+    pub fn from(data: &'a mut [u8], protocol: &ProtocolId, key: Option<&SessionKey>) -> Result<Self> {
+        // foreign traffic gets rejected before doing any real work
+        assert(has_magic(data), "unknown protocol")?;
+        let size = data.len();
+        let (_, data) = data.split_at_mut(MAGIC.len());
+        let id = *data.first().ok_or_else(|| Error::from(ErrorKind::UnexpectedEof))?;
+        // small connection requests could be used for amplification attacks
+        assert(id != 0x00 || size >= CONNECTION_REQUEST_SIZE, "connection request too small")?;
+        let signature = Signature::select(id, key);
+        assert(data.len() >= prefix_len(id) + signature.len(), "packet too short")?;
+        let (header, body) = data.split_at_mut(prefix_len(id) + signature.len());
+        let mut data = signature.open(protocol, header, body)?;
+
+        match id {
+            0x00 => Ok({
+                let mode = AuthenticationMode::from_u8(data.read_u8()?)?;
+                let compression = data.read_u8()? & FLAG_COMPRESSION != 0;
+                let nonce = data.read_u32::<NetworkEndian>()?;
+                let len = data.read_u8()? as usize;
+                assert(len <= MAX_CONNECTION_PAYLOAD_SIZE && len <= data.len(), "wrong payload size")?;
+                Packet::ConnectionRequest(mode, compression, nonce, &data[..len])
+            }),
+            0x01 => Ok({
+                let client_id = data.read_u16::<NetworkEndian>()?;
+                let flags = data.read_u8()?;
+                let nonce = data.read_u32::<NetworkEndian>()?;
+                let key = match flags & FLAG_KEY {
+                    0x00 => None,
+                    _ => {
+                        let mut key = ConnectionKey::default();
+                        std::io::Read::read_exact(&mut data, &mut key)?;
+                        Some(key)
+                    }
+                };
+                Packet::ConnectionAccepted(client_id, key, flags & FLAG_COMPRESSION != 0, nonce)
+            }),
+            0x02 => Ok(Packet::ConnectionDenied(data.read_u32::<NetworkEndian>()?, DenyReason::from_u8(data.read_u8()?)?)),
+            0x03 => Ok(Packet::KeepAlive(read_sequence(&mut data)?, read_ack(&mut data, protocol.ack_width())?)),
+            0x04 => Ok(Packet::Disconnect(match data.read_u8()? {
+                0x00 => None,
+                _ => Some(DisconnectCode(data.read_u8()?))
+            })),
             0x05 => Ok({
-                let sequence = data.read_u16::<NetworkEndian>()?;
-                let ack = SequenceNumberSet::from_bitfield(
-                    data.read_u16::<NetworkEndian>()?,
-                    data.read_u32::<NetworkEndian>()?
-                );
-                let len = data.read_u16::<NetworkEndian>()? as usize;
+                let sequence = read_sequence(&mut data)?;
+                let ack = read_ack(&mut data, protocol.ack_width())?;
+                let len = data.read_varint()? as usize;
                 assert(len == data.len(), "wrong packet size")?;
                 Packet::Payload(sequence, ack, data)
             }),
+            0x06 => Ok({
+                let sequence = read_sequence(&mut data)?;
+                let ack = read_ack(&mut data, protocol.ack_width())?;
+                validate_batch(data)?;
+                Packet::Batch(sequence, ack, data)
+            }),
+            0x07 => Ok(Packet::Ack(read_ack(&mut data, protocol.ack_width())?)),
+            0x08 => Ok({
+                let probe = data.read_u16::<NetworkEndian>()?;
+                assert(probe as usize <= size, "probe too small")?;
+                Packet::Probe(probe)
+            }),
+            0x09 => Ok(Packet::ProbeAck(data.read_u16::<NetworkEndian>()?)),
+            0x0A => Ok({
+                assert(size >= QUERY_PACKET_SIZE, "ping too small")?;
+                Packet::UnconnectedPing(data.read_u32::<NetworkEndian>()?)
+            }),
+            0x0B => Ok({
+                let token = data.read_u32::<NetworkEndian>()?;
+                let len = data.read_u16::<NetworkEndian>()? as usize;
+                assert(len <= MAX_QUERY_RESPONSE_SIZE && len <= data.len(), "wrong response size")?;
+                Packet::UnconnectedPong(token, &data[..len])
+            }),
+            0x0C => Ok(Packet::DisconnectAck),
+            0x0D => Ok(Packet::Ping(read_sequence(&mut data)?, read_ack(&mut data, protocol.ack_width())?)),
+            0x0E => Ok({
+                let sequence = read_sequence(&mut data)?;
+                let ack = read_ack(&mut data, protocol.ack_width())?;
+                let len = data.read_varint()? as usize;
+                assert(len == data.len(), "wrong packet size")?;
+                Packet::Messages(sequence, ack, data)
+            }),
+            #[cfg(feature = "compression")]
+            0x85 => Ok({
+                let sequence = read_sequence(&mut data)?;
+                let ack = read_ack(&mut data, protocol.ack_width())?;
+                let len = data.read_varint()? as usize;
+                assert(len == data.len(), "wrong packet size")?;
+                Packet::CompressedPayload(sequence, ack, data)
+            }),
             _ => Err(Error::new(ErrorKind::InvalidData, "Invalid packet id"))
         }
     }
 
-    pub fn write<'b>(&self, data: &'b mut [u8], salt: &[u8]) -> Result<&'b [u8]> {
+    fn id(&self) -> u8 {
+        match self {
+            Packet::ConnectionRequest(_, _, _, _) => 0x00,
+            Packet::ConnectionAccepted(_, _, _, _) => 0x01,
+            Packet::ConnectionDenied(_, _) => 0x02,
+            Packet::KeepAlive(_, _) => 0x03,
+            Packet::Disconnect(_) => 0x04,
+            Packet::Payload(_, _, _) => 0x05,
+            Packet::Batch(_, _, _) => 0x06,
+            Packet::Ack(_) => 0x07,
+            Packet::Probe(_) => 0x08,
+            Packet::ProbeAck(_) => 0x09,
+            Packet::UnconnectedPing(_) => 0x0A,
+            Packet::UnconnectedPong(_, _) => 0x0B,
+            Packet::DisconnectAck => 0x0C,
+            Packet::Ping(_, _) => 0x0D,
+            Packet::Messages(_, _, _) => 0x0E,
+            #[cfg(feature = "compression")]
+            Packet::CompressedPayload(_, _, _) => 0x85
+        }
+    }
+
+    /// The variable length data carried by this packet
+    pub fn payload(&self) -> Option<&'a [u8]> {
+        match self {
+            Packet::ConnectionRequest(_, _, _, data) => Some(data),
+            Packet::UnconnectedPong(_, data) => Some(data),
+            Packet::Payload(_, _, data) => Some(data),
+            Packet::Batch(_, _, data) => Some(data),
+            Packet::Messages(_, _, data) => Some(data),
+            #[cfg(feature = "compression")]
+            Packet::CompressedPayload(_, _, data) => Some(data),
+            _ => None
+        }
+    }
+
+    /// Whether this packet informs the remote about the packets we received
+    pub fn carries_ack(&self) -> bool {
+        match self {
+            #[cfg(feature = "compression")]
+            Packet::CompressedPayload(_, _, _) => true,
+            packet => matches!(packet, Packet::KeepAlive(_, _) | Packet::Ping(_, _) | Packet::Payload(_, _, _) | Packet::Batch(_, _, _) | Packet::Messages(_, _, _) | Packet::Ack(_))
+        }
+    }
+
+    /// Serializes the packet. The `connection` nonce is ignored for handshake packets.
+    /// The `nonce` must be unique per session key when using encryption.
+    pub fn write<'b>(&self, data: &'b mut [u8], protocol: &ProtocolId, key: Option<&SessionKey>, connection: ConnectionNonce, nonce: u64) -> Result<&'b [u8]> {
+        let id = self.id();
+        let signature = Signature::select(id, key);
+
         let mut data = Cursor::new(data);
-        data.write_u32::<NetworkEndian>(0)?;
-        let len1 = data.position() as usize;
+        data.write_all(&MAGIC)?;
+        data.write_u8(id)?;
+        if !is_handshake(id) {
+            data.write_u32::<NetworkEndian>(connection)?;
+        }
+        data.write_uint::<NetworkEndian>(0, signature.len())?;
 
         match self {
-            Packet::ConnectionRequest => {
-                data.write_u8(0x00)?;
+            Packet::ConnectionRequest(mode, compression, nonce, payload) => {
+                assert(payload.len() <= MAX_CONNECTION_PAYLOAD_SIZE, "connection payload too large")?;
+                data.write_u8(mode.to_u8())?;
+                data.write_u8(if *compression { FLAG_COMPRESSION } else { 0x00 })?;
+                data.write_u32::<NetworkEndian>(*nonce)?;
+                data.write_u8(payload.len() as u8)?;
+                data.write_all(payload)?;
+                let padding = CONNECTION_REQUEST_SIZE.saturating_sub(data.position() as usize);
+                data.write_all(&[0u8; CONNECTION_REQUEST_SIZE][..padding])?;
             },
-            Packet::ConnectionAccepted(id) => {
-                data.write_u8(0x01)?;
+            Packet::ConnectionAccepted(id, key, compression, nonce) => {
                 data.write_u16::<NetworkEndian>(*id)?;
+                let flags = if *compression { FLAG_COMPRESSION } else { 0x00 };
+                match key {
+                    None => data.write_u8(flags)?,
+                    Some(_) => data.write_u8(flags | FLAG_KEY)?
+                }
+                data.write_u32::<NetworkEndian>(*nonce)?;
+                if let Some(key) = key {
+                    data.write_all(key)?;
+                }
             },
-            Packet::ConnectionDenied => {
-                data.write_u8(0x02)?;
+            Packet::ConnectionDenied(nonce, reason) => {
+                data.write_u32::<NetworkEndian>(*nonce)?;
+                data.write_u8(DenyReason::to_u8(*reason))?;
             },
-            Packet::KeepAlive(ack) => {
-                data.write_u8(0x03)?;
-                data.write_u16::<NetworkEndian>(ack.latest())?;
-                data.write_u32::<NetworkEndian>(ack.bitfield())?;
+            Packet::KeepAlive(sequence, ack) | Packet::Ping(sequence, ack) => {
+                write_sequence(&mut data, *sequence)?;
+                write_ack(&mut data, protocol.ack_width(), ack)?;
             },
-            Packet::Disconnect => {
-                data.write_u8(0x04)?;
+            Packet::Disconnect(code) => match code {
+                None => data.write_u8(0x00)?,
+                Some(code) => {
+                    data.write_u8(0x01)?;
+                    data.write_u8(code.0)?;
+                }
             },
-            Packet::Payload(sequence, ack, payload) => {
-                data.write_u8(0x05)?;
-                data.write_u16::<NetworkEndian>(*sequence)?;
-                data.write_u16::<NetworkEndian>(ack.latest())?;
-                data.write_u32::<NetworkEndian>(ack.bitfield())?;
-                data.write_u16::<NetworkEndian>(payload.len() as u16)?;
+            Packet::DisconnectAck => {},
+            Packet::Payload(sequence, ack, payload) | Packet::Messages(sequence, ack, payload) => {
+                write_sequence(&mut data, *sequence)?;
+                write_ack(&mut data, protocol.ack_width(), ack)?;
+                data.write_varint(payload.len() as u32)?;
+                data.write_all(payload)?;
+            }
+            Packet::Batch(sequence, ack, payloads) => {
+                write_sequence(&mut data, *sequence)?;
+                write_ack(&mut data, protocol.ack_width(), ack)?;
+                data.write_all(payloads)?;
+            }
+            Packet::Ack(ack) => {
+                write_ack(&mut data, protocol.ack_width(), ack)?;
+            }
+            Packet::Probe(size) => {
+                data.write_u16::<NetworkEndian>(*size)?;
+                let padding = (*size as usize).saturating_sub(data.position() as usize + signature.trailer_len());
+                data.write_all(&[0u8; MAX_PACKET_SIZE][..padding])?;
+            }
+            Packet::ProbeAck(size) => {
+                data.write_u16::<NetworkEndian>(*size)?;
+            }
+            Packet::UnconnectedPing(token) => {
+                data.write_u32::<NetworkEndian>(*token)?;
+                let padding = QUERY_PACKET_SIZE.saturating_sub(data.position() as usize);
+                data.write_all(&[0u8; QUERY_PACKET_SIZE][..padding])?;
+            }
+            Packet::UnconnectedPong(token, response) => {
+                assert(response.len() <= MAX_QUERY_RESPONSE_SIZE, "query response too large")?;
+                data.write_u32::<NetworkEndian>(*token)?;
+                data.write_u16::<NetworkEndian>(response.len() as u16)?;
+                data.write_all(response)?;
+            }
+            #[cfg(feature = "compression")]
+            Packet::CompressedPayload(sequence, ack, payload) => {
+                write_sequence(&mut data, *sequence)?;
+                write_ack(&mut data, protocol.ack_width(), ack)?;
+                data.write_varint(payload.len() as u32)?;
                 data.write_all(payload)?;
             }
         }
-        let len2 = data.position() as usize;
-        let mut hasher = Hasher::new();
-        hasher.update(salt);
-        hasher.update(&data.get_ref()[len1..len2]);
-        data.set_position(0);
-        data.write_u32::<NetworkEndian>(hasher.finalize())?;
-        Ok(&data.into_inner()[..len2])
+        let len = data.position() as usize;
+        let data = data.into_inner();
+        let len = MAGIC.len() + signature.seal(protocol, nonce, &mut data[MAGIC.len()..], len - MAGIC.len())?;
+        Ok(&data[..len])
+    }
+
+    /// Turns a [`Packet::CompressedPayload`] into a regular [`Packet::Payload`] using `buffer` as storage
+    #[cfg(feature = "compression")]
+    pub fn decompress<'b>(self, buffer: &'b mut [u8]) -> Result<Packet<'b>> where 'a: 'b {
+        match self {
+            Packet::CompressedPayload(sequence, ack, data) => {
+                let len = lz4_flex::block::decompress_into(data, buffer)
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, "decompression failed"))?;
+                Ok(Packet::Payload(sequence, ack, &buffer[..len]))
+            }
+            packet => Ok(packet)
+        }
     }
 
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::packets::{Packet};
-    use crate::sequencing::SequenceNumberSet;
+    use crate::constants::{CONNECTION_REQUEST_SIZE, MAX_CONNECTION_PAYLOAD_SIZE, MAX_PACKET_SIZE, MAX_QUERY_RESPONSE_SIZE};
+    use crate::packets::{Authentication, AuthenticationMode, batch_iter, Check, CONNECTION_DENIED_SIZE, DenyReason, DisconnectCode, generate_key, MAGIC, Packet, payload_overhead, peek_connection_nonce, ProtocolId, SessionKeys};
+    use crate::sequencing::{AckWidth, SequenceNumberSet};
+
+    fn protocol() -> ProtocolId {
+        ProtocolId::new("test", AckWidth::default())
+    }
 
-    const SALT: [u8; 4] = 123456u32.to_be_bytes();
+    fn authentications() -> Vec<Authentication> {
+        vec![
+            Authentication::Checksum,
+            Authentication::Mac([3; 16]),
+            #[cfg(feature = "encryption")]
+            Authentication::Encrypted([7; 32])
+        ]
+    }
 
     #[test]
     fn test_packets() {
-        let mut buffer = [0u8; 128];
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let key = generate_key();
 
         let test_cases = [
-            Packet::ConnectionRequest,
-            Packet::ConnectionAccepted(45),
-            Packet::ConnectionDenied,
-            Packet::KeepAlive(SequenceNumberSet::new(0)),
-            Packet::Disconnect,
-            Packet::Payload(0, SequenceNumberSet::new(0), &[1,2,3])
+            Packet::ConnectionRequest(AuthenticationMode::Checksum, false, 3, &[]),
+            Packet::ConnectionRequest(AuthenticationMode::Mac, true, 3, &[1, 2, 3]),
+            Packet::ConnectionRequest(AuthenticationMode::Mac, false, 3, &[7; MAX_CONNECTION_PAYLOAD_SIZE]),
+            Packet::ConnectionAccepted(45, None, false, 3),
+            Packet::ConnectionAccepted(45, None, true, 3),
+            Packet::ConnectionAccepted(45, Some(key), true, 3),
+            Packet::ConnectionDenied(3, None),
+            Packet::ConnectionDenied(3, Some(DenyReason::NotAccepting)),
+            Packet::KeepAlive(0, SequenceNumberSet::new(0)),
+            Packet::Ping(7, SequenceNumberSet::from_bitfield(AckWidth::Bits32, 3, 0b11)),
+            Packet::Disconnect(None),
+            Packet::Disconnect(Some(DisconnectCode(7))),
+            Packet::DisconnectAck,
+            Packet::Payload(0, SequenceNumberSet::new(0), &[1,2,3]),
+            Packet::Batch(0, SequenceNumberSet::new(0), &[]),
+            Packet::Batch(0, SequenceNumberSet::new(0), &[0, 1, 7, 0, 0, 0, 2, 8, 9]),
+            Packet::Messages(5, SequenceNumberSet::new(4), &[0]),
+            Packet::Ack(SequenceNumberSet::from_bitfield(AckWidth::Bits32, 7, 0b101)),
+            Packet::Probe(1200),
+            Packet::ProbeAck(1200),
+            Packet::UnconnectedPing(7),
+            Packet::UnconnectedPong(7, &[]),
+            Packet::UnconnectedPong(7, &[9; MAX_QUERY_RESPONSE_SIZE])
         ];
 
         for test in test_cases {
-            print!("Testing {:?}: ", test);
-            let bin = test.write(&mut buffer, &SALT).unwrap();
-            let rev = Packet::from(bin, &SALT).unwrap();
-            assert_eq!(test, rev);
-            println!("ok")
+            for authentication in authentications() {
+                let client = SessionKeys::derive(&authentication, key, false);
+                let server = SessionKeys::derive(&authentication, key, true);
+                print!("Testing {:?} ({:?}): ", test, authentication.mode());
+                let mut bin = test.write(&mut buffer, &protocol(), client.as_ref().map(|k| &k.send), 3, 1).unwrap().to_vec();
+                let rev = Packet::from(&mut bin, &protocol(), server.as_ref().map(|k| &k.recv)).unwrap();
+                assert_eq!(test, rev);
+                println!("ok")
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "wide_sequence_numbers")]
+    fn test_wide_sequence_numbers() {
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let ack = SequenceNumberSet::from_bitfield(AckWidth::Bits32, 100_000, 0b101);
+        for test in [Packet::Payload(70_000, ack, &[1, 2, 3]), Packet::KeepAlive(u32::MAX, ack), Packet::Ack(ack)] {
+            let mut bin = test.write(&mut buffer, &protocol(), None, 3, 1).unwrap().to_vec();
+            assert_eq!(Packet::from(&mut bin, &protocol(), None).unwrap(), test);
+        }
+    }
+
+    #[test]
+    fn test_ack_width() {
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let key = generate_key();
+        for width in [AckWidth::Bits32, AckWidth::Bits64, AckWidth::Bits128] {
+            let protocol = ProtocolId::new("test", width);
+            let ack = SequenceNumberSet::from_bitfield(width, 7, u128::MAX);
+            for authentication in authentications() {
+                let client = SessionKeys::derive(&authentication, key, false);
+                let server = SessionKeys::derive(&authentication, key, true);
+                for test in [Packet::Payload(3, ack, &[1, 2, 3]), Packet::Ack(ack), Packet::KeepAlive(3, ack)] {
+                    let mut bin = test.write(&mut buffer, &protocol, client.as_ref().map(|k| &k.send), 3, 1).unwrap().to_vec();
+                    if let Packet::Payload(_, _, payload) = test {
+                        assert!(bin.len() <= payload.len() + payload_overhead(width));
+                    }
+                    let mut copy = bin.clone();
+                    let rev = Packet::from(&mut copy, &protocol, server.as_ref().map(|k| &k.recv)).unwrap();
+                    assert_eq!(test, rev);
+                    for other in [AckWidth::Bits32, AckWidth::Bits64, AckWidth::Bits128].into_iter().filter(|w| *w != width) {
+                        assert!(Packet::from(&mut bin, &ProtocolId::new("test", other), server.as_ref().map(|k| &k.recv)).is_err());
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_probe_size() {
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        for authentication in authentications() {
+            let keys = SessionKeys::derive(&authentication, generate_key(), false);
+            let len = Packet::Probe(1024).write(&mut buffer, &protocol(), keys.as_ref().map(|k| &k.send), 3, 1).unwrap().len();
+            assert_eq!(len, 1024);
         }
+        let mut forged = Packet::Probe(1400).write(&mut buffer, &protocol(), None, 0, 0).unwrap()[..1200].to_vec();
+        assert!(Packet::from(&mut forged, &protocol(), None).is_err());
+    }
+
+    #[test]
+    fn test_connection_request_padding() {
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let keys = SessionKeys::derive(&Authentication::Mac([3; 16]), generate_key(), true).unwrap();
+        let request = Packet::ConnectionRequest(AuthenticationMode::Mac, false, 3, &[])
+            .write(&mut buffer, &protocol(), None, 0, 0).unwrap().len();
+        assert_eq!(request, CONNECTION_REQUEST_SIZE);
+
+        let ping = Packet::UnconnectedPing(7).write(&mut buffer, &protocol(), None, 0, 0).unwrap().len();
+        let pong = Packet::UnconnectedPong(7, &[9; MAX_QUERY_RESPONSE_SIZE]).write(&mut buffer, &protocol(), None, 0, 0).unwrap().len();
+        assert!(pong <= ping);
+
+        for response in [Packet::ConnectionAccepted(45, Some(keys.handshake), true, 3), Packet::ConnectionDenied(3, Some(DenyReason::ServerFull))] {
+            let response = response.write(&mut buffer, &protocol(), Some(&keys.send), 0, 0).unwrap().len();
+            assert!(response <= request);
+        }
+
+        let denied = Packet::ConnectionDenied(3, Some(DenyReason::ServerFull)).write(&mut buffer, &protocol(), None, 0, 0).unwrap().len();
+        assert_eq!(denied, CONNECTION_DENIED_SIZE);
+
+        let mut small = [MAGIC[0], MAGIC[1], 0x00, 0, 0, 0, 0, AuthenticationMode::Checksum.to_u8(), 0, 0];
+        let check = Check::Checksum.compute(&protocol(), &small[2..3], &small[7..]);
+        small[3..7].copy_from_slice(&(check as u32).to_be_bytes());
+        assert!(Packet::from(&mut small, &protocol(), None).is_err());
     }
 
     #[test]
     #[should_panic]
     fn test_packet_crc() {
-        let mut buffer = [0u8; 10];
-        let test = Packet::ConnectionRequest;
-        let len = test.write(&mut buffer,&SALT).unwrap().len();
+        let mut buffer = [0u8; CONNECTION_REQUEST_SIZE];
+        let test = Packet::ConnectionRequest(AuthenticationMode::Checksum, false, 3, &[]);
+        let len = test.write(&mut buffer,&protocol(), None, 0, 0).unwrap().len();
         let bin= &mut buffer[..len];
         bin[4] += 1;
-        Packet::from(bin,&SALT).unwrap();
+        Packet::from(bin,&protocol(), None).unwrap();
+    }
+
+    #[test]
+    fn test_packet_signature() {
+        let mut buffer = [0u8; 64];
+        let test = Packet::Payload(0, SequenceNumberSet::new(0), &[1,2,3]);
+
+        for authentication in authentications().into_iter().skip(1) {
+            let client = SessionKeys::derive(&authentication, generate_key(), false).unwrap();
+            let other = SessionKeys::derive(&authentication, generate_key(), false).unwrap();
+
+            let mut bin = test.write(&mut buffer, &protocol(), None, 0, 0).unwrap().to_vec();
+            assert!(Packet::from(&mut bin, &protocol(), Some(&client.send)).is_err());
+
+            let mut bin = test.write(&mut buffer, &protocol(), Some(&other.send), 0, 0).unwrap().to_vec();
+            assert!(Packet::from(&mut bin, &protocol(), Some(&client.send)).is_err());
+
+            let mut bin = test.write(&mut buffer, &protocol(), Some(&client.send), 0, 0).unwrap().to_vec();
+            assert!(Packet::from(&mut bin.clone(), &protocol(), None).is_err());
+            assert!(Packet::from(&mut bin.clone(), &protocol(), Some(&client.recv)).is_err());
+            let last = bin.len() - 1;
+            let mut corrupted = bin.clone();
+            corrupted[last] ^= 0x01;
+            assert!(Packet::from(&mut corrupted, &protocol(), Some(&client.send)).is_err());
+            assert_eq!(Packet::from(&mut bin, &protocol(), Some(&client.send)).unwrap(), test);
+        }
+    }
+
+    #[test]
+    fn test_connection_nonce() {
+        let mut buffer = [0u8; 64];
+        let test = Packet::Payload(0, SequenceNumberSet::new(0), &[1,2,3]);
+        for authentication in authentications() {
+            let keys = SessionKeys::derive(&authentication, generate_key(), false);
+            let mut bin = test.write(&mut buffer, &protocol(), keys.as_ref().map(|k| &k.send), 7, 0).unwrap().to_vec();
+            assert_eq!(peek_connection_nonce(&bin), Some(7));
+            let mut changed = bin.clone();
+            changed[6] ^= 0x01;
+            assert!(Packet::from(&mut changed, &protocol(), keys.as_ref().map(|k| &k.send)).is_err());
+            assert_eq!(Packet::from(&mut bin, &protocol(), keys.as_ref().map(|k| &k.send)).unwrap(), test);
+        }
+        let bin = Packet::ConnectionDenied(7, None).write(&mut buffer, &protocol(), None, 0, 0).unwrap();
+        assert_eq!(peek_connection_nonce(bin), None);
+    }
+
+    #[test]
+    fn test_packet_batch() {
+        let mut buffer = [0u8; 64];
+        let payloads: Vec<&[u8]> = batch_iter(&[0, 1, 7, 0, 0, 0, 2, 8, 9]).collect();
+        assert_eq!(payloads, [&[7][..], &[], &[8, 9]]);
+
+        let mut bin = Packet::Batch(0, SequenceNumberSet::new(0), &[0, 3, 7])
+            .write(&mut buffer, &protocol(), None, 0, 0).unwrap().to_vec();
+        assert!(Packet::from(&mut bin, &protocol(), None).is_err());
+    }
+
+    #[test]
+    fn test_mac_key() {
+        let mut buffer = [0u8; 64];
+        let handshake = generate_key();
+        let keys = SessionKeys::derive(&Authentication::Mac([3; 16]), handshake, false).unwrap();
+        let server = SessionKeys::derive(&Authentication::Mac([3; 16]), handshake, true).unwrap();
+        let forged = SessionKeys::derive(&Authentication::Mac([4; 16]), handshake, true).unwrap();
+        let test = Packet::Payload(0, SequenceNumberSet::new(0), &[1, 2, 3]);
+        let bin = test.write(&mut buffer, &protocol(), Some(&keys.send), 7, 0).unwrap().to_vec();
+        assert!(Packet::from(&mut bin.clone(), &protocol(), Some(&forged.recv)).is_err());
+        assert_eq!(Packet::from(&mut bin.clone(), &protocol(), Some(&server.recv)).unwrap(), test);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_packet_encryption() {
+        let mut buffer = [0u8; 64];
+        let keys = SessionKeys::derive(&Authentication::Encrypted([7; 32]), generate_key(), false).unwrap();
+        let test = Packet::Payload(0, SequenceNumberSet::new(0), b"secret");
+        let bin = test.write(&mut buffer, &protocol(), Some(&keys.send), 0, 0).unwrap();
+        assert!(!bin.windows(6).any(|w| w == b"secret"));
     }
 
 }
@@ -1,185 +1,1827 @@
-use std::io::{Error, ErrorKind, Read, Write};
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Write};
 use std::io::Result;
-use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use std::time::{Duration, Instant};
+use byteorder::ReadBytesExt;
+use crate::constants::{LOW_PRIORITY_SHARE, MAX_MESSAGE_SIZE, MAX_PACKET_SIZE, MESSAGE_BUFFER_CAPACITY, MESSAGE_FRAGMENT_SIZE, MESSAGE_PACKET_BUDGET, MESSAGE_RESEND_INTERVAL};
+use crate::error::{ChannelError, ConnectionError};
+use crate::packets::MAX_PAYLOAD_OVERHEAD;
 use crate::sequencing::{sequence_greater_than, sequence_less_than, SequenceBuffer, SequenceNumber};
+use crate::wire::{read_slice, ReadVarint, varint_len, WriteVarint};
 
-#[derive(Clone, Default)]
+/// An upper bound for the headers that come with a single message or fragment, including the section headers
+const MAX_ENTRY_OVERHEAD: usize = 2 + 4 * varint_len(u32::MAX);
+/// The blocks of priorities other than [`Priority::Normal`] start with a tag and section headers of their own
+const PRIORITY_BLOCK_OVERHEAD: usize = 3;
+/// The number of slots of a [`SequencedChannel`]
+const SEQUENCED_SLOTS: usize = 256;
+/// The value count in front of the values of a [`SequencedChannel`]
+const SEQUENCED_HEADER_SIZE: usize = varint_len(SEQUENCED_SLOTS as u32);
+/// Slot, sequence number and length of a value
+const SEQUENCED_ENTRY_OVERHEAD: usize = 1 + 2 * varint_len(u32::MAX);
+
+/// Ordered by urgency: lost and unsent fragments come first, then the ones that were sent the longest time ago
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+enum FragmentState {
+    /// Every packet that carried the fragment was lost
+    Lost,
+    Unsent,
+    Sent(Instant),
+    Acknowledged
+}
+
+impl FragmentState {
+
+    fn is_due(self, now: Instant, resend_interval: Duration) -> bool {
+        match self {
+            FragmentState::Lost | FragmentState::Unsent => true,
+            FragmentState::Sent(time) => now.saturating_duration_since(time) >= resend_interval,
+            FragmentState::Acknowledged => false
+        }
+    }
+
+}
+
+#[derive(Clone)]
 struct Message {
-    data: Box<[u8]>,
-    sequence_number: Vec<SequenceNumber>
+    /// Owned buffers are stored as they are, without copying them
+    data: Vec<u8>,
+    queued: Instant,
+    /// The packets that carried this message, together with the index of the fragment they carried
+    sequence_number: Vec<(SequenceNumber, usize)>,
+    /// Messages that fit into a single fragment are sent whole
+    fragments: Box<[FragmentState]>
 }
 
-#[derive(Debug)]
+impl Message {
+
+    fn new(data: Vec<u8>) -> Self {
+        let count = match data.len() {
+            len if len > MESSAGE_FRAGMENT_SIZE => len.div_ceil(MESSAGE_FRAGMENT_SIZE),
+            _ => 1
+        };
+        Self {
+            data,
+            queued: Instant::now(),
+            sequence_number: Vec::new(),
+            fragments: vec![FragmentState::Unsent; count].into_boxed_slice()
+        }
+    }
+
+    fn is_fragmented(&self) -> bool {
+        self.fragments.len() > 1
+    }
+
+    fn fragment(&self, index: usize) -> &[u8] {
+        let start = index * MESSAGE_FRAGMENT_SIZE;
+        &self.data[start..self.data.len().min(start + MESSAGE_FRAGMENT_SIZE)]
+    }
+
+    /// The most urgent fragment that is due to be sent
+    fn next_fragment(&self, now: Instant, resend_interval: Duration) -> Option<usize> {
+        self.fragments
+            .iter()
+            .enumerate()
+            .filter(|(_, state)| state.is_due(now, resend_interval))
+            .min_by_key(|(index, state)| (**state, *index))
+            .map(|(index, _)| index)
+    }
+
+    /// Returns whether the fragment was sent before
+    fn on_send(&mut self, seq: SequenceNumber, index: usize, now: Instant) -> bool {
+        let resent = self.fragments[index] != FragmentState::Unsent;
+        self.fragments[index] = FragmentState::Sent(now);
+        self.sequence_number.push((seq, index));
+        resent
+    }
+
+    fn is_in_flight(&self) -> bool {
+        self.fragments.iter().any(|state| matches!(state, FragmentState::Sent(_) | FragmentState::Acknowledged))
+    }
+
+    /// Returns whether every fragment of the message is acknowledged now
+    fn on_ack(&mut self, seq: SequenceNumber) -> bool {
+        for &(_, index) in self.sequence_number.iter().filter(|(s, _)| *s == seq) {
+            self.fragments[index] = FragmentState::Acknowledged;
+        }
+        let fragments = &self.fragments;
+        self.sequence_number.retain(|(_, index)| fragments[*index] != FragmentState::Acknowledged);
+        self.fragments.iter().all(|state| *state == FragmentState::Acknowledged)
+    }
+
+    /// Fragments that are not in flight in any other packet become due right away
+    fn on_loss(&mut self, seq: SequenceNumber) {
+        let mut lost = Vec::new();
+        self.sequence_number.retain(|&(s, index)| {
+            if s == seq {
+                lost.push(index);
+            }
+            s != seq
+        });
+        for index in lost {
+            let in_flight = self.sequence_number.iter().any(|(_, i)| *i == index);
+            if !in_flight && self.fragments[index] != FragmentState::Acknowledged {
+                self.fragments[index] = FragmentState::Lost;
+            }
+        }
+    }
+
+}
+
+#[derive(Debug, Clone)]
+enum IncomingMessage {
+    Complete(Box<[u8]>),
+    Partial {
+        fragments: Box<[Option<Box<[u8]>>]>,
+        missing: usize
+    },
+    /// Remembers the ids of messages that were delivered out of order to drop their duplicates
+    Delivered
+}
+
+/// How a [`MessageChannel`] delivers its messages
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ChannelMode {
+    /// Messages are received in the order they were queued, a missing message holds back all messages behind it.
+    /// This only holds for messages of the same [`Priority`], every priority is ordered on its own.
+    Ordered,
+    /// Messages are received as soon as they arrive
+    Unordered,
+    /// Messages are sent once and received as soon as they arrive, if they arrive at all
+    Unreliable
+}
+
+/// Messages of a higher priority are sent first when they do not all fit into a packet.
+/// Messages of the same priority are sent in the order they were queued.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low
+}
+
+impl Priority {
+
+    const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            0x00 => Ok(Priority::High),
+            0x01 => Ok(Priority::Normal),
+            0x02 => Ok(Priority::Low),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Invalid message priority"))
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Priority::High => 0x00,
+            Priority::Normal => 0x01,
+            Priority::Low => 0x02
+        }
+    }
+
+}
+
+/// Counters and the current state of a [`MessageChannel`]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct MessageChannelStats {
+    pub messages_queued: u64,
+    /// Messages that were sent at least once and are not acknowledged yet
+    pub messages_in_flight: usize,
+    pub messages_acknowledged: u64,
+    /// Messages and fragments that were sent again, because they were lost or their resend interval elapsed
+    pub retransmissions: u64,
+    /// Complete messages, duplicates are not counted
+    pub messages_received: u64,
+    /// Messages that were returned by [`MessageChannel::receive_message`]
+    pub messages_delivered: u64,
+    /// Received messages that wait for a message in front of them or for their missing fragments
+    pub incoming_buffered: usize,
+    /// How long ago the oldest message that is not acknowledged yet was queued. Zero if there is none.
+    pub oldest_unacked_age: Duration
+}
+
+/// The entries of one section of the packet that is currently written
+#[derive(Debug, Default, Clone)]
+struct Section {
+    data: Vec<u8>,
+    count: u32,
+    /// Message ids are delta encoded from the previous id in the section
+    previous: SequenceNumber
+}
+
+impl Section {
+
+    fn clear(&mut self) {
+        self.data.clear();
+        self.count = 0;
+        self.previous = 0;
+    }
+
+    /// Appends an entry unless the packet would grow beyond `limit`. `extra` is added to the cost of the entry.
+    fn push(&mut self, id: SequenceNumber, header: &[u32], payload: &[u8], extra: usize, limit: usize, size: &mut usize) -> Result<bool> {
+        let delta: SequenceNumber = id.wrapping_sub(self.previous);
+        let delta = delta as u32;
+        let len = varint_len(delta) + header.iter().map(|value| varint_len(*value)).sum::<usize>() + payload.len();
+        let cost = extra + len + varint_len(self.count + 1) - varint_len(self.count);
+        if *size + cost > limit {
+            return Ok(false);
+        }
+        self.data.write_varint(delta)?;
+        for value in header {
+            self.data.write_varint(*value)?;
+        }
+        self.data.write_all(payload)?;
+        self.count += 1;
+        self.previous = id;
+        *size += cost;
+        Ok(true)
+    }
+
+    fn write_to(&self, packet: &mut impl Write) -> Result<()> {
+        packet.write_varint(self.count)?;
+        packet.write_all(&self.data)
+    }
+
+}
+
+/// The messages of a single priority. Their ids and their order are independent of the other priorities.
+#[derive(Debug, Clone)]
+struct Lane {
+    outgoing_messages: SequenceBuffer<Message>,
+    incoming_messages: SequenceBuffer<IncomingMessage>,
+    last_read_message: SequenceNumber,
+    /// The number of bytes this lane is owed because higher priorities took up the packets
+    credit: usize,
+    /// The messages and the fragments of the packet that is currently written
+    sections: [Section; 2]
+}
+
+impl Lane {
+
+    fn new(config: &MessageChannelConfig) -> Self {
+        Self {
+            outgoing_messages: SequenceBuffer::with_capacity(config.outgoing_capacity),
+            incoming_messages: SequenceBuffer::with_capacity(config.incoming_capacity),
+            last_read_message: 0,
+            credit: 0,
+            sections: Default::default()
+        }
+    }
+
+    fn receive_message(&mut self) -> Option<Box<[u8]>> {
+        let next = self.last_read_message.wrapping_add(1);
+        match self.incoming_messages.get(next) {
+            Some(IncomingMessage::Complete(_)) => match self.incoming_messages.remove(next) {
+                Some(IncomingMessage::Complete(msg)) => {
+                    self.last_read_message = next;
+                    Some(msg)
+                }
+                _ => unreachable!()
+            },
+            _ => None
+        }
+    }
+
+    fn has_next_message(&self) -> bool {
+        matches!(self.incoming_messages.get(self.last_read_message.wrapping_add(1)), Some(IncomingMessage::Complete(_)))
+    }
+
+    fn has_due_messages(&self, now: Instant, resend_interval: Duration) -> bool {
+        self.outgoing_messages
+            .iter()
+            .any(|(_, msg)| msg.fragments.iter().any(|state| state.is_due(now, resend_interval)))
+    }
+
+    fn is_written(&self) -> bool {
+        self.sections.iter().any(|section| section.count > 0)
+    }
+
+    /// Adds due messages and fragments to the sections until the packet would grow beyond `limit`
+    /// Returns the number of retransmissions
+    fn write_entries(&mut self, seq: SequenceNumber, now: Instant, resend_interval: Duration, tagged: bool, limit: usize, size: &mut usize) -> Result<u64> {
+        let Lane { outgoing_messages, sections: [messages, fragments], .. } = self;
+        let block_overhead = |messages: &Section, fragments: &Section| match tagged && messages.count == 0 && fragments.count == 0 {
+            true => PRIORITY_BLOCK_OVERHEAD,
+            false => 0
+        };
+        let due = |msg: &Message| msg.fragments[0].is_due(now, resend_interval) && !msg.sequence_number.contains(&(seq, 0));
+        let mut retransmissions = 0;
+        for (id, msg) in outgoing_messages.iter_mut().filter(|(_, msg)| !msg.is_fragmented() && due(msg)) {
+            let extra = block_overhead(messages, fragments);
+            if messages.push(id, &[msg.data.len() as u32], &msg.data, extra, limit, size)? && msg.on_send(seq, 0, now) {
+                retransmissions += 1;
+            }
+        }
+        'messages: for (id, msg) in outgoing_messages.iter_mut().filter(|(_, msg)| msg.is_fragmented()) {
+            while let Some(index) = msg.next_fragment(now, resend_interval) {
+                if msg.sequence_number.contains(&(seq, index)) {
+                    break;
+                }
+                let fragment = msg.fragment(index);
+                let header = [index as u32, msg.fragments.len() as u32, fragment.len() as u32];
+                let extra = block_overhead(messages, fragments);
+                if !fragments.push(id, &header, fragment, extra, limit, size)? {
+                    break 'messages;
+                }
+                if msg.on_send(seq, index, now) {
+                    retransmissions += 1;
+                }
+            }
+        }
+        Ok(retransmissions)
+    }
+
+}
+
+/// Settings of a [`MessageChannel`]. The peer may use different values.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MessageChannelConfig {
+    pub mode: ChannelMode,
+    /// The number of messages per priority that can wait to be sent or acknowledged.
+    /// Must be a power of two up to 32768.
+    pub outgoing_capacity: usize,
+    /// The number of received messages per priority the channel keeps track of. Newer messages push older ones
+    /// out of the buffer, even if they were not received yet, so this should be at least the `outgoing_capacity`
+    /// of the peer. Must be a power of two up to 32768.
+    pub incoming_capacity: usize,
+    /// Messages larger than this can neither be queued nor received.
+    /// Partially received messages never take up more than this.
+    pub max_message_size: usize,
+    /// The number of bytes a packet of the channel takes up at most. Should be set to
+    /// [`Client::max_payload_size`](crate::Client::max_payload_size) minus any headers of the application.
+    pub packet_budget: usize,
+    /// How long a sent message waits for its acknowledgement before it is sent again
+    pub resend_interval: Duration
+}
+
+impl Default for MessageChannelConfig {
+    fn default() -> Self {
+        Self {
+            mode: ChannelMode::Ordered,
+            outgoing_capacity: MESSAGE_BUFFER_CAPACITY,
+            incoming_capacity: MESSAGE_BUFFER_CAPACITY,
+            max_message_size: MAX_MESSAGE_SIZE,
+            packet_budget: MESSAGE_PACKET_BUDGET,
+            resend_interval: MESSAGE_RESEND_INTERVAL
+        }
+    }
+}
+
+impl MessageChannelConfig {
+
+    pub fn with_mode(self, mode: ChannelMode) -> Self {
+        Self {
+            mode,
+            ..self
+        }
+    }
+
+    pub fn with_outgoing_capacity(self, outgoing_capacity: usize) -> Self {
+        Self {
+            outgoing_capacity,
+            ..self
+        }
+    }
+
+    pub fn with_incoming_capacity(self, incoming_capacity: usize) -> Self {
+        Self {
+            incoming_capacity,
+            ..self
+        }
+    }
+
+    pub fn with_max_message_size(self, max_message_size: usize) -> Self {
+        Self {
+            max_message_size,
+            ..self
+        }
+    }
+
+    pub fn with_packet_budget(self, packet_budget: usize) -> Self {
+        Self {
+            packet_budget,
+            ..self
+        }
+    }
+
+    pub fn with_resend_interval(self, resend_interval: Duration) -> Self {
+        Self {
+            resend_interval,
+            ..self
+        }
+    }
+
+    pub(crate) fn validate(&self) -> std::result::Result<(), ConnectionError> {
+        let valid_capacity = |capacity: usize| capacity.is_power_of_two() && capacity <= 1 << 15;
+        if !valid_capacity(self.outgoing_capacity) || !valid_capacity(self.incoming_capacity) {
+            return Err(ConnectionError::InvalidConfig("the message capacities must be powers of two up to 32768"));
+        }
+        if self.packet_budget < MAX_ENTRY_OVERHEAD + PRIORITY_BLOCK_OVERHEAD {
+            return Err(ConnectionError::InvalidConfig("the packet budget must leave room for at least an empty message"));
+        }
+        if self.packet_budget > MAX_PACKET_SIZE - MAX_PAYLOAD_OVERHEAD {
+            return Err(ConnectionError::InvalidConfig("the packet budget must not exceed the maximum payload size"));
+        }
+        Ok(())
+    }
+
+}
+
+#[derive(Debug, Clone)]
 pub struct MessageChannel {
     buffer: Vec<u8>,
-    outgoing_messages: SequenceBuffer<Message>,
-    incoming_messages: SequenceBuffer2<Box<[u8]>>,
-    last_read_message: SequenceNumber
+    /// Indexed by [`Priority`]
+    lanes: [Lane; 3],
+    /// Messages of an unordered or unreliable channel that can be received right away
+    ready_messages: VecDeque<Box<[u8]>>,
+    config: MessageChannelConfig,
+    /// Only the counters, the rest is filled in by [`stats`](Self::stats)
+    stats: MessageChannelStats
 }
 
 impl MessageChannel {
 
+    /// Same as [`new_ordered`](Self::new_ordered)
     pub fn new() -> Self {
+        Self::new_ordered()
+    }
+
+    /// See [`ChannelMode::Ordered`]
+    pub fn new_ordered() -> Self {
+        Self::from_config(MessageChannelConfig::default())
+    }
+
+    /// See [`ChannelMode::Unordered`]. Ordered and unordered channels send the same packets,
+    /// so only the receiving side decides about the order.
+    pub fn new_unordered() -> Self {
+        Self::with_mode(ChannelMode::Unordered)
+    }
+
+    /// See [`ChannelMode::Unreliable`]
+    pub fn new_unreliable() -> Self {
+        Self::with_mode(ChannelMode::Unreliable)
+    }
+
+    pub fn with_mode(mode: ChannelMode) -> Self {
+        Self::from_config(MessageChannelConfig::default().with_mode(mode))
+    }
+
+    pub fn with_config(config: MessageChannelConfig) -> std::result::Result<Self, ConnectionError> {
+        config.validate()?;
+        Ok(Self::from_config(config))
+    }
+
+    fn from_config(config: MessageChannelConfig) -> Self {
         Self {
             buffer: Vec::new(),
-            outgoing_messages: SequenceBuffer::with_capacity(256),
-            incoming_messages: SequenceBuffer2::with_capacity(256),
-            last_read_message: 0
+            lanes: [Lane::new(&config), Lane::new(&config), Lane::new(&config)],
+            ready_messages: VecDeque::new(),
+            config,
+            stats: MessageChannelStats::default()
         }
     }
 
+    /// See [`MessageChannelConfig::max_message_size`]. Validated like [`with_config`](Self::with_config).
+    pub fn with_max_message_size(self, max_message_size: usize) -> std::result::Result<Self, ConnectionError> {
+        let config = self.config.with_max_message_size(max_message_size);
+        self.with_validated(config)
+    }
+
+    /// See [`MessageChannelConfig::packet_budget`]. Validated like [`with_config`](Self::with_config).
+    pub fn with_packet_budget(self, packet_budget: usize) -> std::result::Result<Self, ConnectionError> {
+        let config = self.config.with_packet_budget(packet_budget);
+        self.with_validated(config)
+    }
+
+    fn with_validated(self, config: MessageChannelConfig) -> std::result::Result<Self, ConnectionError> {
+        config.validate()?;
+        Ok(Self {
+            config,
+            ..self
+        })
+    }
+
+    pub fn config(&self) -> &MessageChannelConfig {
+        &self.config
+    }
+
+    pub fn max_message_size(&self) -> usize {
+        self.config.max_message_size
+    }
+
+    pub fn packet_budget(&self) -> usize {
+        self.config.packet_budget
+    }
+
+    /// See [`MessageChannelConfig::resend_interval`]
+    pub fn with_resend_interval(mut self, resend_interval: Duration) -> Self {
+        self.set_resend_interval(resend_interval);
+        self
+    }
+
+    /// Allows following the round trip time, e.g. with the `resend_interval` of [`Client::connection`](crate::Client::connection)
+    pub fn set_resend_interval(&mut self, resend_interval: Duration) {
+        self.config.resend_interval = resend_interval;
+    }
+
+    pub fn resend_interval(&self) -> Duration {
+        self.config.resend_interval
+    }
+
+    pub fn mode(&self) -> ChannelMode {
+        self.config.mode
+    }
+
+    /// Ordered channels return the messages of higher priorities first
     pub fn receive_message(&mut self) -> Option<Box<[u8]>> {
-        let next = self.last_read_message.wrapping_add(1);
-        match self.incoming_messages.remove(next){
-            None => None,
-            Some(msg) => {
-                self.last_read_message = next;
-                Some(msg)
-            }
+        let msg = match self.config.mode {
+            ChannelMode::Ordered => self.lanes.iter_mut().find_map(Lane::receive_message),
+            _ => self.ready_messages.pop_front()
+        };
+        if msg.is_some() {
+            self.stats.messages_delivered += 1;
+        }
+        msg
+    }
+
+    /// Receives every message that can be received right now, exactly like repeated calls of [`receive_message`](Self::receive_message)
+    pub fn receive_messages(&mut self) -> impl Iterator<Item=Box<[u8]>> + '_ {
+        std::iter::from_fn(|| self.receive_message())
+    }
+
+    /// Appends every message that can be received right now to `messages` and returns how many there were
+    pub fn receive_into(&mut self, messages: &mut Vec<Box<[u8]>>) -> usize {
+        let len = messages.len();
+        messages.extend(self.receive_messages());
+        messages.len() - len
+    }
+
+    /// Whether [`receive_message`](Self::receive_message) would return a message
+    pub fn pending_incoming(&self) -> bool {
+        match self.config.mode {
+            ChannelMode::Ordered => self.lanes.iter().any(Lane::has_next_message),
+            _ => !self.ready_messages.is_empty()
         }
     }
 
-    pub fn queue_message(&mut self, msg: &[u8]) -> Result<()>{
-        match self.outgoing_messages.try_insert(Message {
-            data: msg.into(),
-            sequence_number: Vec::new()
-        }) {
-            None => Err(Error::new(ErrorKind::Other, "can not queue any more messages")),
-            Some(_) => Ok(())
+    /// Queues the message with [`Priority::Normal`]
+    pub fn queue_message(&mut self, msg: &[u8]) -> std::result::Result<(), ChannelError> {
+        self.queue_message_with_priority(msg, Priority::Normal)
+    }
+
+    pub fn queue_message_with_priority(&mut self, msg: &[u8], priority: Priority) -> std::result::Result<(), ChannelError> {
+        self.queue_message_owned_with_priority(msg, priority)
+    }
+
+    /// Like [`queue_message`](Self::queue_message), but takes over the buffer of the message instead of copying it.
+    /// This includes the messages returned by [`receive_message`](Self::receive_message).
+    pub fn queue_message_owned(&mut self, msg: impl Into<Vec<u8>>) -> std::result::Result<(), ChannelError> {
+        self.queue_message_owned_with_priority(msg, Priority::Normal)
+    }
+
+    pub fn queue_message_owned_with_priority(&mut self, msg: impl Into<Vec<u8>>, priority: Priority) -> std::result::Result<(), ChannelError> {
+        let msg = msg.into();
+        let max = self.max_queued_message_size(priority);
+        if msg.len() > max {
+            return Err(ChannelError::MessageTooLarge { len: msg.len(), max });
+        }
+        if self.outgoing_free_slots_with_priority(priority) == 0 {
+            return Err(ChannelError::QueueFull { returned: msg.into_boxed_slice() });
         }
+        self.lanes[priority as usize].outgoing_messages
+            .try_insert(Message::new(msg))
+            .expect("there is a free slot");
+        self.stats.messages_queued += 1;
+        Ok(())
+    }
+
+    /// Messages that do not fit into a single fragment have to fit into the packet budget as a whole
+    fn max_queued_message_size(&self, priority: Priority) -> usize {
+        let overhead = match priority {
+            Priority::Normal => MAX_ENTRY_OVERHEAD,
+            _ => MAX_ENTRY_OVERHEAD + PRIORITY_BLOCK_OVERHEAD
+        };
+        match self.config.packet_budget.saturating_sub(overhead) {
+            fits if fits >= MESSAGE_FRAGMENT_SIZE => self.config.max_message_size,
+            fits => fits.min(self.config.max_message_size)
+        }
+    }
+
+    /// The number of messages with [`Priority::Normal`] that can be queued right now
+    pub fn outgoing_free_slots(&self) -> usize {
+        self.outgoing_free_slots_with_priority(Priority::Normal)
     }
 
+    /// Messages are acknowledged out of order, but their slots only become free once all older messages are acknowledged as well
+    pub fn outgoing_free_slots_with_priority(&self, priority: Priority) -> usize {
+        let outgoing_messages = &self.lanes[priority as usize].outgoing_messages;
+        match outgoing_messages.oldest() {
+            Some(oldest) => outgoing_messages.capacity() - outgoing_messages.next_sequence_number().wrapping_sub(oldest) as usize,
+            None => outgoing_messages.capacity()
+        }
+    }
+
+    /// The block of [`Priority::Normal`] comes first, the blocks of the other priorities follow with a tag
     pub fn on_receive(&mut self, mut packet: &[u8]) -> Result<()> {
-        let len = packet.read_u8()?;
+        let mut priority = Priority::Normal;
+        loop {
+            self.on_receive_block(priority, &mut packet)?;
+            if packet.is_empty() {
+                return Ok(());
+            }
+            priority = Priority::from_u8(packet.read_u8()?)?;
+        }
+    }
+
+    fn on_receive_block(&mut self, priority: Priority, packet: &mut &[u8]) -> Result<()> {
+        let len = packet.read_varint()?;
+        let mut msg_id: SequenceNumber = 0;
         for _ in 0..len {
-            let msg_id = packet.read_u16::<NetworkEndian>()?;
-            let size = packet.read_u8()? as usize;
-            if sequence_less_than(self.last_read_message, msg_id) && !self.incoming_messages.exists(msg_id) {
-                let mut buf = vec![0u8; size].into_boxed_slice();
-                packet.read_exact(buf.as_mut())?;
-                self.incoming_messages.insert(msg_id, buf);
-            } else {
-                for _ in 0..size {
-                    packet.read_u8()?;
-                }
+            msg_id = msg_id.wrapping_add(packet.read_varint()? as SequenceNumber);
+            let size = packet.read_varint()? as usize;
+            let msg = read_slice(packet, size)?;
+            if size > MESSAGE_FRAGMENT_SIZE.min(self.config.max_message_size) {
+                return Err(Error::new(ErrorKind::InvalidData, "message exceeds the maximum message size"));
+            }
+            if self.is_new(priority, msg_id) {
+                self.on_message(priority, msg_id, msg.into());
+            }
+        }
+        // Fragments follow in a section of their own that is omitted when there are none and nothing else follows
+        if packet.is_empty() {
+            return Ok(());
+        }
+        let len = packet.read_varint()?;
+        let mut msg_id: SequenceNumber = 0;
+        for _ in 0..len {
+            msg_id = msg_id.wrapping_add(packet.read_varint()? as SequenceNumber);
+            let index = packet.read_varint()? as usize;
+            let count = packet.read_varint()? as usize;
+            let size = packet.read_varint()? as usize;
+            let fragment = read_slice(packet, size)?;
+            self.on_fragment(priority, msg_id, index, count, fragment)?;
+        }
+        Ok(())
+    }
+
+    fn on_fragment(&mut self, priority: Priority, msg_id: SequenceNumber, index: usize, count: usize, fragment: &[u8]) -> Result<()> {
+        let expected_size = match index + 1 == count {
+            true => 1..=MESSAGE_FRAGMENT_SIZE,
+            false => MESSAGE_FRAGMENT_SIZE..=MESSAGE_FRAGMENT_SIZE
+        };
+        if count < 2 || index >= count || !expected_size.contains(&fragment.len()) {
+            return Err(Error::new(ErrorKind::InvalidData, "malformed message fragment"));
+        }
+        let too_large = match index + 1 == count {
+            true => (count - 1) * MESSAGE_FRAGMENT_SIZE + fragment.len() > self.config.max_message_size,
+            false => count > self.config.max_message_size.div_ceil(MESSAGE_FRAGMENT_SIZE)
+        };
+        if too_large {
+            return Err(Error::new(ErrorKind::InvalidData, "message exceeds the maximum message size"));
+        }
+        let lane = &mut self.lanes[priority as usize];
+        if self.config.mode == ChannelMode::Ordered && !sequence_less_than(lane.last_read_message, msg_id) {
+            return Ok(());
+        }
+        if !lane.incoming_messages.contains(msg_id) {
+            let fragments = vec![None; count].into_boxed_slice();
+            // like whole messages, partial messages that are too old for the buffer are resent by the peer.
+            // Newer messages push partial ones out of the buffer the same way.
+            if lane.incoming_messages.insert_at(msg_id, IncomingMessage::Partial { fragments, missing: count }).is_err() {
+                return Ok(());
+            }
+        }
+        let entry = lane.incoming_messages.get_mut(msg_id).expect("message was just inserted");
+        if let IncomingMessage::Partial { fragments, missing } = entry {
+            if fragments.len() != count {
+                return Err(Error::new(ErrorKind::InvalidData, "inconsistent fragment count"));
+            }
+            if fragments[index].is_none() {
+                fragments[index] = Some(fragment.into());
+                *missing -= 1;
+            }
+            if *missing == 0 {
+                self.stats.messages_received += 1;
+                let msg = fragments
+                    .iter()
+                    .flatten()
+                    .flat_map(|fragment| fragment.iter())
+                    .copied()
+                    .collect();
+                *entry = match self.config.mode == ChannelMode::Ordered {
+                    true => IncomingMessage::Complete(msg),
+                    false => {
+                        self.ready_messages.push_back(msg);
+                        IncomingMessage::Delivered
+                    }
+                };
             }
         }
         Ok(())
     }
 
-    pub fn on_ack(&mut self, seq: SequenceNumber) {
-        let mut tmp = Vec::new();
-        for (id, msg) in self.outgoing_messages.iter_mut() {
-            if msg.sequence_number.contains(&seq) {
-                tmp.push(id);
+    /// Whether a message with this id was neither received nor delivered yet
+    fn is_new(&self, priority: Priority, msg_id: SequenceNumber) -> bool {
+        let lane = &self.lanes[priority as usize];
+        let delivered = self.config.mode == ChannelMode::Ordered && !sequence_less_than(lane.last_read_message, msg_id);
+        !delivered && !lane.incoming_messages.contains(msg_id)
+    }
+
+    fn on_message(&mut self, priority: Priority, msg_id: SequenceNumber, msg: Box<[u8]>) {
+        let incoming_messages = &mut self.lanes[priority as usize].incoming_messages;
+        // messages that are too old for the buffer are dropped and resent by the peer
+        let inserted = match self.config.mode == ChannelMode::Ordered {
+            true => incoming_messages.insert_at(msg_id, IncomingMessage::Complete(msg)).is_ok(),
+            false => match incoming_messages.insert_at(msg_id, IncomingMessage::Delivered).is_ok() {
+                true => {
+                    self.ready_messages.push_back(msg);
+                    true
+                }
+                false => false
             }
+        };
+        if inserted {
+            self.stats.messages_received += 1;
         }
+    }
 
-        for id in tmp {
-            self.outgoing_messages.remove(id);
+    pub fn on_ack(&mut self, seq: SequenceNumber) {
+        for lane in &mut self.lanes {
+            let len = lane.outgoing_messages.len();
+            lane.outgoing_messages.retain(|_, msg| !msg.on_ack(seq));
+            self.stats.messages_acknowledged += (len - lane.outgoing_messages.len()) as u64;
         }
     }
 
+    /// Resends the messages of a lost packet without waiting for the resend interval,
+    /// e.g. after [`ClientEvent::PacketLost`](crate::ClientEvent::PacketLost)
+    pub fn on_loss(&mut self, seq: SequenceNumber) {
+        for lane in &mut self.lanes {
+            for (_, msg) in lane.outgoing_messages.iter_mut() {
+                msg.on_loss(seq);
+            }
+        }
+    }
+
+    /// The packet never exceeds the largest payload a connection can carry, even if the packet budget is larger.
+    /// `seq` has to be the sequence number of the packet that carries the result. Flush batched payloads before
+    /// taking it from `peek_next_sequence_number` of the connection, otherwise the batch takes it when the packet is sent.
     pub fn send_packets(&mut self, seq: SequenceNumber) -> Result<&[u8]> {
-        let packet = &mut self.buffer;
+        self.write_packet(seq, self.config.packet_budget)
+    }
 
-        packet.clear();
-        packet.write_u8(0)?;
+    /// Like [`send_packets`](Self::send_packets), but writes the packet into `out` and returns its length.
+    /// The packet takes up at most the packet budget or the length of `out`, whichever is smaller.
+    pub fn send_packets_into(&mut self, seq: SequenceNumber, out: &mut [u8]) -> Result<usize> {
+        self.write_packet_into(seq, self.config.packet_budget, out)
+    }
+
+    /// Like `send_packets`, but stays within the given budget instead of the one of the channel
+    pub(crate) fn write_packet(&mut self, seq: SequenceNumber, budget: usize) -> Result<&[u8]> {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.resize(MAX_PACKET_SIZE - MAX_PAYLOAD_OVERHEAD, 0);
+        let len = self.write_packet_into(seq, budget, &mut buffer);
+        self.buffer = buffer;
+        Ok(&self.buffer[..len?])
+    }
 
-        for (id, msg) in self.outgoing_messages.iter_mut().take(5) {
-            packet[0] += 1;
-            packet.write_u16::<NetworkEndian>(id)?;
-            packet.write_u8(msg.data.len() as u8)?;
-            packet.write_all(msg.data.as_ref())?;
-            msg.sequence_number.push(seq);
+    fn write_packet_into(&mut self, seq: SequenceNumber, budget: usize, out: &mut [u8]) -> Result<usize> {
+        let budget = budget.min(out.len());
+        let now = Instant::now();
+        let resend_interval = self.config.resend_interval;
+        for lane in &mut self.lanes {
+            lane.sections.iter_mut().for_each(Section::clear);
         }
 
-        Ok(packet.as_slice())
+        // The block of the normal priority is always there, with both of its section counts
+        let mut size = 2;
+        // Lower priorities that have to wait for higher ones earn a share of the budget with every packet.
+        // It is filled before the higher priorities get their turn, so they can not starve the lower ones.
+        let mut waiting = false;
+        for (priority, lane) in Priority::ALL.into_iter().zip(&mut self.lanes) {
+            let due = lane.has_due_messages(now, resend_interval);
+            lane.credit = match due && waiting {
+                true => (lane.credit + (budget as f32 * LOW_PRIORITY_SHARE) as usize).min(budget),
+                false => 0
+            };
+            waiting |= due;
+            let start = size;
+            self.stats.retransmissions += lane.write_entries(seq, now, resend_interval, priority != Priority::Normal, budget.min(size + lane.credit), &mut size)?;
+            lane.credit -= size - start;
+        }
+        for (priority, lane) in Priority::ALL.into_iter().zip(&mut self.lanes) {
+            let start = size;
+            self.stats.retransmissions += lane.write_entries(seq, now, resend_interval, priority != Priority::Normal, budget, &mut size)?;
+            lane.credit = lane.credit.saturating_sub(size - start);
+        }
+
+        let capacity = out.len();
+        let mut packet = &mut out[..];
+        let [high, normal, low] = &self.lanes;
+        let [messages, fragments] = &normal.sections;
+        messages.write_to(&mut packet)?;
+        // The fragment section of the normal priority is omitted when there is nothing to follow it
+        let tagged = [(Priority::High, high), (Priority::Low, low)];
+        if fragments.count > 0 || tagged.iter().any(|(_, lane)| lane.is_written()) {
+            fragments.write_to(&mut packet)?;
+        }
+        for (priority, lane) in tagged.into_iter().filter(|(_, lane)| lane.is_written()) {
+            packet.write_all(&[priority.to_u8()])?;
+            for section in &lane.sections {
+                section.write_to(&mut packet)?;
+            }
+        }
+        let len = capacity - packet.len();
+
+        // Unreliable messages are forgotten as soon as all of their fragments went out once
+        if self.config.mode == ChannelMode::Unreliable {
+            for lane in &mut self.lanes {
+                lane.outgoing_messages.retain(|_, msg| msg.fragments.contains(&FragmentState::Unsent));
+            }
+        }
+
+        Ok(len)
+    }
+
+    pub fn stats(&self) -> MessageChannelStats {
+        let now = Instant::now();
+        let outgoing = || self.lanes.iter().flat_map(|lane| lane.outgoing_messages.iter()).map(|(_, msg)| msg);
+        let incoming_buffered = self.lanes
+            .iter()
+            .flat_map(|lane| lane.incoming_messages.iter())
+            .filter(|(_, msg)| !matches!(msg, IncomingMessage::Delivered))
+            .count();
+        MessageChannelStats {
+            messages_in_flight: outgoing().filter(|msg| msg.is_in_flight()).count(),
+            incoming_buffered,
+            oldest_unacked_age: outgoing()
+                .map(|msg| now.saturating_duration_since(msg.queued))
+                .max()
+                .unwrap_or_default(),
+            ..self.stats
+        }
+    }
+
+    /// Returns the channel to its initial state, keeping its configuration, stats and allocations.
+    /// The messages that were not acknowledged yet are handed back, by priority and oldest first, so they can be queued again.
+    ///
+    /// The message ids of a channel only make sense to the channel on the other side of the same connection.
+    /// Call this on both sides once the connection ends, e.g. on [`ClientEvent::Disconnected`](crate::ClientEvent::Disconnected)
+    /// or [`ServerEvent::ClientDisconnected`](crate::ServerEvent::ClientDisconnected), and before the channel is used
+    /// for the next [`ClientEvent::Connected`](crate::ClientEvent::Connected). Acknowledgements of the old connection must not reach it afterwards.
+    pub fn reset(&mut self) -> Vec<(Priority, Box<[u8]>)> {
+        let mut undelivered = Vec::new();
+        for (priority, lane) in Priority::ALL.into_iter().zip(&mut self.lanes) {
+            undelivered.extend(lane.outgoing_messages
+                .drain_filter(|_, _| true)
+                .map(|(_, msg)| (priority, msg.data.into_boxed_slice())));
+            lane.outgoing_messages.reset();
+            lane.incoming_messages.reset();
+            lane.last_read_message = 0;
+            lane.credit = 0;
+        }
+        self.ready_messages.clear();
+        undelivered
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = MessageChannelStats::default();
     }
 
     pub fn has_unsend_messages(&self) -> bool {
-        !self.outgoing_messages.is_empty()
+        self.lanes.iter().any(|lane| !lane.outgoing_messages.is_empty())
+    }
+
+    /// Whether any message is due to be sent, either for the first time or because its resend interval elapsed
+    pub fn has_due_messages(&self) -> bool {
+        let now = Instant::now();
+        self.lanes.iter().any(|lane| lane.has_due_messages(now, self.config.resend_interval))
+    }
+
+}
+
+impl Default for MessageChannel {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
+#[derive(Debug, Default)]
+struct OutgoingValue {
+    sequence: SequenceNumber,
+    data: Vec<u8>,
+    pending: bool
 }
 
 #[derive(Debug)]
-pub struct SequenceBuffer2<T: Clone + Default> {
-    sequence_num: SequenceNumber,
-    entry_sequences: Box<[Option<SequenceNumber>]>,
-    entries: Box<[T]>,
+struct IncomingValue {
+    sequence: SequenceNumber,
+    data: Vec<u8>,
+    changed: bool
 }
 
-impl<T: Clone + Default> SequenceBuffer2<T> {
-    pub fn with_capacity(size: u16) -> Self {
+/// Sends only the latest value of each of its 256 slots, e.g. for positions or other state that is updated continuously.
+/// Values are neither acknowledged nor resent. A value that is replaced before it went out is never sent,
+/// and the receiving side drops values that arrive after a newer one of the same slot.
+///
+/// The sequence numbers of the slots start over with a new channel, so both sides should create new ones for a new connection.
+///
+/// Packet layout: `[count: varint]` followed by `count` times `[slot: 1][sequence: varint][length: varint][value]`
+#[derive(Debug)]
+pub struct SequencedChannel {
+    buffer: Vec<u8>,
+    outgoing: Box<[OutgoingValue]>,
+    /// The slot the next packet starts with, so that the first slots can not keep the others out
+    next_slot: usize,
+    incoming: Box<[Option<IncomingValue>]>,
+    changes: VecDeque<u8>,
+    packet_budget: usize
+}
+
+impl SequencedChannel {
+
+    pub fn new() -> Self {
         Self {
-            sequence_num: 0,
-            entry_sequences: vec![None; size as usize].into_boxed_slice(),
-            entries: vec![T::default(); size as usize].into_boxed_slice(),
+            buffer: Vec::new(),
+            outgoing: (0..SEQUENCED_SLOTS).map(|_| OutgoingValue::default()).collect(),
+            next_slot: 0,
+            incoming: (0..SEQUENCED_SLOTS).map(|_| None).collect(),
+            changes: VecDeque::new(),
+            packet_budget: MESSAGE_PACKET_BUDGET
         }
     }
 
-    pub fn insert(&mut self, sequence_num: SequenceNumber, entry: T) -> Option<&mut T> {
-        if sequence_less_than(
-            sequence_num,
-            self.sequence_num
-                .wrapping_sub(self.entry_sequences.len() as u16),
-        ) {
-            return None;
+    /// See [`MessageChannelConfig::packet_budget`]
+    pub fn with_packet_budget(mut self, packet_budget: usize) -> Self {
+        self.packet_budget = packet_budget;
+        self
+    }
+
+    pub fn packet_budget(&self) -> usize {
+        self.packet_budget
+    }
+
+    /// The largest value that fits into a packet
+    pub fn max_value_size(&self) -> usize {
+        self.packet_budget.saturating_sub(SEQUENCED_HEADER_SIZE + SEQUENCED_ENTRY_OVERHEAD)
+    }
+
+    /// Replaces the value of `slot`. It goes out with the next packet unless it is replaced again before.
+    pub fn set(&mut self, slot: u8, data: &[u8]) -> std::result::Result<(), ChannelError> {
+        let max = self.max_value_size();
+        if data.len() > max {
+            return Err(ChannelError::MessageTooLarge { len: data.len(), max });
         }
+        let value = &mut self.outgoing[slot as usize];
+        value.sequence = value.sequence.wrapping_add(1);
+        value.data.clear();
+        value.data.extend_from_slice(data);
+        value.pending = true;
+        Ok(())
+    }
 
-        self.advance_sequence(sequence_num);
+    /// Whether any value was set since it was last sent
+    pub fn has_pending(&self) -> bool {
+        self.outgoing.iter().any(|value| value.pending)
+    }
 
-        let index = self.index(sequence_num);
-        self.entry_sequences[index] = Some(sequence_num);
-        self.entries[index] = entry;
-        Some(&mut self.entries[index])
+    /// Writes the pending values that fit into the packet budget. The others wait for the next packet.
+    pub fn send_packets(&mut self) -> Result<&[u8]> {
+        // the count is written in front of the values once it is known
+        self.buffer.clear();
+        self.buffer.resize(SEQUENCED_HEADER_SIZE, 0);
+        let mut count = 0;
+        let mut skipped = None;
+        for slot in (0..SEQUENCED_SLOTS).map(|i| (self.next_slot + i) % SEQUENCED_SLOTS) {
+            let value = &mut self.outgoing[slot];
+            if !value.pending {
+                continue;
+            }
+            let sequence: SequenceNumber = value.sequence;
+            let sequence = sequence as u32;
+            let len = 1 + varint_len(sequence) + varint_len(value.data.len() as u32) + value.data.len();
+            if self.buffer.len() + len > self.packet_budget {
+                skipped.get_or_insert(slot);
+                continue;
+            }
+            self.buffer.push(slot as u8);
+            self.buffer.write_varint(sequence)?;
+            self.buffer.write_varint(value.data.len() as u32)?;
+            self.buffer.extend_from_slice(&value.data);
+            value.pending = false;
+            count += 1;
+        }
+        if let Some(slot) = skipped {
+            self.next_slot = slot;
+        }
+        let start = SEQUENCED_HEADER_SIZE - varint_len(count);
+        (&mut self.buffer[start..SEQUENCED_HEADER_SIZE]).write_varint(count)?;
+        Ok(&self.buffer[start..])
     }
 
-    pub fn exists(&self, sequence_num: SequenceNumber) -> bool {
-        let index = self.index(sequence_num);
-        if let Some(s) = self.entry_sequences[index] {
-            return s == sequence_num;
+    /// Takes the values of a packet written by [`send_packets`](Self::send_packets) on the other side
+    pub fn on_receive(&mut self, mut packet: &[u8]) -> Result<()> {
+        if packet.is_empty() {
+            return Ok(());
+        }
+        let count = packet.read_varint()?;
+        for _ in 0..count {
+            let slot = packet.read_u8()?;
+            let sequence = packet.read_varint()? as SequenceNumber;
+            let len = packet.read_varint()? as usize;
+            let data = read_slice(&mut packet, len)?;
+            self.on_value(slot, sequence, data);
         }
-        false
+        Ok(())
     }
 
-    pub fn remove(&mut self, sequence_num: SequenceNumber) -> Option<T> {
-        if self.exists(sequence_num) {
-            let index = self.index(sequence_num);
-            let value = std::mem::take(&mut self.entries[index]);
-            self.entry_sequences[index] = None;
-            return Some(value);
+    fn on_value(&mut self, slot: u8, sequence: SequenceNumber, data: &[u8]) {
+        let entry = &mut self.incoming[slot as usize];
+        if entry.as_ref().is_some_and(|value| !sequence_greater_than(sequence, value.sequence)) {
+            return;
         }
-        None
+        let value = entry.get_or_insert_with(|| IncomingValue { sequence, data: Vec::new(), changed: false });
+        value.sequence = sequence;
+        value.data.clear();
+        value.data.extend_from_slice(data);
+        if !value.changed {
+            value.changed = true;
+            self.changes.push_back(slot);
+        }
+    }
+
+    /// The newest value received for `slot`
+    pub fn latest(&self, slot: u8) -> Option<&[u8]> {
+        self.incoming[slot as usize].as_ref().map(|value| value.data.as_slice())
     }
 
-    fn advance_sequence(&mut self, sequence_num: SequenceNumber) {
-        if sequence_greater_than(sequence_num.wrapping_add(1), self.sequence_num) {
-            self.remove_entries(u32::from(sequence_num));
-            self.sequence_num = sequence_num.wrapping_add(1);
+    /// The slots that received a newer value since the last call, in the order they first changed, with their latest values.
+    /// Changes that are not iterated are dropped as well.
+    pub fn changes(&mut self) -> impl Iterator<Item=(u8, &[u8])> + '_ {
+        let Self { incoming, changes, .. } = self;
+        for &slot in changes.iter() {
+            if let Some(value) = &mut incoming[slot as usize] {
+                value.changed = false;
+            }
         }
+        let incoming = &*incoming;
+        changes.drain(..).filter_map(move |slot| incoming[slot as usize]
+            .as_ref()
+            .map(|value| (slot, value.data.as_slice())))
     }
 
-    fn remove_entries(&mut self, mut finish_sequence: u32) {
-        let start_sequence = u32::from(self.sequence_num);
-        if finish_sequence < start_sequence {
-            finish_sequence += 65536;
+}
+
+impl Default for SequencedChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::constants::{MAX_MESSAGE_SIZE, MAX_PACKET_SIZE, MESSAGE_FRAGMENT_SIZE, MESSAGE_PACKET_BUDGET};
+    use crate::error::{ChannelError, ConnectionError};
+    use crate::packets::MAX_PAYLOAD_OVERHEAD;
+    use crate::reliable::{ChannelMode, FragmentState, IncomingMessage, MessageChannel, MessageChannelConfig, MessageChannelStats, Priority, SequencedChannel};
+    use std::time::Duration;
+    use crate::sequencing::SequenceNumber;
+
+    const NORMAL: usize = Priority::Normal as usize;
+
+    #[test]
+    fn test_message_framing() {
+        let mut sender = MessageChannel::new();
+        let mut receiver = MessageChannel::new();
+        let messages: Vec<Vec<u8>> = [0, 4, 127, 128, 300]
+            .into_iter()
+            .map(|len| vec![len as u8; len])
+            .collect();
+        for msg in &messages {
+            sender.queue_message(msg).unwrap();
+        }
+        let packet = sender.send_packets(0).unwrap();
+        assert_eq!(packet.len(), 1 + messages.iter().map(|m| m.len() + 2).sum::<usize>() + 2);
+        receiver.on_receive(packet).unwrap();
+        for msg in &messages {
+            assert_eq!(receiver.receive_message().unwrap().as_ref(), msg.as_slice());
         }
+        assert!(receiver.receive_message().is_none());
+    }
 
-        if finish_sequence - start_sequence < self.entry_sequences.len() as u32 {
-            for sequence in start_sequence..=finish_sequence {
-                self.remove(sequence as u16);
+    #[test]
+    fn test_message_sizes() {
+        let mut sender = MessageChannel::new();
+        let mut receiver = MessageChannel::new();
+        for (seq, len) in [255, 256, 1200].into_iter().enumerate() {
+            let msg: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            sender.queue_message(&msg).unwrap();
+            for seq in 3 * seq..3 * seq + 3 {
+                receiver.on_receive(sender.send_packets(seq as SequenceNumber).unwrap()).unwrap();
+                sender.on_ack(seq as SequenceNumber);
             }
-        } else {
-            for index in 0..self.entry_sequences.len() {
-                self.entries[index] = T::default();
-                self.entry_sequences[index] = None;
+            assert!(!sender.has_unsend_messages());
+            assert_eq!(receiver.receive_message().unwrap().as_ref(), msg.as_slice());
+        }
+        assert!(receiver.receive_message().is_none());
+    }
+
+    #[test]
+    fn test_malformed_size() {
+        let mut receiver = MessageChannel::new();
+        // declares far more bytes than the packet contains
+        assert!(receiver.on_receive(&[1, 1, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F, 1, 2]).is_err());
+        assert!(receiver.on_receive(&[1, 1, 3, 1, 2]).is_err());
+        assert!(receiver.on_receive(&[0, 1, 1, 0, 2, 3, 1, 2]).is_err());
+        let mut packet = vec![1, 1, 0x81, 0x04];
+        packet.extend_from_slice(&[0; MESSAGE_FRAGMENT_SIZE + 1]);
+        assert!(receiver.on_receive(&packet).is_err());
+        assert!(receiver.lanes[NORMAL].incoming_messages.is_empty());
+    }
+
+    #[test]
+    fn test_on_ack() {
+        let mut sender = MessageChannel::new();
+        sender.queue_message(&[1]).unwrap();
+        sender.queue_message(&[2]).unwrap();
+        sender.send_packets(10).unwrap();
+        sender.queue_message(&[3]).unwrap();
+        sender.send_packets(11).unwrap();
+
+        sender.on_ack(12);
+        assert_eq!(sender.lanes[NORMAL].outgoing_messages.len(), 3);
+        sender.on_ack(10);
+        assert_eq!(sender.lanes[NORMAL].outgoing_messages.iter().map(|(_, msg)| msg.data.as_slice()).collect::<Vec<_>>(), [&[3]]);
+        assert!(sender.has_unsend_messages());
+        sender.on_ack(11);
+        assert!(!sender.has_unsend_messages());
+    }
+
+    #[test]
+    fn test_out_of_order_acks() {
+        let mut sender = MessageChannel::new();
+        for msg in [1, 2, 3] {
+            sender.queue_message(&[msg]).unwrap();
+        }
+        // pretend every message went out in its own packet
+        for (id, msg) in sender.lanes[NORMAL].outgoing_messages.iter_mut() {
+            msg.sequence_number.push((10 + id, 0));
+        }
+
+        sender.on_ack(12);
+        assert_eq!(sender.lanes[NORMAL].outgoing_messages.len(), 2);
+        sender.queue_message(&[4]).unwrap();
+        assert_eq!(sender.lanes[NORMAL].outgoing_messages.iter().map(|(id, msg)| (id, msg.data[0])).collect::<Vec<_>>(), [(1, 1), (3, 3), (4, 4)]);
+        assert_eq!(sender.send_packets(20).unwrap(), [3, 1, 1, 1, 2, 1, 3, 1, 1, 4]);
+
+        sender.on_ack(13);
+        sender.on_ack(11);
+        assert_eq!(sender.lanes[NORMAL].outgoing_messages.iter().map(|(id, _)| id).collect::<Vec<_>>(), [4]);
+        sender.on_ack(20);
+        assert!(!sender.has_unsend_messages());
+        assert!(sender.lanes[NORMAL].outgoing_messages.is_empty());
+    }
+
+    #[test]
+    fn test_fragmentation() {
+        let mut sender = MessageChannel::new()
+            .with_packet_budget(MESSAGE_FRAGMENT_SIZE + 24).unwrap()
+            .with_resend_interval(Duration::ZERO);
+        let mut receiver = MessageChannel::new();
+        let large: Vec<u8> = (0..3 * MESSAGE_FRAGMENT_SIZE + 10).map(|i| i as u8).collect();
+        sender.queue_message(&large).unwrap();
+        sender.queue_message(&[1, 2, 3]).unwrap();
+
+        let packets: Vec<Vec<u8>> = (0..4)
+            .map(|seq| sender.send_packets(seq).unwrap().to_vec())
+            .collect();
+        assert!(packets.iter().all(|packet| packet.len() <= MESSAGE_FRAGMENT_SIZE + 24));
+        for packet in packets[1..].iter().rev() {
+            receiver.on_receive(packet).unwrap();
+            assert!(receiver.receive_message().is_none());
+        }
+        sender.on_ack(1);
+        sender.on_ack(2);
+        sender.on_ack(3);
+        assert!(sender.has_unsend_messages());
+
+        // the first packet got lost, so only its fragment is sent again
+        let packet = sender.send_packets(4).unwrap();
+        assert_eq!(packet[..5], [0, 1, 1, 0, 4]);
+        receiver.on_receive(packet).unwrap();
+        sender.on_ack(4);
+        assert!(!sender.has_unsend_messages());
+
+        assert_eq!(receiver.receive_message().unwrap().as_ref(), large.as_slice());
+        assert_eq!(receiver.receive_message().unwrap().as_ref(), [1, 2, 3]);
+        receiver.on_receive(&packets[1]).unwrap();
+        assert!(receiver.receive_message().is_none());
+        assert!(receiver.lanes[NORMAL].incoming_messages.is_empty());
+    }
+
+    #[test]
+    fn test_max_message_size() {
+        let mut sender = MessageChannel::new().with_max_message_size(4 * MESSAGE_FRAGMENT_SIZE).unwrap();
+        let mut receiver = MessageChannel::new().with_max_message_size(2 * MESSAGE_FRAGMENT_SIZE).unwrap();
+        assert!(sender.queue_message(&vec![0; 4 * MESSAGE_FRAGMENT_SIZE + 1]).is_err());
+        sender.queue_message(&vec![0; 3 * MESSAGE_FRAGMENT_SIZE]).unwrap();
+        let packet = sender.send_packets(0).unwrap();
+        assert!(receiver.on_receive(packet).is_err());
+        assert!(receiver.lanes[NORMAL].incoming_messages.is_empty());
+
+        let mut sender = MessageChannel::new().with_resend_interval(Duration::ZERO);
+        sender.queue_message(&vec![0; 2 * MESSAGE_FRAGMENT_SIZE + 1]).unwrap();
+        sender.send_packets(0).unwrap();
+        sender.send_packets(1).unwrap();
+        let packet = sender.send_packets(2).unwrap();
+        assert!(receiver.on_receive(packet).is_err());
+    }
+
+    #[test]
+    fn test_partial_message_eviction() {
+        let mut sender = MessageChannel::new();
+        let mut receiver = MessageChannel::new();
+        sender.queue_message(&vec![7; 2 * MESSAGE_FRAGMENT_SIZE]).unwrap();
+        receiver.on_receive(sender.send_packets(0).unwrap()).unwrap();
+        assert!(matches!(receiver.lanes[NORMAL].incoming_messages.get(1), Some(IncomingMessage::Partial { missing: 1, .. })));
+
+        // newer messages push the partial one out of the window, completing it is no longer possible
+        assert_eq!(receiver.lanes[NORMAL].incoming_messages.capacity(), 256);
+        receiver.on_receive(&[1, 0x81, 0x02, 1, 0]).unwrap();
+        assert!(receiver.lanes[NORMAL].incoming_messages.get(1).is_none());
+        receiver.on_receive(sender.send_packets(1).unwrap()).unwrap();
+        assert!(receiver.lanes[NORMAL].incoming_messages.get(1).is_none());
+        assert!(receiver.receive_message().is_none());
+    }
+
+    #[test]
+    fn test_unordered() {
+        let mut sender = MessageChannel::new();
+        let mut ordered = MessageChannel::new_ordered();
+        let mut unordered = MessageChannel::new_unordered();
+        assert_eq!(ordered.mode(), ChannelMode::Ordered);
+        assert_eq!(unordered.mode(), ChannelMode::Unordered);
+
+        let large = vec![7; 2 * MESSAGE_FRAGMENT_SIZE];
+        let mut packets = Vec::new();
+        for (seq, msg) in [&[1][..], &[2], &large, &[3]].into_iter().enumerate() {
+            sender.queue_message(msg).unwrap();
+            packets.push(sender.send_packets(seq as SequenceNumber * 2).unwrap().to_vec());
+            packets.push(sender.send_packets(seq as SequenceNumber * 2 + 1).unwrap().to_vec());
+            sender.on_ack(seq as SequenceNumber * 2);
+            sender.on_ack(seq as SequenceNumber * 2 + 1);
+        }
+        assert!(!sender.has_unsend_messages());
+
+        // the packet with the first message is delayed
+        for packet in packets[2..].iter().chain(&packets[..2]).chain(&packets) {
+            ordered.on_receive(packet).unwrap();
+            unordered.on_receive(packet).unwrap();
+        }
+        let expected: [&[u8]; 4] = [&[2], &large, &[3], &[1]];
+        for msg in expected {
+            assert_eq!(unordered.receive_message().unwrap().as_ref(), msg);
+        }
+        assert!(unordered.receive_message().is_none());
+        let expected: [&[u8]; 4] = [&[1], &[2], &large, &[3]];
+        for msg in expected {
+            assert_eq!(ordered.receive_message().unwrap().as_ref(), msg);
+        }
+        assert!(ordered.receive_message().is_none());
+    }
+
+    #[test]
+    fn test_unordered_wrap() {
+        let mut sender = MessageChannel::new();
+        let mut receiver = MessageChannel::new_unordered();
+        let mut seq: SequenceNumber = 0;
+        for i in 0..70_000u32 {
+            sender.queue_message(&i.to_be_bytes()).unwrap();
+            let packet = sender.send_packets(seq).unwrap();
+            receiver.on_receive(packet).unwrap();
+            receiver.on_receive(packet).unwrap();
+            sender.on_ack(seq);
+            seq = seq.wrapping_add(1);
+            assert_eq!(receiver.receive_message().unwrap().as_ref(), i.to_be_bytes());
+            assert!(receiver.receive_message().is_none());
+        }
+    }
+
+    #[test]
+    fn test_unreliable() {
+        let mut sender = MessageChannel::new_unreliable();
+        let mut receiver = MessageChannel::new_unreliable();
+        sender.queue_message(&vec![7; 2 * MESSAGE_FRAGMENT_SIZE]).unwrap();
+        sender.queue_message(&[1]).unwrap();
+        sender.send_packets(1).unwrap();
+        assert!(sender.has_unsend_messages());
+        let packet = sender.send_packets(2).unwrap();
+        assert_eq!(packet[0], 0);
+        receiver.on_receive(packet).unwrap();
+        assert!(!sender.has_unsend_messages());
+        assert_eq!(sender.send_packets(3).unwrap(), [0]);
+
+        // the first packet with the other fragment and the small message never arrived
+        assert!(receiver.receive_message().is_none());
+        sender.queue_message(&[2]).unwrap();
+        receiver.on_receive(sender.send_packets(4).unwrap()).unwrap();
+        assert_eq!(receiver.receive_message().unwrap().as_ref(), [2]);
+        assert!(receiver.receive_message().is_none());
+    }
+
+    #[test]
+    fn test_packet_budget() {
+        let mut state = 0x2545F491u32;
+        let mut random = move |max: u32| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state % max) as usize
+        };
+        for budget in [100, 300, 960, 1400] {
+            let mut sender = MessageChannel::new().with_packet_budget(budget).unwrap();
+            let mut receiver = MessageChannel::new();
+            let mut queued = Vec::new();
+            let mut received = Vec::new();
+            for seq in 0..400 {
+                if sender.lanes[NORMAL].outgoing_messages.len() < 32 {
+                    let msg = vec![seq as u8; random(1500)];
+                    match sender.queue_message(&msg) {
+                        Ok(()) => queued.push(msg),
+                        Err(_) => assert!(msg.len().min(MESSAGE_FRAGMENT_SIZE) + 22 > budget)
+                    }
+                }
+                let packet = sender.send_packets(seq).unwrap();
+                assert!(packet.len() <= budget, "{} > {}", packet.len(), budget);
+                receiver.on_receive(packet).unwrap();
+                receiver.receive_into(&mut received);
+                sender.on_ack(seq);
+            }
+            for seq in 400..1000 {
+                if !sender.has_unsend_messages() {
+                    break;
+                }
+                receiver.on_receive(sender.send_packets(seq).unwrap()).unwrap();
+                sender.on_ack(seq);
+            }
+            assert!(!sender.has_unsend_messages());
+            receiver.receive_into(&mut received);
+            assert_eq!(received.len(), queued.len());
+            for (received, queued) in received.iter().zip(&queued) {
+                assert_eq!(received.as_ref(), queued.as_slice());
             }
         }
     }
 
-    fn index(&self, sequence: SequenceNumber) -> usize {
-        sequence as usize % self.entry_sequences.len()
+    #[test]
+    fn test_tiny_messages() {
+        let mut sender = MessageChannel::new();
+        let mut receiver = MessageChannel::new();
+        for _ in 0..200 {
+            sender.queue_message(&[]).unwrap();
+        }
+        let packet = sender.send_packets(1).unwrap();
+        assert_eq!(packet.len(), 2 + 200 * 2);
+        assert_eq!(packet[..2], [0xC8, 0x01]);
+        receiver.on_receive(packet).unwrap();
+        sender.on_ack(1);
+        assert!(!sender.has_unsend_messages());
+        assert_eq!(receiver.receive_messages().count(), 200);
+    }
+
+    #[test]
+    fn test_resend_interval() {
+        let mut sender = MessageChannel::new().with_resend_interval(Duration::from_millis(30));
+        sender.queue_message(&[1]).unwrap();
+        assert!(sender.has_due_messages());
+        assert_eq!(sender.send_packets(1).unwrap(), [1, 1, 1, 1]);
+        assert!(!sender.has_due_messages());
+        assert_eq!(sender.send_packets(2).unwrap(), [0]);
+
+        // fresh messages do not wait for the older ones
+        sender.queue_message(&[2]).unwrap();
+        assert_eq!(sender.send_packets(3).unwrap(), [1, 2, 1, 2]);
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(sender.has_due_messages());
+        assert_eq!(sender.send_packets(4).unwrap(), [2, 1, 1, 1, 1, 1, 2]);
+        sender.on_ack(1);
+        sender.on_ack(3);
+        assert!(!sender.has_unsend_messages());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_on_loss() {
+        let mut sender = MessageChannel::new().with_resend_interval(Duration::from_secs(10));
+        sender.queue_message(&[1]).unwrap();
+        sender.send_packets(1).unwrap();
+        sender.queue_message(&[2]).unwrap();
+        sender.send_packets(2).unwrap();
+        assert!(!sender.has_due_messages());
+
+        sender.on_loss(1);
+        assert!(sender.has_due_messages());
+        assert_eq!(sender.send_packets(3).unwrap(), [1, 1, 1, 1]);
+        sender.on_loss(3);
+        sender.on_loss(2);
+        assert_eq!(sender.send_packets(4).unwrap(), [2, 1, 1, 1, 1, 1, 2]);
+
+        sender.on_ack(4);
+        sender.on_loss(4);
+        assert!(!sender.has_unsend_messages());
+
+        // the message is still in flight in a later packet
+        let mut sender = MessageChannel::new().with_resend_interval(Duration::ZERO);
+        sender.queue_message(&[1]).unwrap();
+        sender.send_packets(1).unwrap();
+        sender.send_packets(2).unwrap();
+        sender.on_loss(1);
+        assert!(matches!(sender.lanes[NORMAL].outgoing_messages.get(1).unwrap().fragments[0], FragmentState::Sent(_)));
+        sender.on_loss(2);
+        assert_eq!(sender.lanes[NORMAL].outgoing_messages.get(1).unwrap().fragments[0], FragmentState::Lost);
+    }
+
+    #[test]
+    fn test_priorities() {
+        let mut sender = MessageChannel::new().with_packet_budget(200).unwrap();
+        let mut receiver = MessageChannel::new();
+        for i in 0..40u8 {
+            sender.queue_message_with_priority(&[i; 20], Priority::Low).unwrap();
+        }
+        sender.queue_message(&[100; 20]).unwrap();
+        sender.queue_message_with_priority(&[200; 20], Priority::High).unwrap();
+        sender.queue_message_with_priority(&[201; 20], Priority::High).unwrap();
+
+        let packet = sender.send_packets(1).unwrap();
+        assert!(packet.len() <= 200);
+        receiver.on_receive(packet).unwrap();
+        let received: Vec<u8> = receiver.receive_messages().map(|msg| msg[0]).collect();
+        // the low priority messages still get their share of the packet
+        assert_eq!(received[..3], [200, 201, 100]);
+        assert_eq!(received[3..], (0..received.len() as u8 - 3).collect::<Vec<_>>());
+        sender.on_ack(1);
+
+        for seq in 2..20 {
+            receiver.on_receive(sender.send_packets(seq).unwrap()).unwrap();
+            sender.on_ack(seq);
+        }
+        assert!(!sender.has_unsend_messages());
+        let received: Vec<u8> = receiver.receive_messages().map(|msg| msg[0]).collect();
+        assert_eq!(received.first(), Some(&(40 - received.len() as u8)));
+        assert!(received.windows(2).all(|pair| pair[0] + 1 == pair[1]));
+    }
+
+    #[test]
+    fn test_priority_ordering() {
+        let mut sender = MessageChannel::new();
+        let mut receiver = MessageChannel::new();
+        sender.queue_message_with_priority(&[1], Priority::Low).unwrap();
+        sender.queue_message_with_priority(&[2], Priority::High).unwrap();
+        let packet = sender.send_packets(1).unwrap();
+        assert_eq!(packet, [0, 0, 0x00, 1, 1, 1, 2, 0, 0x02, 1, 1, 1, 1, 0]);
+        receiver.on_receive(packet).unwrap();
+        assert_eq!(receiver.receive_message().unwrap().as_ref(), [2]);
+        assert_eq!(receiver.receive_message().unwrap().as_ref(), [1]);
+
+        // the lost low priority message does not hold back the others
+        sender.queue_message_with_priority(&[3], Priority::Low).unwrap();
+        sender.send_packets(2).unwrap();
+        sender.queue_message_with_priority(&[4], Priority::High).unwrap();
+        sender.queue_message(&[5]).unwrap();
+        receiver.on_receive(sender.send_packets(3).unwrap()).unwrap();
+        assert_eq!(receiver.receive_message().unwrap().as_ref(), [4]);
+        assert_eq!(receiver.receive_message().unwrap().as_ref(), [5]);
+        assert!(receiver.receive_message().is_none());
+
+        assert!(receiver.on_receive(&[0, 0, 0x03, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_priority_starvation() {
+        let mut sender = MessageChannel::new().with_resend_interval(Duration::ZERO);
+        let mut receiver = MessageChannel::new();
+        let large = vec![7; 4 * MESSAGE_FRAGMENT_SIZE];
+        sender.queue_message_with_priority(&large, Priority::Low).unwrap();
+        for seq in 0..40 {
+            // more high priority messages than fit into a packet
+            while sender.lanes[Priority::High as usize].outgoing_messages.len() < 16 {
+                sender.queue_message_with_priority(&[1; 100], Priority::High).unwrap();
+            }
+            let packet = sender.send_packets(seq).unwrap();
+            assert!(packet.len() <= MESSAGE_PACKET_BUDGET);
+            receiver.on_receive(packet).unwrap();
+            sender.on_ack(seq);
+            while let Some(msg) = receiver.receive_message() {
+                if msg.len() == large.len() {
+                    assert!(seq >= 8);
+                    return;
+                }
+            }
+        }
+        panic!("the low priority message never arrived");
+    }
+
+    #[test]
+    fn test_receive_messages() {
+        let mut sender = MessageChannel::new();
+        let mut ordered = MessageChannel::new_ordered();
+        let mut unordered = MessageChannel::new_unordered();
+        let mut packets = Vec::new();
+        for (seq, msg) in [1u8, 2, 3, 4].into_iter().enumerate() {
+            sender.queue_message(&[msg]).unwrap();
+            packets.push(sender.send_packets(seq as SequenceNumber).unwrap().to_vec());
+        }
+        for packet in packets.iter().filter(|packet| packet[3] != 2) {
+            ordered.on_receive(packet).unwrap();
+            unordered.on_receive(packet).unwrap();
+        }
+        assert!(ordered.pending_incoming());
+        assert!(unordered.pending_incoming());
+
+        // the ordered channel stops at the missing message
+        let mut received = Vec::new();
+        assert_eq!(ordered.receive_into(&mut received), 1);
+        assert_eq!(received, [vec![1].into_boxed_slice()]);
+        assert!(!ordered.pending_incoming());
+        assert_eq!(ordered.receive_into(&mut received), 0);
+        assert_eq!(unordered.receive_messages().map(|msg| msg[0]).collect::<Vec<_>>(), [1, 3, 4]);
+        assert!(!unordered.pending_incoming());
+
+        ordered.on_receive(&packets[1]).unwrap();
+        assert!(ordered.pending_incoming());
+        assert_eq!(ordered.receive_messages().map(|msg| msg[0]).collect::<Vec<_>>(), [2, 3, 4]);
+        assert!(ordered.receive_message().is_none());
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut sender = MessageChannel::new().with_resend_interval(Duration::ZERO);
+        let mut receiver = MessageChannel::new();
+        assert_eq!(sender.stats(), MessageChannelStats::default());
+        for msg in [1, 2, 3] {
+            sender.queue_message(&[msg]).unwrap();
+        }
+        sender.queue_message(&vec![4; 2 * MESSAGE_FRAGMENT_SIZE]).unwrap();
+        let lost = sender.send_packets(1).unwrap().to_vec();
+        let packet = sender.send_packets(2).unwrap().to_vec();
+        std::thread::sleep(Duration::from_millis(5));
+        let stats = sender.stats();
+        assert_eq!(stats.messages_queued, 4);
+        assert_eq!(stats.messages_in_flight, 4);
+        assert_eq!(stats.retransmissions, 3);
+        assert!(stats.oldest_unacked_age >= Duration::from_millis(5));
+
+        sender.on_ack(2);
+        let stats = sender.stats();
+        assert_eq!(stats.messages_acknowledged, 3);
+        assert_eq!(stats.messages_in_flight, 1);
+        sender.on_ack(1);
+        let stats = sender.stats();
+        assert_eq!(stats.messages_acknowledged, 4);
+        assert_eq!(stats.messages_in_flight, 0);
+        assert_eq!(stats.oldest_unacked_age, Duration::ZERO);
+
+        // the first packet carries the first fragment of the large message, the second one the other
+        receiver.on_receive(&packet).unwrap();
+        assert_eq!(receiver.stats().messages_received, 3);
+        assert_eq!(receiver.receive_messages().count(), 3);
+        assert_eq!(receiver.stats().incoming_buffered, 1);
+        receiver.on_receive(&lost).unwrap();
+        receiver.on_receive(&lost).unwrap();
+        let stats = receiver.stats();
+        assert_eq!(stats.messages_received, 4);
+        assert_eq!(stats.messages_delivered, 3);
+        assert_eq!(stats.incoming_buffered, 1);
+        assert!(receiver.receive_message().is_some());
+        assert_eq!(receiver.stats().incoming_buffered, 0);
+        receiver.reset_stats();
+        assert_eq!(receiver.stats(), MessageChannelStats::default());
+    }
+
+    #[test]
+    fn test_config() {
+        let config = MessageChannelConfig::default();
+        let channel = MessageChannel::with_config(config).unwrap();
+        assert_eq!(channel.config(), &config);
+        assert_eq!(channel.lanes[NORMAL].outgoing_messages.capacity(), 256);
+        assert_eq!(channel.mode(), ChannelMode::Ordered);
+        for invalid in [
+            config.with_outgoing_capacity(100),
+            config.with_incoming_capacity(0),
+            config.with_incoming_capacity(1 << 16),
+            config.with_packet_budget(10),
+            config.with_packet_budget(MAX_PACKET_SIZE)
+        ] {
+            assert!(matches!(MessageChannel::with_config(invalid), Err(ConnectionError::InvalidConfig(_))));
+        }
+
+        let config = config
+            .with_mode(ChannelMode::Unordered)
+            .with_outgoing_capacity(4)
+            .with_resend_interval(Duration::ZERO);
+        let mut sender = MessageChannel::with_config(config).unwrap();
+        let mut receiver = MessageChannel::with_config(config.with_incoming_capacity(2)).unwrap();
+        assert_eq!(sender.resend_interval(), Duration::ZERO);
+        for msg in 0..4 {
+            sender.queue_message(&[msg]).unwrap();
+        }
+        assert!(sender.queue_message(&[4]).is_err());
+        // every priority has its own buffer
+        sender.queue_message_with_priority(&[5], Priority::Low).unwrap();
+
+        receiver.on_receive(sender.send_packets(1).unwrap()).unwrap();
+        assert_eq!(receiver.receive_messages().map(|msg| msg[0]).collect::<Vec<_>>(), [0, 1, 2, 3, 5]);
+        // the receiver only remembers the two newest messages, duplicates of older ones are dropped as well
+        receiver.on_receive(sender.send_packets(2).unwrap()).unwrap();
+        assert!(!receiver.pending_incoming());
+        assert_eq!(receiver.stats().messages_received, 5);
+        sender.on_ack(2);
+        sender.queue_message(&[4]).unwrap();
+    }
+
+    #[test]
+    fn test_owned_messages() {
+        let mut sender = MessageChannel::new();
+        let mut receiver = MessageChannel::new();
+        let msg = vec![1, 2, 3];
+        let ptr = msg.as_ptr();
+        sender.queue_message_owned(msg).unwrap();
+        assert_eq!(sender.lanes[NORMAL].outgoing_messages.get(1).unwrap().data.as_ptr(), ptr);
+
+        // received messages can be sent back without copying them either
+        receiver.on_receive(sender.send_packets(1).unwrap()).unwrap();
+        let msg = receiver.receive_message().unwrap();
+        let ptr = msg.as_ptr();
+        receiver.queue_message_owned_with_priority(msg, Priority::High).unwrap();
+        assert_eq!(receiver.lanes[Priority::High as usize].outgoing_messages.get(1).unwrap().data.as_ptr(), ptr);
+
+        assert!(sender.queue_message_owned(vec![0; MAX_MESSAGE_SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn test_send_packets_into() {
+        let mut sender = MessageChannel::new().with_resend_interval(Duration::ZERO);
+        let mut receiver = MessageChannel::new();
+        let sizes = [0, 1, 127, 128, 511, 512, 513, 1023, 1024, 1025, 16383, 16384, 16385];
+        let mut queued = Vec::new();
+        for (i, &len) in sizes.iter().enumerate() {
+            let msg = vec![i as u8; len];
+            let priority = Priority::ALL[i % 3];
+            sender.queue_message_with_priority(&msg, priority).unwrap();
+            queued.push((priority, msg));
+        }
+
+        // the buffer is smaller than the budget and often too small for a whole fragment
+        let mut out = [0; 1000];
+        for seq in 0..2000 {
+            if !sender.has_unsend_messages() {
+                break;
+            }
+            let capacity = [0, 1, 2, 25, 100, 530, 540, 600, 1000][seq % 9];
+            let result = sender.send_packets_into(seq as SequenceNumber, &mut out[..capacity]);
+            if capacity == 0 {
+                assert!(result.is_err());
+                continue;
+            }
+            let len = result.unwrap();
+            assert!(len <= capacity);
+            receiver.on_receive(&out[..len]).unwrap();
+            sender.on_ack(seq as SequenceNumber);
+        }
+        assert!(!sender.has_unsend_messages());
+        queued.sort_by_key(|(priority, _)| *priority);
+        let received: Vec<_> = receiver.receive_messages().collect();
+        assert_eq!(received.len(), queued.len());
+        for (received, (_, queued)) in received.iter().zip(&queued) {
+            assert_eq!(received.as_ref(), queued.as_slice());
+        }
+
+        // budgets beyond the largest possible payload are rejected
+        assert!(matches!(MessageChannel::new().with_packet_budget(100_000), Err(ConnectionError::InvalidConfig(_))));
+        let mut sender = MessageChannel::new().with_packet_budget(MAX_PACKET_SIZE - MAX_PAYLOAD_OVERHEAD).unwrap();
+        for _ in 0..10 {
+            sender.queue_message(&[7; MESSAGE_FRAGMENT_SIZE]).unwrap();
+        }
+        assert!(sender.send_packets(1).unwrap().len() <= MAX_PACKET_SIZE - MAX_PAYLOAD_OVERHEAD);
+    }
+
+    #[test]
+    fn test_queue_full() {
+        let config = MessageChannelConfig::default().with_outgoing_capacity(4);
+        let mut sender = MessageChannel::with_config(config).unwrap();
+        assert_eq!(sender.outgoing_free_slots(), 4);
+        for msg in 1..=4 {
+            sender.queue_message(&[msg]).unwrap();
+        }
+        assert_eq!(sender.outgoing_free_slots(), 0);
+        assert_eq!(sender.outgoing_free_slots_with_priority(Priority::High), 4);
+        let returned = match sender.queue_message_owned(vec![5, 6]) {
+            Err(ChannelError::QueueFull { returned }) => returned,
+            other => panic!("unexpected result: {:?}", other)
+        };
+        assert_eq!(returned.as_ref(), [5, 6]);
+
+        // the slots of acknowledged messages only become free once the older ones are acknowledged as well
+        for (seq, (_, msg)) in sender.lanes[NORMAL].outgoing_messages.iter_mut().enumerate() {
+            msg.sequence_number.push((seq as SequenceNumber, 0));
+        }
+        sender.on_ack(1);
+        assert_eq!(sender.outgoing_free_slots(), 0);
+        assert!(matches!(sender.queue_message(&returned), Err(ChannelError::QueueFull { .. })));
+        sender.on_ack(0);
+        assert_eq!(sender.outgoing_free_slots(), 2);
+        sender.queue_message_owned(returned).unwrap();
+        assert_eq!(sender.outgoing_free_slots(), 1);
+
+        let mut sender = MessageChannel::new().with_max_message_size(2000).unwrap().with_packet_budget(100).unwrap();
+        assert!(matches!(sender.queue_message(&[0; 2001]), Err(ChannelError::MessageTooLarge { len: 2001, max: 78 })));
+        assert!(matches!(sender.queue_message_with_priority(&[0; 78], Priority::Low), Err(ChannelError::MessageTooLarge { len: 78, max: 75 })));
+        sender.queue_message(&[0; 78]).unwrap();
+        let mut sender = MessageChannel::new().with_max_message_size(2000).unwrap();
+        assert!(matches!(sender.queue_message(&[0; 2001]), Err(ChannelError::MessageTooLarge { len: 2001, max: 2000 })));
+        sender.queue_message(&[0; 2000]).unwrap();
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut sender = MessageChannel::new();
+        let mut receiver = MessageChannel::new();
+        sender.queue_message(&[1]).unwrap();
+        sender.queue_message(&[2]).unwrap();
+        receiver.on_receive(sender.send_packets(0).unwrap()).unwrap();
+        sender.on_ack(0);
+        assert_eq!(receiver.receive_message().as_deref(), Some([1].as_slice()));
+        sender.queue_message(&[3]).unwrap();
+        sender.queue_message_with_priority(&[4], Priority::High).unwrap();
+        sender.send_packets(1).unwrap();
+
+        // the packet with the last two messages is lost along with the connection
+        assert_eq!(sender.reset(), [(Priority::High, Box::from([4])), (Priority::Normal, Box::from([3]))]);
+        assert!(receiver.reset().is_empty());
+        assert!(!sender.has_unsend_messages());
+        assert!(!receiver.pending_incoming());
+        assert_eq!(sender.stats().messages_acknowledged, 2);
+        assert_eq!(sender.reset(), []);
+
+        // the new connection starts over with its sequence numbers and the message ids
+        sender.queue_message(&[3]).unwrap();
+        receiver.on_receive(sender.send_packets(0).unwrap()).unwrap();
+        assert_eq!(receiver.receive_message().as_deref(), Some([3].as_slice()));
+        sender.on_ack(1);
+        assert!(sender.has_unsend_messages());
+        sender.on_ack(0);
+        assert!(!sender.has_unsend_messages());
+    }
+
+    #[test]
+    fn test_sequenced_channel() {
+        let mut sender = SequencedChannel::new();
+        let mut receiver = SequencedChannel::new();
+        assert!(!sender.has_pending());
+        sender.set(1, &[1]).unwrap();
+        sender.set(2, &[2]).unwrap();
+        sender.set(1, &[3]).unwrap();
+        assert!(sender.has_pending());
+        let first: Box<[u8]> = sender.send_packets().unwrap().into();
+        assert!(!sender.has_pending());
+        assert_eq!(sender.send_packets().unwrap(), [0]);
+        sender.set(1, &[4]).unwrap();
+        let second: Box<[u8]> = sender.send_packets().unwrap().into();
+
+        // the older packet arrives last, its value for slot 1 is stale
+        receiver.on_receive(&second).unwrap();
+        receiver.on_receive(&first).unwrap();
+        receiver.on_receive(&[]).unwrap();
+        assert_eq!(receiver.latest(0), None);
+        assert_eq!(receiver.latest(1), Some([4].as_slice()));
+        assert_eq!(receiver.latest(2), Some([2].as_slice()));
+        let changes: Vec<(u8, Vec<u8>)> = receiver.changes().map(|(slot, data)| (slot, data.to_vec())).collect();
+        assert_eq!(changes, [(1, vec![4]), (2, vec![2])]);
+        assert_eq!(receiver.changes().count(), 0);
+        receiver.on_receive(&second).unwrap();
+        assert_eq!(receiver.changes().count(), 0);
+        assert!(receiver.on_receive(&second[..second.len() - 1]).is_err());
+
+        // values that do not fit wait for the next packet, which starts with them
+        let mut sender = SequencedChannel::new().with_packet_budget(100);
+        let mut receiver = SequencedChannel::new();
+        assert_eq!(sender.max_value_size(), 87);
+        assert!(matches!(sender.set(0, &[0; 88]), Err(ChannelError::MessageTooLarge { len: 88, max: 87 })));
+        sender.set(0, &[0; 60]).unwrap();
+        sender.set(1, &[1; 60]).unwrap();
+        sender.set(2, &[2; 10]).unwrap();
+        let packet = sender.send_packets().unwrap();
+        assert!(packet.len() <= 100);
+        receiver.on_receive(packet).unwrap();
+        assert_eq!(receiver.changes().map(|(slot, _)| slot).collect::<Vec<_>>(), [0, 2]);
+        sender.set(0, &[3; 60]).unwrap();
+        receiver.on_receive(sender.send_packets().unwrap()).unwrap();
+        assert_eq!(receiver.changes().map(|(slot, _)| slot).collect::<Vec<_>>(), [1]);
+        receiver.on_receive(sender.send_packets().unwrap()).unwrap();
+        assert_eq!(receiver.latest(0), Some([3; 60].as_slice()));
+        assert!(!sender.has_pending());
+    }
+
+}
@@ -1,35 +1,78 @@
 use std::fmt::{Debug, Formatter};
 
+/// Identifies a packet of a connection. It wraps around, so use [`sequence_greater_than`] and
+/// [`sequence_less_than`] instead of the regular comparison operators to order them.
+#[cfg(not(feature = "wide_sequence_numbers"))]
 pub type SequenceNumber = u16;
+/// Identifies a packet of a connection. It wraps around, so use [`sequence_greater_than`] and
+/// [`sequence_less_than`] instead of the regular comparison operators to order them.
+#[cfg(feature = "wide_sequence_numbers")]
+pub type SequenceNumber = u32;
 
+/// A ring buffer that hands out consecutive sequence numbers and keeps the entries of the last `capacity` of them.
+/// Entries can be removed in any order, the window only advances past the oldest entry.
 #[derive(Clone)]
-pub struct SequenceBuffer<T: Clone> {
+pub struct SequenceBuffer<T> {
     newest_sequence_number: SequenceNumber,
-    len: usize,
+    /// The size of the window from the oldest entry to `newest_sequence_number`, including holes
+    span: usize,
+    /// The number of entries that are actually present
+    count: usize,
     entries: Box<[Option<T>]>
 }
 
-impl<T: Clone> Debug for SequenceBuffer<T> {
+impl<T> Debug for SequenceBuffer<T> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_set().entries(self.iter().map(|(i, _)| i)).finish()
     }
 }
 
-impl<T: Clone> SequenceBuffer<T> {
+impl<T> SequenceBuffer<T> {
 
     pub fn with_capacity(size: usize) -> Self {
         Self {
             newest_sequence_number: 0,
-            len: 0,
-            entries: vec![None; size].into_boxed_slice()
+            span: 0,
+            count: 0,
+            entries: std::iter::repeat_with(|| None).take(size).collect()
         }
     }
 
+    /// Stores `data` under the next sequence number. Once the buffer is full this evicts the oldest entry, which is returned.
     pub fn insert(&mut self, data: T) -> (SequenceNumber, Option<T>) {
         let prev = self.remove_at(self.index(self.next_sequence_number()));
         (self.try_insert(data).expect("This should never fail"), prev)
     }
 
+    /// Stores `data` under the given sequence number, which may lie anywhere in or ahead of the window.
+    /// Jumping ahead evicts the entries that no longer fit. Returns the entry that was stored under the same sequence number.
+    pub fn insert_at(&mut self, sequence: SequenceNumber, data: T) -> Result<Option<T>, TooOld> {
+        if sequence_greater_than(sequence, self.newest_sequence_number) {
+            let oldest_kept = sequence.wrapping_sub(self.capacity() as SequenceNumber).wrapping_add(1);
+            self.drain_older(oldest_kept).for_each(drop);
+            let oldest = self.oldest_sequence_number();
+            self.newest_sequence_number = sequence;
+            self.span = match self.span {
+                0 => 1,
+                _ => sequence.wrapping_sub(oldest) as usize + 1
+            };
+        } else {
+            let offset = self.newest_sequence_number.wrapping_sub(sequence) as usize;
+            if offset >= self.capacity() {
+                return Err(TooOld);
+            }
+            self.span = self.span.max(offset + 1);
+        }
+        let index = self.index(sequence);
+        let prev = self.entries[index].replace(data);
+        if prev.is_none() {
+            self.count += 1;
+        }
+        debug_assert!(self.span <= self.entries.len());
+        Ok(prev)
+    }
+
+    /// Like [`insert`](Self::insert), but gives up instead of evicting an entry
     pub fn try_insert(&mut self, data: T) -> Option<SequenceNumber> {
         let sequence_number = self.next_sequence_number();
         let index = self.index(sequence_number);
@@ -37,8 +80,9 @@ impl<T: Clone> SequenceBuffer<T> {
             ref mut entry @ None => {
                 self.newest_sequence_number = sequence_number;
                 *entry = Some(data);
-                self.len += 1;
-                debug_assert!(self.len <= self.entries.len());
+                self.span += 1;
+                self.count += 1;
+                debug_assert!(self.span <= self.entries.len());
                 Some(sequence_number)
             }
             Some(_) => None
@@ -46,54 +90,135 @@ impl<T: Clone> SequenceBuffer<T> {
     }
 
     pub fn remove(&mut self, sequence: SequenceNumber) -> Option<T> {
-        if sequence_greater_than(sequence, self.newest_sequence_number)
-            || sequence_less_than(sequence, self.oldest_sequence_number()) {
-            None
-        } else {
-            self.remove_at(self.index(sequence))
+        match self.in_window(sequence) {
+            true => self.remove_at(self.index(sequence)),
+            false => None
+        }
+    }
+
+    pub fn get(&self, sequence: SequenceNumber) -> Option<&T> {
+        match self.in_window(sequence) {
+            true => self.entries[self.index(sequence)].as_ref(),
+            false => None
         }
+    }
+
+    pub fn get_mut(&mut self, sequence: SequenceNumber) -> Option<&mut T> {
+        match self.in_window(sequence) {
+            true => self.entries[self.index(sequence)].as_mut(),
+            false => None
+        }
+    }
+
+    pub fn contains(&self, sequence: SequenceNumber) -> bool {
+        self.get(sequence).is_some()
+    }
+
+    /// Removes all entries. The sequence numbers continue where they left off.
+    pub fn clear(&mut self) {
+        self.entries.iter_mut().for_each(|entry| *entry = None);
+        self.span = 0;
+        self.count = 0;
+    }
+
+    /// Removes all entries and starts the sequence numbers over, as if the buffer was new
+    pub fn reset(&mut self) {
+        self.clear();
+        self.newest_sequence_number = 0;
+    }
 
+    /// Whether `sequence` lies between the oldest entry and the newest sequence number handed out
+    fn in_window(&self, sequence: SequenceNumber) -> bool {
+        self.span > 0
+            && !sequence_greater_than(sequence, self.newest_sequence_number)
+            && !sequence_less_than(sequence, self.oldest_sequence_number())
     }
 
     fn remove_at(&mut self, index: usize) -> Option<T> {
         let old = self.entries[index].take();
-        while self.entries[self.index(self.oldest_sequence_number())].is_none() && self.len > 0 {
-            self.len -= 1;
+        if old.is_some() {
+            self.count -= 1;
+        }
+        while self.entries[self.index(self.oldest_sequence_number())].is_none() && self.span > 0 {
+            self.span -= 1;
         }
         old
     }
 
     fn oldest_sequence_number(&self) -> SequenceNumber {
         self.newest_sequence_number
-            .wrapping_sub(self.len as SequenceNumber)
+            .wrapping_sub(self.span as SequenceNumber)
             .wrapping_add(1)
     }
 
+    /// The sequence number of the oldest entry
+    pub fn oldest(&self) -> Option<SequenceNumber> {
+        self.iter().next().map(|(seq, _)| seq)
+    }
+
+    /// The sequence number of the newest entry, which is not necessarily the last one that was handed out
+    pub fn newest(&self) -> Option<SequenceNumber> {
+        (0..self.span)
+            .map(|i| self.newest_sequence_number.wrapping_sub(i as SequenceNumber))
+            .find(|seq| self.entries[self.index(*seq)].is_some())
+    }
+
     pub fn is_empty(&self) -> bool {
-        self.len == 0
+        self.count == 0
     }
 
-    pub fn iter_mut(&mut self) -> SequenceBufferIterMut<T> {
+    /// The number of entries
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Iterates over the entries from oldest to newest
+    pub fn iter_mut(&mut self) -> SequenceBufferIterMut<'_, T> {
         SequenceBufferIterMut {
+            remaining: self.count,
             inner: self,
             index: 0
         }
     }
 
-    pub fn iter(&self) -> SequenceBufferIter<T> {
+    /// Iterates over the entries from oldest to newest
+    pub fn iter(&self) -> SequenceBufferIter<'_, T> {
         SequenceBufferIter {
             inner: self,
-            index: 0
+            index: 0,
+            remaining: self.count
         }
     }
 
-    pub fn drain_older(&mut self, target: SequenceNumber) -> SequenceBufferDrain<T> {
+    /// Removes and returns the entries older than `target`, from oldest to newest
+    pub fn drain_older(&mut self, target: SequenceNumber) -> SequenceBufferDrain<'_, T> {
         SequenceBufferDrain {
             inner: self,
             target
         }
     }
     
+    /// Removes and returns the entries for which `filter` returns true, from oldest to newest.
+    /// Entries that are not reached because the iterator is dropped early are kept.
+    pub fn drain_filter<F>(&mut self, filter: F) -> SequenceBufferDrainFilter<'_, T, F> where F: FnMut(SequenceNumber, &mut T) -> bool {
+        SequenceBufferDrainFilter {
+            next: self.oldest_sequence_number(),
+            remaining: self.span,
+            inner: self,
+            filter
+        }
+    }
+
+    /// Keeps only the entries for which `keep` returns true
+    pub fn retain<F>(&mut self, mut keep: F) where F: FnMut(SequenceNumber, &mut T) -> bool {
+        self.drain_filter(|seq, data| !keep(seq, data)).for_each(drop);
+    }
+
+    /// The sequence number the next inserted entry will get
     pub fn next_sequence_number(&self) -> SequenceNumber {
         self.newest_sequence_number.wrapping_add(1)
     }
@@ -104,17 +229,21 @@ impl<T: Clone> SequenceBuffer<T> {
 
 }
 
-pub struct SequenceBufferDrain<'a, T: Clone> {
+/// The sequence number passed to [`SequenceBuffer::insert_at`] lies too far behind the window
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TooOld;
+
+pub struct SequenceBufferDrain<'a, T> {
     inner: &'a mut SequenceBuffer<T>,
     target: SequenceNumber
 }
 
-impl <'a, T: Clone> Iterator for SequenceBufferDrain<'a, T> {
+impl <'a, T> Iterator for SequenceBufferDrain<'a, T> {
     type Item = (SequenceNumber, T);
 
     fn next(&mut self) -> Option<Self::Item> {
         let oldest = self.inner.oldest_sequence_number();
-        if sequence_less_than(oldest, self.target) {
+        if !self.inner.is_empty() && sequence_less_than(oldest, self.target) {
             let index = self.inner.index(oldest);
             Some((oldest, self.inner.remove_at(index).unwrap()))
         } else {
@@ -123,42 +252,84 @@ impl <'a, T: Clone> Iterator for SequenceBufferDrain<'a, T> {
     }
 }
 
-pub struct SequenceBufferIter<'a, T: Clone> {
+pub struct SequenceBufferDrainFilter<'a, T, F> where F: FnMut(SequenceNumber, &mut T) -> bool {
+    inner: &'a mut SequenceBuffer<T>,
+    next: SequenceNumber,
+    remaining: usize,
+    filter: F
+}
+
+impl<'a, T, F> Iterator for SequenceBufferDrainFilter<'a, T, F> where F: FnMut(SequenceNumber, &mut T) -> bool {
+    type Item = (SequenceNumber, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // removing entries only ever moves the oldest sequence number forward, so walking by sequence number stays valid
+        while self.remaining > 0 {
+            let seq = self.next;
+            self.next = seq.wrapping_add(1);
+            self.remaining -= 1;
+            let index = self.inner.index(seq);
+            if let Some(data) = self.inner.entries[index].as_mut() {
+                if (self.filter)(seq, data) {
+                    return self.inner.remove_at(index).map(|data| (seq, data));
+                }
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.remaining.min(self.inner.count)))
+    }
+}
+
+pub struct SequenceBufferIter<'a, T> {
     inner: &'a SequenceBuffer<T>,
-    index: usize
+    index: usize,
+    remaining: usize
 }
 
-impl<'a, T: Clone> Iterator for SequenceBufferIter<'a, T> {
+impl<'a, T> Iterator for SequenceBufferIter<'a, T> {
     type Item = (SequenceNumber, &'a T);
 
     fn next(&mut self) -> Option<Self::Item> {
         let b = self.inner;
-        while self.index < b.len {
+        while self.index < b.span {
             self.index += 1;
-            let seq = b.newest_sequence_number.wrapping_sub((b.len - self.index) as SequenceNumber);
+            let seq = b.newest_sequence_number.wrapping_sub((b.span - self.index) as SequenceNumber);
             unsafe {
                 match b.entries.get_unchecked(b.index(seq)) {
                     None => continue,
-                    Some(data) => return Some((seq, data))
+                    Some(data) => {
+                        self.remaining -= 1;
+                        return Some((seq, data))
+                    }
                 }
             }
         }
         None
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
 }
 
-pub struct SequenceBufferIterMut<'a, T: 'a + Clone> {
+impl<'a, T> ExactSizeIterator for SequenceBufferIter<'a, T> {}
+
+pub struct SequenceBufferIterMut<'a, T: 'a> {
     inner: &'a mut SequenceBuffer<T>,
-    index: usize
+    index: usize,
+    remaining: usize
 }
 
-impl<'a, T: Clone + 'a> Iterator for SequenceBufferIterMut<'a, T>{
+impl<'a, T: 'a> Iterator for SequenceBufferIterMut<'a, T>{
     type Item = (SequenceNumber, &'a mut T);
 
     fn next(&mut self) -> Option<Self::Item> {
-        while self.index < self.inner.len {
+        while self.index < self.inner.span {
             self.index += 1;
-            let seq = self.inner.newest_sequence_number.wrapping_sub((self.inner.len - self.index) as SequenceNumber);
+            let seq = self.inner.newest_sequence_number.wrapping_sub((self.inner.span - self.index) as SequenceNumber);
             let index = self.inner.index(seq);
             unsafe {
                 let elem = self.inner.entries.get_unchecked_mut(index);
@@ -166,55 +337,138 @@ impl<'a, T: Clone + 'a> Iterator for SequenceBufferIterMut<'a, T>{
                 // the std stuff does this too, but afaik this breaks the borrow checker
                 match elem {
                     None => continue,
-                    Some(data) => return Some((seq, &mut *(data as *mut T)))
+                    Some(data) => {
+                        self.remaining -= 1;
+                        return Some((seq, &mut *(data as *mut T)))
+                    }
                 }
             }
 
         }
         None
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
 }
 
+impl<'a, T: 'a> ExactSizeIterator for SequenceBufferIterMut<'a, T> {}
+
+/// Whether `s1` is newer than `s2`, treating sequence numbers that are more than half the range apart as wrapped around
 pub fn sequence_greater_than(s1: SequenceNumber, s2: SequenceNumber) -> bool {
     const HALF: SequenceNumber = SequenceNumber::MAX / 2;
     ((s1 > s2) && (s1 - s2 <= HALF)) || ((s1 < s2) && (s2 - s1 > HALF))
 }
 
+/// Whether `s1` is older than `s2`, see [`sequence_greater_than`]
 pub fn sequence_less_than(s1: SequenceNumber, s2: SequenceNumber) -> bool {
     sequence_greater_than(s2, s1)
 }
 
+/// How a received sequence number relates to the ones seen before, see [`SequenceNumberSet::insert`]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum SequenceResult {
+    /// Newer than all previous ones
     Latest,
+    /// Older than the latest one, but not seen before
     Fresh,
+    /// Seen before
     Duplicate,
-    TooOld
+    /// Too old to tell whether it was seen before
+    TooOld,
+    /// So far ahead of the latest one that it is rejected. Only reported by connections, never by [`SequenceNumberSet`].
+    TooNew
+}
+
+/// The number of sequence numbers before the newest one that every ack covers.
+/// Both peers have to use the same width, as it is mixed into the protocol identifier.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default)]
+pub enum AckWidth {
+    #[default]
+    Bits32,
+    Bits64,
+    Bits128
+}
+
+impl AckWidth {
+
+    pub const fn bits(self) -> u32 {
+        match self {
+            AckWidth::Bits32 => 32,
+            AckWidth::Bits64 => 64,
+            AckWidth::Bits128 => 128
+        }
+    }
+
+    /// The size of the bitfield on the wire
+    pub const fn bytes(self) -> usize {
+        self.bits() as usize / 8
+    }
+
+    fn mask(self) -> SequenceBitfield {
+        SequenceBitfield::MAX >> (SequenceBitfield::BITS - self.bits())
+    }
+
 }
 
-type SequenceBitfield = u32;
+type SequenceBitfield = u128;
 
+/// The newest sequence number and a bitfield of which of the 32, 64 or 128 before it are part of the set
 #[derive(Copy, Clone, PartialEq)]
 pub struct SequenceNumberSet {
     latest: SequenceNumber,
-    bitfield: SequenceBitfield
+    bitfield: SequenceBitfield,
+    width: AckWidth,
+    /// Nothing was inserted yet, so not even `latest` is part of the set
+    empty: bool
 }
 
 impl SequenceNumberSet {
 
-    pub fn from_bitfield(latest: SequenceNumber, bitfield: SequenceBitfield) -> Self {
+    /// Bits beyond the width are discarded
+    pub fn from_bitfield(width: AckWidth, latest: SequenceNumber, bitfield: SequenceBitfield) -> Self {
         Self {
             latest,
-            bitfield
+            bitfield: bitfield & width.mask(),
+            width,
+            empty: false
         }
     }
 
+    /// A set that only contains `sequence`
     pub fn new(sequence: SequenceNumber) -> Self{
-        Self::from_bitfield(sequence, 0)
+        Self::from_bitfield(AckWidth::default(), sequence, 0)
+    }
+
+    /// A set without any sequence numbers, the first inserted one always is the latest.
+    /// It is written to the wire like `new(0)`, which is harmless as sequence numbers start at 1.
+    pub fn empty() -> Self {
+        Self {
+            empty: true,
+            ..Self::new(0)
+        }
     }
 
-    pub const fn capacity() -> usize {
-        SequenceBitfield::BITS as usize + 1
+    /// The same set with a different width, dropping the sequence numbers that no longer fit
+    pub fn with_width(self, width: AckWidth) -> Self {
+        Self {
+            bitfield: self.bitfield & width.mask(),
+            width,
+            ..self
+        }
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.empty
+    }
+
+    pub fn width(self) -> AckWidth {
+        self.width
+    }
+
+    pub const fn capacity(self) -> usize {
+        self.width.bits() as usize + 1
     }
 
     pub fn latest(self) -> SequenceNumber {
@@ -226,6 +480,9 @@ impl SequenceNumberSet {
     }
 
     pub fn contains(self, sequence: SequenceNumber) -> bool {
+        if self.empty {
+            return false;
+        }
         match self.latest == sequence {
             true => true,
             false => match self.index(sequence) {
@@ -235,16 +492,22 @@ impl SequenceNumberSet {
         }
     }
 
+    /// Adds `sequence`, moving the window forward if it is the newest one yet
     pub fn insert(&mut self, sequence: SequenceNumber) -> SequenceResult {
+        if self.empty {
+            *self = Self::new(sequence).with_width(self.width);
+            return SequenceResult::Latest;
+        }
         match sequence_greater_than(sequence, self.latest) {
             true => {
-                let offset = sequence.wrapping_sub(self.latest);
+                let offset = sequence.wrapping_sub(self.latest) as usize;
                 self.bitfield <<= 1;
                 self.bitfield |= 0b1;
-                self.bitfield = self.bitfield.checked_shl((offset - 1).into()).unwrap_or(0);
+                self.bitfield = self.bitfield.checked_shl((offset - 1) as u32).unwrap_or(0) & self.width.mask();
                 self.latest = sequence;
                 SequenceResult::Latest
             }
+            false if sequence == self.latest => SequenceResult::Duplicate,
             false => match self.index(sequence) {
                 None => SequenceResult::TooOld,
                 Some(index) => {
@@ -261,8 +524,8 @@ impl SequenceNumberSet {
 
     fn index(self, sequence: SequenceNumber) -> Option<usize> {
         if sequence_less_than(sequence, self.latest) {
-            let offset: usize = self.latest.wrapping_sub(sequence).into();
-            if offset >= Self::capacity() {
+            let offset = self.latest.wrapping_sub(sequence) as usize;
+            if offset >= self.capacity() {
                 return None
             }
             return Some(offset - 1)
@@ -270,8 +533,34 @@ impl SequenceNumberSet {
         None
     }
 
+    /// Iterates over the sequence numbers between the oldest contained one and `latest` that are not part of the set,
+    /// from oldest to newest. Older ones can't be told apart from ones that were never sent, so they are not reported.
+    pub fn missing(self) -> impl Iterator<Item=SequenceNumber> {
+        (0..self.highest_bit().unwrap_or(0))
+            .rev()
+            .filter(move |i| (self.bitfield >> i) & 0b1 == 0)
+            .map(move |i| self.latest.wrapping_sub(i as SequenceNumber + 1))
+    }
+
+    /// The number of sequence numbers [`missing`](Self::missing) yields
+    pub fn missing_count(self) -> usize {
+        match self.highest_bit() {
+            None => 0,
+            Some(highest) => highest + 1 - self.bitfield.count_ones() as usize
+        }
+    }
+
+    /// The index of the oldest sequence number in the bitfield
+    fn highest_bit(self) -> Option<usize> {
+        match self.bitfield {
+            0 => None,
+            bitfield => Some((SequenceBitfield::BITS - 1 - bitfield.leading_zeros()) as usize)
+        }
+    }
+
+    /// Iterates over the contained sequence numbers from oldest to newest
     pub fn iter(self) -> impl Iterator<Item=SequenceNumber> {
-        (0..Self::capacity())
+        (0..self.capacity())
             .rev()
             .map(move |i|self.latest.wrapping_sub(i as SequenceNumber))
             .filter(move |i|self.contains(*i))
@@ -289,7 +578,7 @@ impl Debug for SequenceNumberSet {
 mod tests {
 
     mod sequence_buffer {
-        use crate::sequencing::SequenceBuffer;
+        use crate::sequencing::{SequenceBuffer, SequenceNumber, TooOld};
 
         #[test]
         fn test_insert_remove() {
@@ -391,55 +680,357 @@ mod tests {
             assert_eq!(iter.next(), Some(6));
             assert_eq!(iter.next(), None);
         }
+        #[test]
+        fn test_accessors() {
+            let mut buffer = SequenceBuffer::with_capacity(4);
+            assert_eq!(buffer.capacity(), 4);
+            assert_eq!((buffer.len(), buffer.oldest(), buffer.newest()), (0, None, None));
+            assert_eq!(buffer.get(0), None);
+
+            let (s1, _) = buffer.insert(1);
+            let (s2, _) = buffer.insert(2);
+            let (s3, _) = buffer.insert(3);
+            assert_eq!(buffer.len(), 3);
+            assert_eq!((buffer.oldest(), buffer.newest()), (Some(s1), Some(s3)));
+            assert_eq!(buffer.get(s2), Some(&2));
+            *buffer.get_mut(s2).unwrap() = 7;
+            assert_eq!(buffer.remove(s2), Some(7));
+            assert!(!buffer.contains(s2));
+            assert_eq!(buffer.len(), 2);
+            assert_eq!(buffer.iter().len(), 2);
+
+            // the newest entry is gone, but its sequence number was handed out
+            assert_eq!(buffer.remove(s3), Some(3));
+            assert_eq!((buffer.oldest(), buffer.newest()), (Some(s1), Some(s1)));
+            assert_eq!(buffer.get(s3), None);
+
+            buffer.clear();
+            assert!(buffer.is_empty());
+            assert_eq!((buffer.len(), buffer.oldest(), buffer.newest()), (0, None, None));
+            assert_eq!(buffer.get(s1), None);
+            let (s4, _) = buffer.insert(4);
+            assert_eq!(s4, s3.wrapping_add(1));
+            assert_eq!(buffer.oldest(), Some(s4));
+
+            buffer.reset();
+            assert!(buffer.is_empty());
+            assert_eq!(buffer.get(s4), None);
+            assert_eq!(buffer.next_sequence_number(), SequenceBuffer::<u8>::with_capacity(4).next_sequence_number());
+        }
+
+        #[test]
+        fn test_wrap() {
+            let mut buffer = SequenceBuffer::with_capacity(8);
+            buffer.newest_sequence_number = SequenceNumber::MAX - 5;
+            buffer.insert(());
+            buffer.insert(());
+            let before: Vec<_> = (0..3).map(|_| buffer.insert(()).0).collect();
+            let after: Vec<_> = (0..3).map(|_| buffer.insert(()).0).collect();
+            assert_eq!(before, [SequenceNumber::MAX - 2, SequenceNumber::MAX - 1, SequenceNumber::MAX]);
+            assert_eq!(after, [0, 1, 2]);
+            assert_eq!((buffer.len(), buffer.oldest(), buffer.newest()), (8, Some(SequenceNumber::MAX - 4), Some(2)));
+
+            for seq in before.iter().chain(&after) {
+                assert!(buffer.contains(*seq));
+            }
+            assert!(!buffer.contains(3));
+            assert!(!buffer.contains(SequenceNumber::MAX - 5));
+            assert_eq!(buffer.get_mut(SequenceNumber::MAX - 5), None);
+
+            assert_eq!(buffer.remove(SequenceNumber::MAX), Some(()));
+            assert_eq!(buffer.remove(0), Some(()));
+            let mut iter = buffer.iter();
+            assert_eq!(iter.size_hint(), (6, Some(6)));
+            assert_eq!(iter.next().map(|(seq, _)| seq), Some(SequenceNumber::MAX - 4));
+            assert_eq!(iter.size_hint(), (5, Some(5)));
+            assert_eq!(buffer.iter().map(|(seq, _)| seq).collect::<Vec<_>>(),
+                       [SequenceNumber::MAX - 4, SequenceNumber::MAX - 3, SequenceNumber::MAX - 2, SequenceNumber::MAX - 1, 1, 2]);
+            assert_eq!(buffer.iter_mut().len(), 6);
+
+            assert_eq!(buffer.drain_older(1).count(), 4);
+            assert_eq!((buffer.len(), buffer.oldest(), buffer.newest()), (2, Some(1), Some(2)));
+        }
+
+        #[test]
+        fn test_drain_filter() {
+            let mut buffer = SequenceBuffer::with_capacity(8);
+            let seqs: Vec<_> = (0..6).map(|i| buffer.insert(i).0).collect();
+
+            // the middle leaves holes without moving the window
+            let drained: Vec<_> = buffer.drain_filter(|_, i| *i == 2 || *i == 3).collect();
+            assert_eq!(drained, [(seqs[2], 2), (seqs[3], 3)]);
+            assert_eq!((buffer.len(), buffer.oldest(), buffer.newest()), (4, Some(seqs[0]), Some(seqs[5])));
+            assert!(!buffer.contains(seqs[2]));
+
+            // the oldest edge moves the window past the holes
+            let drained: Vec<_> = buffer.drain_filter(|_, i| *i < 2).collect();
+            assert_eq!(drained, [(seqs[0], 0), (seqs[1], 1)]);
+            assert_eq!((buffer.len(), buffer.oldest(), buffer.newest()), (2, Some(seqs[4]), Some(seqs[5])));
+            assert_eq!(buffer.remove(seqs[3]), None);
+
+            // the newest edge keeps the sequence numbers going
+            let drained: Vec<_> = buffer.drain_filter(|seq, _| seq == seqs[5]).collect();
+            assert_eq!(drained, [(seqs[5], 5)]);
+            assert_eq!((buffer.len(), buffer.oldest(), buffer.newest()), (1, Some(seqs[4]), Some(seqs[4])));
+            assert_eq!(buffer.insert(6).0, seqs[5].wrapping_add(1));
+
+            // stopping early keeps the rest
+            assert_eq!(buffer.drain_filter(|_, _| true).next(), Some((seqs[4], 4)));
+            assert_eq!(buffer.iter().map(|(_, i)| *i).collect::<Vec<_>>(), [6]);
+            assert_eq!(buffer.drain_filter(|_, _| true).count(), 1);
+            assert!(buffer.is_empty());
+            assert_eq!(buffer.drain_filter(|_, _| true).count(), 0);
+        }
+
+        #[test]
+        fn test_retain() {
+            let mut buffer = SequenceBuffer::with_capacity(4);
+            buffer.newest_sequence_number = SequenceNumber::MAX - 1;
+            for i in 0..4 {
+                buffer.insert(i);
+            }
+            buffer.retain(|_, i| {
+                *i *= 10;
+                *i != 0 && *i != 30
+            });
+            assert_eq!(buffer.iter().map(|(seq, i)| (seq, *i)).collect::<Vec<_>>(), [(0, 10), (1, 20)]);
+            assert_eq!((buffer.len(), buffer.oldest(), buffer.newest()), (2, Some(0), Some(1)));
+            buffer.retain(|_, _| false);
+            assert!(buffer.is_empty());
+            assert_eq!(buffer.len(), 0);
+        }
+
+        #[test]
+        fn test_remove_out_of_order() {
+            let mut buffer = SequenceBuffer::with_capacity(4);
+            let (s1, _) = buffer.insert(1);
+            let (s2, _) = buffer.insert(2);
+            let (s3, _) = buffer.insert(3);
+
+            // the newest entry
+            assert_eq!(buffer.remove(s3), Some(3));
+            assert_eq!((buffer.len(), buffer.oldest(), buffer.newest()), (2, Some(s1), Some(s2)));
+            assert_eq!(buffer.iter().map(|(seq, _)| seq).collect::<Vec<_>>(), [s1, s2]);
+
+            // a middle entry
+            let (s4, _) = buffer.insert(4);
+            assert_eq!(buffer.remove(s2), Some(2));
+            assert_eq!((buffer.len(), buffer.oldest(), buffer.newest()), (2, Some(s1), Some(s4)));
+            assert_eq!(buffer.iter().map(|(seq, _)| seq).collect::<Vec<_>>(), [s1, s4]);
+
+            // re-inserting never reuses a sequence number and evicts by sequence number, skipping the holes
+            let (s5, evicted) = buffer.insert(5);
+            assert_eq!((s5, evicted), (s4.wrapping_add(1), Some(1)));
+            let (_, evicted) = buffer.insert(6);
+            assert_eq!(evicted, None);
+            assert_eq!(buffer.iter().map(|(_, i)| *i).collect::<Vec<_>>(), [4, 5, 6]);
+            assert_eq!(buffer.iter().len(), 3);
+
+            for seq in [s5, s4, s5.wrapping_add(1)] {
+                assert!(!buffer.is_empty());
+                buffer.remove(seq);
+            }
+            assert!(buffer.is_empty());
+            assert_eq!((buffer.len(), buffer.oldest(), buffer.newest()), (0, None, None));
+            assert_eq!(buffer.insert(7).0, s5.wrapping_add(2));
+        }
+
+        #[test]
+        fn test_insert_at() {
+            let mut buffer = SequenceBuffer::with_capacity(8);
+            assert_eq!(buffer.insert_at(5, 5), Ok(None));
+            assert_eq!((buffer.len(), buffer.oldest(), buffer.newest()), (1, Some(5), Some(5)));
+            assert_eq!(buffer.insert_at(3, 3), Ok(None));
+            assert_eq!(buffer.insert_at(3, 33), Ok(Some(3)));
+            assert_eq!(buffer.insert_at(7, 7), Ok(None));
+            assert_eq!(buffer.iter().map(|(seq, i)| (seq, *i)).collect::<Vec<_>>(), [(3, 33), (5, 5), (7, 7)]);
+            assert_eq!(buffer.next_sequence_number(), 8);
+
+            // the window covers the last 8 sequence numbers
+            assert_eq!(buffer.insert_at(0, 0), Ok(None));
+            assert_eq!(buffer.insert_at(SequenceNumber::MAX, 0), Err(TooOld));
+            assert_eq!(buffer.insert_at(9, 9), Ok(None));
+            assert_eq!(buffer.oldest(), Some(3));
+            assert_eq!(buffer.insert_at(10, 10), Ok(None));
+            assert_eq!((buffer.len(), buffer.oldest(), buffer.newest()), (5, Some(3), Some(10)));
+            assert_eq!(buffer.insert_at(2, 2), Err(TooOld));
+
+            // a far jump evicts everything
+            assert_eq!(buffer.insert_at(1000, 1000), Ok(None));
+            assert_eq!(buffer.iter().map(|(seq, _)| seq).collect::<Vec<_>>(), [1000]);
+            assert_eq!(buffer.insert(1001).0, 1001);
+            assert_eq!(buffer.insert_at(995, 995), Ok(None));
+            assert_eq!(buffer.iter().map(|(seq, _)| seq).collect::<Vec<_>>(), [995, 1000, 1001]);
+        }
+
+        #[test]
+        fn test_insert_at_wrap() {
+            let mut buffer = SequenceBuffer::with_capacity(4);
+            buffer.newest_sequence_number = SequenceNumber::MAX - 2;
+            assert_eq!(buffer.insert_at(SequenceNumber::MAX - 1, 1), Ok(None));
+            assert_eq!(buffer.insert_at(1, 3), Ok(None));
+            assert_eq!(buffer.insert_at(0, 2), Ok(None));
+            assert_eq!(buffer.iter().map(|(seq, i)| (seq, *i)).collect::<Vec<_>>(), [(SequenceNumber::MAX - 1, 1), (0, 2), (1, 3)]);
+            assert_eq!(buffer.insert_at(SequenceNumber::MAX, 4), Ok(None));
+            assert_eq!(buffer.len(), 4);
+
+            assert_eq!(buffer.insert_at(2, 5), Ok(None));
+            assert_eq!((buffer.len(), buffer.oldest(), buffer.newest()), (4, Some(SequenceNumber::MAX), Some(2)));
+            assert_eq!(buffer.insert_at(SequenceNumber::MAX - 1, 1), Err(TooOld));
+        }
+
+        #[test]
+        fn test_no_clone() {
+            struct Unique;
+            let mut buffer = SequenceBuffer::with_capacity(2);
+            let (seq, _) = buffer.insert(Unique);
+            assert!(buffer.remove(seq).is_some());
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        use crate::sequencing::{sequence_greater_than, sequence_less_than, SequenceNumber};
+        assert!(sequence_greater_than(2, 1));
+        assert!(sequence_less_than(1, 2));
+        assert!(!sequence_greater_than(1, 1) && !sequence_less_than(1, 1));
+        assert!(sequence_greater_than(0, SequenceNumber::MAX));
+        assert!(sequence_less_than(SequenceNumber::MAX - 10, 10));
+        assert!(sequence_greater_than(SequenceNumber::MAX / 2, 0));
+        assert!(sequence_less_than(SequenceNumber::MAX / 2 + 1, 0));
     }
 
     mod sequence_set {
-        use crate::sequencing::{SequenceNumber, SequenceNumberSet, SequenceResult};
+        use crate::sequencing::{AckWidth, SequenceNumber, SequenceNumberSet, SequenceResult};
+
+        const WIDTHS: [AckWidth; 3] = [AckWidth::Bits32, AckWidth::Bits64, AckWidth::Bits128];
 
         #[test]
+        #[allow(clippy::identity_op)]
         fn test_contains() {
-            let set = SequenceNumberSet::from_bitfield(3, 0b000010001);
-            assert!(!set.contains(4));
-            assert!( set.contains(3));
-            assert!( set.contains(2));
-            assert!(!set.contains(1));
-            assert!(!set.contains(0));
-            assert!(!set.contains(SequenceNumber::MAX - 0));
-            assert!( set.contains(SequenceNumber::MAX - 1));
-            assert!(!set.contains(SequenceNumber::MAX - 2));
-            assert!(!set.contains(SequenceNumber::MAX - 3));
+            for width in WIDTHS {
+                let set = SequenceNumberSet::from_bitfield(width, 3, 0b000010001);
+                assert!(!set.contains(4));
+                assert!( set.contains(3));
+                assert!( set.contains(2));
+                assert!(!set.contains(1));
+                assert!(!set.contains(0));
+                assert!(!set.contains(SequenceNumber::MAX - 0));
+                assert!( set.contains(SequenceNumber::MAX - 1));
+                assert!(!set.contains(SequenceNumber::MAX - 2));
+                assert!(!set.contains(SequenceNumber::MAX - 3));
+
+                let set = SequenceNumberSet::from_bitfield(width, 3, u128::MAX);
+                assert_eq!(set.iter().count(), set.capacity());
+                assert!( set.contains((3 as SequenceNumber).wrapping_sub(width.bits() as SequenceNumber)));
+                assert!(!set.contains((3 as SequenceNumber).wrapping_sub(width.bits() as SequenceNumber + 1)));
+            }
         }
 
         #[test]
         fn test_insert() {
-            let mut set = SequenceNumberSet::new(0);
-            assert_eq!(set.latest(), 0);
-            assert_eq!(set.bitfield(), 0b0);
-
-            assert_eq!(set.insert(5), SequenceResult::Latest);
-            assert_eq!(set.latest(), 5);
-            assert_eq!(set.bitfield(), 0b10000);
+            for width in WIDTHS {
+                let mut set = SequenceNumberSet::new(0).with_width(width);
+                assert_eq!(set.latest(), 0);
+                assert_eq!(set.bitfield(), 0b0);
+
+                assert_eq!(set.insert(5), SequenceResult::Latest);
+                assert_eq!(set.latest(), 5);
+                assert_eq!(set.bitfield(), 0b10000);
+
+                assert_eq!(set.insert(7), SequenceResult::Latest);
+                assert_eq!(set.latest(), 7);
+                assert_eq!(set.bitfield(), 0b1000010);
+
+                assert_eq!(set.insert(3), SequenceResult::Fresh);
+                assert_eq!(set.latest(), 7);
+                assert_eq!(set.bitfield(), 0b1001010);
+
+                assert_eq!(set.insert(3), SequenceResult::Duplicate);
+                assert_eq!(set.insert(7), SequenceResult::Duplicate);
+                assert_eq!(set.insert(SequenceNumber::MAX - 200), SequenceResult::TooOld);
+            }
+        }
 
-            assert_eq!(set.insert(7), SequenceResult::Latest);
-            assert_eq!(set.latest(), 7);
-            assert_eq!(set.bitfield(), 0b1000010);
+        #[test]
+        fn test_width() {
+            for width in WIDTHS {
+                let oldest = width.bits() as SequenceNumber;
+                let mut set = SequenceNumberSet::empty().with_width(width);
+                assert_eq!(set.insert(0), SequenceResult::Latest);
+                assert_eq!(set.insert(oldest), SequenceResult::Latest);
+                assert!(set.contains(0));
+                assert_eq!(set.insert(oldest + 1), SequenceResult::Latest);
+                assert!(!set.contains(0));
+                assert_eq!(set.insert(0), SequenceResult::TooOld);
+                assert_eq!(set.insert(1), SequenceResult::Fresh);
+                assert_eq!(set.iter().count(), 3);
+            }
+            let wide = SequenceNumberSet::from_bitfield(AckWidth::Bits128, 100, u128::MAX);
+            assert_eq!(wide.with_width(AckWidth::Bits32).bitfield(), u32::MAX as u128);
+            assert_eq!(wide.with_width(AckWidth::Bits64).iter().count(), 65);
+        }
 
-            assert_eq!(set.insert(3), SequenceResult::Fresh);
-            assert_eq!(set.latest(), 7);
-            assert_eq!(set.bitfield(), 0b1001010);
+        #[test]
+        fn test_empty() {
+            for width in WIDTHS {
+                let mut set = SequenceNumberSet::empty().with_width(width);
+                assert!(set.is_empty());
+                assert!(!set.contains(0));
+                assert_eq!(set.iter().count(), 0);
+                assert_eq!(set.insert(0), SequenceResult::Latest);
+                assert!(!set.is_empty());
+                assert!(set.contains(0));
+                assert_eq!(set.insert(0), SequenceResult::Duplicate);
+                assert_eq!(set.width(), width);
+
+                let mut set = SequenceNumberSet::empty().with_width(width);
+                assert_eq!(set.insert(SequenceNumber::MAX - 40), SequenceResult::Latest);
+                assert_eq!(set, SequenceNumberSet::new(SequenceNumber::MAX - 40).with_width(width));
+            }
+        }
 
-            assert_eq!(set.insert(3), SequenceResult::Duplicate);
-            assert_eq!(set.insert(SequenceNumber::MAX - 40), SequenceResult::TooOld);
+        #[test]
+        fn test_missing() {
+            for width in WIDTHS {
+                let fresh = SequenceNumberSet::empty().with_width(width);
+                assert_eq!((fresh.missing().count(), fresh.missing_count()), (0, 0));
+                let single = SequenceNumberSet::new(5).with_width(width);
+                assert_eq!((single.missing().count(), single.missing_count()), (0, 0));
+
+                let packed = SequenceNumberSet::from_bitfield(width, 200, u128::MAX);
+                assert_eq!((packed.missing().count(), packed.missing_count()), (0, 0));
+                let hole = SequenceNumberSet::from_bitfield(width, 200, u128::MAX - 0b100);
+                assert_eq!(hole.missing().collect::<Vec<_>>(), [197]);
+                assert_eq!(hole.missing_count(), 1);
+
+                let mut set = SequenceNumberSet::empty().with_width(width);
+                for seq in [SequenceNumber::MAX - 3, SequenceNumber::MAX, 1, 2] {
+                    set.insert(seq);
+                }
+                assert_eq!(set.missing().collect::<Vec<_>>(), [SequenceNumber::MAX - 2, SequenceNumber::MAX - 1, 0]);
+                assert_eq!(set.missing_count(), 3);
+                set.insert(SequenceNumber::MAX - 1);
+                assert_eq!(set.missing().collect::<Vec<_>>(), [SequenceNumber::MAX - 2, 0]);
+                assert_eq!(set.missing_count(), 2);
+
+                // the oldest contained sequence number sits at the very edge of the window
+                let edge = SequenceNumberSet::from_bitfield(width, 3, 1 << (width.bits() - 1));
+                assert_eq!(edge.missing_count(), width.bits() as usize - 1);
+                assert_eq!(edge.missing().next(), Some((3 as SequenceNumber).wrapping_sub(width.bits() as SequenceNumber - 1)));
+                assert_eq!(edge.missing().last(), Some(2));
+            }
         }
 
         #[test]
         fn test_iter() {
-            let mut iter = SequenceNumberSet::from_bitfield(3, 0b000010001).iter();
-            assert_eq!(iter.next(), Some(SequenceNumber::MAX - 1));
-            assert_eq!(iter.next(), Some(2));
-            assert_eq!(iter.next(), Some(3));
-            assert_eq!(iter.next(), None);
-
+            for width in WIDTHS {
+                let mut iter = SequenceNumberSet::from_bitfield(width, 3, 0b000010001).iter();
+                assert_eq!(iter.next(), Some(SequenceNumber::MAX - 1));
+                assert_eq!(iter.next(), Some(2));
+                assert_eq!(iter.next(), Some(3));
+                assert_eq!(iter.next(), None);
+            }
         }
 
     }
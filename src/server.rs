@@ -1,41 +1,197 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Debug;
-use std::net::SocketAddr;
-use std::io::ErrorKind;
-use crate::connection::{PacketSocket, VirtualConnection};
-use crate::constants::{CONNECTION_TIMEOUT, KEEPALIVE_INTERVAL};
-use crate::error::{ConnectionError, IOResult};
-use crate::packets::Packet;
-use crate::sequencing::{SequenceNumber, SequenceResult};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+use std::io::{Error, ErrorKind};
+use crate::connection::{CongestionState, ConnectionConfig, ConnectionStats, Delivery, DeliveryQueue, NetworkQuality, PacketSocket, PendingPayloads, Polled, SocketStats, VirtualConnection};
+use crate::constants::{APPROVAL_TIMEOUT, DENIAL_REPORT_INTERVAL, MAX_QUERY_RESPONSE_SIZE, MAX_REPORTED_DENIALS};
+use crate::error::{ConnectionError, IOResult, is_transient};
+use crate::limiter::{RateLimit, RateLimiter};
+use crate::packets::{Authentication, CONNECTION_DENIED_SIZE, ConnectionNonce, DenyReason, DisconnectCode, generate_key, Packet, SessionKey, SessionKeys};
+use crate::sequencing::{AckWidth, SequenceNumber, SequenceResult};
 use crate::socket::Transport;
 
 #[derive(Debug, Clone)]
 pub enum ServerDisconnectReason {
     Disconnected,
     TimedOut,
+    /// The client was disconnected with [`Server::kick`]
+    Kicked(DisconnectCode),
     SocketError(ErrorKind)
 }
 
 #[derive(Debug)]
 pub enum ServerEvent<'a> {
-    ClientConnected(u16),
+    /// A connection request with its payload that waits for [`Server::accept_pending`] or [`Server::deny_pending`].
+    /// Only emitted if [`Server::set_approval_required`] is enabled.
+    ConnectionRequested(SocketAddr, &'a [u8]),
+    /// A new client with its address and the payload of its connection request
+    ClientConnected(u16, SocketAddr, &'a [u8]),
+    /// Reported at most once per second and address, so retransmitted requests don't flood the application
+    ConnectionDenied(SocketAddr, DenyReason),
     ClientDisconnected(u16, ServerDisconnectReason),
-    PacketReceived(u16, bool, &'a [u8]),
-    PacketAcknowledged(u16, SequenceNumber),
-    PacketLost(u16, SequenceNumber)
+    /// A payload with its sequence number and whether it is the newest one received so far ([`SequenceResult::Latest`])
+    /// or arrived out of order ([`SequenceResult::Fresh`]). The payloads of a batch share the sequence number of their packet.
+    PacketReceived(u16, SequenceNumber, SequenceResult, &'a [u8]),
+    /// A packet was acknowledged, with its tag and the unsmoothed round trip time it took
+    PacketAcknowledged(u16, SequenceNumber, u64, Duration),
+    /// A packet was lost, with its tag
+    PacketLost(u16, SequenceNumber, u64),
+    /// The answer to [`Server::ping`] with the measured round trip time
+    Pong(u16, SequenceNumber, Duration),
+    PongTimeout(u16, SequenceNumber),
+    QualityChanged(u16, NetworkQuality),
+    /// The client continued the connection from a new address, see [`Server::set_migration_enabled`].
+    /// Contains the old and the new address.
+    ClientMigrated(u16, SocketAddr, SocketAddr),
+    /// A message sent with [`Client::send_reliable`](crate::Client::send_reliable). Messages arrive exactly once and in order.
+    ReliableMessage(u16, Box<[u8]>),
+    /// Receiving failed with a transient error. Repeated errors of the same kind are only reported once
+    /// until a packet was received again.
+    SocketError(ErrorKind)
+}
+
+impl ServerEvent<'_> {
+
+    fn payload(&self) -> &[u8] {
+        match self {
+            ServerEvent::ConnectionRequested(_, data) => data,
+            ServerEvent::ClientConnected(_, _, data) => data,
+            ServerEvent::PacketReceived(_, _, _, data) => data,
+            _ => &[]
+        }
+    }
+
+    fn with_payload(self, payload: &[u8]) -> ServerEvent<'_> {
+        match self {
+            ServerEvent::ConnectionRequested(addrs, _) => ServerEvent::ConnectionRequested(addrs, payload),
+            ServerEvent::ClientConnected(id, addrs, _) => ServerEvent::ClientConnected(id, addrs, payload),
+            ServerEvent::ConnectionDenied(addrs, reason) => ServerEvent::ConnectionDenied(addrs, reason),
+            ServerEvent::ClientDisconnected(id, reason) => ServerEvent::ClientDisconnected(id, reason),
+            ServerEvent::PacketReceived(id, seq, result, _) => ServerEvent::PacketReceived(id, seq, result, payload),
+            ServerEvent::PacketAcknowledged(id, seq, tag, rtt) => ServerEvent::PacketAcknowledged(id, seq, tag, rtt),
+            ServerEvent::PacketLost(id, seq, tag) => ServerEvent::PacketLost(id, seq, tag),
+            ServerEvent::Pong(id, seq, rtt) => ServerEvent::Pong(id, seq, rtt),
+            ServerEvent::PongTimeout(id, seq) => ServerEvent::PongTimeout(id, seq),
+            ServerEvent::QualityChanged(id, quality) => ServerEvent::QualityChanged(id, quality),
+            ServerEvent::ClientMigrated(id, old, new) => ServerEvent::ClientMigrated(id, old, new),
+            ServerEvent::ReliableMessage(id, msg) => ServerEvent::ReliableMessage(id, msg),
+            ServerEvent::SocketError(kind) => ServerEvent::SocketError(kind)
+        }
+    }
+
 }
 
+/// A [`ServerEvent`] that owns its payload, so it can be sent to other threads
 #[derive(Debug, Clone)]
-enum ClientState {
-    Disconnected,
-    Connected(VirtualConnection),
-    Disconnecting(ServerDisconnectReason)
+pub enum ServerEventOwned {
+    ConnectionRequested(SocketAddr, Box<[u8]>),
+    ClientConnected(u16, SocketAddr, Box<[u8]>),
+    ConnectionDenied(SocketAddr, DenyReason),
+    ClientDisconnected(u16, ServerDisconnectReason),
+    PacketReceived(u16, SequenceNumber, SequenceResult, Box<[u8]>),
+    PacketAcknowledged(u16, SequenceNumber, u64, Duration),
+    PacketLost(u16, SequenceNumber, u64),
+    Pong(u16, SequenceNumber, Duration),
+    PongTimeout(u16, SequenceNumber),
+    QualityChanged(u16, NetworkQuality),
+    ClientMigrated(u16, SocketAddr, SocketAddr),
+    ReliableMessage(u16, Box<[u8]>),
+    SocketError(ErrorKind)
+}
+
+impl From<ServerEvent<'_>> for ServerEventOwned {
+    fn from(event: ServerEvent<'_>) -> Self {
+        match event {
+            ServerEvent::ConnectionRequested(addrs, data) => ServerEventOwned::ConnectionRequested(addrs, data.into()),
+            ServerEvent::ClientConnected(id, addrs, data) => ServerEventOwned::ClientConnected(id, addrs, data.into()),
+            ServerEvent::ConnectionDenied(addrs, reason) => ServerEventOwned::ConnectionDenied(addrs, reason),
+            ServerEvent::ClientDisconnected(id, reason) => ServerEventOwned::ClientDisconnected(id, reason),
+            ServerEvent::PacketReceived(id, seq, result, data) => ServerEventOwned::PacketReceived(id, seq, result, data.into()),
+            ServerEvent::PacketAcknowledged(id, seq, tag, rtt) => ServerEventOwned::PacketAcknowledged(id, seq, tag, rtt),
+            ServerEvent::PacketLost(id, seq, tag) => ServerEventOwned::PacketLost(id, seq, tag),
+            ServerEvent::Pong(id, seq, rtt) => ServerEventOwned::Pong(id, seq, rtt),
+            ServerEvent::PongTimeout(id, seq) => ServerEventOwned::PongTimeout(id, seq),
+            ServerEvent::QualityChanged(id, quality) => ServerEventOwned::QualityChanged(id, quality),
+            ServerEvent::ClientMigrated(id, old, new) => ServerEventOwned::ClientMigrated(id, old, new),
+            ServerEvent::ReliableMessage(id, msg) => ServerEventOwned::ReliableMessage(id, msg),
+            ServerEvent::SocketError(kind) => ServerEventOwned::SocketError(kind)
+        }
+    }
+}
+
+/// Iterator over all currently available events of a [`Server`]. Created by [`Server::events`].
+#[derive(Debug)]
+pub struct ServerEvents<'a, D = ()> {
+    server: &'a mut Server<D>,
+    error: Option<Error>
+}
+
+impl<D> ServerEvents<'_, D> {
+
+    /// The socket error that ended the iteration early, if any
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
+    }
+
+}
+
+impl<D> Iterator for ServerEvents<'_, D> {
+    type Item = ServerEventOwned;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.error.is_some() {
+            return None;
+        }
+        match self.server.next_event_owned() {
+            Ok(event) => event,
+            Err(err) => {
+                self.error = Some(err);
+                None
+            }
+        }
+    }
 }
 
-impl Default for ClientState {
-    fn default() -> Self {
-        ClientState::Disconnected
+/// Remembers recently reported denials to rate limit [`ServerEvent::ConnectionDenied`]
+/// and the answers to packets of unknown connections
+#[derive(Debug, Default)]
+struct DenialLog(Vec<(SocketAddr, Instant)>);
+
+impl DenialLog {
+
+    /// Whether a denial for this address should be reported or sent
+    fn report(&mut self, addrs: SocketAddr) -> bool {
+        self.0.retain(|(_, time)| time.elapsed() < DENIAL_REPORT_INTERVAL);
+        if self.0.len() >= MAX_REPORTED_DENIALS || self.0.iter().any(|(recent, _)| *recent == addrs) {
+            return false;
+        }
+        self.0.push((addrs, Instant::now()));
+        true
     }
+
+}
+
+#[derive(Debug, Clone)]
+struct PendingRequest {
+    addrs: SocketAddr,
+    nonce: ConnectionNonce,
+    compression: bool,
+    /// Reported again with [`ServerEvent::ClientConnected`] once the request is accepted
+    payload: Box<[u8]>,
+    received: Instant
+}
+
+#[derive(Debug, Clone, Default)]
+enum ClientState {
+    #[default]
+    Disconnected,
+    /// Holds the slot until the application accepts or denies the request
+    Pending(PendingRequest),
+    Connected(Box<VirtualConnection>),
+    /// Sending the remaining number of disconnect packets, one per update, with the code if the client was kicked
+    Closing(Box<VirtualConnection>, u32, Option<DisconnectCode>),
+    Disconnecting(ServerDisconnectReason)
 }
 
 impl ClientState {
@@ -53,29 +209,121 @@ impl ClientState {
             _ => None
         }
     }
+
+    /// The address of the connection, including connections that are still closing
+    fn addrs(&self) -> Option<SocketAddr> {
+        match self {
+            ClientState::Connected(vc) | ClientState::Closing(vc, _, _) => Some(vc.addrs()),
+            _ => None
+        }
+    }
 }
 
 #[derive(Debug)]
-struct ConnectionManager(Box<[ClientState]>);
+struct ConnectionManager<D> {
+    slots: Vec<ClientState>,
+    /// The user data of each slot. Dropped when the slot becomes free.
+    data: Vec<Option<D>>,
+    /// The slot of the connection from each address. A connection that is still closing
+    /// is shadowed by a newer connection from the same address.
+    addrs: HashMap<SocketAddr, u16>,
+    /// Slots at or above this index don't take new connections and are removed once they are free
+    max_clients: u16
+}
 
-impl ConnectionManager {
+impl<D> ConnectionManager<D> {
 
     fn new(max_clients: u16) -> Self{
         Self {
-            0:  vec![ClientState::Disconnected; max_clients as usize].into_boxed_slice()
+            slots: vec![ClientState::Disconnected; max_clients as usize],
+            data: (0..max_clients).map(|_| None).collect(),
+            addrs: HashMap::new(),
+            max_clients
+        }
+    }
+
+    fn resize(&mut self, max_clients: u16) {
+        self.max_clients = max_clients;
+        if self.slots.len() < max_clients as usize {
+            self.slots.resize(max_clients as usize, ClientState::Disconnected);
+            self.data.resize_with(max_clients as usize, || None);
+        }
+        self.trim();
+    }
+
+    /// Removes the free slots at the end that are above the limit
+    fn trim(&mut self) {
+        while self.slots.len() > self.max_clients as usize && matches!(self.slots.last(), Some(ClientState::Disconnected)) {
+            self.slots.pop();
+            self.data.pop();
         }
     }
 
+    fn is_full(&self) -> bool {
+        self.slots.iter()
+            .take(self.max_clients as usize)
+            .all(|state| !matches!(state, ClientState::Disconnected))
+    }
+
+    /// Free slots that can take a new connection
+    fn free_slots_mut(&mut self) -> impl Iterator<Item=(u16, &mut ClientState)> {
+        let max_clients = self.max_clients as usize;
+        self.slots_mut()
+            .take(max_clients)
+            .filter(|(_, state)| matches!(state, ClientState::Disconnected))
+    }
+
     fn get(&self, id: u16) -> Option<&ClientState> {
-        self.0.get(id as usize)
+        self.slots.get(id as usize)
     }
 
     fn get_mut(&mut self, id: u16) -> Option<&mut ClientState> {
-        self.0.get_mut(id as usize)
+        self.slots.get_mut(id as usize)
     }
 
     fn set(&mut self, id: u16, new_state: ClientState) {
-        *self.get_mut(id).unwrap() = new_state;
+        if matches!(new_state, ClientState::Disconnected) {
+            self.data[id as usize] = None;
+        }
+        let state = self.get_mut(id).unwrap();
+        let old = state.addrs();
+        *state = new_state;
+        self.reindex(id, old);
+    }
+
+    /// Runs `f` for every slot and keeps the address index up to date
+    fn update_slots<F: FnMut(u16, &mut ClientState)>(&mut self, mut f: F) {
+        for id in 0..self.slots.len() {
+            let old = self.slots[id].addrs();
+            f(id as u16, &mut self.slots[id]);
+            self.reindex(id as u16, old);
+        }
+    }
+
+    /// Updates the address index after the connection of slot `id` was at `old`
+    fn reindex(&mut self, id: u16, old: Option<SocketAddr>) {
+        let new = self.slots[id as usize].addrs();
+        if old == new {
+            return;
+        }
+        if let Some(old) = old {
+            if self.addrs.get(&old) == Some(&id) {
+                self.addrs.remove(&old);
+                // a closing connection that was shadowed by this one
+                if let Some(other) = self.slots.iter().position(|state| state.addrs() == Some(old)) {
+                    self.addrs.insert(old, other as u16);
+                }
+            }
+        }
+        if let Some(new) = new {
+            self.addrs.insert(new, id);
+        }
+        debug_assert!(self.addrs.iter().all(|(addrs, id)| self.slots[*id as usize].addrs() == Some(*addrs)));
+        debug_assert!(self.slots.iter().enumerate().all(|(id, state)| match state {
+            ClientState::Connected(vc) => self.addrs.get(&vc.addrs()) == Some(&(id as u16)),
+            ClientState::Closing(vc, _, _) => self.addrs.contains_key(&vc.addrs()),
+            _ => true
+        }));
     }
 
     fn get_connection(&self, client_id: u16) -> Result<&VirtualConnection, ConnectionError> {
@@ -101,172 +349,2804 @@ impl ConnectionManager {
     }
 
     fn find_by_addrs(&mut self, addrs: SocketAddr) -> Option<&mut VirtualConnection> {
-        self.connections_mut().find(|c| c.addrs() == addrs)
+        let id = *self.addrs.get(&addrs)?;
+        self.slots[id as usize].get_connection_mut()
+    }
+
+    fn find_connection(&self, addrs: SocketAddr) -> Option<&VirtualConnection> {
+        let id = *self.addrs.get(&addrs)?;
+        self.slots[id as usize].get_connection()
+    }
+
+    fn create_pending(&mut self, addrs: SocketAddr, nonce: ConnectionNonce, compression: bool, payload: &[u8]) -> bool {
+        match self.free_slots_mut().next() {
+            Some((_, state)) => {
+                *state = ClientState::Pending(PendingRequest { addrs, nonce, compression, payload: payload.into(), received: Instant::now() });
+                true
+            },
+            None => false
+        }
+    }
+
+    fn is_pending(&self, addrs: SocketAddr) -> bool {
+        self.slots.iter().any(|state| matches!(state, ClientState::Pending(request) if request.addrs == addrs))
+    }
+
+    fn find_pending_mut(&mut self, addrs: SocketAddr) -> Option<&mut PendingRequest> {
+        self.slots.iter_mut().find_map(|state| match state {
+            ClientState::Pending(request) if request.addrs == addrs => Some(request),
+            _ => None
+        })
     }
 
-    fn create_new_connection(&mut self, addrs: SocketAddr) -> Option<&mut VirtualConnection> {
+    /// Frees the slot of the pending request from this address
+    fn take_pending(&mut self, addrs: SocketAddr) -> Option<(u16, PendingRequest)> {
         self.slots_mut().find_map(|(id, state)| match state {
-            ClientState::Disconnected => {
-                *state = ClientState::Connected(VirtualConnection::new(addrs, id));
-                state.get_connection_mut()
+            ClientState::Pending(request) if request.addrs == addrs => match std::mem::take(state) {
+                ClientState::Pending(request) => Some((id, request)),
+                _ => unreachable!()
             },
             _ => None
         })
     }
 
+    /// The number of slots that are occupied by the address `ip`, regardless of the port
+    fn count_ip(&self, ip: IpAddr) -> usize {
+        self.slots.iter()
+            .filter(|state| match state {
+                ClientState::Pending(request) => request.addrs.ip() == ip,
+                ClientState::Connected(vc) | ClientState::Closing(vc, _, _) => vc.addrs().ip() == ip,
+                ClientState::Disconnected | ClientState::Disconnecting(_) => false
+            })
+            .count()
+    }
+
+    fn find_key(&self, addrs: SocketAddr) -> Option<SessionKey> {
+        self.find_connection(addrs).and_then(|c| c.recv_key())
+    }
+
+    /// The key of the connection with this nonce, used to verify packets from an unknown address
+    fn find_migration_key(&self, nonce: ConnectionNonce) -> Option<SessionKey> {
+        self.connections().find(|c| c.nonce() == nonce).and_then(|c| c.recv_key())
+    }
+
+    /// Moves the connection with this nonce to `addrs` if the packet is newer than everything received so far.
+    /// Only connections with a session key are moved, as a packet signed with a key derived from the pre-shared key
+    /// is the only proof that it is genuine.
+    fn migrate(&mut self, addrs: SocketAddr, nonce: ConnectionNonce, packet: &Packet) -> Option<(u16, SocketAddr)> {
+        let seq = match packet {
+            Packet::Payload(seq, _, _) | Packet::Batch(seq, _, _) | Packet::Messages(seq, _, _) | Packet::KeepAlive(seq, _) | Packet::Ping(seq, _) => *seq,
+            _ => return None
+        };
+        let connection = self.connections_mut().find(|c| c.nonce() == nonce && c.recv_key().is_some())?;
+        if !connection.is_newest(seq) {
+            return None;
+        }
+        let (id, old) = (connection.id(), connection.migrate(addrs));
+        self.reindex(id, Some(old));
+        Some((id, old))
+    }
+
+    /// The nonce of the connection with this address, including connections that are still closing
+    fn find_nonce(&self, addrs: SocketAddr) -> Option<ConnectionNonce> {
+        match &self.slots[*self.addrs.get(&addrs)? as usize] {
+            ClientState::Connected(vc) | ClientState::Closing(vc, _, _) => Some(vc.nonce()),
+            _ => None
+        }
+    }
+
+    fn create_new_connection(&mut self, addrs: SocketAddr, nonce: ConnectionNonce, keys: Option<SessionKeys>, compression: bool, sent_packets_capacity: usize, ack_width: AckWidth) -> Option<&mut VirtualConnection> {
+        let (id, state) = self.free_slots_mut().next()?;
+        *state = ClientState::Connected(Box::new(VirtualConnection::new(addrs, id, nonce, keys, compression, sent_packets_capacity, ack_width)));
+        self.reindex(id, None);
+        self.slots[id as usize].get_connection_mut()
+    }
+
     fn connections(&self) -> impl Iterator<Item=&VirtualConnection> {
-        self.0.iter().filter_map(|c|c.get_connection())
+        self.slots.iter().filter_map(|c|c.get_connection())
     }
 
     fn connections_mut(&mut self) -> impl Iterator<Item=&mut VirtualConnection> {
-        self.0.iter_mut().filter_map(|c|c.get_connection_mut())
+        self.slots.iter_mut().filter_map(|c|c.get_connection_mut())
+    }
+
+    fn slots(&self) -> impl Iterator<Item=(u16, &ClientState)> {
+        self.slots.iter().enumerate().map(|(id, state)|(id as u16, state))
     }
 
     fn slots_mut(&mut self) -> impl Iterator<Item=(u16, &mut ClientState)> {
-        self.0.iter_mut().enumerate().map(|(id, state)|(id as u16, state))
+        self.slots.iter_mut().enumerate().map(|(id, state)|(id as u16, state))
     }
 
 }
 
 
+/// A server for up to `max_clients` clients. Each connected client can have user data of type `D` attached,
+/// see [`Server::set_data`].
 #[derive(Debug)]
-pub struct Server {
+pub struct Server<D = ()> {
     socket: PacketSocket,
-    clients: ConnectionManager,
-    ack_queue: VecDeque<(u16, SequenceNumber, bool)>
+    clients: ConnectionManager<D>,
+    config: ConnectionConfig,
+    ack_queue: DeliveryQueue<(u16, SequenceNumber, Delivery)>,
+    pending: PendingPayloads,
+    query_response: Vec<u8>,
+    approval_required: bool,
+    accepting: bool,
+    max_connections_per_ip: Option<u16>,
+    migration_enabled: bool,
+    migrations: VecDeque<(u16, SocketAddr, SocketAddr)>,
+    /// Clients accepted with `accept_pending` whose [`ServerEvent::ClientConnected`] is not reported yet
+    accepted: VecDeque<(u16, SocketAddr, Box<[u8]>)>,
+    /// The payload of the last reported accepted client, borrowed by its event
+    accepted_payload: Box<[u8]>,
+    denial_log: DenialLog,
+    /// Addresses that were recently told that their connection is unknown
+    reset_log: DenialLog,
+    denials: VecDeque<(SocketAddr, DenyReason)>,
+    rate_limiter: Option<RateLimiter>,
+    rate_limited: u64,
+    /// Set by `shutdown`, clients that are still closing afterwards are dropped
+    shutdown_deadline: Option<Instant>
 }
 
 
 impl Server {
 
+    /// Creates a server on a new non-blocking UDP socket bound to `addrs`.
+    /// Use `new` to supply a custom [`Transport`].
+    pub fn bind<A: ToSocketAddrs>(addrs: A, identifier: &str, max_clients: u16) -> IOResult<Self> {
+        let socket = UdpSocket::bind(addrs)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self::new(socket, identifier, max_clients))
+    }
+
     pub fn new<T: Transport + 'static>(socket: T, identifier: &str, max_clients: u16) -> Self {
-        let socket = PacketSocket::new(socket, identifier);
+        Self::new_with_authentication(socket, identifier, max_clients, Authentication::Checksum)
+    }
+
+    pub fn new_with_authentication<T: Transport + 'static>(socket: T, identifier: &str, max_clients: u16, authentication: Authentication) -> Self {
+        Self::from_config(socket, identifier, max_clients, ConnectionConfig { authentication, ..Default::default() })
+    }
+
+    pub fn new_with_config<T: Transport + 'static>(socket: T, identifier: &str, max_clients: u16, config: ConnectionConfig) -> Result<Self, ConnectionError> {
+        config.validate()?;
+        Ok(Self::from_config(socket, identifier, max_clients, config))
+    }
+
+    fn from_config<T: Transport + 'static>(socket: T, identifier: &str, max_clients: u16, config: ConnectionConfig) -> Self {
+        let socket = PacketSocket::new(socket, identifier, config.ack_width);
         let clients = ConnectionManager::new(max_clients);
         Self {
             socket,
             clients,
-            ack_queue: VecDeque::new()
+            ack_queue: DeliveryQueue::new(config.delivery_queue_size),
+            config,
+            pending: PendingPayloads::default(),
+            query_response: Vec::new(),
+            approval_required: false,
+            accepting: true,
+            max_connections_per_ip: None,
+            migration_enabled: false,
+            migrations: VecDeque::new(),
+            accepted: VecDeque::new(),
+            accepted_payload: Box::default(),
+            denial_log: DenialLog::default(),
+            reset_log: DenialLog::default(),
+            denials: VecDeque::new(),
+            rate_limiter: None,
+            rate_limited: 0,
+            shutdown_deadline: None
+        }
+    }
+
+    /// Changes the type of the user data. The data of already connected clients is dropped.
+    pub fn with_data<D>(self) -> Server<D> {
+        Server {
+            socket: self.socket,
+            clients: ConnectionManager {
+                slots: self.clients.slots,
+                data: self.clients.data.iter().map(|_| None).collect(),
+                addrs: self.clients.addrs,
+                max_clients: self.clients.max_clients
+            },
+            config: self.config,
+            ack_queue: self.ack_queue,
+            pending: self.pending,
+            query_response: self.query_response,
+            approval_required: self.approval_required,
+            accepting: self.accepting,
+            max_connections_per_ip: self.max_connections_per_ip,
+            migration_enabled: self.migration_enabled,
+            migrations: self.migrations,
+            accepted: self.accepted,
+            accepted_payload: self.accepted_payload,
+            denial_log: self.denial_log,
+            reset_log: self.reset_log,
+            denials: self.denials,
+            rate_limiter: self.rate_limiter,
+            rate_limited: self.rate_limited,
+            shutdown_deadline: self.shutdown_deadline
         }
     }
 
+}
+
+impl<D> Server<D> {
+
     pub fn local_addr(&self) -> IOResult<SocketAddr> {
         self.socket.local_addr()
     }
 
-    pub fn update(&mut self) {
-        for (_, client) in self.clients.slots_mut() {
+    pub fn socket_stats(&self) -> SocketStats {
+        SocketStats {
+            requests_dropped_rate_limited: self.rate_limited,
+            events_dropped: self.ack_queue.dropped(),
+            ..self.socket.stats()
+        }
+    }
+
+    pub fn reset_socket_stats(&mut self) {
+        self.socket.reset_stats();
+        self.ack_queue.reset_dropped();
+        self.rate_limited = 0;
+    }
+
+    /// Limits how many packets outside of a connection are answered, e.g. connection requests and queries.
+    /// Excess packets are dropped without a response. Traffic of connected clients is never limited.
+    /// `None` removes the limit, which is the default.
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.rate_limiter = limit.map(RateLimiter::new);
+    }
+
+    /// Sets the payload that is sent in response to unconnected pings.
+    pub fn set_query_response(&mut self, response: &[u8]) -> Result<(), ConnectionError> {
+        if response.len() > MAX_QUERY_RESPONSE_SIZE {
+            return Err(ConnectionError::PayloadTooLarge { len: response.len(), max: MAX_QUERY_RESPONSE_SIZE });
+        }
+        self.query_response.clear();
+        self.query_response.extend_from_slice(response);
+        Ok(())
+    }
+
+    /// If enabled, new clients are no longer accepted right away. Instead, [`ServerEvent::ConnectionRequested`]
+    /// is emitted and the request occupies a slot until it is answered with [`Server::accept_pending`]
+    /// or [`Server::deny_pending`]. Unanswered requests are denied after [`APPROVAL_TIMEOUT`].
+    pub fn set_approval_required(&mut self, required: bool) {
+        self.approval_required = required;
+    }
+
+    /// While disabled, new connection requests are denied, but connected clients are kept.
+    /// Requests that already wait for approval can still be accepted. Enabled by default.
+    pub fn set_accepting(&mut self, accepting: bool) {
+        self.accepting = accepting;
+    }
+
+    pub fn is_accepting(&self) -> bool {
+        self.accepting
+    }
+
+    /// Limits how many clients can connect from the same ip address. `None` removes the limit, which is the default.
+    /// Clients above a new limit stay connected.
+    pub fn set_max_connections_per_ip(&mut self, limit: Option<u16>) {
+        self.max_connections_per_ip = limit;
+    }
+
+    /// Allows clients to continue their connection from a new address, e.g. after their NAT changed the port.
+    /// Only applies to [`Authentication::Mac`] and encrypted connections. Their session keys are derived from the
+    /// pre-shared key, so only peers that know it can move a connection. The move is reported as [`ServerEvent::ClientMigrated`].
+    pub fn set_migration_enabled(&mut self, enabled: bool) {
+        self.migration_enabled = enabled;
+    }
+
+    /// Accepts the pending connection request from `addrs` and returns the id of the new client.
+    /// Like for automatically accepted clients, [`ServerEvent::ClientConnected`] is emitted with the payload of the request.
+    pub fn accept_pending(&mut self, addrs: SocketAddr) -> Result<u16, ConnectionError> {
+        let (id, request) = self.clients.take_pending(addrs).ok_or(ConnectionError::Disconnected)?;
+        let keys = SessionKeys::derive(&self.config.authentication, generate_key(), true);
+        let mut connection = VirtualConnection::new(addrs, id, request.nonce, keys, request.compression && cfg!(feature = "compression"), self.config.sent_packets_capacity, self.config.ack_width);
+        connection.set_rtt_variance_smoothing(self.config.rtt_variance_smoothing);
+        let accepted = Packet::ConnectionAccepted(id, connection.handshake_key(), connection.compression(), request.nonce);
+        self.socket.send_with(accepted, &mut connection).map_err(ConnectionError::Socket)?;
+        self.clients.set(id, ClientState::Connected(Box::new(connection)));
+        self.accepted.push_back((id, addrs, request.payload));
+        Ok(id)
+    }
+
+    /// Denies the pending connection request from `addrs`, usually with [`DenyReason::Rejected`]. The client is disconnected with
+    /// [`ClientDisconnectReason::ConnectionDenied`](crate::ClientDisconnectReason::ConnectionDenied) and `reason`,
+    /// the server reports [`ServerEvent::ConnectionDenied`] like for its own denials.
+    pub fn deny_pending(&mut self, addrs: SocketAddr, reason: DenyReason) -> Result<(), ConnectionError> {
+        let (_, request) = self.clients.take_pending(addrs).ok_or(ConnectionError::Disconnected)?;
+        // a lost denial only means that the client times out
+        let _ = self.socket.send_to(Packet::ConnectionDenied(request.nonce, Some(reason)), addrs);
+        if self.denial_log.report(addrs) {
+            self.denials.push_back((addrs, reason));
+        }
+        Ok(())
+    }
+
+    /// Sends disconnects, keepalives and acks that are due and checks for timeouts.
+    /// Returns the time until the next call is needed, see [`Server::next_update`].
+    pub fn update(&mut self) -> Duration {
+        self.send_due_packets();
+        self.next_update()
+    }
+
+    /// The time until `update` needs to be called again. [`Duration::ZERO`] means right away
+    /// and [`Duration::MAX`] that nothing is scheduled.
+    pub fn next_update(&self) -> Duration {
+        self.clients.slots.iter()
+            .map(|client| match client {
+                ClientState::Connected(connection) => connection.next_update(&self.config),
+                // one disconnect is sent per update
+                ClientState::Closing(..) => Duration::ZERO,
+                ClientState::Pending(request) => APPROVAL_TIMEOUT.saturating_sub(request.received.elapsed()),
+                ClientState::Disconnected | ClientState::Disconnecting(_) => Duration::MAX
+            })
+            .min()
+            .unwrap_or(Duration::MAX)
+    }
+
+    fn send_due_packets(&mut self) {
+        // clients still closing after the deadline get one last disconnect
+        let deadline_passed = self.shutdown_deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        self.clients.update_slots(|_, client| {
+            if let Some(connection) = client.get_connection_mut() {
+                if let Err(e) = self.socket.send_queued(connection) {
+                    *client = ClientState::Disconnecting(ServerDisconnectReason::SocketError(e.kind()));
+                }
+            }
+        });
+        self.flush();
+        self.clients.update_slots(|_, client| {
+            if let ClientState::Pending(request) = client {
+                if request.received.elapsed() > APPROVAL_TIMEOUT {
+                    let _ = self.socket.send_to(Packet::ConnectionDenied(request.nonce, Some(DenyReason::ApprovalTimeout)), request.addrs);
+                    if self.denial_log.report(request.addrs) {
+                        self.denials.push_back((request.addrs, DenyReason::ApprovalTimeout));
+                    }
+                    *client = ClientState::Disconnected;
+                }
+                return;
+            }
+            if let ClientState::Closing(connection, remaining, code) = client {
+                *remaining = if deadline_passed { 0 } else { *remaining - 1 };
+                match self.socket.send_with(Packet::Disconnect(*code), connection) {
+                    Ok(()) if *remaining > 0 => {},
+                    Ok(()) => *client = ClientState::Disconnecting(code.map_or(ServerDisconnectReason::Disconnected, ServerDisconnectReason::Kicked)),
+                    Err(e) => *client = ClientState::Disconnecting(ServerDisconnectReason::SocketError(e.kind()))
+                }
+                return;
+            }
             if let Some(connection) = client.get_connection_mut() {
-                if connection.last_packet_send() > KEEPALIVE_INTERVAL {
+                connection.update_quality(&self.config.quality);
+                connection.update_congestion(&self.config.congestion);
+                if let Err(e) = self.socket.send_messages(connection, &self.config.congestion) {
+                    *client = ClientState::Disconnecting(ServerDisconnectReason::SocketError(e.kind()));
+                    return;
+                }
+                if connection.ack_due() {
+                    if let Err(e) = self.socket.send_ack(connection) {
+                        *client = ClientState::Disconnecting(ServerDisconnectReason::SocketError(e.kind()));
+                        return;
+                    }
+                }
+                if connection.last_packet_send() > self.config.keepalive_interval {
                     if let Err(e) = self.socket.send_keepalive(connection) {
                         *client = ClientState::Disconnecting(ServerDisconnectReason::SocketError(e.kind()));
-                        continue;
+                        return;
                     }
                 }
-                if connection.last_packet_received() > CONNECTION_TIMEOUT {
+                if connection.last_packet_received() > self.config.timeout {
                     *client = ClientState::Disconnecting(ServerDisconnectReason::TimedOut);
                 }
             }
-        }
+        });
+    }
+
+    /// Returns the next event and copies received payloads into `payload`.
+    /// Fails with [`ErrorKind::InvalidInput`] if the payload does not fit. The event is lost in that case.
+    pub fn next_event_into<'a>(&mut self, payload: &'a mut [u8]) -> IOResult<Option<ServerEvent<'a>>> {
+        self.next_event_budgeted(payload, usize::MAX)
     }
 
-    pub fn next_event<'a>(&mut self, payload: &'a mut [u8]) -> IOResult<Option<ServerEvent<'a>>> {
-        if let Some((client, seq, acked)) = self.ack_queue.pop_front() {
-            match acked {
-                true => return Ok(Some(ServerEvent::PacketAcknowledged(client, seq))),
-                false => return Ok(Some(ServerEvent::PacketLost(client, seq)))
+    /// Like `next_event_into`, but reads at most `max_packets` datagrams from the socket, so that a flood of packets
+    /// that don't produce events can't keep the call busy. `None` does not mean that the socket is drained,
+    /// the remaining packets are processed by the following calls.
+    pub fn next_event_budgeted<'a>(&mut self, payload: &'a mut [u8], max_packets: usize) -> IOResult<Option<ServerEvent<'a>>> {
+        match self.next_event_within(max_packets)? {
+            None => Ok(None),
+            Some(event) => {
+                let data = event.payload();
+                let result = payload
+                    .get_mut(..data.len())
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "buffer too small for payload"))?;
+                result.copy_from_slice(data);
+                Ok(Some(event.with_payload(result)))
             }
         }
+    }
+
+    /// Returns an iterator that yields owned events until no more are available.
+    /// Socket errors end the iteration and can be retrieved with [`ServerEvents::take_error`].
+    pub fn events(&mut self) -> ServerEvents<'_, D> {
+        ServerEvents {
+            server: self,
+            error: None
+        }
+    }
 
-        for (id, client) in self.clients.slots_mut() {
-            if let ClientState::Disconnecting(reason) = client {
-                let reason = reason.clone();
-                *client = ClientState::Disconnected;
-                return Ok(Some(ServerEvent::ClientDisconnected(id, reason)));
+    /// Returns the next event with its payload copied into a new allocation.
+    /// Events without a payload do not allocate.
+    pub fn next_event_owned(&mut self) -> IOResult<Option<ServerEventOwned>> {
+        Ok(self.next_event()?.map(ServerEventOwned::from))
+    }
+
+    /// Returns the next event without copying. Received payloads borrow the internal receive buffer,
+    /// so the event has to be dropped before the next call.
+    pub fn next_event(&mut self) -> IOResult<Option<ServerEvent<'_>>> {
+        self.next_event_within(usize::MAX)
+    }
+
+    fn next_event_within(&mut self, budget: usize) -> IOResult<Option<ServerEvent<'_>>> {
+        if self.pending.has_next() {
+            return Ok(self.pending.next().map(|(client, seq, result, data)| ServerEvent::PacketReceived(client, seq, result, data)))
+        }
+        if let Some((id, addrs, payload)) = self.accepted.pop_front() {
+            self.accepted_payload = payload;
+            return Ok(Some(ServerEvent::ClientConnected(id, addrs, &self.accepted_payload)));
+        }
+        Ok(match self.poll(budget)? {
+            None => None,
+            Some(Polled::Event(event)) => Some(event),
+            Some(Polled::Received(event)) => Some(event.with_payload(self.socket.last_payload())),
+            Some(Polled::Pending) => self.pending.next().map(|(client, seq, result, data)| ServerEvent::PacketReceived(client, seq, result, data))
+        })
+    }
+
+    /// Reads at most `budget` datagrams. Queued events don't count against it.
+    fn poll(&mut self, budget: usize) -> IOResult<Option<Polled<ServerEvent<'static>>>> {
+        self.clients.trim();
+        if let Some(event) = self.next_delivery() {
+            return Ok(Some(event));
+        }
+
+        if let Some((addrs, reason)) = self.denials.pop_front() {
+            return Ok(Some(Polled::Event(ServerEvent::ConnectionDenied(addrs, reason))));
+        }
+
+        if let Some((id, old, new)) = self.migrations.pop_front() {
+            return Ok(Some(Polled::Event(ServerEvent::ClientMigrated(id, old, new))));
+        }
+
+        for connection in self.clients.connections_mut() {
+            if let Some(quality) = connection.take_quality_change() {
+                return Ok(Some(Polled::Event(ServerEvent::QualityChanged(connection.id(), quality))));
+            }
+            if let Some(msg) = connection.receive_message() {
+                return Ok(Some(Polled::Event(ServerEvent::ReliableMessage(connection.id(), msg))));
             }
         }
 
-        loop {
-            match self.socket.recv_from() {
-                Ok((packet, src)) => match packet {
-                    Ok(Packet::ConnectionRequest) => match self.clients.find_by_addrs(src) {
-                        None => match self.clients.create_new_connection(src) {
+        let disconnecting = self.clients.slots().find_map(|(id, client)| match client {
+            ClientState::Disconnecting(reason) => Some((id, reason.clone())),
+            _ => None
+        });
+        if let Some((id, reason)) = disconnecting {
+            self.clients.set(id, ClientState::Disconnected);
+            return Ok(Some(Polled::Event(ServerEvent::ClientDisconnected(id, reason))));
+        }
+
+        for _ in 0..budget {
+            let clients = &self.clients;
+            let migration = self.migration_enabled;
+            match self.socket.recv_from(|src, nonce| match clients.find_nonce(src) {
+                None if migration => nonce.and_then(|nonce| clients.find_migration_key(nonce)),
+                _ => clients.find_key(src)
+            }) {
+                Ok((packet, src, size, nonce)) => {
+                    if self.clients.find_nonce(src).is_none() {
+                        if let Some(limiter) = &mut self.rate_limiter {
+                            if !limiter.allow(src.ip()) {
+                                self.rate_limited += 1;
+                                continue;
+                            }
+                        }
+                    }
+                    if let Some(nonce) = nonce {
+                        // The packet was already verified with the key of the connection it claims to belong to.
+                        // The event is reported after the one of the packet itself.
+                        if migration && self.clients.find_nonce(src).is_none() {
+                            if let Some((id, old)) = packet.as_ref().ok().and_then(|packet| self.clients.migrate(src, nonce, packet)) {
+                                self.migrations.push_back((id, old, src));
+                            }
+                        }
+                        match self.clients.find_nonce(src) {
+                            Some(current) if current == nonce => {},
+                            // A delayed packet of an older connection from the same address
+                            Some(_) => continue,
+                            // A delayed packet from the address the connection migrated away from
+                            None if migration && self.clients.connections().any(|c| c.nonce() == nonce) => continue,
+                            // The server doesn't know this connection, most likely because it restarted.
+                            // The denial tells the client right away instead of letting it time out.
+                            // Only the first packet of a burst is answered.
+                            None => {
+                                if size >= CONNECTION_DENIED_SIZE && self.reset_log.report(src) {
+                                    self.socket.send_to(Packet::ConnectionDenied(nonce, None), src)?;
+                                }
+                                continue
+                            }
+                        }
+                    }
+                    match packet {
+                        Ok(Packet::UnconnectedPing(token)) => {
+                            self.socket.send_to(Packet::UnconnectedPong(token, &self.query_response), src)?;
+                        },
+                        Ok(Packet::ConnectionRequest(mode, _, nonce, _)) if mode != self.config.authentication.mode() => {
+                            if let Some(event) = self.deny(src, nonce, DenyReason::AuthenticationMismatch)? {
+                                return Ok(Some(event));
+                            }
+                        },
+                        Ok(Packet::ConnectionRequest(_, _, nonce, _)) if self.shutdown_deadline.is_some() && self.clients.find_connection(src).is_none() => {
+                            if let Some(event) = self.deny(src, nonce, DenyReason::ShuttingDown)? {
+                                return Ok(Some(event));
+                            }
+                        },
+                        // Retransmissions don't create another request while the application decides,
+                        // but a new attempt replaces the nonce so that the answer reaches the client
+                        Ok(Packet::ConnectionRequest(_, compression, nonce, _)) if self.clients.is_pending(src) => {
+                            if let Some(request) = self.clients.find_pending_mut(src) {
+                                request.nonce = nonce;
+                                request.compression = compression;
+                            }
+                        },
+                        // Connected clients still get their accept resent in case it was lost
+                        Ok(Packet::ConnectionRequest(_, _, nonce, _)) if !self.accepting && self.clients.find_connection(src).is_none() => {
+                            if let Some(event) = self.deny(src, nonce, DenyReason::NotAccepting)? {
+                                return Ok(Some(event));
+                            }
+                        },
+                        Ok(Packet::ConnectionRequest(_, _, nonce, _)) if self.clients.find_connection(src).is_none() && self.max_connections_per_ip
+                            .is_some_and(|limit| self.clients.count_ip(src.ip()) >= limit as usize) => {
+                            if let Some(event) = self.deny(src, nonce, DenyReason::TooManyConnections)? {
+                                return Ok(Some(event));
+                            }
+                        },
+                        Ok(Packet::ConnectionRequest(_, compression, nonce, payload)) if self.approval_required && self.clients.find_connection(src).is_none() => {
+                            match self.clients.create_pending(src, nonce, compression, payload) {
+                                true => return Ok(Some(Polled::Received(ServerEvent::ConnectionRequested(src, &[])))),
+                                false => if let Some(event) = self.deny(src, nonce, DenyReason::ServerFull)? {
+                                    return Ok(Some(event));
+                                }
+                            }
+                        },
+                        Ok(Packet::ConnectionRequest(_, compression, nonce, _)) => match self.clients.find_by_addrs(src) {
                             None => {
-                                self.socket.send_to(Packet::ConnectionDenied, src)?;
+                                let keys = SessionKeys::derive(&self.config.authentication, generate_key(), true);
+                                match self.clients.create_new_connection(src, nonce, keys, compression && cfg!(feature = "compression"), self.config.sent_packets_capacity, self.config.ack_width) {
+                                    None => if let Some(event) = self.deny(src, nonce, DenyReason::ServerFull)? {
+                                        return Ok(Some(event));
+                                    },
+                                    Some(conn) => {
+                                        conn.set_rtt_variance_smoothing(self.config.rtt_variance_smoothing);
+                                        self.socket.send_with(Packet::ConnectionAccepted(conn.id(), conn.handshake_key(), conn.compression(), nonce), conn)?;
+                                        return Ok(Some(Polled::Received(ServerEvent::ClientConnected(conn.id(), src, &[]))))
+                                    }
+                                }
                             },
-                            Some(conn) => {
-                                self.socket.send_with(Packet::ConnectionAccepted(conn.id()), conn)?;
-                                return Ok(Some(ServerEvent::ClientConnected(conn.id())))
+                            // Retransmitted request of the current connection
+                            Some(conn) if conn.nonce() == nonce => {
+                                conn.on_receive(size);
+                                self.socket.send_with(Packet::ConnectionAccepted(conn.id(), conn.handshake_key(), conn.compression(), nonce), conn)?;
+                            },
+                            // Either a delayed request of an older attempt or a new attempt while the old connection
+                            // has not timed out yet. Both are ignored, the client keeps retrying in the latter case.
+                            Some(_) => {}
+                        },
+                        Ok(Packet::Payload(seq, ack, _)) => if let Some(conn) = self.clients.find_by_addrs(src) {
+                            let result = conn.handle_seq(seq);
+                            if let SequenceResult::Latest | SequenceResult::Fresh = result {
+                                let id = conn.id();
+                                conn.handle_ack(ack, self.config.packet_lost_cutoff, |i, acked| self.ack_queue.push((id, i, acked)));
+                                conn.on_receive(size);
+                                conn.on_receive_payload();
+                                if conn.ack_due() {
+                                    self.socket.send_ack(conn)?;
+                                }
+                                return Ok(Some(Polled::Received(ServerEvent::PacketReceived(id, seq, result, &[]))))
                             }
                         },
-                        Some(conn) => {
-                            conn.on_receive();
-                            self.socket.send_with(Packet::ConnectionAccepted(conn.id()), conn)?;
-                        }
-                    },
-                    Ok(Packet::Payload(seq, ack, data)) => if let Some(conn) = self.clients.find_by_addrs(src) {
-                        let seq = conn.handle_seq(seq);
-                        if let SequenceResult::Latest | SequenceResult::Fresh = seq {
+                        Ok(Packet::Batch(seq, ack, data)) => if let Some(conn) = self.clients.find_by_addrs(src) {
+                            let result = conn.handle_seq(seq);
+                            if let SequenceResult::Latest | SequenceResult::Fresh = result {
+                                let id = conn.id();
+                                conn.handle_ack(ack, self.config.packet_lost_cutoff, |i, acked| self.ack_queue.push((id, i, acked)));
+                                conn.on_receive(size);
+                                conn.on_receive_payload();
+                                self.pending.store(id, seq, result, data);
+                                if conn.ack_due() {
+                                    self.socket.send_ack(conn)?;
+                                }
+                                if self.pending.has_next() {
+                                    return Ok(Some(Polled::Pending))
+                                }
+                            }
+                        },
+                        Ok(Packet::Messages(seq, ack, data)) => if let Some(conn) = self.clients.find_by_addrs(src) {
+                            if let SequenceResult::Latest | SequenceResult::Fresh = conn.handle_seq(seq) {
+                                let id = conn.id();
+                                conn.handle_ack(ack, self.config.packet_lost_cutoff, |i, acked| self.ack_queue.push((id, i, acked)));
+                                conn.on_receive(size);
+                                conn.on_receive_payload();
+                                conn.on_messages(data);
+                                if conn.ack_due() {
+                                    self.socket.send_ack(conn)?;
+                                }
+                                if let Some(msg) = conn.receive_message() {
+                                    return Ok(Some(Polled::Event(ServerEvent::ReliableMessage(id, msg))))
+                                }
+                            }
+                        },
+                        Ok(Packet::KeepAlive(seq, ack)) => if let Some(conn) = self.clients.find_by_addrs(src) {
                             let id = conn.id();
-                            conn.handle_ack(ack, |i, acked| self.ack_queue.push_back((id, i, acked)));
-                            conn.on_receive();
-                            let result = &mut payload[..data.len()];
-                            result.copy_from_slice(data);
-                            return Ok(Some(ServerEvent::PacketReceived(id, seq == SequenceResult::Latest, result)))
-                        }
-                    },
-                    Ok(Packet::KeepAlive(ack)) => if let Some(conn) = self.clients.find_by_addrs(src) {
-                        let id = conn.id();
-                        conn.on_receive();
-                        conn.handle_ack(ack, |i, acked| self.ack_queue.push_back((id, i, acked)));
-                    },
-                    Ok(Packet::Disconnect) => if let Some(conn) = self.clients.find_by_addrs(src) {
-                        let id = conn.id();
-                        self.clients.set(id, ClientState::Disconnected);
-                        return Ok(Some(ServerEvent::ClientDisconnected(id, ServerDisconnectReason::Disconnected)))
-                    },
-                    _ => continue
-                },
-                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock) => return Ok(None),
+                            if let SequenceResult::Latest | SequenceResult::Fresh = conn.handle_seq(seq) {
+                                conn.on_receive(size);
+                                conn.handle_ack(ack, self.config.packet_lost_cutoff, |i, acked| self.ack_queue.push((id, i, acked)));
+                            }
+                        },
+                        Ok(Packet::Ping(seq, ack)) => if let Some(conn) = self.clients.find_by_addrs(src) {
+                            let id = conn.id();
+                            match conn.handle_seq(seq) {
+                                SequenceResult::Latest | SequenceResult::Fresh => {
+                                    conn.on_receive(size);
+                                    conn.handle_ack(ack, self.config.packet_lost_cutoff, |i, delivery| self.ack_queue.push((id, i, delivery)));
+                                    self.socket.send_ack(conn)?;
+                                }
+                                // the previous ack might have been lost
+                                SequenceResult::Duplicate => self.socket.send_ack(conn)?,
+                                _ => {}
+                            }
+                        },
+                        Ok(Packet::Ack(ack)) => if let Some(conn) = self.clients.find_by_addrs(src) {
+                            let id = conn.id();
+                            conn.on_receive(size);
+                            conn.handle_ack(ack, self.config.packet_lost_cutoff, |i, acked| self.ack_queue.push((id, i, acked)));
+                        },
+                        Ok(Packet::Probe(probe)) => if let Some(conn) = self.clients.find_by_addrs(src) {
+                            conn.on_receive(size);
+                            conn.confirm_size(probe);
+                            self.socket.send_with(Packet::ProbeAck(probe), conn)?;
+                        },
+                        // Retransmissions from clients that are already gone are answered with a denial above
+                        Ok(Packet::Disconnect(_)) => if let Some(conn) = self.clients.find_by_addrs(src) {
+                            let id = conn.id();
+                            self.socket.send_with(Packet::DisconnectAck, conn)?;
+                            self.clients.set(id, ClientState::Disconnected);
+                            return Ok(Some(Polled::Event(ServerEvent::ClientDisconnected(id, ServerDisconnectReason::Disconnected))))
+                        },
+                        _ => continue
+                    }
+                }
+                // acks might have arrived without a packet that produces an event
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock) => return Ok(self.next_delivery()),
+                Err(e) if is_transient(e.kind()) => return Ok(match self.socket.report_recv_error(e.kind()) {
+                    true => Some(Polled::Event(ServerEvent::SocketError(e.kind()))),
+                    false => self.next_delivery()
+                }),
                 Err(e) => return Err(e)
             }
         }
+        Ok(self.next_delivery())
     }
 
-    pub fn connected_clients(&self) -> impl Iterator<Item=u16> +'_ {
-        self.clients.connections().map(|v|v.id())
+    /// Answers the connection request with a denial and returns the event if it should be reported
+    fn deny(&mut self, addrs: SocketAddr, nonce: ConnectionNonce, reason: DenyReason) -> IOResult<Option<Polled<ServerEvent<'static>>>> {
+        self.socket.send_to(Packet::ConnectionDenied(nonce, Some(reason)), addrs)?;
+        Ok(self.denial_log.report(addrs).then_some(Polled::Event(ServerEvent::ConnectionDenied(addrs, reason))))
     }
 
-    pub fn send(&mut self, client_id: u16, payload: &[u8]) -> Result<SequenceNumber, ConnectionError> {
-        let connection = self.clients.get_connection_mut(client_id)?;
-        match self.socket.send_payload(payload, connection) {
-            Ok(seq) => Ok(seq),
-            Err(err) => {
-                self.clients.set(client_id, ClientState::Disconnecting(ServerDisconnectReason::SocketError(err.kind())));
-                Err(ConnectionError::Disconnected)
-            }
-        }
+    fn next_delivery(&mut self) -> Option<Polled<ServerEvent<'static>>> {
+        self.ack_queue.pop().map(|(client, seq, delivery)| Polled::Event(match delivery {
+            Delivery::Acknowledged(tag, rtt) => ServerEvent::PacketAcknowledged(client, seq, tag, rtt),
+            Delivery::Lost(tag) => ServerEvent::PacketLost(client, seq, tag),
+            Delivery::Pong(rtt) => ServerEvent::Pong(client, seq, rtt),
+            Delivery::PongTimeout => ServerEvent::PongTimeout(client, seq)
+        }))
     }
 
-    pub fn disconnect(&mut self, client_id: u16) -> Result<(), ConnectionError> {
-        let connection = self.clients.get_connection_mut(client_id)?;
-        let mut attempts = 10;
-        let reason = loop {
-            match self.socket.send_with (Packet::Disconnect, connection) {
-                Ok(_) => match attempts {
-                    0 | 1 => break ServerDisconnectReason::Disconnected,
-                    _ => attempts -= 1
-                },
-                Err(e) => break ServerDisconnectReason::SocketError(e.kind())
-            }
-        };
-        let id = connection.id();
-        self.clients.set(id, ClientState::Disconnecting(reason));
-        Ok(())
+    /// Changes the number of slots. Growing keeps the ids of all clients. When shrinking, clients in the removed
+    /// slots stay connected until they disconnect, but their slots don't take new connections.
+    pub fn set_max_clients(&mut self, max_clients: u16) {
+        self.clients.resize(max_clients);
     }
 
-    pub fn connection(&self, client_id: u16) -> Result<&VirtualConnection, ConnectionError> {
-        self.clients.get_connection(client_id)
+    pub fn max_clients(&self) -> u16 {
+        self.clients.max_clients
     }
 
-}
+    /// The number of client slots, the same as [`Server::max_clients`]
+    pub fn capacity(&self) -> u16 {
+        self.max_clients()
+    }
+
+    /// Attaches user data to a connected client and returns the previous data.
+    /// The data is dropped together with the slot, when [`ServerEvent::ClientDisconnected`] is emitted.
+    pub fn set_data(&mut self, client_id: u16, data: D) -> Result<Option<D>, ConnectionError> {
+        self.clients.get_connection(client_id)?;
+        Ok(self.clients.data[client_id as usize].replace(data))
+    }
 
+    pub fn data(&self, client_id: u16) -> Option<&D> {
+        self.clients.data.get(client_id as usize)?.as_ref()
+    }
+
+    pub fn data_mut(&mut self, client_id: u16) -> Option<&mut D> {
+        self.clients.data.get_mut(client_id as usize)?.as_mut()
+    }
+
+    /// The number of connected clients
+    pub fn connection_count(&self) -> usize {
+        self.clients.connections().count()
+    }
+
+    /// Whether a new connection would be denied for lack of a slot. Pending requests and clients
+    /// that are still being disconnected occupy a slot as well.
+    pub fn is_full(&self) -> bool {
+        self.clients.is_full()
+    }
+
+    pub fn connected_clients(&self) -> impl Iterator<Item=u16> +'_ {
+        self.clients.connections().map(|v|v.id())
+    }
+
+    pub fn send(&mut self, client_id: u16, payload: &[u8]) -> Result<SequenceNumber, ConnectionError> {
+        self.send_payload(client_id, payload, Some(0))
+    }
+
+    /// Like `send`, but the [`ServerEvent::PacketAcknowledged`] or [`ServerEvent::PacketLost`] event of the packet
+    /// carries `tag` instead of 0, e.g. to find the message that has to be resent.
+    pub fn send_tagged(&mut self, client_id: u16, payload: &[u8], tag: u64) -> Result<SequenceNumber, ConnectionError> {
+        self.send_payload(client_id, payload, Some(tag))
+    }
+
+    /// Sends the payload to every connected client. A failure for one client does not stop the delivery to the others.
+    pub fn broadcast(&mut self, payload: &[u8]) -> Vec<(u16, Result<SequenceNumber, ConnectionError>)> {
+        self.broadcast_filtered(payload, |_| true)
+    }
+
+    /// Like `broadcast`, but only to the connected clients for which `filter` returns `true`.
+    pub fn broadcast_filtered<F: FnMut(u16) -> bool>(&mut self, payload: &[u8], mut filter: F) -> Vec<(u16, Result<SequenceNumber, ConnectionError>)> {
+        let recipients: Vec<u16> = self.connected_clients().filter(|id| filter(*id)).collect();
+        self.send_to_each(recipients, payload)
+    }
+
+    /// Sends the payload to every connected client except `except`, e.g. to relay a message of that client.
+    pub fn send_to_all_except(&mut self, except: u16, payload: &[u8]) -> Vec<(u16, Result<SequenceNumber, ConnectionError>)> {
+        self.broadcast_filtered(payload, |id| id != except)
+    }
+
+    /// Sends the payload to each of the given clients. Ids that are not connected are reported as errors.
+    pub fn send_to_many<I: IntoIterator<Item=u16>>(&mut self, ids: I, payload: &[u8]) -> Vec<(u16, Result<SequenceNumber, ConnectionError>)> {
+        self.send_to_each(ids, payload)
+    }
+
+    fn send_to_each<I: IntoIterator<Item=u16>>(&mut self, ids: I, payload: &[u8]) -> Vec<(u16, Result<SequenceNumber, ConnectionError>)> {
+        ids
+            .into_iter()
+            .map(|id| (id, self.send_payload(id, payload, Some(0))))
+            .collect()
+    }
+
+    /// Measures the round trip time to the client without any smoothing.
+    /// The result is reported as [`ServerEvent::Pong`] or [`ServerEvent::PongTimeout`].
+    pub fn ping(&mut self, client_id: u16) -> Result<SequenceNumber, ConnectionError> {
+        let connection = self.clients.get_connection_mut(client_id)?;
+        match self.socket.send_ping(connection) {
+            Ok(seq) => Ok(seq),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Err(ConnectionError::Backpressured),
+            Err(err) => {
+                self.clients.set(client_id, ClientState::Disconnecting(ServerDisconnectReason::SocketError(err.kind())));
+                Err(ConnectionError::Disconnected)
+            }
+        }
+    }
+
+    /// Like `send`, but the packet never produces a [`ServerEvent::PacketAcknowledged`] or [`ServerEvent::PacketLost`] event.
+    pub fn send_unreliable(&mut self, client_id: u16, payload: &[u8]) -> Result<SequenceNumber, ConnectionError> {
+        self.send_payload(client_id, payload, None)
+    }
+
+    fn send_payload(&mut self, client_id: u16, payload: &[u8], tag: Option<u64>) -> Result<SequenceNumber, ConnectionError> {
+        let connection = self.clients.get_connection_mut(client_id)?;
+        if payload.len() > connection.max_payload_size() {
+            return Err(ConnectionError::PayloadTooLarge { len: payload.len(), max: connection.max_payload_size() });
+        }
+        if connection.exceeds_congestion_budget(&self.config.congestion) {
+            return Err(ConnectionError::Backpressured);
+        }
+        match self.socket.send_payload(payload, connection, tag) {
+            Ok(seq) => Ok(seq),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Err(ConnectionError::Backpressured),
+            Err(err) => {
+                self.clients.set(client_id, ClientState::Disconnecting(ServerDisconnectReason::SocketError(err.kind())));
+                Err(ConnectionError::Disconnected)
+            }
+        }
+    }
+
+    /// Queues the payload to be sent together with other payloads in a single packet.
+    /// The batch is sent once it is full, on the next call to `flush` or `update`, or before the next `send`.
+    /// Returns the sequence number of the packet the payload will be part of.
+    pub fn send_batched(&mut self, client_id: u16, payload: &[u8]) -> Result<SequenceNumber, ConnectionError> {
+        let connection = self.clients.get_connection_mut(client_id)?;
+        if payload.len() + 2 > connection.max_payload_size() {
+            return Err(ConnectionError::PayloadTooLarge { len: payload.len(), max: connection.max_payload_size() - 2 });
+        }
+        if connection.exceeds_congestion_budget(&self.config.congestion) {
+            return Err(ConnectionError::Backpressured);
+        }
+        match self.socket.queue_payload(payload, connection) {
+            Ok(seq) => Ok(seq),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Err(ConnectionError::Backpressured),
+            Err(err) => {
+                self.clients.set(client_id, ClientState::Disconnecting(ServerDisconnectReason::SocketError(err.kind())));
+                Err(ConnectionError::Disconnected)
+            }
+        }
+    }
+
+    /// Queues the message on the built-in [`MessageChannel`](crate::MessageChannel) of the connection. It is sent by `update`,
+    /// resent until it is acknowledged and arrives as [`ClientEvent::ReliableMessage`](crate::ClientEvent::ReliableMessage).
+    /// Messages that are still queued when the connection ends are dropped.
+    /// Their packets don't produce `PacketAcknowledged` or `PacketLost` events.
+    pub fn send_reliable(&mut self, client_id: u16, msg: &[u8]) -> Result<(), ConnectionError> {
+        Ok(self.clients.get_connection_mut(client_id)?.queue_message(msg)?)
+    }
+
+    pub fn flush(&mut self) {
+        self.clients.update_slots(|_, client| {
+            if let Some(connection) = client.get_connection_mut() {
+                if let Err(e) = self.socket.flush(connection) {
+                    *client = ClientState::Disconnecting(ServerDisconnectReason::SocketError(e.kind()));
+                }
+            }
+        });
+    }
+
+    /// Disconnects the client. `update` sends one disconnect packet per call until `disconnect_redundancy`
+    /// packets went out, after which [`ServerEvent::ClientDisconnected`] is emitted.
+    pub fn disconnect(&mut self, client_id: u16) -> Result<(), ConnectionError> {
+        self.start_closing(client_id, None)
+    }
+
+    /// Like `disconnect`, but the client is told why. It is reported as [`ClientDisconnectReason::Kicked`](crate::ClientDisconnectReason::Kicked)
+    /// on the client and as [`ServerDisconnectReason::Kicked`] on the server.
+    pub fn kick(&mut self, client_id: u16, code: DisconnectCode) -> Result<(), ConnectionError> {
+        self.start_closing(client_id, Some(code))
+    }
+
+    /// Disconnects all connected clients, with `code` if given, like `disconnect` or `kick` would.
+    pub fn disconnect_all(&mut self, code: Option<DisconnectCode>) {
+        let connected: Vec<u16> = self.connected_clients().collect();
+        for client_id in connected {
+            // can't fail for connected clients
+            let _ = self.start_closing(client_id, code);
+        }
+    }
+
+    /// Disconnects all clients, denies pending and new connection requests and stops accepting clients for good.
+    /// `update` keeps sending disconnects until all clients are gone or `deadline` passed.
+    /// The shutdown is complete once [`Server::is_draining`] returns `false`.
+    pub fn shutdown(&mut self, deadline: Duration) {
+        self.shutdown_deadline = Some(Instant::now() + deadline);
+        self.disconnect_all(None);
+        self.clients.update_slots(|_, client| {
+            if let ClientState::Pending(request) = client {
+                let _ = self.socket.send_to(Packet::ConnectionDenied(request.nonce, Some(DenyReason::ShuttingDown)), request.addrs);
+                *client = ClientState::Disconnected;
+            }
+        });
+    }
+
+    /// Whether `shutdown` was called and there are still clients whose disconnect was not reported yet
+    pub fn is_draining(&self) -> bool {
+        self.shutdown_deadline.is_some() && self.clients.slots.iter().any(|client| !matches!(client, ClientState::Disconnected))
+    }
+
+    fn start_closing(&mut self, client_id: u16, code: Option<DisconnectCode>) -> Result<(), ConnectionError> {
+        self.clients.get_connection(client_id)?;
+        let slot = self.clients.get_mut(client_id).unwrap();
+        if let ClientState::Connected(connection) = std::mem::take(slot) {
+            self.clients.set(client_id, ClientState::Closing(connection, self.config.disconnect_redundancy, code));
+        }
+        Ok(())
+    }
+
+    pub fn connection(&self, client_id: u16) -> Result<&VirtualConnection, ConnectionError> {
+        self.clients.get_connection(client_id)
+    }
+
+    /// The address of the client. Also available while the server is still sending disconnects to the client.
+    pub fn client_addr(&self, client_id: u16) -> Option<SocketAddr> {
+        match self.clients.get(client_id)? {
+            ClientState::Connected(connection) | ClientState::Closing(connection, _, _) => Some(connection.addrs()),
+            _ => None
+        }
+    }
+
+    /// The id of the client connected from `addrs`, the reverse of [`Server::client_addr`]
+    pub fn client_id_by_addr(&self, addrs: SocketAddr) -> Option<u16> {
+        self.clients.find_connection(addrs).map(VirtualConnection::id)
+    }
+
+    /// All connections together with their id. Their address is returned by `VirtualConnection::addrs`.
+    pub fn iter_connections(&self) -> impl Iterator<Item=(u16, &VirtualConnection)> + '_ {
+        self.clients.connections().map(|connection| (connection.id(), connection))
+    }
+
+    pub fn max_payload_size(&self, client_id: u16) -> Result<usize, ConnectionError> {
+        self.connection(client_id).map(VirtualConnection::max_payload_size)
+    }
+
+    /// The quality as of the last `update`. Changes are reported as [`ServerEvent::QualityChanged`].
+    pub fn quality(&self, client_id: u16) -> Result<NetworkQuality, ConnectionError> {
+        self.connection(client_id).map(VirtualConnection::quality)
+    }
+
+    /// Whether the connection to the client is in congestion avoidance as of the last `update`
+    pub fn congestion_state(&self, client_id: u16) -> Result<CongestionState, ConnectionError> {
+        self.connection(client_id).map(VirtualConnection::congestion_state)
+    }
+
+    pub fn client_stats(&self, client_id: u16) -> Result<ConnectionStats, ConnectionError> {
+        self.connection(client_id).map(VirtualConnection::stats)
+    }
+
+    /// The stats of all connected clients
+    pub fn all_stats(&self) -> impl Iterator<Item=(u16, ConnectionStats)> + '_ {
+        self.clients.connections().map(|connection| (connection.id(), connection.stats()))
+    }
+
+    /// The number of payloads sent to this client that were neither acknowledged nor declared lost yet
+    pub fn in_flight(&self, client_id: u16) -> Result<usize, ConnectionError> {
+        self.connection(client_id).map(VirtualConnection::in_flight)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::time::{Duration, Instant};
+    use crate::{Authentication, Channels, Client, ClientEventOwned, MessageChannel, ServerChannels, DenyReason, RateLimit, DisconnectCode, ClientStats, ConnectionConfig, NetworkQuality, QualityConfig, ClientDisconnectReason, ClientEvent, ConnectConfig, ConnectError, ConnectionError, MAX_CONNECTION_PAYLOAD_SIZE, MAX_PACKET_SIZE, MAX_QUERY_RESPONSE_SIZE, Server, ServerDisconnectReason, ServerEvent, ServerEventOwned, SocketStats};
+    use crate::constants::{ACK_THRESHOLD, CONNECTION_RETRY_INTERVAL, DISCONNECT_REDUNDANCY, FALLBACK_PROBE_SIZE, KEEPALIVE_INTERVAL, MAX_ACK_DELAY, PACKET_LOST_CUTOFF, PROBE_INTERVAL};
+    use crate::packets::{MAGIC, MAX_PAYLOAD_OVERHEAD, Packet, payload_overhead, ProtocolId, SEQUENCE_NUMBER_SIZE};
+    use crate::sequencing::{AckWidth, SequenceNumber, SequenceResult};
+    use crate::socket::Transport;
+    use crate::socket::Endpoint;
+    use crate::testing::{Inbox, MemoryNetwork};
+
+    const KEY: [u8; 16] = [42; 16];
+
+    /// Connects the client to the server on port 1 and drains the events of both. Returns the id of the client.
+    fn connect<D>(server: &mut Server<D>, client: &mut Client) -> Option<u16> {
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        client.connect(Endpoint::local_port(1)).unwrap();
+        client.update();
+        while server.next_event_into(&mut buffer).unwrap().is_some() {}
+        while client.next_event_into(&mut buffer).unwrap().is_some() {}
+        client.connection().map(|vc| vc.id()).ok()
+    }
+
+    /// A server with a single slot on port 1 and a client on port 2 that is connected to it
+    fn connected_pair(network: &MemoryNetwork) -> (Server, Client) {
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let mut client = Client::new(network.bind(2), "test");
+        assert_eq!(connect(&mut server, &mut client), Some(0));
+        (server, client)
+    }
+
+    fn handshake(client: Authentication, server: Authentication) -> (Option<ClientDisconnectReason>, bool) {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new_with_authentication(network.bind(1), "test", 1, server);
+        let mut client = Client::new_with_authentication(network.bind(2), "test", client);
+        client.connect(Endpoint::local_port(1)).unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let mut server_connected = false;
+        for _ in 0..10 {
+            client.update();
+            server.update();
+            while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+                match event {
+                    ServerEvent::ClientConnected(..) => server_connected = true,
+                    ServerEvent::ConnectionDenied(_, DenyReason::AuthenticationMismatch) => {},
+                    event => panic!("unexpected event {:?}", event)
+                }
+            }
+            match client.next_event_into(&mut buffer).unwrap() {
+                None | Some(ClientEvent::Connecting(_)) => {},
+                Some(ClientEvent::Connected(_)) => return (None, server_connected),
+                Some(ClientEvent::Disconnected(reason)) => return (Some(reason), server_connected),
+                Some(event) => panic!("unexpected event {:?}", event)
+            }
+        }
+        panic!("handshake did not finish")
+    }
+
+    /// Reads the nonce of the next connection request
+    fn request_nonce(server: &impl Transport) -> u32 {
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let (len, _) = server.recv_from(&mut buffer).unwrap();
+        match Packet::from(&mut buffer[..len], &ProtocolId::new("test", AckWidth::default()), None).unwrap() {
+            Packet::ConnectionRequest(_, _, nonce, _) => nonce,
+            packet => panic!("unexpected packet {:?}", packet)
+        }
+    }
+
+    #[test]
+    fn test_mac_handshake() {
+        assert!(matches!(handshake(Authentication::Checksum, Authentication::Checksum), (None, true)));
+        assert!(matches!(handshake(Authentication::Mac(KEY), Authentication::Mac(KEY)), (None, true)));
+        assert!(matches!(handshake(Authentication::Checksum, Authentication::Mac(KEY)), (Some(ClientDisconnectReason::ConnectionDenied(DenyReason::AuthenticationMismatch)), false)));
+        assert!(matches!(handshake(Authentication::Mac(KEY), Authentication::Checksum), (Some(ClientDisconnectReason::ConnectionDenied(DenyReason::AuthenticationMismatch)), false)));
+    }
+
+    #[test]
+    fn test_connection_payload() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let mut client = Client::new(network.bind(2), "test");
+        assert!(matches!(
+            client.connect_with_payload(Endpoint::local_port(1), &[0; MAX_CONNECTION_PAYLOAD_SIZE + 1]),
+            Err(ConnectError::PayloadTooLarge { .. })));
+        client.connect_with_payload(Endpoint::local_port(1), b"token").unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        client.update();
+        match server.next_event_into(&mut buffer).unwrap() {
+            Some(ServerEvent::ClientConnected(0, _, token)) => assert_eq!(token, b"token"),
+            event => panic!("unexpected event {:?}", event)
+        }
+    }
+
+    #[test]
+    fn test_batched_payloads() {
+        let network = MemoryNetwork::default();
+        let (mut server, mut client) = connected_pair(&network);
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+        let seq = client.send_batched(&[1]).unwrap();
+        assert_eq!(client.send_batched(&[2, 2]).unwrap(), seq);
+        assert_eq!(client.send_batched(&[]).unwrap(), seq);
+        assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+        client.flush().unwrap();
+
+        let mut received = Vec::new();
+        while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+            if let ServerEvent::PacketReceived(0, received_seq, SequenceResult::Latest, data) = event {
+                assert_eq!(received_seq, seq);
+                received.push(data.to_vec());
+            }
+        }
+        assert_eq!(received, [vec![1], vec![2, 2], vec![]]);
+        let single = client.send(&[3]).unwrap();
+        assert_ne!(single, seq);
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::PacketReceived(0, received_seq, SequenceResult::Latest, [3])) if received_seq == single));
+
+        let large = [7u8; 500];
+        let first = client.send_batched(&large).unwrap();
+        client.send_batched(&large).unwrap();
+        assert_ne!(client.send_batched(&large).unwrap(), first);
+    }
+
+    #[test]
+    fn test_mac_payload() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new_with_authentication(network.bind(1), "test", 1, Authentication::Mac(KEY));
+        let mut client = Client::new_with_authentication(network.bind(2), "test", Authentication::Mac(KEY));
+        let mut plain = Client::new(network.bind(3), "test");
+        client.connect(Endpoint::local_port(1)).unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let mut received = Vec::new();
+        for i in 0..10u8 {
+            client.update();
+            server.update();
+            if client.is_connected() {
+                client.send(&[i]).unwrap();
+            }
+            plain.connect(Endpoint::local_port(1)).unwrap();
+            plain.update();
+            while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+                if let ServerEvent::PacketReceived(_, _, _, data) = event {
+                    received.push(data.to_vec());
+                }
+            }
+            while client.next_event_into(&mut buffer).unwrap().is_some() {}
+            while plain.next_event_into(&mut buffer).unwrap().is_some() {}
+        }
+        assert!(!received.is_empty());
+        assert!(received.iter().all(|data| data.len() == 1));
+    }
+
+    #[test]
+    fn test_ack_packet() {
+        let network = MemoryNetwork::default();
+        let (mut server, mut client) = connected_pair(&network);
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+        for i in 0..ACK_THRESHOLD {
+            client.send(&i.to_be_bytes()).unwrap();
+        }
+        while server.next_event_into(&mut buffer).unwrap().is_some() {}
+
+        // acks are reported starting with the call after the one that received them
+        let mut acked = 0;
+        for _ in 0..2 {
+            while let Some(event) = client.next_event_into(&mut buffer).unwrap() {
+                match event {
+                    ClientEvent::PacketAcknowledged(..) => acked += 1,
+                    event => panic!("unexpected event {:?}", event)
+                }
+            }
+        }
+        assert_eq!(acked, ACK_THRESHOLD);
+    }
+
+    #[test]
+    fn test_unreliable_send() {
+        let network = MemoryNetwork::default();
+        let (mut server, mut client) = connected_pair(&network);
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+        let mut tracked = Vec::new();
+        for i in 0..ACK_THRESHOLD {
+            if i % 2 == 0 {
+                tracked.push(client.send(&i.to_be_bytes()).unwrap());
+            } else {
+                client.send_unreliable(&i.to_be_bytes()).unwrap();
+            }
+        }
+        let mut received = 0;
+        while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+            if let ServerEvent::PacketReceived(..) = event {
+                received += 1;
+            }
+        }
+        assert_eq!(received, ACK_THRESHOLD);
+
+        let mut acked = Vec::new();
+        for _ in 0..2 {
+            while let Some(event) = client.next_event_into(&mut buffer).unwrap() {
+                match event {
+                    ClientEvent::PacketAcknowledged(seq, _, _) => acked.push(seq),
+                    event => panic!("unexpected event {:?}", event)
+                }
+            }
+        }
+        assert_eq!(acked, tracked);
+    }
+
+    #[test]
+    fn test_probing() {
+        let network = MemoryNetwork::with_mtu(1100);
+        let (mut server, mut client) = connected_pair(&network);
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        assert_eq!(client.max_payload_size().unwrap(), 1200 - MAX_PAYLOAD_OVERHEAD);
+
+        std::thread::sleep(PROBE_INTERVAL);
+        client.update();
+        while server.next_event_into(&mut buffer).unwrap().is_some() {}
+        while client.next_event_into(&mut buffer).unwrap().is_some() {}
+        assert_eq!(client.max_payload_size().unwrap(), 1024 - MAX_PAYLOAD_OVERHEAD);
+        assert_eq!(server.max_payload_size(0).unwrap(), 1024 - MAX_PAYLOAD_OVERHEAD);
+
+        assert!(matches!(client.send(&[0; 1024]), Err(ConnectionError::PayloadTooLarge { .. })));
+        assert!(matches!(server.send(0, &[0; 1024]), Err(ConnectionError::PayloadTooLarge { .. })));
+        assert!(client.send(&[0; 1024 - MAX_PAYLOAD_OVERHEAD]).is_ok());
+    }
+
+    #[test]
+    fn test_payload_size() {
+        let network = MemoryNetwork::default();
+        let (mut server, mut client) = connected_pair(&network);
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+        let max = client.max_payload_size().unwrap();
+        assert_eq!(server.max_payload_size(0).unwrap(), max);
+        let payload = vec![7u8; max + 1];
+        let next = client.connection().unwrap().peek_next_sequence_number();
+        assert!(matches!(client.send(&payload), Err(ConnectionError::PayloadTooLarge { len, max: m }) if len == max + 1 && m == max));
+        assert!(matches!(server.send(0, &payload), Err(ConnectionError::PayloadTooLarge { len, max: m }) if len == max + 1 && m == max));
+        assert_eq!(client.connection().unwrap().peek_next_sequence_number(), next);
+
+        assert_eq!(client.send(&payload[..max]).unwrap(), next);
+        assert!(client.send(&[]).is_ok());
+        let mut received = Vec::new();
+        while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+            if let ServerEvent::PacketReceived(_, _, _, data) = event {
+                received.push(data.to_vec());
+            }
+        }
+        assert_eq!(received, [&payload[..max], &[]]);
+    }
+
+    #[test]
+    fn test_next_event_ref() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let mut client = Client::new(network.bind(2), "test");
+        client.connect_with_payload(Endpoint::local_port(1), b"token").unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        client.update();
+        match server.next_event().unwrap() {
+            Some(ServerEvent::ClientConnected(0, _, token)) => assert_eq!(token, b"token"),
+            event => panic!("unexpected event {:?}", event)
+        }
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Connecting(1))));
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Connected(0))));
+
+        client.send(&[1, 2, 3]).unwrap();
+        client.send_batched(&[4]).unwrap();
+        client.send_batched(&[5, 6]).unwrap();
+        client.flush().unwrap();
+        let mut received = Vec::new();
+        while let Some(event) = server.next_event().unwrap() {
+            if let ServerEvent::PacketReceived(0, _, _, data) = event {
+                received.push(data.to_vec());
+            }
+        }
+        assert_eq!(received, [vec![1, 2, 3], vec![4], vec![5, 6]]);
+    }
+
+    /// Compares the borrowing `next_event` with the copying `next_event_into`.
+    /// Run with `cargo test --release test_next_event_benchmark -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn test_next_event_benchmark() {
+        const ROUNDS: usize = 200;
+        const PACKETS: usize = 250;
+        let network = MemoryNetwork::default();
+        let (mut server, mut client) = connected_pair(&network);
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+        let payload = vec![7; client.connection().unwrap().max_payload_size()];
+        let mut run = |copy: bool| {
+            let mut elapsed = Duration::ZERO;
+            let mut bytes = 0;
+            for _ in 0..ROUNDS {
+                for _ in 0..PACKETS {
+                    client.send_unreliable(&payload).unwrap();
+                }
+                let start = Instant::now();
+                loop {
+                    let event = match copy {
+                        true => server.next_event_into(&mut buffer).unwrap(),
+                        false => server.next_event().unwrap()
+                    };
+                    match event {
+                        Some(ServerEvent::PacketReceived(_, _, _, data)) => bytes += data.len(),
+                        Some(_) => {},
+                        None => break
+                    }
+                }
+                elapsed += start.elapsed();
+                while client.next_event_into(&mut buffer).unwrap().is_some() {}
+            }
+            assert_eq!(bytes, ROUNDS * PACKETS * payload.len());
+            elapsed
+        };
+        let copied = run(true);
+        let borrowed = run(false);
+        let per_packet = |elapsed: Duration| elapsed / (ROUNDS * PACKETS) as u32;
+        println!("next_event_into: {:?} per packet", per_packet(copied));
+        println!("next_event:      {:?} per packet", per_packet(borrowed));
+    }
+
+    #[test]
+    fn test_socket_stats() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let stranger = network.bind(2);
+
+        stranger.send_to(&[0xFF; 64], Endpoint::local_port(1)).unwrap();
+        stranger.send_to(&[MAGIC[0], MAGIC[1], 0x03, 0, 0, 0, 0], Endpoint::local_port(1)).unwrap();
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+        assert_eq!(server.socket_stats(), SocketStats { packets_received: 2, bytes_received: 71, foreign_packets: 1, invalid_packets: 1, ..Default::default() });
+
+        let mut packet = [0u8; MAX_PACKET_SIZE];
+        let len = Packet::ConnectionDenied(5, None).write(&mut packet, &ProtocolId::new("test", AckWidth::default()), None, 0, 0).unwrap().len();
+        packet[len - 1] ^= 0xFF;
+        stranger.send_to(&packet[..len], Endpoint::local_port(1)).unwrap();
+        server.reset_socket_stats();
+        assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+        assert_eq!(server.socket_stats(), SocketStats { packets_received: 1, bytes_received: len as u64, bad_signature_packets: 1, ..Default::default() });
+    }
+
+    #[cfg(feature = "network_simulator")]
+    #[test]
+    fn test_socket_traffic_stats() {
+        use crate::{NetworkOptions, TransportExtension};
+        let options = NetworkOptions {
+            packet_loss: 0.3,
+            ..Default::default()
+        };
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1).with_options(options), "test", 1);
+        let mut client = Client::new(network.bind(2), "test");
+        client.connect_with(Endpoint::local_port(1), ConnectConfig { retry_interval: Duration::ZERO, max_attempts: 100, ..Default::default() }).unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        for i in 0..100u8 {
+            client.update();
+            server.update();
+            if client.is_connected() {
+                let _ = client.send_unreliable(&[i; 16]);
+            }
+            while server.next_event_into(&mut buffer).unwrap().is_some() {}
+            while client.next_event_into(&mut buffer).unwrap().is_some() {}
+        }
+        let sent = client.socket_stats();
+        let received = server.socket_stats();
+        assert!(received.packets_received > 0);
+        assert!(sent.packets_sent > received.packets_received);
+        assert!(sent.bytes_sent > received.bytes_received);
+        assert!(client.socket_stats().packets_received > 0);
+    }
+
+    #[test]
+    fn test_query() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let mut client = Client::new(network.bind(2), "test");
+        server.set_query_response(b"server info").unwrap();
+        assert!(matches!(server.set_query_response(&[0; MAX_QUERY_RESPONSE_SIZE + 1]), Err(ConnectionError::PayloadTooLarge { .. })));
+
+        client.query(Endpoint::local_port(1)).unwrap();
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+        assert_eq!(server.connected_clients().count(), 0);
+
+        match client.next_event_into(&mut buffer).unwrap() {
+            Some(ClientEvent::QueryResponse(addrs, _, data)) => {
+                assert_eq!(addrs, Endpoint::local_port(1));
+                assert_eq!(data, b"server info");
+            },
+            e => panic!("unexpected event: {:?}", e)
+        }
+        assert!(client.is_disconnected());
+    }
+
+    #[test]
+    fn test_connecting_events() {
+        let network = MemoryNetwork::default();
+        let mut client = Client::new(network.bind(2), "test");
+        client.connect(Endpoint::local_port(1)).unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        client.update();
+        client.update();
+        assert_eq!(client.connection_attempts(), 1);
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Connecting(1))));
+        assert!(client.next_event_into(&mut buffer).unwrap().is_none());
+
+        std::thread::sleep(CONNECTION_RETRY_INTERVAL);
+        client.update();
+        assert_eq!(client.connection_attempts(), 2);
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Connecting(2))));
+        assert!(client.next_event_into(&mut buffer).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_connect_config() {
+        let network = MemoryNetwork::default();
+        let server = network.bind(1);
+        let mut client = Client::new(network.bind(2), "test");
+        client.connect_with(Endpoint::local_port(1), ConnectConfig { retry_interval: Duration::ZERO, max_attempts: 3, ..Default::default() }).unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        for _ in 0..10 {
+            client.update();
+        }
+        let mut requests = 0;
+        while server.recv_from(&mut buffer).is_ok() {
+            requests += 1;
+        }
+        assert_eq!(requests, 3);
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Disconnected(ClientDisconnectReason::TimedOut))));
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let mut client = Client::new(network.bind(2), "test");
+        assert!(matches!(client.reconnect(), Err(ConnectionError::NoRemoteAddress)));
+        client.connect_with_payload(Endpoint::local_port(1), b"token").unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        client.update();
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ClientConnected(0, _, _))));
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Connecting(1))));
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Connected(0))));
+
+        client.send(&[1]).unwrap();
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::PacketReceived(0, _, _, _))));
+        server.send(0, &[2]).unwrap();
+        server.disconnect(0).unwrap();
+        for _ in 0..DISCONNECT_REDUNDANCY {
+            assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+            server.update();
+        }
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ClientDisconnected(0, _))));
+        loop {
+            match client.next_event_into(&mut buffer).unwrap() {
+                Some(ClientEvent::Disconnected(reason)) => break assert!(matches!(reason, ClientDisconnectReason::Disconnected)),
+                Some(_) => continue,
+                None => panic!("client was not disconnected")
+            }
+        }
+        assert!(client.remote_addr().is_none());
+
+        client.reconnect().unwrap();
+        assert_eq!(client.remote_addr(), Some(Endpoint::local_port(1)));
+        client.update();
+        match server.next_event_into(&mut buffer).unwrap() {
+            Some(ServerEvent::ClientConnected(0, _, token)) => assert_eq!(token, b"token"),
+            event => panic!("unexpected event {:?}", event)
+        }
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Connecting(1))));
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Connected(0))));
+    }
+
+    #[test]
+    fn test_connect_address() {
+        let network = MemoryNetwork::default();
+        let mut client = Client::new(network.bind(2), "test");
+        assert!(matches!(client.connect(&[][..] as &[std::net::SocketAddr]), Err(ConnectError::NoUsableAddress)));
+        assert!(matches!(client.connect("not an address"), Err(ConnectError::AddressResolution(_))));
+        client.connect(("::1".parse::<std::net::IpAddr>().unwrap(), 1)).unwrap();
+        client.connect(["[::1]:1".parse().unwrap(), Endpoint::local_port(1)].as_slice()).unwrap();
+        assert_eq!(client.remote_addr(), Some(Endpoint::local_port(1)));
+        client.connect("127.0.0.1:1").unwrap();
+        assert_eq!(client.remote_addr(), Some(Endpoint::local_port(1)));
+    }
+
+    #[test]
+    fn test_candidate_rotation() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let dead = network.bind(3);
+        let mut client = Client::new(network.bind(2), "test");
+        let config = ConnectConfig { retry_interval: Duration::ZERO, max_attempts: 2, ..Default::default() };
+        client.connect_with([Endpoint::local_port(3), Endpoint::local_port(1)].as_slice(), config).unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        client.update();
+        client.update();
+        assert_eq!(client.remote_addr(), Some(Endpoint::local_port(3)));
+        client.update();
+        assert_eq!(client.remote_addr(), Some(Endpoint::local_port(1)));
+
+        let nonce = request_nonce(&dead);
+        let accepted = Packet::ConnectionAccepted(7, None, false, nonce).write(&mut buffer, &ProtocolId::new("test", AckWidth::default()), None, 0, 0).unwrap();
+        dead.send_to(accepted, Endpoint::local_port(2)).unwrap();
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ClientConnected(0, _, _))));
+        let mut events = Vec::new();
+        while let Some(event) = client.next_event_into(&mut buffer).unwrap() {
+            events.push(format!("{:?}", event));
+        }
+        assert_eq!(events, ["Connecting(3)", "Connected(0)"]);
+        assert_eq!(client.remote_addr(), Some(Endpoint::local_port(1)));
+    }
+
+    #[test]
+    fn test_graceful_disconnect() {
+        let network = MemoryNetwork::default();
+        let (mut server, mut client) = connected_pair(&network);
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+        client.disconnect().unwrap();
+        assert!(!client.is_connected() && !client.is_disconnected());
+        assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+        client.update();
+        assert!(client.next_event_into(&mut buffer).unwrap().is_none());
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ClientDisconnected(0, ServerDisconnectReason::Disconnected))));
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Disconnected(ClientDisconnectReason::Disconnected))));
+        assert!(client.is_disconnected());
+    }
+
+    #[test]
+    fn test_connect_while_connected() {
+        let network = MemoryNetwork::default();
+        let mut server_a = Server::new(network.bind(1), "test", 1);
+        let mut server_b = Server::new(network.bind(3), "test", 1);
+        let mut client = Client::new(network.bind(2), "test");
+        assert_eq!(connect(&mut server_a, &mut client), Some(0));
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+        client.send(&[1]).unwrap();
+        assert!(matches!(server_a.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::PacketReceived(0, _, _, _))));
+        server_a.send(0, &[2]).unwrap();
+
+        client.connect(Endpoint::local_port(3)).unwrap();
+        assert!(matches!(server_a.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ClientDisconnected(0, ServerDisconnectReason::Disconnected))));
+        client.update();
+        assert!(matches!(server_b.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ClientConnected(0, _, _))));
+        let mut events = Vec::new();
+        while let Some(event) = client.next_event_into(&mut buffer).unwrap() {
+            events.push(format!("{:?}", event));
+        }
+        assert_eq!(events, ["Disconnected(Disconnected)", "Connecting(1)", "Connected(0)"]);
+        assert_eq!(client.remote_addr(), Some(Endpoint::local_port(3)));
+    }
+
+    #[test]
+    fn test_client_stats() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let mut client = Client::new(network.bind(2), "test");
+        assert_eq!(client.stats(), ClientStats::default());
+        client.connect(Endpoint::local_port(1)).unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        client.update();
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ClientConnected(0, _, _))));
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Connecting(1))));
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Connected(0))));
+
+        client.send(&[1, 2, 3]).unwrap();
+        client.send(&[4, 5, 6]).unwrap();
+        let stats = client.stats();
+        assert_eq!(stats.packets_sent, 2);
+        assert_eq!(stats.bytes_sent, 2 * (3 + 2 + 1 + 4 + 4 + 2 * SEQUENCE_NUMBER_SIZE + 4 + 1) as u64);
+        assert_eq!(stats.in_flight, 2);
+        assert_eq!(stats.send_rate_bps, 8 * stats.bytes_sent);
+
+        while server.next_event_into(&mut buffer).unwrap().is_some() {}
+        server.send(0, &[7]).unwrap();
+        while client.next_event_into(&mut buffer).unwrap().is_some() {}
+        let stats = client.stats();
+        assert_eq!(stats.packets_received, 1);
+        assert_eq!(stats.in_flight, 0);
+        assert_eq!(stats.duplicates_dropped, 0);
+
+        let stats = server.client_stats(0).unwrap();
+        assert_eq!(stats.packets_received, 2);
+        assert_eq!(stats.packets_sent, 2);
+        assert_eq!(stats.in_flight, 1);
+        assert_eq!(stats.recv_rate_bps, 8 * stats.bytes_received);
+        assert_eq!(server.all_stats().map(|(id, stats)| (id, stats.packets_received)).collect::<Vec<_>>(), [(0, 2)]);
+        assert!(matches!(server.client_stats(1), Err(ConnectionError::Disconnected)));
+
+        // a replayed packet is dropped and counted
+        let seq = server.send(0, &[8]).unwrap();
+        let packets = network.hold(2);
+        network.deliver(2, packets.clone());
+        network.deliver(2, packets);
+        while client.next_event_into(&mut buffer).unwrap().is_some() {}
+        let stats = client.stats();
+        assert_eq!((stats.duplicates_dropped, stats.stale_dropped), (1, 0));
+        assert_eq!(client.connection().unwrap().received_window().latest(), seq);
+    }
+
+    #[test]
+    fn test_connection_config() {
+        let network = MemoryNetwork::default();
+        let invalid = ConnectionConfig { keepalive_interval: Duration::from_secs(5), ..Default::default() };
+        assert!(matches!(Server::new_with_config(network.bind(1), "test", 1, invalid), Err(ConnectionError::InvalidConfig(_))));
+        assert!(matches!(Client::new_with_config(network.bind(2), "test", invalid), Err(ConnectionError::InvalidConfig(_))));
+
+        let config = ConnectionConfig { timeout: Duration::from_millis(50), keepalive_interval: Duration::from_millis(10), ..Default::default() };
+        let mut server = Server::new_with_config(network.bind(1), "test", 1, config).unwrap();
+        let mut client = Client::new(network.bind(2), "test");
+        client.connect(Endpoint::local_port(1)).unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        client.update();
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ClientConnected(0, _, _))));
+        std::thread::sleep(Duration::from_millis(60));
+        server.update();
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ClientDisconnected(0, ServerDisconnectReason::TimedOut))));
+    }
+
+    #[test]
+    fn test_events() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let mut client = Client::new(network.bind(2), "test");
+        client.connect(Endpoint::local_port(1)).unwrap();
+
+        client.update();
+        assert!(matches!(server.events().collect::<Vec<_>>().as_slice(), [ServerEventOwned::ClientConnected(0, _, _)]));
+        assert!(matches!(client.events().collect::<Vec<_>>().as_slice(), [ClientEventOwned::Connecting(1), ClientEventOwned::Connected(0)]));
+
+        client.send(&[1, 2, 3]).unwrap();
+        client.send(&[4, 5]).unwrap();
+        let mut events = server.events();
+        let received: Vec<_> = events
+            .by_ref()
+            .filter_map(|event| match event {
+                ServerEventOwned::PacketReceived(0, _, _, data) => Some(data),
+                _ => None
+            })
+            .collect();
+        assert!(events.take_error().is_none());
+        assert_eq!(received, [Box::from([1, 2, 3]), Box::from([4, 5])]);
+    }
+
+    #[test]
+    fn test_send_reliable() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let mut client = Client::new(network.bind(2), "test");
+        assert!(matches!(client.send_reliable(&[1]), Err(ConnectionError::Disconnected)));
+        connect(&mut server, &mut client);
+        assert!(client.connection().unwrap().message_channel().is_none());
+
+        client.send_reliable(&[1, 2]).unwrap();
+        client.send_reliable(&[3]).unwrap();
+        server.send_reliable(0, &[4; 2000]).unwrap();
+        client.update();
+        server.update();
+        let received: Vec<_> = server.events().collect();
+        assert!(matches!(received.as_slice(), [ServerEventOwned::ReliableMessage(0, a), ServerEventOwned::ReliableMessage(0, b)] if a.as_ref() == [1, 2] && b.as_ref() == [3]));
+        let received: Vec<_> = client.events().collect();
+        assert!(matches!(received.as_slice(), [ClientEventOwned::ReliableMessage(msg)] if msg.as_ref() == [4; 2000]));
+
+        // the acks are delayed, as there are no packets they could be piggybacked on. They don't produce any events.
+        std::thread::sleep(MAX_ACK_DELAY);
+        client.update();
+        server.update();
+        assert_eq!(server.events().count(), 0);
+        assert_eq!(client.events().count(), 0);
+        assert!(!client.connection().unwrap().message_channel().unwrap().has_unsend_messages());
+        assert!(!server.connection(0).unwrap().message_channel().unwrap().has_unsend_messages());
+    }
+
+    #[test]
+    fn test_server_channels() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let mut client = Client::new(network.bind(2), "test");
+        let mut server_channels = ServerChannels::default();
+        let mut client_channels = Channels::new([MessageChannel::new()]);
+        client.connect(Endpoint::local_port(1)).unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        client.update();
+        while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+            server_channels.handle_event(&event).unwrap();
+        }
+        while client.next_event_into(&mut buffer).unwrap().is_some() {}
+        assert!(server_channels.channels(0).is_some());
+
+        client_channels.queue_message(0, &[1]).unwrap();
+        let seq = client.connection().unwrap().peek_next_sequence_number();
+        client.send(client_channels.send_packets(seq).unwrap()).unwrap();
+        while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+            server_channels.handle_event(&event).unwrap();
+        }
+        assert_eq!(server_channels.receive_messages().collect::<Vec<_>>(), [(0, 0, Box::from([1]))]);
+
+        server_channels.queue(0, &[2]).unwrap();
+        assert!(matches!(server_channels.broadcast_reliable(&[3]).as_slice(), [(0, Ok(()))]));
+        assert!(matches!(server_channels.queue(1, &[4]), Err(ConnectionError::Disconnected)));
+        assert!(server_channels.flush(&mut server).is_empty());
+        while let Some(event) = client.next_event_into(&mut buffer).unwrap() {
+            match event {
+                ClientEvent::PacketReceived(_, _, data) => client_channels.on_receive(data).unwrap(),
+                ClientEvent::PacketAcknowledged(seq, _, _) => client_channels.on_ack(seq),
+                _ => {}
+            }
+        }
+        assert_eq!(client_channels.receive_messages().collect::<Vec<_>>(), [(0, Box::from([2])), (0, Box::from([3]))]);
+        assert!(!client_channels.has_unsend_messages());
+
+        // nothing else is sent that the ack could be piggybacked on
+        std::thread::sleep(MAX_ACK_DELAY);
+        client.update();
+        assert!(server_channels.channels(0).unwrap().has_unsend_messages());
+        while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+            server_channels.handle_event(&event).unwrap();
+        }
+        assert!(!server_channels.channels(0).unwrap().has_unsend_messages());
+
+        client.disconnect().unwrap();
+        client.update();
+        while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+            server_channels.handle_event(&event).unwrap();
+        }
+        assert!(server_channels.channels(0).is_none());
+    }
+
+    #[test]
+    fn test_server_channels_approval() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        server.set_approval_required(true);
+        let mut client = Client::new(network.bind(2), "test");
+        let mut server_channels = ServerChannels::default();
+        client.connect(Endpoint::local_port(1)).unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        client.update();
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ConnectionRequested(..))));
+        assert_eq!(server.accept_pending(Endpoint::local_port(2)).unwrap(), 0);
+        while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+            server_channels.handle_event(&event).unwrap();
+        }
+        server_channels.queue(0, &[1]).unwrap();
+        assert!(server_channels.flush(&mut server).is_empty());
+    }
+
+    #[test]
+    fn test_server_channels_batched() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let mut client = Client::new(network.bind(2), "test");
+        let mut server_channels = ServerChannels::default();
+        client.connect(Endpoint::local_port(1)).unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        client.update();
+        while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+            server_channels.handle_event(&event).unwrap();
+        }
+        while client.next_event_into(&mut buffer).unwrap().is_some() {}
+
+        // the batch goes out first, the channels must not mistake its sequence number for their own
+        server.send_batched(0, &[9]).unwrap();
+        server_channels.queue(0, &[2]).unwrap();
+        assert!(server_channels.flush(&mut server).is_empty());
+        let mut packets = network.hold(2);
+        assert_eq!(packets.len(), 2);
+        packets.truncate(1);
+        network.deliver(2, packets);
+        let received: Vec<_> = client.events().collect();
+        assert!(matches!(received.as_slice(), [ClientEventOwned::PacketReceived(_, _, data)] if data.as_ref() == [9]));
+
+        std::thread::sleep(MAX_ACK_DELAY);
+        client.update();
+        while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+            server_channels.handle_event(&event).unwrap();
+        }
+        assert!(server_channels.channels(0).unwrap().has_unsend_messages());
+    }
+
+    #[test]
+    fn test_next_event_owned() {
+        fn assert_send<T: Send + 'static>(_: &T) {}
+
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let mut client = Client::new(network.bind(2), "test");
+        client.connect_with_payload(Endpoint::local_port(1), b"token").unwrap();
+
+        client.update();
+        let event = server.next_event_owned().unwrap().unwrap();
+        assert_send(&event);
+        let handle = std::thread::spawn(move || match event {
+            ServerEventOwned::ClientConnected(0, _, token) => token,
+            event => panic!("unexpected event {:?}", event)
+        });
+        assert_eq!(handle.join().unwrap().as_ref(), b"token");
+
+        let event = client.next_event_owned().unwrap().unwrap();
+        assert_send(&event);
+        assert!(matches!(event, ClientEventOwned::Connecting(1)));
+    }
+
+    #[test]
+    fn test_next_event_into_small_buffer() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let mut client = Client::new(network.bind(2), "test");
+        client.connect_with_payload(Endpoint::local_port(1), b"token").unwrap();
+
+        client.update();
+        let mut buffer = [0u8; 4];
+        let err = server.next_event_into(&mut buffer).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_disconnect_now() {
+        let network = MemoryNetwork::default();
+        let server = network.bind(1);
+        let mut client = Client::new(network.bind(2), "test");
+        client.connect(Endpoint::local_port(1)).unwrap();
+        client.update();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let nonce = request_nonce(&server);
+        let accepted = Packet::ConnectionAccepted(0, None, false, nonce).write(&mut buffer, &ProtocolId::new("test", AckWidth::default()), None, 0, 0).unwrap();
+        server.send_to(accepted, Endpoint::local_port(2)).unwrap();
+        while client.next_event_into(&mut buffer).unwrap().is_some() {}
+        assert!(client.is_connected());
+        while server.recv_from(&mut buffer).is_ok() {}
+
+        client.disconnect_now().unwrap();
+        for _ in 0..DISCONNECT_REDUNDANCY {
+            client.update();
+            assert!(client.next_event_into(&mut buffer).unwrap().is_none());
+        }
+        client.update();
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Disconnected(ClientDisconnectReason::Disconnected))));
+        let mut packets = 0;
+        while server.recv_from(&mut buffer).is_ok() {
+            packets += 1;
+        }
+        assert_eq!(packets, DISCONNECT_REDUNDANCY);
+    }
+
+    #[test]
+    fn test_would_block() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let mut client = Client::new(network.bind_flaky(2), "test");
+        client.connect(Endpoint::local_port(1)).unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        while !client.is_connected() {
+            client.update();
+            server.update();
+            while server.next_event_into(&mut buffer).unwrap().is_some() {}
+            while client.next_event_into(&mut buffer).unwrap().is_some() {}
+        }
+
+        for i in 0..20u8 {
+            client.send(&[i]).unwrap();
+        }
+        let mut received = Vec::new();
+        for _ in 0..100 {
+            client.update();
+            server.update();
+            while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+                if let ServerEvent::PacketReceived(0, _, _, payload) = event {
+                    received.push(payload[0]);
+                }
+            }
+            while let Some(event) = client.next_event_into(&mut buffer).unwrap() {
+                assert!(!matches!(event, ClientEvent::Disconnected(_)), "unexpected event {:?}", event);
+            }
+        }
+        assert_eq!(received, (0..20).collect::<Vec<u8>>());
+        assert!(client.is_connected());
+    }
+
+    #[test]
+    fn test_stale_accept() {
+        let network = MemoryNetwork::default();
+        let server = network.bind(1);
+        let mut client = Client::new(network.bind(2), "test");
+        client.connect(Endpoint::local_port(1)).unwrap();
+        client.update();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let old = request_nonce(&server);
+        client.connect(Endpoint::local_port(1)).unwrap();
+        client.update();
+        let stale = Packet::ConnectionAccepted(0, None, false, old).write(&mut buffer, &ProtocolId::new("test", AckWidth::default()), None, 0, 0).unwrap();
+        server.send_to(stale, Endpoint::local_port(2)).unwrap();
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Connecting(1))));
+        assert!(client.next_event_into(&mut buffer).unwrap().is_none());
+        assert!(!client.is_connected());
+
+        let current = request_nonce(&server);
+        assert_ne!(old, current);
+        let accepted = Packet::ConnectionAccepted(0, None, false, current).write(&mut buffer, &ProtocolId::new("test", AckWidth::default()), None, 0, 0).unwrap();
+        server.send_to(accepted, Endpoint::local_port(2)).unwrap();
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Connected(0))));
+    }
+
+    #[test]
+    fn test_server_restart() {
+        let network = MemoryNetwork::default();
+        let (mut server, mut client) = connected_pair(&network);
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        assert!(client.is_connected());
+
+        // packets of the old connection that are stuck in the network
+        server.send(0, &[1]).unwrap();
+        client.send(&[2]).unwrap();
+        let to_client = network.hold(2);
+        let to_server = network.hold(1);
+
+        drop(server);
+        let mut server = Server::new(network.bind(1), "test", 1);
+        client.send(&[3]).unwrap();
+        client.send(&[4]).unwrap();
+        assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+        // only the first packet of the burst is answered
+        let replies = network.hold(2);
+        assert_eq!(replies.len(), 1);
+        network.deliver(2, replies);
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Disconnected(ClientDisconnectReason::ConnectionReset))));
+
+        assert_eq!(connect(&mut server, &mut client), Some(0));
+
+        network.deliver(1, to_server);
+        network.deliver(2, to_client);
+        assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+        assert!(client.next_event_into(&mut buffer).unwrap().is_none());
+        assert!(client.is_connected());
+        assert_eq!(server.connected_clients().count(), 1);
+    }
+
+    #[test]
+    fn test_in_flight() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let mut client = Client::new(network.bind(2), "test");
+        assert!(client.in_flight().is_err());
+        connect(&mut server, &mut client);
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        assert_eq!(client.in_flight().unwrap(), 0);
+
+        for i in 1..=3 {
+            client.send(&[i]).unwrap();
+            assert_eq!(client.in_flight().unwrap(), i as usize);
+        }
+        server.send(0, &[4]).unwrap();
+        assert_eq!(server.in_flight(0).unwrap(), 1);
+
+        while server.next_event_into(&mut buffer).unwrap().is_some() {}
+        server.send(0, &[5]).unwrap();
+        while client.next_event_into(&mut buffer).unwrap().is_some() {}
+        assert_eq!(client.in_flight().unwrap(), 0);
+        client.send(&[6]).unwrap();
+        while server.next_event_into(&mut buffer).unwrap().is_some() {}
+        assert_eq!(server.in_flight(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 3);
+        let mut clients: Vec<Client> = (2..5).map(|port| Client::new(network.bind(port), "test")).collect();
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        for client in clients.iter_mut() {
+            connect(&mut server, client);
+            assert!(client.is_connected());
+        }
+        server.disconnect(2).unwrap();
+
+        let sent = server.broadcast(&[1]);
+        assert_eq!(sent.iter().map(|(id, _)| *id).collect::<Vec<_>>(), [0, 1]);
+        assert!(sent.iter().all(|(_, result)| result.is_ok()));
+        let sent = server.broadcast_filtered(&[2], |id| id != 0);
+        assert_eq!(sent.iter().map(|(id, _)| *id).collect::<Vec<_>>(), [1]);
+
+        let mut received = Vec::new();
+        for (id, client) in clients.iter_mut().enumerate() {
+            while let Some(event) = client.next_event_into(&mut buffer).unwrap() {
+                if let ClientEvent::PacketReceived(_, _, payload) = event {
+                    received.push((id, payload[0]));
+                }
+            }
+        }
+        assert_eq!(received, [(0, 1), (1, 1), (1, 2)]);
+    }
+
+    #[test]
+    fn test_broadcast_acks() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 2);
+        let mut clients: Vec<Client> = (2..4).map(|port| Client::new(network.bind(port), "test")).collect();
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        for client in clients.iter_mut() {
+            connect(&mut server, client);
+        }
+
+        let sent: Vec<(u16, SequenceNumber)> = server.broadcast(&[1]).into_iter()
+            .map(|(id, result)| (id, result.unwrap()))
+            .collect();
+        // the broadcast to client 1 is lost
+        network.hold(3);
+        for client in clients.iter_mut() {
+            while client.next_event_into(&mut buffer).unwrap().is_some() {}
+            client.send(&[2]).unwrap();
+        }
+        let mut acked = Vec::new();
+        while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+            if let ServerEvent::PacketAcknowledged(id, seq, _, _) = event {
+                acked.push((id, seq));
+            }
+        }
+        assert_eq!(acked, [sent[0]]);
+
+        let missing: Vec<u16> = sent.iter().filter(|sent| !acked.contains(sent)).map(|(id, _)| *id).collect();
+        assert_eq!(missing, [1]);
+        assert_eq!(server.send_to_many(missing, &[1]).len(), 1);
+        assert!(matches!(clients[1].next_event_into(&mut buffer).unwrap(), Some(ClientEvent::PacketReceived(_, _, [1]))));
+    }
+
+    #[test]
+    fn test_send_to_many() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 3);
+        let mut clients: Vec<Client> = (2..4).map(|port| Client::new(network.bind(port), "test")).collect();
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        for client in clients.iter_mut() {
+            connect(&mut server, client);
+        }
+
+        let sent = server.send_to_all_except(0, &[1]);
+        assert_eq!(sent.iter().map(|(id, _)| *id).collect::<Vec<_>>(), [1]);
+        let sent = server.send_to_many([1, 2, 7], &[2]);
+        assert_eq!(sent.len(), 3);
+        assert!(sent[0].1.is_ok());
+        assert!(matches!(sent[1], (2, Err(ConnectionError::Disconnected))));
+        assert!(matches!(sent[2], (7, Err(ConnectionError::Disconnected))));
+
+        assert!(clients[0].next_event_into(&mut buffer).unwrap().is_none());
+        let mut received = Vec::new();
+        while let Some(event) = clients[1].next_event_into(&mut buffer).unwrap() {
+            if let ClientEvent::PacketReceived(_, _, payload) = event {
+                received.push(payload[0]);
+            }
+        }
+        assert_eq!(received, [1, 2]);
+    }
+
+    #[test]
+    fn test_connection_approval() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 2);
+        server.set_approval_required(true);
+        let mut accepted = Client::new(network.bind(2), "test");
+        let mut denied = Client::new(network.bind(3), "test");
+        let config = ConnectConfig { retry_interval: Duration::ZERO, ..Default::default() };
+        accepted.connect_with(Endpoint::local_port(1), config).unwrap();
+        denied.connect_with_payload(Endpoint::local_port(1), b"bad").unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        accepted.update();
+        denied.update();
+        match server.next_event_into(&mut buffer).unwrap() {
+            Some(ServerEvent::ConnectionRequested(addrs, _)) => assert_eq!(addrs, Endpoint::local_port(2)),
+            event => panic!("unexpected event {:?}", event)
+        }
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ConnectionRequested(_, b"bad"))));
+        assert!(server.connected_clients().next().is_none());
+
+        accepted.update();
+        assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+
+        assert_eq!(server.accept_pending(Endpoint::local_port(2)).unwrap(), 0);
+        server.deny_pending(Endpoint::local_port(3), DenyReason::Rejected).unwrap();
+        assert!(matches!(server.accept_pending(Endpoint::local_port(3)), Err(ConnectionError::Disconnected)));
+        assert_eq!(server.connected_clients().collect::<Vec<_>>(), [0]);
+        match server.next_event_into(&mut buffer).unwrap() {
+            Some(ServerEvent::ClientConnected(0, addrs, payload)) => assert_eq!((addrs, payload), (Endpoint::local_port(2), &[][..])),
+            event => panic!("unexpected event {:?}", event)
+        }
+        match server.next_event_into(&mut buffer).unwrap() {
+            Some(ServerEvent::ConnectionDenied(addrs, DenyReason::Rejected)) => assert_eq!(addrs, Endpoint::local_port(3)),
+            event => panic!("unexpected event {:?}", event)
+        }
+
+        let mut events = Vec::new();
+        while let Some(event) = accepted.next_event_into(&mut buffer).unwrap() {
+            events.push(format!("{:?}", event));
+        }
+        assert_eq!(events, ["Connecting(2)", "Connected(0)"]);
+        assert!(matches!(denied.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Connecting(1))));
+        assert!(matches!(denied.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Disconnected(ClientDisconnectReason::ConnectionDenied(DenyReason::Rejected)))));
+    }
+
+    #[test]
+    fn test_set_accepting() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 3);
+        let config = ConnectConfig { retry_interval: Duration::ZERO, ..Default::default() };
+        let mut connected = Client::new(network.bind(2), "test");
+        let mut refused = Client::new(network.bind(3), "test");
+        let mut pending = Client::new(network.bind(4), "test");
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+        connected.connect_with(Endpoint::local_port(1), config).unwrap();
+        connected.update();
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ClientConnected(0, _, _))));
+        network.hold(2);
+        server.set_accepting(false);
+        assert!(!server.is_accepting());
+        connected.update();
+        assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+        while connected.next_event_into(&mut buffer).unwrap().is_some() {}
+        assert!(connected.is_connected());
+
+        refused.connect(Endpoint::local_port(1)).unwrap();
+        refused.update();
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ConnectionDenied(_, DenyReason::NotAccepting))));
+        assert!(matches!(refused.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Connecting(1))));
+        assert!(matches!(refused.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Disconnected(ClientDisconnectReason::ConnectionDenied(DenyReason::NotAccepting)))));
+
+        // requests that wait for approval are not affected
+        server.set_accepting(true);
+        server.set_approval_required(true);
+        pending.connect_with(Endpoint::local_port(1), config).unwrap();
+        pending.update();
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ConnectionRequested(..))));
+        server.set_accepting(false);
+        pending.update();
+        assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+        assert_eq!(server.accept_pending(Endpoint::local_port(4)).unwrap(), 1);
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ClientConnected(1, _, _))));
+        while pending.next_event_into(&mut buffer).unwrap().is_some() {}
+        assert!(pending.is_connected());
+    }
+
+    #[test]
+    fn test_max_connections_per_ip() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 4);
+        server.set_max_connections_per_ip(Some(1));
+        let mut first = Client::new(network.bind(2), "test");
+        let mut second = Client::new(network.bind(3), "test");
+        first.connect(Endpoint::local_port(1)).unwrap();
+        second.connect(Endpoint::local_port(1)).unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        first.update();
+        second.update();
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ClientConnected(0, _, _))));
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ConnectionDenied(_, DenyReason::TooManyConnections))));
+        assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+        while first.next_event_into(&mut buffer).unwrap().is_some() {}
+        assert!(first.is_connected());
+        assert!(matches!(second.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Connecting(1))));
+        assert!(matches!(second.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Disconnected(ClientDisconnectReason::ConnectionDenied(DenyReason::TooManyConnections)))));
+
+        server.set_max_connections_per_ip(None);
+        second.connect(Endpoint::local_port(1)).unwrap();
+        second.update();
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ClientConnected(1, _, _))));
+    }
+
+    #[test]
+    fn test_set_max_clients() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let mut clients: Vec<Client> = (2..5).map(|port| Client::new(network.bind(port), "test")).collect();
+
+        assert!(!server.is_full());
+        assert_eq!(connect(&mut server, &mut clients[0]), Some(0));
+        assert!(server.is_full());
+        assert_eq!(connect(&mut server, &mut clients[1]), None);
+        server.set_max_clients(3);
+        assert!(!server.is_full());
+        assert_eq!(server.max_clients(), 3);
+        assert_eq!(server.capacity(), 3);
+        assert_eq!(connect(&mut server, &mut clients[1]), Some(1));
+        assert_eq!(connect(&mut server, &mut clients[2]), Some(2));
+        assert_eq!(server.connection_count(), 3);
+        assert_eq!(server.connected_clients().collect::<Vec<_>>(), [0, 1, 2]);
+
+        server.set_max_clients(1);
+        assert!(server.is_full());
+        assert_eq!(server.connection_count(), 3);
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        clients[2].disconnect().unwrap();
+        clients[2].update();
+        while server.next_event_into(&mut buffer).unwrap().is_some() {}
+        assert_eq!(server.connection_count(), 2);
+        assert_eq!(connect(&mut server, &mut clients[2]), None);
+    }
+
+    #[test]
+    fn test_user_data() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1).with_data::<String>();
+        let mut client = Client::new(network.bind(2), "test");
+        assert!(matches!(server.set_data(0, "nobody".into()), Err(ConnectionError::Disconnected)));
+        assert_eq!(connect(&mut server, &mut client), Some(0));
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        assert!(server.data(0).is_none());
+        assert_eq!(server.set_data(0, "player".into()).unwrap(), None);
+        server.data_mut(0).unwrap().push_str(" one");
+        assert_eq!(server.data(0).map(String::as_str), Some("player one"));
+
+        server.disconnect(0).unwrap();
+        assert!(server.data(0).is_some());
+        while !matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ClientDisconnected(0, _))) {
+            server.update();
+        }
+        assert!(server.data(0).is_none());
+    }
+
+    #[test]
+    fn test_kick() {
+        let network = MemoryNetwork::default();
+        let (mut server, mut client) = connected_pair(&network);
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+        server.kick(0, DisconnectCode(3)).unwrap();
+        for _ in 0..3 {
+            server.update();
+            drop(network.hold(2));
+        }
+        assert!(client.next_event_into(&mut buffer).unwrap().is_none());
+        while !matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ClientDisconnected(0, ServerDisconnectReason::Kicked(DisconnectCode(3))))) {
+            server.update();
+        }
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Disconnected(ClientDisconnectReason::Kicked(DisconnectCode(3))))));
+    }
+
+    #[test]
+    fn test_migration() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new_with_authentication(network.bind(1), "test", 1, Authentication::Mac(KEY));
+        let mut client = Client::new_with_authentication(network.bind(2), "test", Authentication::Mac(KEY));
+        assert_eq!(connect(&mut server, &mut client), Some(0));
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let _ = network.bind(5);
+        let rebind = |inbox: Inbox| inbox.into_iter().map(|(data, _)| (data, Endpoint::local_port(5))).collect::<Inbox>();
+
+        // not enabled: the packet from the new address is denied
+        client.send(&[1]).unwrap();
+        network.deliver(1, rebind(network.hold(1)));
+        assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+        assert_eq!(server.connection(0).unwrap().addrs(), Endpoint::local_port(2));
+        drop(network.hold(5));
+
+        server.set_migration_enabled(true);
+        client.send(&[2]).unwrap();
+        let held = network.hold(1);
+        network.deliver(1, rebind(held.clone()));
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::PacketReceived(0, _, _, [2]))));
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ClientMigrated(0, old, new))
+            if old == Endpoint::local_port(2) && new == Endpoint::local_port(5)));
+        // replays from the old address can't move the connection back
+        network.deliver(1, held);
+        assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+
+        server.send(0, &[3]).unwrap();
+        network.deliver(2, network.hold(5));
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::PacketReceived(_, _, [3]))));
+    }
+
+    #[test]
+    fn test_migration_requires_key() {
+        let network = MemoryNetwork::default();
+        let (mut server, mut client) = connected_pair(&network);
+        server.set_migration_enabled(true);
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let _ = network.bind(5);
+
+        client.send(&[1]).unwrap();
+        let rebound = network.hold(1).into_iter().map(|(data, _)| (data, Endpoint::local_port(5))).collect();
+        network.deliver(1, rebound);
+        assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+        assert_eq!(server.connection(0).unwrap().addrs(), Endpoint::local_port(2));
+    }
+
+    #[test]
+    fn test_client_addr() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let mut client = Client::new(network.bind(2), "test");
+        client.connect(Endpoint::local_port(1)).unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        client.update();
+        match server.next_event_into(&mut buffer).unwrap() {
+            Some(ServerEvent::ClientConnected(0, addrs, _)) => assert_eq!(addrs, Endpoint::local_port(2)),
+            event => panic!("unexpected event {:?}", event)
+        }
+        assert_eq!(server.client_addr(0), Some(Endpoint::local_port(2)));
+        server.disconnect(0).unwrap();
+        assert_eq!(server.client_addr(0), Some(Endpoint::local_port(2)));
+        while server.next_event_into(&mut buffer).unwrap().is_none() {
+            server.update();
+        }
+        assert_eq!(server.client_addr(0), None);
+        assert_eq!(server.client_addr(1), None);
+    }
+
+    #[test]
+    fn test_denial_events() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let mut first = Client::new(network.bind(2), "test");
+        let mut second = Client::new(network.bind(3), "test");
+        first.connect(Endpoint::local_port(1)).unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        first.update();
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ClientConnected(0, _, _))));
+
+        for _ in 0..3 {
+            second.connect(Endpoint::local_port(1)).unwrap();
+            second.update();
+        }
+        match server.next_event_into(&mut buffer).unwrap() {
+            Some(ServerEvent::ConnectionDenied(addrs, DenyReason::ServerFull)) => assert_eq!(addrs, Endpoint::local_port(3)),
+            event => panic!("unexpected event {:?}", event)
+        }
+        assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 4);
+        server.set_rate_limit(Some(RateLimit { per_ip: 0.0, global: 100.0, burst: 2.0 }));
+        let mut attacker = Client::new(network.bind(3), "test");
+        attacker.connect(Endpoint::local_port(1)).unwrap();
+        attacker.update();
+        let (request, _) = network.hold(1).pop_front().unwrap();
+        let spoofed = |port: u16| std::net::SocketAddr::from(([10, 0, 0, 1], port));
+        network.deliver(1, (0..50).map(|i| (request.clone(), spoofed(4000 + i))).collect());
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ClientConnected(0, addrs, _)) if addrs == spoofed(4000)));
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ClientConnected(1, addrs, _)) if addrs == spoofed(4001)));
+        assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+        assert_eq!(server.socket_stats().requests_dropped_rate_limited, 48);
+
+        let mut client = Client::new(network.bind(2), "test");
+        assert_eq!(connect(&mut server, &mut client), Some(2));
+        for i in 0..10 {
+            client.send(&[i]).unwrap();
+        }
+        for _ in 0..10 {
+            assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::PacketReceived(2, _, _, _))));
+        }
+    }
+
+    #[test]
+    fn test_client_id_by_addr() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 2);
+        let mut clients: Vec<Client> = (2..4).map(|port| Client::new(network.bind(port), "test")).collect();
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        for client in clients.iter_mut() {
+            client.connect(Endpoint::local_port(1)).unwrap();
+            client.update();
+            while server.next_event_into(&mut buffer).unwrap().is_some() {}
+        }
+
+        for (id, connection) in server.iter_connections() {
+            let addrs = connection.addrs();
+            assert_eq!(connection.id(), id);
+            assert_eq!(server.client_addr(id), Some(addrs));
+            assert_eq!(server.client_id_by_addr(addrs), Some(id));
+        }
+        assert_eq!(server.iter_connections().count(), 2);
+        assert_eq!(server.client_id_by_addr(Endpoint::local_port(4)), None);
+    }
+
+    #[test]
+    fn test_socket_errors() {
+        let network = MemoryNetwork::default();
+        let (mut server, mut client) = connected_pair(&network);
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+        network.fail_recv(1, Some(ErrorKind::NetworkDown));
+        network.fail_recv(2, Some(ErrorKind::ConnectionReset));
+        for _ in 0..2 {
+            server.update();
+            client.update();
+            assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::SocketError(ErrorKind::NetworkDown))));
+            assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::SocketError(ErrorKind::ConnectionReset))));
+            // only reported once per burst
+            assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+            assert!(client.next_event_into(&mut buffer).unwrap().is_none());
+
+            // receiving a packet ends the burst
+            network.fail_recv(1, None);
+            network.fail_recv(2, None);
+            client.send(b"hello").unwrap();
+            assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::PacketReceived(0, _, _, b"hello"))));
+            server.send(0, b"hello").unwrap();
+            assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::PacketReceived(_, _, b"hello"))));
+            while client.next_event_into(&mut buffer).unwrap().is_some() {}
+            network.fail_recv(1, Some(ErrorKind::NetworkDown));
+            network.fail_recv(2, Some(ErrorKind::ConnectionReset));
+        }
+        assert!(client.is_connected());
+
+        while server.next_event_into(&mut buffer).unwrap().is_some() {}
+        network.fail_recv(1, Some(ErrorKind::PermissionDenied));
+        assert_eq!(server.next_event_into(&mut buffer).unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_short_writes() {
+        let network = MemoryNetwork::default();
+        let (mut server, mut client) = connected_pair(&network);
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+        network.truncate_sends(2, Some(10));
+        assert!(matches!(client.send(b"hello"), Err(ConnectionError::Disconnected)));
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Disconnected(ClientDisconnectReason::SocketError(ErrorKind::WriteZero)))));
+
+        network.truncate_sends(1, Some(10));
+        assert!(matches!(server.send(0, b"hello"), Err(ConnectionError::Disconnected)));
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ClientDisconnected(0, ServerDisconnectReason::SocketError(ErrorKind::WriteZero)))));
+
+        // packets outside of a connection fail the same way
+        let mut client = Client::new(network.bind(3), "test");
+        network.truncate_sends(3, Some(10));
+        client.connect(Endpoint::local_port(1)).unwrap();
+        client.update();
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Disconnected(ClientDisconnectReason::SocketError(ErrorKind::WriteZero)))));
+    }
+
+    #[test]
+    fn test_reconnect_while_closing() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 2);
+        let mut client = Client::new(network.bind(2), "test");
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        assert_eq!(connect(&mut server, &mut client), Some(0));
+
+        // the old connection keeps its slot until all disconnects are sent
+        server.disconnect(0).unwrap();
+        assert_eq!(connect(&mut server, &mut client), Some(1));
+        assert_eq!(server.client_id_by_addr(Endpoint::local_port(2)), Some(1));
+
+        client.send(b"hello").unwrap();
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::PacketReceived(1, _, _, b"hello"))));
+    }
+
+    #[test]
+    fn test_bind() {
+        let mut server = Server::bind(Endpoint::local_any(), "test", 1).unwrap();
+        let mut client = Client::bind("test").unwrap();
+        // would block forever if the sockets were blocking
+        assert!(server.next_event().unwrap().is_none());
+        assert!(client.next_event().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_packet_budget() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let stranger = network.bind(2);
+        for _ in 0..10_000 {
+            stranger.send_to(&[0xFF; 64], Endpoint::local_port(1)).unwrap();
+        }
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        assert!(server.next_event_budgeted(&mut buffer, 100).unwrap().is_none());
+        assert_eq!(server.socket_stats().packets_received, 100);
+        // the remaining packets are processed by later calls
+        let mut calls = 1;
+        while server.socket_stats().packets_received < 10_000 {
+            assert!(server.next_event_budgeted(&mut buffer, 100).unwrap().is_none());
+            calls += 1;
+        }
+        assert_eq!(calls, 100);
+        assert_eq!(server.socket_stats().foreign_packets, 10_000);
+    }
+
+    #[test]
+    fn test_delivery_queue_size() {
+        let network = MemoryNetwork::default();
+        let config = ConnectionConfig { delivery_queue_size: 4, ..Default::default() };
+        let mut server = Server::new_with_config(network.bind(1), "test", 1, config).unwrap();
+        let mut client = Client::new(network.bind(2), "test");
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        connect(&mut server, &mut client);
+
+        for i in 0..6 {
+            server.send(0, &[i]).unwrap();
+        }
+        while client.next_event_into(&mut buffer).unwrap().is_some() {}
+        // acknowledges all six packets at once
+        client.send(&[0]).unwrap();
+        let mut acked = 0;
+        while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+            if let ServerEvent::PacketAcknowledged(..) = event {
+                acked += 1;
+            }
+        }
+        assert_eq!(acked, 4);
+        assert_eq!(server.socket_stats().events_dropped, 2);
+        assert!(Server::new_with_config(network.bind(3), "test", 1, ConnectionConfig { delivery_queue_size: 0, ..Default::default() }).is_err());
+    }
+
+    #[test]
+    fn test_packet_lost_cutoff() {
+        let burst = |config: ConnectionConfig| {
+            let network = MemoryNetwork::default();
+            let mut server = Server::new_with_config(network.bind(1), "test", 1, config).unwrap();
+            let mut client = Client::new(network.bind(2), "test");
+            let mut buffer = [0u8; MAX_PACKET_SIZE];
+            connect(&mut server, &mut client);
+
+            // 2000 packets go out before any ack returns and the acks arrive newest first
+            let mut acks = Vec::new();
+            for _ in 0..250 {
+                for i in 0..8 {
+                    server.send(0, &[i]).unwrap();
+                }
+                while client.next_event_into(&mut buffer).unwrap().is_some() {}
+                client.update();
+                acks.extend(network.hold(1));
+            }
+            network.deliver(1, acks.into_iter().rev().collect());
+            let (mut acked, mut lost) = (0, 0);
+            while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+                match event {
+                    ServerEvent::PacketAcknowledged(..) => acked += 1,
+                    ServerEvent::PacketLost(..) => lost += 1,
+                    _ => {}
+                }
+            }
+            (acked, lost)
+        };
+        let (acked, lost) = burst(ConnectionConfig::default());
+        assert!(lost > 0 && acked + lost < 2000);
+        assert_eq!(burst(ConnectionConfig { sent_packets_capacity: 4096, packet_lost_cutoff: 2048, ..Default::default() }), (2000, 0));
+
+        let network = MemoryNetwork::default();
+        for (sent_packets_capacity, packet_lost_cutoff) in [(1000, 40), (1 << 16, 40), (1024, 1024), (1024, 0)] {
+            let invalid = ConnectionConfig { sent_packets_capacity, packet_lost_cutoff, ..Default::default() };
+            assert!(matches!(Server::new_with_config(network.bind(1), "test", 1, invalid), Err(ConnectionError::InvalidConfig(_))));
+        }
+    }
+
+    #[test]
+    fn test_receive_order() {
+        let network = MemoryNetwork::default();
+        let (mut server, mut client) = connected_pair(&network);
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+        let first = client.send(&[1]).unwrap();
+        let second = client.send(&[2]).unwrap();
+        let packets = network.hold(1);
+        network.deliver(1, packets.iter().rev().chain(packets.iter()).cloned().collect());
+        let mut events = Vec::new();
+        while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+            if let ServerEvent::PacketReceived(0, seq, result, _) = event {
+                events.push((seq, result));
+            }
+        }
+        assert_eq!(events, [(second, SequenceResult::Latest), (first, SequenceResult::Fresh)]);
+        assert_eq!(server.client_stats(0).unwrap().duplicates_dropped, 2);
+    }
+
+    #[test]
+    fn test_ack_width() {
+        let burst = |ack_width: AckWidth| {
+            let network = MemoryNetwork::default();
+            let config = ConnectionConfig { ack_width, ..Default::default() };
+            let mut server = Server::new_with_config(network.bind(1), "test", 1, config).unwrap();
+            let mut client = Client::new_with_config(network.bind(2), "test", config).unwrap();
+            let mut buffer = [0u8; MAX_PACKET_SIZE];
+            connect(&mut server, &mut client);
+            assert_eq!(server.max_payload_size(0).unwrap(), FALLBACK_PROBE_SIZE as usize - payload_overhead(ack_width));
+
+            // every packet arrives, but only the newest ack makes it back.
+            // the client acks every ACK_THRESHOLD packets, so that one covers all of them
+            for i in 0..ACK_THRESHOLD * 7 {
+                server.send(0, &[i as u8]).unwrap();
+            }
+            while client.next_event_into(&mut buffer).unwrap().is_some() {}
+            client.update();
+            let newest = network.hold(1).pop_back().unwrap();
+            network.deliver(1, [newest].into_iter().collect());
+            let (mut acked, mut lost) = (0, 0);
+            while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+                match event {
+                    ServerEvent::PacketAcknowledged(..) => acked += 1,
+                    ServerEvent::PacketLost(..) => lost += 1,
+                    _ => {}
+                }
+            }
+            (acked, lost)
+        };
+        let (acked, lost) = burst(AckWidth::Bits32);
+        assert!(acked <= 33 && lost > 0);
+        assert_eq!(burst(AckWidth::Bits64), (56, 0));
+        assert_eq!(burst(AckWidth::Bits128), (56, 0));
+
+        // peers with different widths speak different protocols
+        let network = MemoryNetwork::default();
+        let mut server = Server::new_with_config(network.bind(1), "test", 1, ConnectionConfig { ack_width: AckWidth::Bits128, ..Default::default() }).unwrap();
+        let mut client = Client::new(network.bind(2), "test");
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        client.connect(Endpoint::local_port(1)).unwrap();
+        client.update();
+        assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+        assert_eq!(server.socket_stats().bad_signature_packets, 1);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 2);
+        let mut clients: Vec<Client> = (2..4).map(|port| Client::new(network.bind(port), "test")).collect();
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        for client in clients.iter_mut() {
+            connect(&mut server, client);
+        }
+
+        server.shutdown(Duration::ZERO);
+        assert!(server.is_draining());
+        server.update();
+        let mut disconnected = 0;
+        while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+            assert!(matches!(event, ServerEvent::ClientDisconnected(_, ServerDisconnectReason::Disconnected)));
+            disconnected += 1;
+        }
+        assert_eq!(disconnected, 2);
+        assert!(!server.is_draining());
+        for client in clients.iter_mut() {
+            assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::Disconnected(ClientDisconnectReason::Disconnected))));
+        }
+
+        clients[0].connect(Endpoint::local_port(1)).unwrap();
+        clients[0].update();
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::ConnectionDenied(_, DenyReason::ShuttingDown))));
+    }
+
+    #[test]
+    fn test_ping() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let mut client = Client::new(network.bind(2), "test");
+        assert!(matches!(client.ping(), Err(ConnectionError::Disconnected)));
+        connect(&mut server, &mut client);
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+        let first = client.ping().unwrap();
+        let second = client.ping().unwrap();
+        assert_ne!(first, second);
+        assert_eq!(client.in_flight().unwrap(), 0);
+        assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+        let mut pongs = Vec::new();
+        while let Some(event) = client.next_event_into(&mut buffer).unwrap() {
+            match event {
+                ClientEvent::Pong(seq, _) => pongs.push(seq),
+                event => panic!("unexpected event {:?}", event)
+            }
+        }
+        assert_eq!(pongs, [first, second]);
+
+        let seq = server.ping(0).unwrap();
+        while client.next_event_into(&mut buffer).unwrap().is_some() {}
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::Pong(0, s, _)) if s == seq));
+    }
+
+    #[test]
+    fn test_first_payload() {
+        let network = MemoryNetwork::default();
+        let (mut server, mut client) = connected_pair(&network);
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+        let seq = client.send(b"first").unwrap();
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::PacketReceived(0, s, SequenceResult::Latest, b"first")) if s == seq));
+        let seq = server.send(0, b"first").unwrap();
+        assert!(matches!(client.next_event_into(&mut buffer).unwrap(), Some(ClientEvent::PacketReceived(s, SequenceResult::Latest, b"first")) if s == seq));
+    }
+
+    #[test]
+    fn test_tagged_packets() {
+        let network = MemoryNetwork::default();
+        let (mut server, mut client) = connected_pair(&network);
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+        let tagged = client.send_tagged(&[1], 7).unwrap();
+        let untagged = client.send(&[2]).unwrap();
+        let lost = server.send_tagged(0, &[3], 42).unwrap();
+        network.hold(2);
+        while server.next_event_into(&mut buffer).unwrap().is_some() {}
+        for i in 0..=PACKET_LOST_CUTOFF {
+            server.send_tagged(0, &[4], i as u64 + 100).unwrap();
+        }
+        let mut acked = Vec::new();
+        while let Some(event) = client.next_event_into(&mut buffer).unwrap() {
+            if let ClientEvent::PacketAcknowledged(seq, tag, _) = event {
+                acked.push((seq, tag));
+            }
+        }
+        assert_eq!(acked, [(tagged, 7), (untagged, 0)]);
+
+        client.send(&[5]).unwrap();
+        let (mut acked, mut lost_tags) = (Vec::new(), Vec::new());
+        while let Some(event) = server.next_event_into(&mut buffer).unwrap() {
+            match event {
+                ServerEvent::PacketLost(0, seq, tag) => lost_tags.push((seq, tag)),
+                ServerEvent::PacketAcknowledged(0, _, tag, _) => acked.push(tag),
+                _ => {}
+            }
+        }
+        assert_eq!(lost_tags, [(lost, 42)]);
+        assert_eq!(acked, (100..=100 + PACKET_LOST_CUTOFF as u64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_ack_rtt() {
+        let network = MemoryNetwork::default();
+        let (mut server, mut client) = connected_pair(&network);
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+        let seq = client.send(&[1]).unwrap();
+        while server.next_event_into(&mut buffer).unwrap().is_some() {}
+        std::thread::sleep(Duration::from_millis(20));
+        server.send(0, &[2]).unwrap();
+        let mut rtts = Vec::new();
+        while let Some(event) = client.next_event_into(&mut buffer).unwrap() {
+            if let ClientEvent::PacketAcknowledged(s, _, rtt) = event {
+                assert_eq!(s, seq);
+                rtts.push(rtt);
+            }
+        }
+        assert!(matches!(rtts[..], [rtt] if rtt >= Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn test_next_update() {
+        let network = MemoryNetwork::default();
+        let mut server = Server::new(network.bind(1), "test", 1);
+        let mut client = Client::new(network.bind(2), "test");
+        assert_eq!(server.update(), Duration::MAX);
+        assert_eq!(client.update(), Duration::MAX);
+        let config = ConnectConfig { retry_interval: Duration::from_secs(1), ..Default::default() };
+        client.connect_with(Endpoint::local_port(1), config).unwrap();
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let next = client.update();
+        assert!(next > Duration::from_millis(500) && next <= Duration::from_secs(1), "{:?}", next);
+        while server.next_event_into(&mut buffer).unwrap().is_some() {}
+        while client.next_event_into(&mut buffer).unwrap().is_some() {}
+        assert!(client.is_connected());
+
+        let next = server.update();
+        assert!(next <= KEEPALIVE_INTERVAL && next > KEEPALIVE_INTERVAL / 2, "{:?}", next);
+        client.send_batched(&[1]).unwrap();
+        assert_eq!(client.next_update(), Duration::ZERO);
+        let next = client.update();
+        assert!(next > Duration::ZERO && next <= PROBE_INTERVAL, "{:?}", next);
+
+        server.disconnect(0).unwrap();
+        assert_eq!(server.update(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_quality_events() {
+        let network = MemoryNetwork::default();
+        let poor = ConnectionConfig { quality: QualityConfig { bad_rtt_ms: 0, ..Default::default() }, ..Default::default() };
+        let mut server = Server::new_with_config(network.bind(1), "test", 1, poor).unwrap();
+        let mut client = Client::new(network.bind(2), "test");
+        connect(&mut server, &mut client);
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        assert_eq!(client.quality().unwrap(), NetworkQuality::Good);
+        assert_eq!(server.quality(0).unwrap(), NetworkQuality::Good);
+
+        client.update();
+        server.update();
+        assert!(client.next_event_into(&mut buffer).unwrap().is_none());
+        assert!(matches!(server.next_event_into(&mut buffer).unwrap(), Some(ServerEvent::QualityChanged(0, NetworkQuality::Poor))));
+        assert!(server.next_event_into(&mut buffer).unwrap().is_none());
+        assert_eq!(server.quality(0).unwrap(), NetworkQuality::Poor);
+    }
+
+}
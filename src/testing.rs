@@ -0,0 +1,122 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use crate::socket::{Endpoint, Transport};
+
+pub type Inbox = VecDeque<(Box<[u8]>, SocketAddr)>;
+
+#[derive(Debug, Default, Clone)]
+pub struct MemoryNetwork {
+    inboxes: Rc<RefCell<HashMap<SocketAddr, Inbox>>>,
+    recv_errors: Rc<RefCell<HashMap<SocketAddr, ErrorKind>>>,
+    send_limits: Rc<RefCell<HashMap<SocketAddr, usize>>>,
+    mtu: Option<usize>
+}
+
+impl MemoryNetwork {
+
+    /// Creates a network that silently drops all packets larger than `mtu`
+    pub fn with_mtu(mtu: usize) -> Self {
+        Self {
+            inboxes: Default::default(),
+            recv_errors: Default::default(),
+            send_limits: Default::default(),
+            mtu: Some(mtu)
+        }
+    }
+
+    /// Takes all packets waiting for `port`, they can be delivered later using [`MemoryNetwork::deliver`]
+    pub fn hold(&self, port: u16) -> Inbox {
+        self.inboxes.borrow_mut().get_mut(&Endpoint::local_port(port)).map(std::mem::take).unwrap_or_default()
+    }
+
+    pub fn deliver(&self, port: u16, packets: Inbox) {
+        if let Some(inbox) = self.inboxes.borrow_mut().get_mut(&Endpoint::local_port(port)) {
+            inbox.extend(packets);
+        }
+    }
+
+    /// Makes every receive on `port` fail with `kind` until it is reset with `None`
+    pub fn fail_recv(&self, port: u16, kind: Option<ErrorKind>) {
+        let addrs = Endpoint::local_port(port);
+        match kind {
+            Some(kind) => self.recv_errors.borrow_mut().insert(addrs, kind),
+            None => self.recv_errors.borrow_mut().remove(&addrs)
+        };
+    }
+
+    /// Makes every send from `port` write at most `limit` bytes, like a short write, until it is reset with `None`
+    pub fn truncate_sends(&self, port: u16, limit: Option<usize>) {
+        let addrs = Endpoint::local_port(port);
+        match limit {
+            Some(limit) => self.send_limits.borrow_mut().insert(addrs, limit),
+            None => self.send_limits.borrow_mut().remove(&addrs)
+        };
+    }
+
+    pub fn bind(&self, port: u16) -> MemoryTransport {
+        let addrs = Endpoint::local_port(port);
+        self.inboxes.borrow_mut().insert(addrs, VecDeque::new());
+        MemoryTransport {
+            network: self.clone(),
+            addrs,
+            flaky: None
+        }
+    }
+
+    /// Binds a transport that fails every other send with `WouldBlock`
+    pub fn bind_flaky(&self, port: u16) -> MemoryTransport {
+        MemoryTransport {
+            flaky: Some(Cell::new(false)),
+            ..self.bind(port)
+        }
+    }
+
+}
+
+#[derive(Debug)]
+pub struct MemoryTransport {
+    network: MemoryNetwork,
+    addrs: SocketAddr,
+    flaky: Option<Cell<bool>>
+}
+
+impl Transport for MemoryTransport {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
+        if let Some(blocked) = &self.flaky {
+            if blocked.replace(!blocked.get()) {
+                return Err(Error::from(ErrorKind::WouldBlock));
+            }
+        }
+        if self.network.mtu.is_some_and(|mtu| buf.len() > mtu) {
+            return Ok(buf.len());
+        }
+        let buf = match self.network.send_limits.borrow().get(&self.addrs) {
+            Some(&limit) => &buf[..buf.len().min(limit)],
+            None => buf
+        };
+        if let Some(inbox) = self.network.inboxes.borrow_mut().get_mut(&addr) {
+            inbox.push_back((buf.into(), self.addrs));
+        }
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        if let Some(&kind) = self.network.recv_errors.borrow().get(&self.addrs) {
+            return Err(Error::from(kind));
+        }
+        match self.network.inboxes.borrow_mut().get_mut(&self.addrs).and_then(|inbox| inbox.pop_front()) {
+            None => Err(Error::from(ErrorKind::WouldBlock)),
+            Some((data, src)) => {
+                buf[..data.len()].copy_from_slice(&data);
+                Ok((data.len(), src))
+            }
+        }
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.addrs)
+    }
+}
@@ -0,0 +1,170 @@
+use std::io::Result;
+use std::marker::PhantomData;
+use std::time::Duration;
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::error::ChannelError;
+use crate::reliable::{MessageChannel, Priority};
+use crate::sequencing::SequenceNumber;
+
+/// Variable length integers and no trailing bytes
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+/// A [`MessageChannel`] that sends values of `T` instead of raw bytes. The values are encoded with bincode.
+///
+/// ```
+/// # use udp_connections::TypedChannel;
+/// let mut sender = TypedChannel::<u32>::new();
+/// let mut receiver = TypedChannel::<u32>::new();
+/// sender.queue(&42).unwrap();
+/// receiver.on_receive(sender.send_packets(0).unwrap()).unwrap();
+/// assert_eq!(receiver.receive().unwrap().unwrap(), 42);
+/// ```
+#[derive(Debug)]
+pub struct TypedChannel<T> {
+    channel: MessageChannel,
+    _message: PhantomData<fn(T) -> T>
+}
+
+impl<T: Serialize + DeserializeOwned> TypedChannel<T> {
+
+    /// An ordered channel, other channels can be converted with [`From`]
+    pub fn new() -> Self {
+        Self::from(MessageChannel::new())
+    }
+
+    pub fn channel(&self) -> &MessageChannel {
+        &self.channel
+    }
+
+    pub fn channel_mut(&mut self) -> &mut MessageChannel {
+        &mut self.channel
+    }
+
+    pub fn into_inner(self) -> MessageChannel {
+        self.channel
+    }
+
+    /// Queues the message with [`Priority::Normal`]
+    pub fn queue(&mut self, msg: &T) -> std::result::Result<(), ChannelError> {
+        self.queue_with_priority(msg, Priority::Normal)
+    }
+
+    /// See [`MessageChannel::queue_message_with_priority`]. The encoded message that [`ChannelError::QueueFull`] hands back
+    /// can be queued on the inner channel directly.
+    pub fn queue_with_priority(&mut self, msg: &T, priority: Priority) -> std::result::Result<(), ChannelError> {
+        let data = options()
+            .serialize(msg)
+            .map_err(|err| ChannelError::Unserializable(err.to_string()))?;
+        self.channel.queue_message_owned_with_priority(data, priority)
+    }
+
+    /// The next message, or [`ChannelError::InvalidMessage`] for a message that could not be decoded.
+    /// Such messages are dropped, so the following ones can still be received.
+    pub fn receive(&mut self) -> Option<std::result::Result<T, ChannelError>> {
+        self.channel.receive_message().map(|msg| options()
+            .deserialize(&msg)
+            .map_err(|err| ChannelError::InvalidMessage(err.to_string())))
+    }
+
+    pub fn on_receive(&mut self, packet: &[u8]) -> Result<()> {
+        self.channel.on_receive(packet)
+    }
+
+    pub fn on_ack(&mut self, seq: SequenceNumber) {
+        self.channel.on_ack(seq)
+    }
+
+    pub fn on_loss(&mut self, seq: SequenceNumber) {
+        self.channel.on_loss(seq)
+    }
+
+    pub fn send_packets(&mut self, seq: SequenceNumber) -> Result<&[u8]> {
+        self.channel.send_packets(seq)
+    }
+
+    pub fn has_unsend_messages(&self) -> bool {
+        self.channel.has_unsend_messages()
+    }
+
+    pub fn has_due_messages(&self) -> bool {
+        self.channel.has_due_messages()
+    }
+
+    pub fn set_resend_interval(&mut self, resend_interval: Duration) {
+        self.channel.set_resend_interval(resend_interval)
+    }
+
+    /// See [`MessageChannel::reset`]. The messages that were not acknowledged are handed back decoded.
+    pub fn reset(&mut self) -> Vec<(Priority, T)> {
+        self.channel
+            .reset()
+            .into_iter()
+            .filter_map(|(priority, msg)| options().deserialize(&msg).ok().map(|msg| (priority, msg)))
+            .collect()
+    }
+
+}
+
+impl<T: Serialize + DeserializeOwned> Default for TypedChannel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> From<MessageChannel> for TypedChannel<T> {
+    fn from(channel: MessageChannel) -> Self {
+        Self {
+            channel,
+            _message: PhantomData
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ChannelError;
+    use crate::reliable::{MessageChannel, Priority};
+    use crate::typed::TypedChannel;
+
+    #[test]
+    fn test_typed_messages() {
+        let mut sender = TypedChannel::<(u32, String, Vec<u16>)>::new();
+        let mut receiver = TypedChannel::<(u32, String, Vec<u16>)>::new();
+        let msg = (7, String::from("hello"), vec![1, 300, 65535]);
+        sender.queue(&msg).unwrap();
+        sender.queue_with_priority(&(8, String::new(), Vec::new()), Priority::High).unwrap();
+        receiver.on_receive(sender.send_packets(0).unwrap()).unwrap();
+        assert_eq!(receiver.receive().unwrap().unwrap(), (8, String::new(), Vec::new()));
+        assert_eq!(receiver.receive().unwrap().unwrap(), msg);
+        assert!(receiver.receive().is_none());
+        assert_eq!(sender.reset(), [(Priority::High, (8, String::new(), Vec::new())), (Priority::Normal, msg)]);
+
+        // small numbers take up a single byte
+        let mut sender = TypedChannel::<u32>::new();
+        sender.queue(&42).unwrap();
+        sender.queue(&u32::MAX).unwrap();
+        let msg = sender.channel_mut().reset();
+        assert_eq!(msg.iter().map(|(_, msg)| msg.len()).collect::<Vec<_>>(), [1, 5]);
+    }
+
+    #[test]
+    fn test_invalid_message() {
+        let mut sender = MessageChannel::new();
+        let mut receiver = TypedChannel::<u32>::new();
+        // an invalid integer tag, trailing bytes, a truncated integer and finally a valid message
+        for msg in [[255].as_slice(), &[1, 2], &[252, 0], &[3]] {
+            sender.queue_message(msg).unwrap();
+        }
+        receiver.on_receive(sender.send_packets(0).unwrap()).unwrap();
+        for _ in 0..3 {
+            assert!(matches!(receiver.receive(), Some(Err(ChannelError::InvalidMessage(_)))));
+        }
+        assert_eq!(receiver.receive().unwrap().unwrap(), 3);
+        assert!(receiver.receive().is_none());
+    }
+
+}
@@ -0,0 +1,92 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use byteorder::{ReadBytesExt, WriteBytesExt};
+
+/// The number of bytes a varint of the given value occupies
+pub const fn varint_len(mut value: u32) -> usize {
+    let mut len = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        len += 1;
+    }
+    len
+}
+
+/// Reads LEB128 encoded variable length integers
+pub trait ReadVarint: Read {
+    fn read_varint(&mut self) -> Result<u32> {
+        let mut value = 0u32;
+        for shift in (0..32).step_by(7) {
+            let byte = self.read_u8()?;
+            value |= ((byte & 0x7F) as u32) << shift;
+            if byte & 0x80 == 0 {
+                if shift == 28 && byte > 0x0F {
+                    break;
+                }
+                return Ok(value);
+            }
+        }
+        Err(Error::new(ErrorKind::InvalidData, "varint too long"))
+    }
+}
+
+impl<R: Read + ?Sized> ReadVarint for R {}
+
+/// Writes LEB128 encoded variable length integers
+pub trait WriteVarint: Write {
+    fn write_varint(&mut self, mut value: u32) -> Result<()> {
+        while value >= 0x80 {
+            self.write_u8(value as u8 | 0x80)?;
+            value >>= 7;
+        }
+        self.write_u8(value as u8)
+    }
+}
+
+impl<W: Write + ?Sized> WriteVarint for W {}
+
+/// Splits the next `len` bytes off `data` without copying them
+pub fn read_slice<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if len > data.len() {
+        return Err(Error::from(ErrorKind::UnexpectedEof));
+    }
+    let (slice, rest) = data.split_at(len);
+    *data = rest;
+    Ok(slice)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wire::{read_slice, ReadVarint, varint_len, WriteVarint};
+
+    #[test]
+    fn test_varint() {
+        let values = [0, 1, 127, 128, 255, 16383, 16384, 65535, 65536, u32::MAX];
+        let lengths = [1, 1, 1, 2, 2, 2, 3, 3, 3, 5];
+        for (value, len) in values.into_iter().zip(lengths) {
+            let mut buffer = Vec::new();
+            buffer.write_varint(value).unwrap();
+            assert_eq!(buffer.len(), len, "{}", value);
+            assert_eq!(varint_len(value), len, "{}", value);
+            let mut data = buffer.as_slice();
+            assert_eq!(data.read_varint().unwrap(), value);
+            assert!(data.is_empty());
+            assert!(buffer[..len - 1].as_ref().read_varint().is_err());
+        }
+    }
+
+    #[test]
+    fn test_varint_overflow() {
+        assert!([0xFF, 0xFF, 0xFF, 0xFF, 0x10].as_ref().read_varint().is_err());
+        assert!([0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01].as_ref().read_varint().is_err());
+    }
+
+    #[test]
+    fn test_read_slice() {
+        let mut data = [1, 2, 3].as_ref();
+        assert_eq!(read_slice(&mut data, 2).unwrap(), [1, 2]);
+        assert!(read_slice(&mut data, 2).is_err());
+        assert_eq!(read_slice(&mut data, 1).unwrap(), [3]);
+        assert!(data.is_empty());
+    }
+
+}